@@ -0,0 +1,429 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::middleware::Logger;
+use actix_web::{web, Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use tracing::{error, info, warn};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::future::{ready, Ready};
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::waf::percent_decode;
+use crate::AppState;
+
+const DEFAULT_TEMPLATE: &str = r#"{remote} {request_id} {user} "{method} {path}" {status} {bytes} {duration_ms}ms {upstream}"#;
+
+/// Placeholders an access log template may use.
+const FIELDS: &[&str] = &[
+    "time",
+    "remote",
+    "method",
+    "path",
+    "query",
+    "status",
+    "duration_ms",
+    "bytes",
+    "user",
+    "upstream",
+    "upstream_addr",
+    "request_id",
+    "user_agent",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    /// actix's request logger, through the application log
+    App,
+    Stdout,
+    File(String),
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Json,
+    /// Text with `{field}` placeholders
+    Template(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub target: AccessLogTarget,
+    pub format: AccessLogFormat,
+    /// Size at which the log file is rotated; never when 0
+    pub max_bytes: u64,
+    /// Rotated files kept next to the log (`<path>.1` is the newest)
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    /// `ACCESS_LOG` is `app` (the default), `stdout`, `off` or a file path;
+    /// `ACCESS_LOG_FORMAT` is `json` or a template such as
+    /// `{method} {path} {status} {duration_ms}`. Files rotate at
+    /// `ACCESS_LOG_MAX_BYTES`, keeping `ACCESS_LOG_MAX_FILES`.
+    pub fn from_env() -> Self {
+        let target = match env::var("ACCESS_LOG").ok().filter(|v| !v.is_empty()).as_deref() {
+            None | Some("app") => AccessLogTarget::App,
+            Some("stdout") => AccessLogTarget::Stdout,
+            Some("off") => AccessLogTarget::Off,
+            Some(path) => AccessLogTarget::File(path.to_string()),
+        };
+        let format = match env::var("ACCESS_LOG_FORMAT").ok().filter(|v| !v.is_empty()) {
+            Some(format) if format == "json" => AccessLogFormat::Json,
+            Some(template) => {
+                for field in placeholders(&template).filter(|field| !FIELDS.contains(field)) {
+                    warn!("Unknown access log field {{{}}} will be written as is", field);
+                }
+                AccessLogFormat::Template(template)
+            }
+            None => AccessLogFormat::Template(DEFAULT_TEMPLATE.to_string()),
+        };
+        AccessLogConfig {
+            target,
+            format,
+            max_bytes: parse_env("ACCESS_LOG_MAX_BYTES").unwrap_or(100 * 1024 * 1024),
+            max_files: parse_env("ACCESS_LOG_MAX_FILES").unwrap_or(5),
+        }
+    }
+}
+
+/// Query parameters holding credentials, like the `?token=` browsers send
+/// on WebSocket upgrades; their values never reach a log.
+const SECRET_PARAMS: &[&str] = &["token", "access_token"];
+
+/// `query` with the values of credential parameters replaced. Names are
+/// compared decoded, as the handlers reading them see them.
+pub fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&percent_decode(name, true).as_str()) => format!("{}=REDACTED", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// The request line `middleware::Logger` writes for `%r`, with the query
+// redacted
+fn request_line(req: &ServiceRequest) -> String {
+    let target = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), redact_query(query)),
+    };
+    format!("{} {} {:?}", req.method(), target, req.version())
+}
+
+/// actix's `Logger` in its default format, except that credentials in the
+/// query are redacted.
+pub fn request_logger() -> Logger {
+    Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("request_line", request_line)
+}
+
+// Names inside `{...}` in a template
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+}
+
+// Log file rotated by size
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    // Shift `<path>.N` up by one, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(format!("{}.{}", self.path, n), format!("{}.{}", self.path, n + 1));
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+enum Sink {
+    None,
+    Stdout,
+    File(Mutex<RotatingFile>),
+}
+
+/// Writes one line per request to its own destination, apart from the
+/// application log.
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    sink: Sink,
+}
+
+impl AccessLogger {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let sink = match &config.target {
+            AccessLogTarget::App | AccessLogTarget::Off => Sink::None,
+            AccessLogTarget::Stdout => Sink::Stdout,
+            AccessLogTarget::File(path) => match RotatingFile::open(path, config.max_bytes, config.max_files) {
+                Ok(file) => {
+                    info!("Writing access log to {}", path);
+                    Sink::File(Mutex::new(file))
+                }
+                Err(e) => {
+                    error!("Failed to open access log {}: {}", path, e);
+                    Sink::None
+                }
+            },
+        };
+        AccessLogger {
+            format: config.format.clone(),
+            sink,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !matches!(self.sink, Sink::None)
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match &self.format {
+            AccessLogFormat::Json => entry.json(),
+            AccessLogFormat::Template(template) => entry.render(template),
+        };
+        match &self.sink {
+            Sink::None => {}
+            Sink::Stdout => println!("{}", line),
+            Sink::File(file) => {
+                if let Err(e) = file.lock().unwrap().write_line(&line) {
+                    error!("Failed to write access log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// What is logged about one request
+struct Entry {
+    time: String,
+    remote: Option<String>,
+    method: String,
+    path: String,
+    query: String,
+    status: u16,
+    duration_ms: f64,
+    bytes: Option<u64>,
+    user: Option<String>,
+    upstream: Option<(String, String)>,
+    request_id: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn field(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "time" => self.time.clone(),
+            "remote" => self.remote.clone()?,
+            "method" => self.method.clone(),
+            "path" => self.path.clone(),
+            "query" => Some(self.query.clone()).filter(|q| !q.is_empty())?,
+            "status" => self.status.to_string(),
+            "duration_ms" => format!("{:.3}", self.duration_ms),
+            "bytes" => self.bytes?.to_string(),
+            "user" => self.user.clone()?,
+            "upstream" => self.upstream.as_ref()?.0.clone(),
+            "upstream_addr" => self.upstream.as_ref()?.1.clone(),
+            "request_id" => self.request_id.clone()?,
+            "user_agent" => self.user_agent.clone()?,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Fill in `{field}` placeholders; missing values are written as `-`
+    fn render(&self, template: &str) -> String {
+        let mut line = String::with_capacity(template.len() + 64);
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            line.push_str(&rest[..start]);
+            match rest[start + 1..].split_once('}') {
+                Some((name, after)) if FIELDS.contains(&name) => {
+                    line.push_str(self.field(name).as_deref().unwrap_or("-"));
+                    rest = after;
+                }
+                _ => {
+                    line.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        line.push_str(rest);
+        line
+    }
+
+    fn json(&self) -> String {
+        serde_json::json!({
+            "time": self.time,
+            "remote": self.remote,
+            "method": self.method,
+            "path": self.path,
+            "query": Some(&self.query).filter(|q| !q.is_empty()),
+            "status": self.status,
+            "duration_ms": (self.duration_ms * 1000.0).round() / 1000.0,
+            "bytes": self.bytes,
+            "user": self.user,
+            "upstream": self.upstream.as_ref().map(|(service, _)| service),
+            "upstream_addr": self.upstream.as_ref().map(|(_, instance)| instance),
+            "request_id": self.request_id,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+}
+
+/// Middleware writing the access log. Duration runs until the response head
+/// is ready, and streamed bodies of unknown length are logged as `-` bytes.
+pub struct AccessLog {
+    logger: Arc<AccessLogger>,
+}
+
+impl AccessLog {
+    pub fn new(logger: Arc<AccessLogger>) -> Self {
+        AccessLog { logger }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware {
+            service: Rc::new(service),
+            logger: self.logger.clone(),
+        }))
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: Rc<S>,
+    logger: Arc<AccessLogger>,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let logger = self.logger.clone();
+        if !logger.enabled() {
+            return Box::pin(service.call(req));
+        }
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let mut entry = Entry {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            remote: req
+                .app_data::<web::Data<AppState>>()
+                .and_then(|data| data.ip_filter.client_ip(req.request()))
+                .map(|ip| ip.to_string()),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            query: redact_query(req.query_string()),
+            status: 0,
+            duration_ms: 0.0,
+            bytes: None,
+            user: None,
+            upstream: None,
+            request_id: None,
+            user_agent: header("User-Agent"),
+        };
+
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = service.call(req).await;
+            entry.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+            match &result {
+                Ok(res) => {
+                    entry.status = res.status().as_u16();
+                    if let BodySize::Sized(size) = res.response().body().size() {
+                        entry.bytes = Some(size);
+                    }
+                    let req = res.request();
+                    entry.user = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+                    entry.upstream = crate::inflight::upstream(req);
+                    entry.request_id = crate::logging::request_id(req);
+                }
+                Err(e) => entry.status = e.as_response_error().status_code().as_u16(),
+            }
+            logger.write(&entry);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_tokens_in_the_query() {
+        assert_eq!(redact_query("room=1&token=abc.def.ghi"), "room=1&token=REDACTED");
+        assert_eq!(redact_query("access_token=abc"), "access_token=REDACTED");
+        assert_eq!(redact_query("tokens=1&room=2"), "tokens=1&room=2");
+    }
+
+    #[test]
+    fn redacts_encoded_parameter_names() {
+        assert_eq!(redact_query("%74oken=abc"), "%74oken=REDACTED");
+    }
+}
@@ -0,0 +1,1202 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Result};
+use futures_util::future::LocalBoxFuture;
+use tracing::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use crate::apikeys::ApiKeyRequest;
+use crate::audit;
+use crate::auth::AuthMiddleware;
+use crate::cidr::Cidr;
+use crate::config::effective_settings;
+use crate::exemptions::ExemptionRequest;
+use crate::faults::FaultRequest;
+use crate::flags::Flag;
+use crate::logging::{self, LogFilter};
+use crate::maintenance::MaintenanceWindow;
+use crate::routes::BUILTIN_SERVICES;
+use crate::status::circuit_state_name;
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    /// JWT role that opens the admin API, besides `ADMIN_API_TOKEN`
+    pub role: String,
+    /// Networks admin requests may come from; any when empty
+    pub allowlist: Vec<Cidr>,
+}
+
+impl AdminConfig {
+    /// Read `ADMIN_ROLE` and `ADMIN_IP_ALLOWLIST`. An unparsable allowlist
+    /// entry is an error: dropping it could leave the list empty and open.
+    pub fn from_env() -> Result<Self, String> {
+        let allowlist = env::var("ADMIN_IP_ALLOWLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .map(|cidr| cidr.parse().map_err(|e| format!("invalid ADMIN_IP_ALLOWLIST entry: {}", e)))
+            .collect::<Result<_, String>>()?;
+        Ok(AdminConfig {
+            role: env::var("ADMIN_ROLE").ok().filter(|role| !role.is_empty()).unwrap_or_else(|| "admin".to_string()),
+            allowlist,
+        })
+    }
+}
+
+// Who `AdminGuard` let in, for the handlers and the audit trail
+#[derive(Clone)]
+struct AdminActor(String);
+
+// Compare without short-circuiting so response timing does not leak the token
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Check the `X-Admin-Token` header against `ADMIN_API_TOKEN`; the token path
+// is disabled when no token is configured
+#[allow(clippy::result_large_err)]
+fn token_actor(req: &HttpRequest) -> Result<String, HttpResponse> {
+    let expected = match env::var("ADMIN_API_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            return Err(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Admin API disabled"
+            })))
+        }
+    };
+
+    let presented = req
+        .headers()
+        .get("X-Admin-Token")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+        return Err(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid admin token"
+        })));
+    }
+
+    let actor = req
+        .headers()
+        .get("X-Admin-Actor")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("admin");
+    Ok(actor.to_string())
+}
+
+/// Return the actor name recorded in the audit log: the caller `AdminGuard`
+/// admitted, or else whoever presents a valid `X-Admin-Token`.
+#[allow(clippy::result_large_err)]
+pub fn authorize(req: &HttpRequest) -> Result<String, HttpResponse> {
+    if let Some(AdminActor(actor)) = req.extensions().get::<AdminActor>() {
+        return Ok(actor.clone());
+    }
+    token_actor(req)
+}
+
+// Admit a caller holding the admin token, or a token with the admin role
+#[allow(clippy::result_large_err)]
+async fn admit(req: &HttpRequest, role: &str) -> Result<String, HttpResponse> {
+    if req.headers().contains_key("X-Admin-Token") {
+        return token_actor(req);
+    }
+    let claims = AuthMiddleware::validate_token(req).await?;
+    if !claims.has_any_role(&[role.to_string()]) {
+        warn!("User {} without the {} role tried {} {}", claims.sub, role, req.method(), req.path());
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Insufficient role",
+            "code": "missing_role",
+            "required_roles": [role],
+        })));
+    }
+    Ok(format!("user:{}", claims.sub))
+}
+
+/// Middleware guarding the `/admin` scope: the client must be on
+/// `ADMIN_IP_ALLOWLIST` and present the admin token or a JWT with the admin
+/// role. Every request, refused or not, lands in the audit log.
+pub struct AdminGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AdminGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AdminGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) => data.clone(),
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            let ip = data.ip_filter.client_ip(req.request());
+            let (method, path) = (req.method().to_string(), req.path().to_string());
+            let details = serde_json::json!({
+                "method": method,
+                "path": path,
+                "ip": ip.map(|ip| ip.to_string()),
+            });
+
+            let allowlist = &data.config.load().admin.allowlist;
+            let admitted = match ip {
+                Some(ip) if allowlist.is_empty() || allowlist.iter().any(|cidr| cidr.contains(ip)) => {
+                    admit(req.request(), &data.config.load().admin.role).await
+                }
+                None if allowlist.is_empty() => admit(req.request(), &data.config.load().admin.role).await,
+                _ => Err(HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "Access denied",
+                    "code": "ip_denied"
+                }))),
+            };
+            let actor = match admitted {
+                Ok(actor) => actor,
+                Err(response) => {
+                    let mut details = details;
+                    details["status"] = response.status().as_u16().into();
+                    data.audit.record("admin_access_denied", "anonymous", details);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+
+            req.extensions_mut().insert(AdminActor(actor.clone()));
+            let res = service.call(req).await?;
+            let mut details = details;
+            details["status"] = res.status().as_u16().into();
+            info!("Admin {} called {} {}", actor, method, path);
+            data.audit.record("admin_request", &actor, details);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+async fn list_exemptions(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    let exemptions: Vec<_> = data.exemptions.list().iter().map(|e| e.redacted()).collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "exemptions": exemptions })))
+}
+
+async fn create_exemption(
+    req: HttpRequest,
+    body: web::Json<ExemptionRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match data.exemptions.add(body.into_inner()) {
+        Ok(exemption) => {
+            let redacted = exemption.redacted();
+            data.audit.record("rate_limit_exemption_created", &actor, serde_json::json!(redacted));
+            Ok(HttpResponse::Created().json(redacted))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid exemption",
+            "details": e
+        }))),
+    }
+}
+
+async fn delete_exemption(
+    req: HttpRequest,
+    path: web::Path<(u64,)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (id,) = path.into_inner();
+    match data.exemptions.remove(id) {
+        Some(exemption) => {
+            data.audit.record(
+                "rate_limit_exemption_deleted",
+                &actor,
+                serde_json::json!(exemption.redacted()),
+            );
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Exemption not found"
+        }))),
+    }
+}
+
+// Stored audit records, or the response to send when they cannot be read
+#[allow(clippy::result_large_err)]
+fn audit_lines(data: &AppState) -> Result<Vec<String>, HttpResponse> {
+    match data.audit.export() {
+        Some(Ok(lines)) => Ok(lines),
+        Some(Err(e)) => Err(HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "Failed to read audit log",
+            "details": e.to_string()
+        }))),
+        None => Err(HttpResponse::NotImplemented().json(serde_json::json!({
+            "error": "Audit log has no readable storage",
+            "details": "Set AUDIT_LOG_FILE or AUDIT_SINK=memory"
+        }))),
+    }
+}
+
+async fn export_audit(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let lines = match audit_lines(&data) {
+        Ok(lines) => lines,
+        Err(response) => return Ok(response),
+    };
+
+    data.audit.record("audit_exported", &actor, serde_json::json!({ "records": lines.len() }));
+    let mut body = lines.join("\n");
+    body.push('\n');
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header(("Content-Disposition", "attachment; filename=\"audit.jsonl\""))
+        .body(body))
+}
+
+async fn verify_audit(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    let lines = match audit_lines(&data) {
+        Ok(lines) => lines,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(match audit::verify(lines.iter().map(String::as_str)) {
+        Ok(count) => HttpResponse::Ok().json(serde_json::json!({ "valid": true, "records": count })),
+        Err(e) => HttpResponse::Conflict().json(serde_json::json!({ "valid": false, "error": e })),
+    })
+}
+
+async fn list_inflight(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "requests": data.inflight.list() })))
+}
+
+async fn cancel_inflight(
+    req: HttpRequest,
+    path: web::Path<(u64,)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (id,) = path.into_inner();
+    let request = data.inflight.list().into_iter().find(|r| r.id == id);
+    match request {
+        Some(request) if data.inflight.cancel(id) => {
+            data.audit.record("inflight_request_cancelled", &actor, serde_json::json!(request));
+            Ok(HttpResponse::Accepted().json(serde_json::json!({ "cancelled": id })))
+        }
+        _ => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "No in-flight request with that ID"
+        }))),
+    }
+}
+
+#[derive(Deserialize)]
+struct PinRequest {
+    /// Instance URL, or a bare IP substituted into the service's configured URL
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct ExclusionRequest {
+    instance: String,
+}
+
+async fn list_overrides(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(data.overrides.snapshot()))
+}
+
+async fn pin_upstream(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    body: web::Json<PinRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let (service,) = path.into_inner();
+    let upstreams = data.upstreams.load();
+    let upstream = match upstreams.get(&service) {
+        Some(upstream) => upstream,
+        None => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Unknown upstream service"
+            })))
+        }
+    };
+
+    let target = body.target.trim().trim_end_matches('/');
+    let target = match target.parse::<IpAddr>() {
+        Ok(ip) => {
+            let mut url = match upstream.instances().first().and_then(|i| reqwest::Url::parse(i).ok()) {
+                Some(url) => url,
+                None => {
+                    return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": "Service has no URL to pin an IP into"
+                    })))
+                }
+            };
+            let _ = url.set_ip_host(ip);
+            url.as_str().trim_end_matches('/').to_string()
+        }
+        Err(_) => match reqwest::Url::parse(target) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => target.to_string(),
+            _ => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Target must be an http(s) URL or an IP address"
+                })))
+            }
+        },
+    };
+
+    data.overrides.pin(&service, &target);
+    data.audit.record(
+        "upstream_pinned",
+        &actor,
+        serde_json::json!({ "service": service, "target": target }),
+    );
+    Ok(HttpResponse::Ok().json(data.overrides.snapshot()))
+}
+
+async fn unpin_upstream(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let (service,) = path.into_inner();
+    if !data.overrides.unpin(&service) {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Service is not pinned"
+        })));
+    }
+    data.audit.record("upstream_unpinned", &actor, serde_json::json!({ "service": service }));
+    Ok(HttpResponse::Ok().json(data.overrides.snapshot()))
+}
+
+async fn exclude_instance(
+    req: HttpRequest,
+    body: web::Json<ExclusionRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let instance = body.instance.trim().trim_end_matches('/');
+    if !data.upstreams.load().iter().any(|u| u.instances().iter().any(|i| i == instance)) {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Unknown upstream instance"
+        })));
+    }
+
+    data.overrides.exclude(instance);
+    data.audit.record("upstream_instance_excluded", &actor, serde_json::json!({ "instance": instance }));
+    Ok(HttpResponse::Ok().json(data.overrides.snapshot()))
+}
+
+async fn include_instance(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let instance = query.get("instance").map(|i| i.trim().trim_end_matches('/')).unwrap_or("");
+    if !data.overrides.include(instance) {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Instance is not excluded"
+        })));
+    }
+    data.audit.record("upstream_instance_included", &actor, serde_json::json!({ "instance": instance }));
+    Ok(HttpResponse::Ok().json(data.overrides.snapshot()))
+}
+
+async fn list_faults(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "faults": data.faults.list() })))
+}
+
+async fn create_fault(req: HttpRequest, body: web::Json<FaultRequest>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match data.faults.add(body.into_inner()) {
+        Ok(fault) => {
+            data.audit.record("fault_injection_created", &actor, serde_json::json!(fault));
+            Ok(HttpResponse::Created().json(fault))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid fault",
+            "details": e
+        }))),
+    }
+}
+
+async fn delete_fault(req: HttpRequest, path: web::Path<(u64,)>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (id,) = path.into_inner();
+    match data.faults.remove(id) {
+        Some(fault) => {
+            data.audit.record("fault_injection_deleted", &actor, serde_json::json!(fault));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Fault not found"
+        }))),
+    }
+}
+
+async fn clear_faults(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let removed = data.faults.clear();
+    data.audit.record("fault_injection_cleared", &actor, serde_json::json!({ "removed": removed }));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed })))
+}
+
+async fn list_flags(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "flags": data.flags.list() })))
+}
+
+async fn set_flag(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    body: web::Json<Flag>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (name,) = path.into_inner();
+    let flag = Flag { name, ..body.into_inner() };
+    match data.flags.set(flag.clone()).await {
+        Ok(()) => {
+            data.audit.record("feature_flag_set", &actor, serde_json::json!(flag));
+            Ok(HttpResponse::Ok().json(flag))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Flag not saved",
+            "details": e
+        }))),
+    }
+}
+
+async fn delete_flag(req: HttpRequest, path: web::Path<(String,)>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (name,) = path.into_inner();
+    match data.flags.remove(&name).await {
+        Ok(Some(flag)) => {
+            data.audit.record("feature_flag_deleted", &actor, serde_json::json!(flag));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Flag not found"
+        }))),
+        Err(e) => Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Flag not deleted",
+            "details": e
+        }))),
+    }
+}
+
+async fn list_api_keys(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "api_keys": data.api_keys.list() })))
+}
+
+async fn create_api_key(req: HttpRequest, body: web::Json<ApiKeyRequest>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match data.api_keys.add(body.into_inner()) {
+        Ok((key, plaintext)) => {
+            data.audit.record("api_key_created", &actor, serde_json::json!(key));
+            let mut response = serde_json::json!(key);
+            // Shown once; only the hash is kept
+            response["key"] = serde_json::json!(plaintext);
+            Ok(HttpResponse::Created().json(response))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid API key",
+            "details": e
+        }))),
+    }
+}
+
+async fn delete_api_key(req: HttpRequest, path: web::Path<(u64,)>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let (id,) = path.into_inner();
+    match data.api_keys.remove(id) {
+        Some(key) => {
+            data.audit.record("api_key_revoked", &actor, serde_json::json!(key));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "API key not found"
+        }))),
+    }
+}
+
+async fn get_ip_filter(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(data.ip_filter.rules()))
+}
+
+async fn reload_ip_filter(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match data.ip_filter.reload() {
+        Ok(rules) => {
+            data.audit.record("ip_filter_reloaded", &actor, serde_json::json!(rules));
+            Ok(HttpResponse::Ok().json(rules))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid IP filter rules",
+            "details": e
+        }))),
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    #[serde(default)]
+    message: Option<String>,
+    /// Expected length, announced to clients through `Retry-After`
+    #[serde(default)]
+    duration_seconds: Option<u64>,
+}
+
+async fn get_maintenance(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "maintenance": data.maintenance.current() })))
+}
+
+async fn start_maintenance(
+    req: HttpRequest,
+    body: Option<web::Json<MaintenanceRequest>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let request = body.map(|body| body.into_inner());
+    let now = chrono::Utc::now();
+    let window = MaintenanceWindow {
+        message: request
+            .as_ref()
+            .and_then(|r| r.message.clone())
+            .unwrap_or_else(|| "The service is down for maintenance".to_string()),
+        started_by: actor.clone(),
+        started_at: now.to_rfc3339(),
+        until: request
+            .and_then(|r| r.duration_seconds)
+            .map(|seconds| now + chrono::Duration::seconds(seconds as i64)),
+    };
+    warn!("Maintenance mode started by {}", actor);
+    data.maintenance.start(window.clone());
+    data.audit.record("maintenance_started", &actor, serde_json::json!(window));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "maintenance": window })))
+}
+
+async fn end_maintenance(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match data.maintenance.end() {
+        Some(window) => {
+            info!("Maintenance mode ended by {}", actor);
+            data.audit.record("maintenance_ended", &actor, serde_json::json!(window));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Not in maintenance mode"
+        }))),
+    }
+}
+
+async fn list_circuits(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    let circuits: Vec<_> = data
+        .circuits
+        .snapshot()
+        .into_iter()
+        .map(|(service, state, failures)| {
+            serde_json::json!({
+                "service": service,
+                "state": circuit_state_name(state),
+                "consecutive_failures": failures,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "circuits": circuits })))
+}
+
+#[derive(Deserialize)]
+struct CircuitResetRequest {
+    /// Every circuit when unset
+    #[serde(default)]
+    service: Option<String>,
+}
+
+async fn reset_circuits(
+    req: HttpRequest,
+    body: Option<web::Json<CircuitResetRequest>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let service = body.and_then(|body| body.into_inner().service);
+    let reset = data.circuits.reset(service.as_deref());
+    data.audit.record(
+        "circuits_reset",
+        &actor,
+        serde_json::json!({ "service": service, "reset": reset }),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "reset": reset })))
+}
+
+// Drop what the gateway holds on behalf of other systems so the next
+// request reads it fresh
+async fn flush_caches(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let mut flushed = serde_json::Map::new();
+    let statuses = std::mem::take(&mut *data.service_statuses.write().await).len();
+    flushed.insert("health_statuses".to_string(), serde_json::json!(statuses));
+    flushed.insert("responses".to_string(), serde_json::json!(data.response_cache.clear()));
+    if data.config.load().jwks.url.is_some() {
+        let outcome = match data.jwks.refresh().await {
+            Ok(keys) => serde_json::json!({ "keys": keys }),
+            Err(e) => serde_json::json!({ "error": e }),
+        };
+        flushed.insert("jwks".to_string(), outcome);
+    }
+    if let Err(e) = data.flags.refresh().await {
+        flushed.insert("feature_flags".to_string(), serde_json::json!({ "error": e }));
+    }
+    data.audit.record("caches_flushed", &actor, serde_json::json!(flushed));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "flushed": flushed })))
+}
+
+async fn list_cache(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    let path = query.get("path").map(String::as_str);
+    let (used_bytes, max_bytes) = data.response_cache.usage();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "used_bytes": used_bytes,
+        "max_bytes": max_bytes,
+        "regions": data.response_cache.regions(),
+        "entries": data.response_cache.entries(path),
+    })))
+}
+
+async fn purge_cache(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let (key, path) = (query.get("key").map(String::as_str), query.get("path").map(String::as_str));
+    if key.is_none() && path.is_none() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Give the key or path of the responses to purge"
+        })));
+    }
+    let purged = data.response_cache.purge(key, path);
+    data.audit.record("cache_purged", &actor, serde_json::json!({ "key": key, "path": path, "purged": purged }));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "purged": purged })))
+}
+
+async fn jwt_secret_status(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(data.secrets.rotation_status()))
+}
+
+#[derive(Deserialize)]
+struct RotateSecretRequest {
+    secret: String,
+}
+
+// New tokens are signed with the given secret at once; tokens signed with
+// the replaced one keep verifying for the rotation window
+async fn rotate_jwt_secret(
+    req: HttpRequest,
+    body: web::Json<RotateSecretRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let secret = body.into_inner().secret;
+    if secret.len() < 32 {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "The secret must be at least 32 bytes"
+        })));
+    }
+    if !data.secrets.rotate(secret) {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "That is already the primary secret"
+        })));
+    }
+    let status = data.secrets.rotation_status();
+    warn!("JWT secret rotated to {} by {}", status.primary, actor);
+    data.audit.record("jwt_secret_rotated", &actor, serde_json::json!(status));
+    Ok(HttpResponse::Ok().json(status))
+}
+
+async fn retire_jwt_secondary(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let status = data.secrets.rotation_status();
+    if !data.secrets.retire_secondary() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "No secondary secret"
+        })));
+    }
+    info!("Secondary JWT secret retired by {}", actor);
+    data.audit.record("jwt_secondary_retired", &actor, serde_json::json!({ "secondary": status.secondary }));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    /// Address or CIDR network
+    ip: String,
+    #[serde(default)]
+    reason: Option<String>,
+    /// Permanent until lifted when unset
+    #[serde(default)]
+    duration_seconds: Option<u64>,
+}
+
+async fn list_bans(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "bans": data.ip_filter.bans() })))
+}
+
+async fn ban_ip(req: HttpRequest, body: web::Json<BanRequest>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let request = body.into_inner();
+    let duration = request.duration_seconds.map(std::time::Duration::from_secs);
+    match data.ip_filter.ban(&request.ip, request.reason, duration, &actor) {
+        Ok(ban) => {
+            warn!("{} banned by {}", ban.network, actor);
+            data.audit.record("ip_banned", &actor, serde_json::json!(ban));
+            Ok(HttpResponse::Created().json(ban))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid address",
+            "details": e
+        }))),
+    }
+}
+
+async fn unban_ip(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    let ip = query.get("ip").map(String::as_str).unwrap_or("");
+    match data.ip_filter.unban(ip) {
+        Ok(Some(ban)) => {
+            data.audit.record("ip_unbanned", &actor, serde_json::json!(ban));
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Not banned"
+        }))),
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid address",
+            "details": e
+        }))),
+    }
+}
+
+async fn reload_config(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+
+    match crate::reload::reload(&data) {
+        Ok(reloaded) => {
+            info!("Configuration reloaded from {} by {}", reloaded.path, actor);
+            data.audit.record("config_reloaded", &actor, serde_json::json!(reloaded));
+            Ok(HttpResponse::Ok().json(reloaded))
+        }
+        Err(e) => Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid configuration, keeping the running one",
+            "details": e
+        }))),
+    }
+}
+
+async fn service_status(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    let services: Vec<_> = data.service_statuses.read().await.values().cloned().collect();
+    let routed = data.config.load().routes.services();
+    let failed_over: Vec<_> = BUILTIN_SERVICES
+        .into_iter()
+        .chain(routed.iter().map(|(service, _)| service.as_str()))
+        .filter(|service| data.failover.is_active(service))
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "environment": data.config.load().profile,
+        "services": services,
+        "inflight": data.inflight.list().len(),
+        "failed_over": failed_over,
+    })))
+}
+
+// The settings after file and environment layering, and the configuration
+// resolved from them as logged at startup; secrets are masked in the former
+// by `config::redact`, in the latter by the config types' `Debug` impls
+async fn dump_config(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(serde_json::json!({
+            "config_file": env::var("GATEWAY_CONFIG").ok().filter(|path| !path.is_empty()),
+            "settings": effective_settings(),
+            "effective": format!("{:#?}", data.config.load()),
+        })))
+}
+
+#[derive(Deserialize)]
+struct RateLimitResetRequest {
+    /// Client key as the limiter sees it: `user:<id>` or `ip:<address>`
+    key: String,
+}
+
+async fn reset_rate_limit(
+    req: HttpRequest,
+    body: web::Json<RateLimitResetRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let key = body.key.trim();
+    if !(key.starts_with("user:") || key.starts_with("ip:")) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Key must be user:<id> or ip:<address>"
+        })));
+    }
+
+    let rules = data.config.load().rate_limits.rules.len();
+    match data.rate_limiter.reset(rules, key).await {
+        Ok(()) => {
+            data.audit.record("rate_limit_reset", &actor, serde_json::json!({ "key": key }));
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "reset": key, "rules": rules })))
+        }
+        Err(e) => Ok(HttpResponse::BadGateway().json(serde_json::json!({
+            "error": "Failed to reset rate limit buckets",
+            "details": e
+        }))),
+    }
+}
+
+// Relay a ban or unban to the user service, which owns account state
+async fn relay_ban(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    body: Option<web::Json<serde_json::Value>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let (user_id,) = path.into_inner();
+    if user_id.is_empty() || !user_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid user ID"
+        })));
+    }
+
+    let method = req.method().as_str();
+    let mut body = body.map(web::Json::into_inner).unwrap_or_else(|| serde_json::json!({}));
+    if let Some(body) = body.as_object_mut() {
+        body.insert("bannedBy".to_string(), actor.clone().into());
+    }
+    let response = crate::proxy_request(&data, &req, "user", &format!("/users/{}/ban", user_id), method, Some(body.clone())).await?;
+    if response.status().is_success() {
+        let action = if method == "DELETE" { "user_unbanned" } else { "user_banned" };
+        data.audit.record(action, &actor, serde_json::json!({ "user_id": user_id, "request": body }));
+    }
+    Ok(response)
+}
+
+async fn field_encryption_status(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(data.field_crypto.status()))
+}
+
+/// Register the `/admin` routes.
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    /// Whole filter in `LOG_LEVEL` syntax; replaces `level` and `modules`
+    #[serde(default)]
+    filter: Option<String>,
+    #[serde(default)]
+    level: Option<String>,
+    /// Level per module path; replaces the current module overrides
+    #[serde(default)]
+    modules: Option<HashMap<String, String>>,
+}
+
+impl LogLevelRequest {
+    fn apply(&self, current: &LogFilter) -> Result<LogFilter, String> {
+        if let Some(spec) = &self.filter {
+            return LogFilter::parse(spec);
+        }
+        let mut filter = current.clone();
+        if let Some(level) = &self.level {
+            filter.default = logging::parse_level(level)?;
+        }
+        if let Some(modules) = &self.modules {
+            filter.modules = modules
+                .iter()
+                .map(|(module, level)| Ok((module.clone(), logging::parse_level(level)?)))
+                .collect::<Result<_, String>>()?;
+        }
+        Ok(filter)
+    }
+}
+
+fn log_filter_json(filter: &LogFilter) -> serde_json::Value {
+    let modules: HashMap<&str, String> =
+        filter.modules.iter().map(|(module, level)| (module.as_str(), level.as_str().to_lowercase())).collect();
+    serde_json::json!({
+        "filter": filter.spec(),
+        "level": filter.default.as_str().to_lowercase(),
+        "modules": modules,
+    })
+}
+
+async fn get_log_level(req: HttpRequest) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    Ok(HttpResponse::Ok().json(log_filter_json(&logging::filter())))
+}
+
+async fn set_log_level(req: HttpRequest, body: web::Json<LogLevelRequest>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let actor = match authorize(&req) {
+        Ok(actor) => actor,
+        Err(response) => return Ok(response),
+    };
+    let previous = logging::filter();
+    let updated = match body.apply(&previous) {
+        Ok(filter) => filter,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": e }))),
+    };
+
+    info!("Log filter changed from {} to {} by {}", previous.spec(), updated.spec(), actor);
+    logging::set_filter(updated.clone());
+    data.audit.record(
+        "log_level_changed",
+        &actor,
+        serde_json::json!({ "from": previous.spec(), "to": updated.spec() }),
+    );
+    Ok(HttpResponse::Ok().json(log_filter_json(&updated)))
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .wrap(AdminGuard)
+            .route("/status", web::get().to(service_status))
+            .route("/status/stream", web::get().to(crate::status::stream))
+            .route("/config", web::get().to(dump_config))
+            .route("/config/reload", web::post().to(reload_config))
+            .route("/rate-limit/reset", web::post().to(reset_rate_limit))
+            .route("/users/{id}/ban", web::post().to(relay_ban))
+            .route("/users/{id}/ban", web::delete().to(relay_ban))
+            .route("/rate-limit/exemptions", web::get().to(list_exemptions))
+            .route("/rate-limit/exemptions", web::post().to(create_exemption))
+            .route("/rate-limit/exemptions/{id}", web::delete().to(delete_exemption))
+            .route("/audit/export", web::get().to(export_audit))
+            .route("/audit/verify", web::get().to(verify_audit))
+            .route("/inflight", web::get().to(list_inflight))
+            .route("/inflight/{id}", web::delete().to(cancel_inflight))
+            .route("/upstreams/overrides", web::get().to(list_overrides))
+            .route("/upstreams/exclusions", web::post().to(exclude_instance))
+            .route("/upstreams/exclusions", web::delete().to(include_instance))
+            .route("/upstreams/{service}/pin", web::put().to(pin_upstream))
+            .route("/upstreams/{service}/pin", web::delete().to(unpin_upstream))
+            .route("/faults", web::get().to(list_faults))
+            .route("/faults", web::post().to(create_fault))
+            .route("/faults", web::delete().to(clear_faults))
+            .route("/faults/{id}", web::delete().to(delete_fault))
+            .route("/flags", web::get().to(list_flags))
+            .route("/flags/{name}", web::put().to(set_flag))
+            .route("/flags/{name}", web::delete().to(delete_flag))
+            .route("/api-keys", web::get().to(list_api_keys))
+            .route("/api-keys", web::post().to(create_api_key))
+            .route("/api-keys/{id}", web::delete().to(delete_api_key))
+            .route("/ip-filter", web::get().to(get_ip_filter))
+            .route("/ip-filter/reload", web::post().to(reload_ip_filter))
+            .route("/ip-filter/bans", web::get().to(list_bans))
+            .route("/ip-filter/bans", web::post().to(ban_ip))
+            .route("/ip-filter/bans", web::delete().to(unban_ip))
+            .route("/maintenance", web::get().to(get_maintenance))
+            .route("/maintenance", web::put().to(start_maintenance))
+            .route("/maintenance", web::delete().to(end_maintenance))
+            .route("/circuits", web::get().to(list_circuits))
+            .route("/circuits/reset", web::post().to(reset_circuits))
+            .route("/caches/flush", web::post().to(flush_caches))
+            .route("/cache", web::get().to(list_cache))
+            .route("/cache", web::delete().to(purge_cache))
+            .route("/secrets/jwt", web::get().to(jwt_secret_status))
+            .route("/secrets/jwt", web::put().to(rotate_jwt_secret))
+            .route("/secrets/jwt/secondary", web::delete().to(retire_jwt_secondary))
+            .route("/field-encryption", web::get().to(field_encryption_status))
+            .route("/log-level", web::get().to(get_log_level))
+            .route("/log-level", web::put().to(set_log_level)),
+    );
+}
@@ -0,0 +1,233 @@
+use actix_web::web;
+use tracing::{error, info, warn};
+use reqwest::Client;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::circuit::CircuitState;
+use crate::config::parse_env;
+use crate::health;
+use crate::metrics::Metrics;
+use crate::AppState;
+
+#[derive(Clone)]
+pub struct AlertConfig {
+    /// Slack-compatible incoming webhook; alerts are only logged when unset
+    pub webhook_url: Option<String>,
+    /// Share of failed upstream calls over `window` that raises an alert;
+    /// error-rate alerts are off when zero
+    pub error_rate: f64,
+    pub window: Duration,
+    /// Calls needed in the window before its error rate counts
+    pub min_requests: u64,
+    /// Least time between two deliveries of the same alert
+    pub cooldown: Duration,
+    /// How often error rates and upstream health are evaluated; never when zero
+    pub check_interval: Duration,
+}
+
+// The webhook URL is itself the credential
+impl std::fmt::Debug for AlertConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlertConfig")
+            .field("webhook_url", &self.webhook_url.as_ref().map(|_| "[redacted]"))
+            .field("error_rate", &self.error_rate)
+            .field("window", &self.window)
+            .field("min_requests", &self.min_requests)
+            .field("cooldown", &self.cooldown)
+            .field("check_interval", &self.check_interval)
+            .finish()
+    }
+}
+
+impl AlertConfig {
+    /// Read `ALERT_WEBHOOK_URL`, `ALERT_ERROR_RATE` (0-1, default 0.1),
+    /// `ALERT_WINDOW_SECONDS`, `ALERT_MIN_REQUESTS`, `ALERT_COOLDOWN_SECONDS`
+    /// and `ALERT_CHECK_INTERVAL_SECONDS`.
+    pub fn from_env() -> Self {
+        let secs = |key: &str, default: u64| {
+            Duration::from_secs(parse_env(key).unwrap_or(default))
+        };
+        AlertConfig {
+            webhook_url: env::var("ALERT_WEBHOOK_URL").ok().filter(|url| !url.is_empty()),
+            error_rate: parse_env("ALERT_ERROR_RATE")
+                .unwrap_or(0.1_f64)
+                .clamp(0.0, 1.0),
+            window: secs("ALERT_WINDOW_SECONDS", 60),
+            min_requests: parse_env("ALERT_MIN_REQUESTS").unwrap_or(20),
+            cooldown: secs("ALERT_COOLDOWN_SECONDS", 300),
+            check_interval: secs("ALERT_CHECK_INTERVAL_SECONDS", 15),
+        }
+    }
+}
+
+// Upstream calls of one service, counted per second over the alert window
+#[derive(Default)]
+struct ErrorWindow {
+    // (second since start, calls, failures)
+    buckets: VecDeque<(u64, u64, u64)>,
+}
+
+impl ErrorWindow {
+    fn record(&mut self, second: u64, failed: bool) {
+        match self.buckets.back_mut() {
+            Some((s, calls, failures)) if *s == second => {
+                *calls += 1;
+                *failures += failed as u64;
+            }
+            _ => self.buckets.push_back((second, 1, failed as u64)),
+        }
+    }
+
+    fn prune(&mut self, oldest: u64) {
+        while self.buckets.front().is_some_and(|(s, _, _)| *s < oldest) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        self.buckets.iter().fold((0, 0), |(calls, failures), (_, c, f)| (calls + c, failures + f))
+    }
+}
+
+/// Delivers operational alerts to the configured webhook.
+pub struct Alerter {
+    config: AlertConfig,
+    client: Client,
+    metrics: Arc<Metrics>,
+    started: Instant,
+    windows: Mutex<HashMap<String, ErrorWindow>>,
+    last_sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig, client: Client, metrics: Arc<Metrics>) -> Self {
+        Alerter {
+            config,
+            client,
+            metrics,
+            started: Instant::now(),
+            windows: Mutex::new(HashMap::new()),
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn send(&self, title: &str, details: &str) {
+        info!("ALERT {}: {}", title, details);
+
+        let url = match &self.config.webhook_url {
+            Some(url) => url,
+            None => return,
+        };
+        let payload = serde_json::json!({
+            "text": format!("*[gateway] {}*\n{}", title, details)
+        });
+        if let Err(e) = self.client.post(url).json(&payload).send().await {
+            error!("Failed to deliver alert to webhook: {}", e);
+        }
+    }
+
+    /// Send an alert unless the one with the same `key` went out within the
+    /// cooldown. Returns whether it was sent.
+    pub async fn alert(&self, kind: &str, key: &str, title: &str, details: &str) -> bool {
+        let now = Instant::now();
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if last_sent.get(key).is_some_and(|sent| now.duration_since(*sent) < self.config.cooldown) {
+                self.metrics.incr("gateway_alerts_total", &[("alert", kind), ("outcome", "suppressed")], 1);
+                return false;
+            }
+            last_sent.insert(key.to_string(), now);
+        }
+        self.metrics.incr("gateway_alerts_total", &[("alert", kind), ("outcome", "sent")], 1);
+        self.send(title, details).await;
+        true
+    }
+
+    /// Count one upstream call of `service` towards its error rate.
+    pub fn record(&self, service: &str, failed: bool) {
+        if self.config.error_rate <= 0.0 {
+            return;
+        }
+        let second = self.started.elapsed().as_secs();
+        self.windows.lock().unwrap().entry(service.to_string()).or_default().record(second, failed);
+    }
+
+    // (calls, failures) of every service over the window
+    fn error_rates(&self) -> Vec<(String, u64, u64)> {
+        let oldest = self.started.elapsed().as_secs().saturating_sub(self.config.window.as_secs());
+        let mut windows = self.windows.lock().unwrap();
+        windows
+            .iter_mut()
+            .map(|(service, window)| {
+                window.prune(oldest);
+                let (calls, failures) = window.totals();
+                (service.clone(), calls, failures)
+            })
+            .collect()
+    }
+}
+
+/// Evaluate upstream error rates and health on an interval, alerting when a
+/// service's error rate crosses the threshold or all of its instances are down,
+/// and again once it recovers.
+pub async fn watch(data: web::Data<AppState>) {
+    let config = data.alerter.config.clone();
+    if config.check_interval.is_zero() {
+        return;
+    }
+    let mut firing: HashSet<String> = HashSet::new();
+    let mut ticker = tokio::time::interval(config.check_interval);
+    loop {
+        ticker.tick().await;
+
+        for (service, calls, failures) in data.alerter.error_rates() {
+            let key = format!("error_rate:{}", service);
+            let rate = if calls == 0 { 0.0 } else { failures as f64 / calls as f64 };
+            if calls >= config.min_requests && rate >= config.error_rate {
+                warn!("Error rate of {} is {:.1}% over {} calls", service, rate * 100.0, calls);
+                firing.insert(key.clone());
+                let details = format!(
+                    "{} of {} calls failed in the last {}s ({:.1}%, threshold {:.1}%)",
+                    failures,
+                    calls,
+                    config.window.as_secs(),
+                    rate * 100.0,
+                    config.error_rate * 100.0
+                );
+                data.alerter
+                    .alert("error_rate", &key, &format!("High error rate on {} service", service), &details)
+                    .await;
+            } else if firing.remove(&key) {
+                let details = format!("{:.1}% of {} calls failed in the last {}s", rate * 100.0, calls, config.window.as_secs());
+                data.alerter
+                    .alert("error_rate", &format!("{}:resolved", key), &format!("Error rate on {} service recovered", service), &details)
+                    .await;
+            }
+        }
+
+        for upstream in data.upstreams.load().iter() {
+            let key = format!("upstream_down:{}", upstream.name);
+            let all_down = health::availability(&data, upstream).await.all_down(upstream);
+            let circuit_open = data.circuits.state(&upstream.name) == CircuitState::Open;
+            if all_down || circuit_open {
+                firing.insert(key.clone());
+                let reason = if all_down { "Every instance fails health checks" } else { "Its circuit breaker is open" };
+                data.alerter
+                    .alert("upstream_down", &key, &format!("{} service is down", upstream.name), reason)
+                    .await;
+            } else if firing.remove(&key) {
+                data.alerter
+                    .alert(
+                        "upstream_down",
+                        &format!("{}:resolved", key),
+                        &format!("{} service recovered", upstream.name),
+                        "Health checks pass and its circuit is closed",
+                    )
+                    .await;
+            }
+        }
+    }
+}
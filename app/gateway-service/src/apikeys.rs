@@ -0,0 +1,319 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use crate::auth::Claims;
+use crate::config::invalid;
+use crate::rbac::routed_path;
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct ApiKeyConfig {
+    /// Path prefixes where an `X-Api-Key` is accepted instead of a bearer token
+    pub routes: Vec<String>,
+}
+
+impl ApiKeyConfig {
+    pub fn from_env() -> Self {
+        let routes = env::var("API_KEY_ROUTES")
+            .unwrap_or_else(|_| "/api/chat,/api/messages".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(str::to_string)
+            .collect();
+        ApiKeyConfig { routes }
+    }
+
+    fn accepts(&self, path: &str) -> bool {
+        self.routes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// API key as submitted through `API_KEYS` or the admin API. Seeded keys give
+/// the SHA-256 `key_hash` (or the plain `key`); the admin API generates one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub key_hash: Option<String>,
+    /// `service:read` / `service:write`, `service:*` or `*`
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Requests per minute; unlimited when unset
+    pub rate_limit_per_minute: Option<u64>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub rate_limit_per_minute: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    hash: String,
+}
+
+impl ApiKey {
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+
+    fn allows(&self, scope: &str) -> bool {
+        let service = scope.split(':').next().unwrap_or_default();
+        self.scopes
+            .iter()
+            .any(|s| s == "*" || s == scope || *s == format!("{}:*", service))
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    digest(&SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Why a presented API key was turned down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiKeyError {
+    Unknown,
+    MissingScope(String),
+    /// Seconds until the key's rate-limit window resets
+    RateLimited(u64),
+}
+
+/// Keys for bots and internal tools, stored only as hashes.
+pub struct ApiKeyRegistry {
+    entries: RwLock<Vec<ApiKey>>,
+    // Requests per key in the current minute: (window start, count)
+    windows: Mutex<HashMap<u64, (i64, u64)>>,
+    next_id: AtomicU64,
+    rng: SystemRandom,
+}
+
+impl ApiKeyRegistry {
+    /// Seed the registry from `API_KEYS`, a JSON array of keys.
+    pub fn from_env() -> Self {
+        let registry = ApiKeyRegistry {
+            entries: RwLock::new(Vec::new()),
+            windows: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            rng: SystemRandom::new(),
+        };
+
+        if let Ok(raw) = env::var("API_KEYS") {
+            match serde_json::from_str::<Vec<ApiKeyRequest>>(&raw) {
+                Ok(requests) => {
+                    for request in requests {
+                        match registry.add(request) {
+                            Ok((key, _)) => info!("API key '{}' loaded", key.name),
+                            Err(e) => {
+                                error!("Ignoring API key: {}", e);
+                                invalid("API_KEYS", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Invalid API_KEYS: {}", e);
+                    invalid("API_KEYS", e);
+                }
+            }
+        }
+        registry
+    }
+
+    /// Register a key, returning it with the plaintext key when one was
+    /// generated; the plaintext is not kept and cannot be shown again.
+    pub fn add(&self, request: ApiKeyRequest) -> Result<(ApiKey, Option<String>), String> {
+        let name = request.name.trim().to_string();
+        if name.is_empty() {
+            return Err("name must not be empty".to_string());
+        }
+        let (hash, generated) = match (request.key_hash, request.key) {
+            (Some(hash), _) => {
+                let hash = hash.trim().to_ascii_lowercase();
+                if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err("key_hash must be a hex SHA-256 digest".to_string());
+                }
+                (hash, None)
+            }
+            (None, Some(key)) if !key.is_empty() => (hash_key(&key), None),
+            _ => {
+                let mut bytes = [0u8; 24];
+                self.rng.fill(&mut bytes).map_err(|_| "random source unavailable")?;
+                let key = format!("gk_{}", URL_SAFE_NO_PAD.encode(bytes));
+                (hash_key(&key), Some(key))
+            }
+        };
+
+        let mut entries = self.entries.write().unwrap();
+        if entries.iter().any(|k| k.name == name) {
+            return Err(format!("an API key named '{}' already exists", name));
+        }
+        if entries.iter().any(|k| k.hash == hash) {
+            return Err("this key is already registered".to_string());
+        }
+        let key = ApiKey {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            name,
+            scopes: request.scopes,
+            rate_limit_per_minute: request.rate_limit_per_minute,
+            created_at: Utc::now(),
+            expires_at: request.expires_at,
+            hash,
+        };
+        entries.push(key.clone());
+        Ok((key, generated))
+    }
+
+    pub fn remove(&self, id: u64) -> Option<ApiKey> {
+        let mut entries = self.entries.write().unwrap();
+        let index = entries.iter().position(|k| k.id == id)?;
+        self.windows.lock().unwrap().remove(&id);
+        Some(entries.remove(index))
+    }
+
+    /// Active keys; expired ones are dropped along the way.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let now = Utc::now();
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|k| !k.expired(now));
+        entries.clone()
+    }
+
+    /// Check a presented key against `scope` and count it against its rate limit.
+    pub fn authenticate(&self, presented: &str, scope: &str) -> Result<ApiKey, ApiKeyError> {
+        let now = Utc::now();
+        let hash = hash_key(presented);
+        let key = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|k| k.hash == hash && !k.expired(now))
+            .cloned()
+            .ok_or(ApiKeyError::Unknown)?;
+        if !key.allows(scope) {
+            return Err(ApiKeyError::MissingScope(scope.to_string()));
+        }
+
+        if let Some(limit) = key.rate_limit_per_minute {
+            let window = now.timestamp() / 60 * 60;
+            let mut windows = self.windows.lock().unwrap();
+            let entry = windows.entry(key.id).or_insert((window, 0));
+            if entry.0 != window {
+                *entry = (window, 0);
+            }
+            if entry.1 >= limit {
+                return Err(ApiKeyError::RateLimited((window + 60 - now.timestamp()).max(1) as u64));
+            }
+            entry.1 += 1;
+        }
+        Ok(key)
+    }
+}
+
+// Scope needed for a request: `<service>:read` for reads, `<service>:write` otherwise
+fn required_scope(req: &HttpRequest) -> String {
+    let service = routed_path(req)
+        .trim_start_matches('/')
+        .trim_start_matches("api/")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let access = if matches!(req.method().as_str(), "GET" | "HEAD" | "OPTIONS") {
+        "read"
+    } else {
+        "write"
+    };
+    format!("{}:{}", service, access)
+}
+
+/// Authenticate a request by its `X-Api-Key` header. The key stands in for
+/// the user: `sub` is `apikey:<name>` and `exp` the key's expiry (0 if none).
+#[allow(clippy::result_large_err)]
+pub fn authenticate(req: &HttpRequest, data: &web::Data<AppState>, presented: &str) -> Result<Claims, HttpResponse> {
+    let outcome = |outcome: &str| data.metrics.incr("gateway_api_key_requests_total", &[("outcome", outcome)], 1);
+    if !data.config.load().api_keys.accepts(routed_path(req)) {
+        outcome("route_not_allowed");
+        return Err(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "API keys are not accepted on this route"
+        })));
+    }
+
+    match data.api_keys.authenticate(presented, &required_scope(req)) {
+        Ok(key) => {
+            outcome("accepted");
+            let sub = format!("apikey:{}", key.name);
+            crate::inflight::set_user(req, &sub, &key.name);
+            Ok(Claims {
+                sub,
+                username: key.name,
+                exp: key.expires_at.map(|at| at.timestamp() as usize).unwrap_or(0),
+                jti: None,
+                roles: Vec::new(),
+                role: None,
+                // Key scopes were checked above, in their own scheme
+                scope: None,
+                scopes: Vec::new(),
+                sid: None,
+                did: None,
+                typ: None,
+            })
+        }
+        Err(ApiKeyError::Unknown) => {
+            outcome("invalid");
+            Err(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Invalid API key"
+            })))
+        }
+        Err(ApiKeyError::MissingScope(scope)) => {
+            outcome("forbidden");
+            Err(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "API key lacks the required scope",
+                "scope": scope
+            })))
+        }
+        Err(ApiKeyError::RateLimited(retry_after)) => {
+            outcome("rate_limited");
+            Err(HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after.to_string()))
+                .json(serde_json::json!({
+                    "error": "API key rate limit exceeded"
+                })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn encoded_paths_need_the_scope_of_the_routed_service() {
+        let req = TestRequest::post().uri("/api/%63hat/rooms").to_http_request();
+        assert_eq!(required_scope(&req), "chat:write");
+        assert!(ApiKeyConfig { routes: vec!["/api/chat".to_string()] }.accepts(routed_path(&req)));
+
+        let req = TestRequest::get().uri("/api/%6Dessages/recent").to_http_request();
+        assert_eq!(required_scope(&req), "messages:read");
+    }
+}
@@ -0,0 +1,355 @@
+use tracing::{error, info, warn};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use crate::config::invalid;
+
+// prev_hash of the first record in a chain
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditSinkKind {
+    /// Records only go to the application log
+    None,
+    File(String),
+    Memory,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub sink: AuditSinkKind,
+    /// Record every proxied POST, PUT and DELETE
+    pub mutations: bool,
+    /// Add a SHA-256 of the request body to those records; the body itself is
+    /// never stored
+    pub body_hash: bool,
+}
+
+impl AuditConfig {
+    /// `AUDIT_SINK` selects `file`, `memory` or `none`; it defaults to `file`
+    /// when `AUDIT_LOG_FILE` is set. `AUDIT_MUTATIONS` and `AUDIT_BODY_HASH`
+    /// control records of proxied writes.
+    pub fn from_env() -> Self {
+        let file = env::var("AUDIT_LOG_FILE").ok().filter(|path| !path.is_empty());
+        let file_sink = file.map(AuditSinkKind::File).unwrap_or(AuditSinkKind::None);
+        let sink = match env::var("AUDIT_SINK").ok().as_deref() {
+            Some("memory") => AuditSinkKind::Memory,
+            Some("none") => AuditSinkKind::None,
+            Some("file") | None => file_sink,
+            Some(other) => {
+                warn!("Unknown AUDIT_SINK '{}', ignoring", other);
+                invalid("AUDIT_SINK", format!("'{}' is not one of file, memory, none", other));
+                file_sink
+            }
+        };
+        let flag = |key: &str, default: bool| env::var(key).map(|v| v == "true" || v == "1").unwrap_or(default);
+        AuditConfig {
+            sink,
+            mutations: flag("AUDIT_MUTATIONS", true),
+            body_hash: flag("AUDIT_BODY_HASH", false),
+        }
+    }
+}
+
+/// Durable storage for serialized audit records, one JSON document per line.
+pub trait AuditSink: Send + Sync {
+    fn append(&self, line: &str) -> io::Result<()>;
+    /// Every stored line in order; used for export and to resume the chain.
+    fn read_all(&self) -> io::Result<Vec<String>>;
+}
+
+pub struct FileSink {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn append(&self, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+
+    fn read_all(&self) -> io::Result<Vec<String>> {
+        let _guard = self.file.lock().unwrap();
+        Ok(fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySink {
+    lines: Mutex<Vec<String>>,
+}
+
+impl AuditSink for MemorySink {
+    fn append(&self, line: &str) -> io::Result<()> {
+        self.lines.lock().unwrap().push(line.to_string());
+        Ok(())
+    }
+
+    fn read_all(&self) -> io::Result<Vec<String>> {
+        Ok(self.lines.lock().unwrap().clone())
+    }
+}
+
+// The hashed part of a record; field order defines the hash input
+#[derive(Debug, Serialize)]
+struct RecordBody<'a> {
+    seq: u64,
+    timestamp: &'a str,
+    action: &'a str,
+    actor: &'a str,
+    details: &'a Value,
+    prev_hash: &'a str,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub timestamp: String,
+    pub action: String,
+    pub actor: String,
+    pub details: Value,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn body(&self) -> RecordBody<'_> {
+        RecordBody {
+            seq: self.seq,
+            timestamp: &self.timestamp,
+            action: &self.action,
+            actor: &self.actor,
+            details: &self.details,
+            prev_hash: &self.prev_hash,
+        }
+    }
+}
+
+fn hash_body(body: &RecordBody) -> String {
+    sha256_hex(&serde_json::to_vec(body).unwrap_or_default())
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    digest(&SHA256, bytes).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A write proxied to an upstream, as kept in the audit trail.
+pub struct Mutation<'a> {
+    pub method: &'a str,
+    pub route: &'a str,
+    pub path: &'a str,
+    pub service: &'a str,
+    pub instance: Option<&'a str>,
+    /// Upstream status, or `None` when no response arrived
+    pub status: Option<u16>,
+    pub latency: std::time::Duration,
+    pub body_sha256: Option<String>,
+    pub request_id: Option<&'a str>,
+}
+
+/// Check that every line is a well-formed record whose hash matches its
+/// contents and whose `prev_hash` is the hash of the line before it. Returns
+/// the number of records verified or a description of the first break.
+pub fn verify<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<u64, String> {
+    let mut previous: Option<AuditRecord> = None;
+    let mut verified = 0;
+
+    for (index, line) in lines.into_iter().enumerate() {
+        let record: AuditRecord =
+            serde_json::from_str(line).map_err(|e| format!("line {}: malformed record: {}", index + 1, e))?;
+        if hash_body(&record.body()) != record.hash {
+            return Err(format!("record {}: contents do not match its hash", record.seq));
+        }
+        if let Some(previous) = &previous {
+            if record.prev_hash != previous.hash {
+                return Err(format!("record {}: chain broken after record {}", record.seq, previous.seq));
+            }
+            if record.seq != previous.seq + 1 {
+                return Err(format!("record {}: expected sequence {}", record.seq, previous.seq + 1));
+            }
+        }
+        previous = Some(record);
+        verified += 1;
+    }
+    Ok(verified)
+}
+
+struct ChainHead {
+    seq: u64,
+    hash: String,
+}
+
+/// Append-only, hash-chained record of security-relevant gateway actions.
+pub struct AuditLog {
+    sink: Option<Box<dyn AuditSink>>,
+    head: Mutex<ChainHead>,
+    mutations: bool,
+    body_hash: bool,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Self {
+        let sink: Option<Box<dyn AuditSink>> = match &config.sink {
+            AuditSinkKind::None => None,
+            AuditSinkKind::Memory => Some(Box::new(MemorySink::default())),
+            AuditSinkKind::File(path) => match FileSink::open(path) {
+                Ok(sink) => Some(Box::new(sink)),
+                Err(e) => {
+                    error!("Failed to open audit log {}: {}", path, e);
+                    None
+                }
+            },
+        };
+
+        // Continue the chain from the last stored record
+        let mut head = ChainHead {
+            seq: 0,
+            hash: GENESIS_HASH.to_string(),
+        };
+        if let Some(last) = sink
+            .as_ref()
+            .and_then(|sink| sink.read_all().ok())
+            .and_then(|lines| lines.last().cloned())
+        {
+            match serde_json::from_str::<AuditRecord>(&last) {
+                Ok(record) => {
+                    head = ChainHead {
+                        seq: record.seq,
+                        hash: record.hash,
+                    }
+                }
+                Err(e) => error!("Last audit record is unreadable, starting a new chain: {}", e),
+            }
+        }
+
+        AuditLog {
+            sink,
+            head: Mutex::new(head),
+            mutations: config.mutations,
+            body_hash: config.body_hash,
+        }
+    }
+
+    /// SHA-256 of a request body as the client sent it, when mutation records
+    /// carry one. Moderators can match it against reported content without
+    /// the trail holding message text.
+    pub fn body_digest(&self, body: &Value) -> Option<String> {
+        (self.mutations && self.body_hash).then(|| sha256_hex(&serde_json::to_vec(body).unwrap_or_default()))
+    }
+
+    /// Record a proxied write by `actor`.
+    pub fn record_mutation(&self, actor: &str, mutation: Mutation) {
+        if !self.mutations {
+            return;
+        }
+        let mut details = serde_json::json!({
+            "method": mutation.method,
+            "route": mutation.route,
+            "path": mutation.path,
+            "service": mutation.service,
+            "upstream": mutation.instance,
+            "status": mutation.status,
+            "latency_ms": mutation.latency.as_millis() as u64,
+        });
+        if let Some(hash) = mutation.body_sha256 {
+            details["body_sha256"] = Value::String(hash);
+        }
+        if let Some(id) = mutation.request_id {
+            details["request_id"] = Value::String(id.to_string());
+        }
+        self.record("request_proxied", actor, details);
+    }
+
+    pub fn record(&self, action: &str, actor: &str, details: Value) {
+        let mut head = self.head.lock().unwrap();
+        let timestamp = chrono::Utc::now().to_rfc3339();
+        let body = RecordBody {
+            seq: head.seq + 1,
+            timestamp: &timestamp,
+            action,
+            actor,
+            details: &details,
+            prev_hash: &head.hash,
+        };
+        let hash = hash_body(&body);
+        let record = AuditRecord {
+            seq: body.seq,
+            timestamp: timestamp.clone(),
+            action: action.to_string(),
+            actor: actor.to_string(),
+            details,
+            prev_hash: head.hash.clone(),
+            hash,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => return error!("Failed to serialize audit record: {}", e),
+        };
+
+        info!(target: "audit", "{}", line);
+        if let Some(sink) = &self.sink {
+            if let Err(e) = sink.append(&line) {
+                // Keep the head where it was so the next record still links to stored data
+                return error!("Failed to write audit record: {}", e);
+            }
+        }
+        head.seq = record.seq;
+        head.hash = record.hash;
+    }
+
+    /// Stored records as JSON lines, or `None` when no readable sink is configured.
+    pub fn export(&self) -> Option<io::Result<Vec<String>>> {
+        self.sink.as_ref().map(|sink| sink.read_all())
+    }
+}
+
+/// `gateway-service audit verify <file>`: check an exported audit trail
+/// offline. Returns the process exit code.
+pub fn verify_command(args: &[String]) -> i32 {
+    let path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("usage: gateway-service audit verify <file>");
+            return 2;
+        }
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("cannot read {}: {}", path, e);
+            return 2;
+        }
+    };
+
+    match verify(contents.lines().filter(|line| !line.trim().is_empty())) {
+        Ok(count) => {
+            println!("OK: {} audit records verified", count);
+            0
+        }
+        Err(e) => {
+            println!("TAMPERED: {}", e);
+            1
+        }
+    }
+}
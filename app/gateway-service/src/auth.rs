@@ -1,23 +1,268 @@
-use actix_web::{HttpRequest, HttpResponse, Result};
-use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Result};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, decode_header, DecodingKey, Algorithm};
+use tracing::{debug, warn};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::Instant;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::jwks;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // user ID
     pub username: String,
     pub exp: usize,
+    /// Token id, used to revoke the token on logout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Single-role form some issuers use instead of `roles`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// OAuth-style space-separated scopes; tokens without any are unrestricted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Array form of `scope`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Device session the token was issued to, signed out with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// Device id the session was started from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did: Option<String>,
+    /// Token type; some types, e.g. `password_reset`, are accepted only once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
 }
 
+impl Claims {
+    /// Whether the token carries at least one of `required`.
+    pub fn has_any_role(&self, required: &[String]) -> bool {
+        self.roles.iter().chain(self.role.iter()).any(|role| required.contains(role))
+    }
+    
+    /// Scopes the token is limited to, or `None` for an unrestricted token.
+    pub fn granted_scopes(&self) -> Option<Vec<&str>> {
+        if self.scope.is_none() && self.scopes.is_empty() {
+            return None;
+        }
+        let from_string = self.scope.iter().flat_map(|scope| scope.split_whitespace());
+        Some(from_string.chain(self.scopes.iter().map(String::as_str)).collect())
+    }
+}
+
+/// Claims of the request, validated by `AuthMiddleware`. Extracting them on
+/// a route the middleware does not cover fails with 401, never open.
+impl FromRequest for Claims {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Claims>().cloned().ok_or_else(|| {
+            let response = HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Authentication required"
+            }));
+            InternalError::from_response("missing claims", response).into()
+        }))
+    }
+}
+
+/// Middleware enforcing the route policies: every route not declared public
+/// needs a valid token (or API key) and the roles and scopes its policy names.
+/// The validated `Claims` are left in the request extensions for handlers to
+/// extract.
 pub struct AuthMiddleware;
 
+impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthMiddlewareService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthMiddlewareService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let config = match req.app_data::<web::Data<crate::AppState>>() {
+                Some(data) => data.config.load(),
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            let policy = match config.route_policies.for_request(&req) {
+                Some(policy) => policy.clone(),
+                None => {
+                    warn!("Refused {} {}: its encoding changes the route policy", req.method(), req.path());
+                    let response = HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": "Invalid request path",
+                        "code": "invalid_path",
+                    }));
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            if policy.is_public() {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            // WebSocket upgrades may carry the token in the query string
+            let websocket = req
+                .headers()
+                .get("Upgrade")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+            let claims = match websocket {
+                true => AuthMiddleware::validate_ws_token(req.request()).await,
+                false => AuthMiddleware::validate_token(req.request()).await,
+            };
+            let method = req.method().as_str();
+            let checked = match claims {
+                Ok(claims) => match policy.authorize(&claims, method, req.path()) {
+                    Ok(()) => crate::guest::check(&claims, method, websocket),
+                    Err(response) => Err(response),
+                },
+                Err(response) => Err(response),
+            };
+            match checked {
+                Ok(()) => service.call(req).await.map(|res| res.map_into_left_body()),
+                Err(response) => Ok(req.into_response(response).map_into_right_body()),
+            }
+        })
+    }
+}
+
 impl AuthMiddleware {
-    pub fn validate_token(req: &HttpRequest) -> Result<Claims, HttpResponse> {
-        // Get JWT secret from environment
-        let jwt_secret = env::var("JWT_SECRET").unwrap_or_else(|_| "super-secret-gateway-key".to_string());
-        
-        // Extract token from Authorization header
+    /// Validate the request's credentials once; later calls for the same
+    /// request, e.g. from the role guard and then this middleware, reuse the
+    /// result.
+    #[allow(clippy::result_large_err)]
+    pub async fn validate_token(req: &HttpRequest) -> Result<Claims, HttpResponse> {
+        if let Some(claims) = req.extensions().get::<Claims>() {
+            return Ok(claims.clone());
+        }
+        if let Some(claims) = Self::api_key_claims(req) {
+            let claims = claims?;
+            crate::identity::remember(req, &claims);
+            req.extensions_mut().insert(claims.clone());
+            return Ok(claims);
+        }
+        let token = Self::presented_token(req)?;
+        Self::verify(req, &token).await
+    }
+    
+    /// Token from the Authorization header or, when there is none, from the
+    /// session cookie.
+    #[allow(clippy::result_large_err)]
+    pub fn presented_token(req: &HttpRequest) -> Result<String, HttpResponse> {
+        if !req.headers().contains_key("Authorization") {
+            let data = req.app_data::<web::Data<crate::AppState>>();
+            if let Some(token) = data.and_then(|data| crate::session::token(req, &data.config.load().session)) {
+                return Ok(token);
+            }
+        }
+        Self::bearer_token(req).map(str::to_string)
+    }
+    
+    /// Subject of the presented token if its signature verifies. Revocation
+    /// and scopes are not checked, so this only suits keying, e.g. rate limits.
+    pub async fn token_subject(req: &HttpRequest) -> Option<String> {
+        let token = Self::presented_token(req).ok()?;
+        Self::decode_token(req, &token).await.ok().map(|claims| claims.sub)
+    }
+    
+    // Decode and admit a token, timing how long validation takes
+    #[allow(clippy::result_large_err)]
+    async fn verify(req: &HttpRequest, token: &str) -> Result<Claims, HttpResponse> {
+        let started = Instant::now();
+        let result = match Self::decode_token(req, token).await {
+            Ok(claims) => Self::admit(req, token, &claims).await.map(|()| claims),
+            Err(response) => Err(response),
+        };
+        if let Some(data) = req.app_data::<web::Data<crate::AppState>>() {
+            let outcome = if result.is_ok() { "valid" } else { "rejected" };
+            data.metrics
+                .observe("gateway_token_validation_seconds", &[("outcome", outcome)], started.elapsed().as_secs_f64());
+        }
+        result
+    }
+
+    // Checks a decoded token must pass before its claims are trusted
+    #[allow(clippy::result_large_err)]
+    async fn admit(req: &HttpRequest, token: &str, claims: &Claims) -> Result<(), HttpResponse> {
+        Self::check_revoked(req, token, claims).await?;
+        if let Some(data) = req.app_data::<web::Data<crate::AppState>>() {
+            crate::scopes::check(req, &data.config.load().scope_policies, claims)?;
+            crate::replay::check(req, data, claims).await?;
+        }
+        crate::inflight::set_user(req, &claims.sub, &claims.username);
+        crate::identity::remember(req, claims);
+        req.extensions_mut().insert(claims.clone());
+        Ok(())
+    }
+    
+    // Bots and internal tools send an `X-Api-Key` instead of a bearer token
+    #[allow(clippy::result_large_err)]
+    fn api_key_claims(req: &HttpRequest) -> Option<Result<Claims, HttpResponse>> {
+        if req.headers().contains_key("Authorization") {
+            return None;
+        }
+        let key = req.headers().get("X-Api-Key")?.to_str().ok()?;
+        let data = req.app_data::<web::Data<crate::AppState>>()?;
+        Some(crate::apikeys::authenticate(req, data, key))
+    }
+    
+    // Reject tokens revoked by logout, or whose device session was signed out
+    #[allow(clippy::result_large_err)]
+    async fn check_revoked(req: &HttpRequest, token: &str, claims: &Claims) -> Result<(), HttpResponse> {
+        let data = match req.app_data::<web::Data<crate::AppState>>() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        data.revocation
+            .ensure_active(&crate::revocation::token_id(token, claims.jti.as_deref()))
+            .await?;
+        if let Some(sid) = &claims.sid {
+            data.revocation.ensure_active(&crate::revocation::session_id(sid)).await?;
+            data.devices.seen(sid);
+        }
+        Ok(())
+    }
+    
+    // Extract the token from a `Bearer` Authorization header
+    #[allow(clippy::result_large_err)]
+    pub fn bearer_token(req: &HttpRequest) -> Result<&str, HttpResponse> {
         let auth_header = req.headers().get("Authorization");
         
         if auth_header.is_none() {
@@ -38,22 +283,102 @@ impl AuthMiddleware {
             })));
         }
         
-        let token = &auth_str[7..]; // Skip "Bearer "
-        
-        // Decode and validate token
-        let decoding_key = DecodingKey::from_secret(jwt_secret.as_bytes());
-        let validation = Validation::new(Algorithm::HS256);
+        Ok(&auth_str[7..]) // Skip "Bearer "
+    }
+    
+    // Browsers cannot set headers on WebSocket upgrades, so also accept ?token=
+    #[allow(clippy::result_large_err)]
+    pub async fn validate_ws_token(req: &HttpRequest) -> Result<Claims, HttpResponse> {
+        if let Some(claims) = req.extensions().get::<Claims>() {
+            return Ok(claims.clone());
+        }
+        let session = req
+            .app_data::<web::Data<crate::AppState>>()
+            .and_then(|data| crate::session::token(req, &data.config.load().session));
+        if req.headers().contains_key("Authorization") || req.headers().contains_key("X-Api-Key") || session.is_some() {
+            return Self::validate_token(req).await;
+        }
         
-        match decode::<Claims>(token, &decoding_key, &validation) {
-            Ok(token_data) => Ok(token_data.claims),
-            Err(_) => Err(HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Invalid or expired token"
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+        match query.as_ref().and_then(|q| q.get("token")) {
+            Some(token) => Self::verify(req, token).await,
+            None => Err(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Authorization header missing"
             }))),
         }
     }
     
-    pub fn extract_user_id(req: &HttpRequest) -> Option<i32> {
-        match Self::validate_token(req) {
+    #[allow(clippy::result_large_err)]
+    async fn decode_token(req: &HttpRequest, token: &str) -> Result<Claims, HttpResponse> {
+        Self::decode_claims(req, token).await
+    }
+    
+    /// Validate a token and return every claim it carries, not just the ones
+    /// the gateway relies on.
+    #[allow(clippy::result_large_err)]
+    pub async fn raw_claims(
+        req: &HttpRequest,
+        token: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, HttpResponse> {
+        Self::decode_claims(req, token).await
+    }
+    
+    // HS* tokens are checked against the shared secret, asymmetric ones against
+    // the JWKS key named by their `kid`; only algorithms in JWT_ALGORITHMS pass
+    #[allow(clippy::result_large_err)]
+    async fn decode_claims<T: serde::de::DeserializeOwned>(req: &HttpRequest, token: &str) -> Result<T, HttpResponse> {
+        let invalid = || {
+            HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Invalid or expired token"
+            }))
+        };
+        let data = req.app_data::<web::Data<crate::AppState>>();
+        // Encrypted gateway tokens carry the signed token inside
+        let token = match data {
+            Some(data) => data.jwe.decrypt(token).map_err(|reason| {
+                debug!("Rejected token: {}", reason);
+                invalid()
+            })?,
+            None => token.to_string(),
+        };
+        let token = token.as_str();
+        let header = decode_header(token).map_err(|_| invalid())?;
+        let allowed = match data {
+            Some(data) => data.config.load().jwks.allows(header.alg),
+            None => header.alg == Algorithm::HS256,
+        };
+        if !allowed {
+            return Err(invalid());
+        }
+        
+        let data = data.ok_or_else(invalid)?;
+        // During a secret rotation tokens signed with the secondary still pass
+        let decoding_keys = if jwks::is_symmetric(header.alg) {
+            data.secrets
+                .verification_secrets()
+                .iter()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes()))
+                .collect()
+        } else {
+            vec![data.jwks.key(header.kid.as_deref(), header.alg).await.ok_or_else(invalid)?]
+        };
+        let validation = data.config.load().jwks.validation(header.alg);
+        
+        let claims = decoding_keys
+            .iter()
+            .find_map(|key| decode::<serde_json::Value>(token, key, &validation).ok())
+            .ok_or_else(invalid)?
+            .claims;
+        if let Err(reason) = data.config.load().jwks.check_lifetime(&claims) {
+            debug!("Rejected token: {}", reason);
+            return Err(invalid());
+        }
+        serde_json::from_value(claims).map_err(|_| invalid())
+    }
+    
+    #[allow(dead_code)]
+    pub async fn extract_user_id(req: &HttpRequest) -> Option<i32> {
+        match Self::validate_token(req).await {
             Ok(claims) => claims.sub.parse::<i32>().ok(),
             Err(_) => None,
         }
@@ -0,0 +1,153 @@
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::parse_env;
+use crate::exemptions::ExemptionMode;
+use crate::metrics::Metrics;
+
+// Largest slice written between throttling decisions
+const MAX_SLICE_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BandwidthConfig {
+    /// Bytes per second for a single download, unlimited when unset
+    pub per_connection: Option<u64>,
+    /// Bytes per second shared by all downloads of one user, unlimited when unset
+    pub per_user: Option<u64>,
+}
+
+impl BandwidthConfig {
+    pub fn from_env() -> Self {
+        let limit = |key: &str| {
+            parse_env(key)
+                .filter(|rate| *rate > 0)
+        };
+        BandwidthConfig {
+            per_connection: limit("DOWNLOAD_RATE_LIMIT_PER_CONNECTION"),
+            per_user: limit("DOWNLOAD_RATE_LIMIT_PER_USER"),
+        }
+    }
+}
+
+// Token bucket measured in bytes that may go into debt; the debt is the wait
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take `bytes` tokens and return how long the caller must wait before sending
+    fn reserve(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        // Burst capacity is one second's worth of bytes
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Applies per-connection and per-user byte rate limits to download streams.
+pub struct BandwidthLimiter {
+    config: BandwidthConfig,
+    users: Mutex<HashMap<String, Arc<Mutex<TokenBucket>>>>,
+    metrics: Arc<Metrics>,
+}
+
+struct ThrottleState {
+    upstream: reqwest::Response,
+    pending: Bytes,
+    connection: Option<TokenBucket>,
+    user: Option<Arc<Mutex<TokenBucket>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: BandwidthConfig, metrics: Arc<Metrics>) -> Self {
+        BandwidthLimiter {
+            config,
+            users: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    fn user_bucket(&self, user_id: &str, rate: u64) -> Arc<Mutex<TokenBucket>> {
+        let mut users = self.users.lock().unwrap();
+        // Forget buckets of users with no download in progress
+        users.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        users
+            .entry(user_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(rate))))
+            .clone()
+    }
+
+    /// Stream the upstream body to the client, pacing it to the configured limits
+    /// as bypassed or relaxed by a rate-limit exemption.
+    pub fn throttle(
+        &self,
+        user_id: &str,
+        exemption: Option<ExemptionMode>,
+        upstream: reqwest::Response,
+    ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+        let limit = |rate: u64| match exemption {
+            Some(mode) => mode.apply(rate),
+            None => Some(rate),
+        };
+        let state = ThrottleState {
+            upstream,
+            pending: Bytes::new(),
+            connection: self.config.per_connection.and_then(limit).map(TokenBucket::new),
+            user: self
+                .config
+                .per_user
+                .and_then(limit)
+                .map(|rate| self.user_bucket(user_id, rate)),
+            metrics: self.metrics.clone(),
+        };
+
+        stream::unfold(state, |mut state| async move {
+            if state.pending.is_empty() {
+                match state.upstream.chunk().await {
+                    Ok(Some(chunk)) => state.pending = chunk,
+                    Ok(None) => return None,
+                    Err(e) => return Some((Err(actix_web::error::ErrorBadGateway(e)), state)),
+                }
+            }
+
+            let slice = state.pending.split_to(state.pending.len().min(MAX_SLICE_BYTES));
+            let mut wait = Duration::ZERO;
+            if let Some(bucket) = state.connection.as_mut() {
+                wait = wait.max(bucket.reserve(slice.len()));
+            }
+            if let Some(bucket) = state.user.as_ref() {
+                wait = wait.max(bucket.lock().unwrap().reserve(slice.len()));
+            }
+            if !wait.is_zero() {
+                state.metrics.incr("gateway_download_throttle_waits_total", &[], 1);
+                tokio::time::sleep(wait).await;
+            }
+
+            state.metrics.incr("gateway_download_bytes_total", &[], slice.len() as u64);
+            Some((Ok(slice), state))
+        })
+    }
+}
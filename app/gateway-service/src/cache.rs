@@ -0,0 +1,740 @@
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use tracing::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::{AuthMiddleware, Claims};
+use crate::cidr::Cidr;
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+
+fn enabled() -> bool {
+    true
+}
+
+fn negative_statuses() -> Vec<u16> {
+    vec![404, 410]
+}
+
+/// Who a cached response may be served to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Every caller, for routes that answer everyone alike
+    Shared,
+    /// The authenticated user it was fetched for, or anonymous callers
+    /// alike
+    #[default]
+    User,
+}
+
+/// What besides the path tells cached responses apart.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KeyComponents {
+    /// Query parameters in the key, the whole query string when unset
+    pub query: Option<Vec<String>>,
+    /// Request headers in the key, e.g. `Accept-Language`
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+/// How GET responses on routes matching `path` are cached. The first
+/// policy matching a request applies, so a disabled one can exempt paths
+/// from a broader policy listed after it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachePolicy {
+    /// Gateway path; `{name}` matches one segment, a trailing `*` any suffix
+    pub path: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// How long a response is served fresh
+    #[serde(default)]
+    pub ttl_seconds: u64,
+    /// For this long past its TTL a response is still served, marked stale,
+    /// while one request refreshes it in the background
+    #[serde(default)]
+    pub stale_seconds: u64,
+    #[serde(default)]
+    pub key: KeyComponents,
+    #[serde(default)]
+    pub scope: Scope,
+    /// Larger responses are passed through uncached
+    #[serde(default)]
+    pub max_object_bytes: Option<usize>,
+    /// How long answers with a `negative_statuses` status are kept, so
+    /// clients retrying a missing resource do not reach the upstream; not
+    /// cached when zero
+    #[serde(default)]
+    pub negative_ttl_seconds: u64,
+    #[serde(default = "negative_statuses")]
+    pub negative_statuses: Vec<u16>,
+}
+
+impl CachePolicy {
+    /// How long a response with `status` is kept, if at all.
+    pub fn lifetime(&self, status: u16) -> Option<Lifetime> {
+        let lifetime = match status {
+            200 => Lifetime {
+                ttl: Duration::from_secs(self.ttl_seconds),
+                stale: Duration::from_secs(self.stale_seconds),
+            },
+            status if self.negative_statuses.contains(&status) => Lifetime {
+                ttl: Duration::from_secs(self.negative_ttl_seconds),
+                stale: Duration::ZERO,
+            },
+            _ => return None,
+        };
+        Some(lifetime).filter(|lifetime| !lifetime.ttl.is_zero())
+    }
+
+    /// Whether a response body of `size` bytes may be kept.
+    pub fn fits(&self, size: usize) -> bool {
+        self.max_object_bytes.is_none_or(|max| size <= max)
+    }
+
+    /// Key a request's response is cached under: its path and the query
+    /// parameters, headers and user the policy tells apart. `None` when the
+    /// request presented credentials the gateway did not validate on a
+    /// user-scoped route, as the upstream may still answer it per user.
+    pub fn key(&self, req: &HttpRequest) -> Option<String> {
+        // Sorted, so the order clients list parameters in does not matter
+        let mut pairs: Vec<&str> = req
+            .query_string()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let name = pair.split('=').next().unwrap_or_default();
+                self.key.query.as_ref().is_none_or(|names| names.iter().any(|n| n == name))
+            })
+            .collect();
+        pairs.sort_unstable();
+        let mut key = match pairs.is_empty() {
+            true => req.path().to_string(),
+            false => format!("{}?{}", req.path(), pairs.join("&")),
+        };
+        // Quoted, so no value can pass for another component
+        for name in &self.key.headers {
+            let value = req.headers().get(name.as_str()).and_then(|value| value.to_str().ok()).unwrap_or_default();
+            key = format!("{} {}={:?}", key, name.to_ascii_lowercase(), value);
+        }
+        if self.scope == Scope::User {
+            let user = match req.extensions().get::<Claims>() {
+                Some(claims) => format!("{:?}", claims.sub),
+                None if presents_credentials(req) => return None,
+                None => "anonymous".to_string(),
+            };
+            key = format!("{} user={}", key, user);
+        }
+        Some(key)
+    }
+}
+
+fn presents_credentials(req: &HttpRequest) -> bool {
+    req.headers().contains_key("Authorization")
+        || req.headers().contains_key("X-Api-Key")
+        || AuthMiddleware::presented_token(req).is_ok()
+}
+
+/// How long a route's responses are served fresh, then stale.
+#[derive(Debug, Clone, Copy)]
+pub struct Lifetime {
+    pub ttl: Duration,
+    pub stale: Duration,
+}
+
+/// Cached responses to purge once a mutating request matching `path` and
+/// `methods` succeeds, e.g. a room's history when a message is sent to it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InvalidationRule {
+    /// POST, PUT and DELETE when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub path: String,
+    /// Cached paths to purge, in the same syntax; `{name}` stands for the
+    /// segment it matched in `path`, or for any segment when unmatched
+    pub purge: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheConfig {
+    pub policies: Vec<CachePolicy>,
+    pub invalidations: Vec<InvalidationRule>,
+    /// Clients whose `Cache-Control: no-cache` skips the cached response
+    pub bypass_clients: Vec<Cidr>,
+    /// Memory all cached responses may hold together; the least recently
+    /// used ones make room for new ones
+    pub max_bytes: usize,
+}
+
+// JSON array given inline in `key` or in the file named by `{key}_FILE`
+pub fn load<T: DeserializeOwned>(key: &str) -> Vec<T> {
+    let raw = match env::var(format!("{}_FILE", key)) {
+        Ok(path) if !path.is_empty() => fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e)),
+        _ => match env::var(key) {
+            Ok(raw) if !raw.trim().is_empty() => Ok(raw),
+            _ => return Vec::new(),
+        },
+    };
+    match raw.and_then(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string())) {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Invalid {} ({}), ignoring it", key, e);
+            invalid(key, e);
+            Vec::new()
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Read `CACHE_ROUTES` and `CACHE_INVALIDATIONS`, JSON arrays given
+    /// inline or in the files named by `CACHE_ROUTES_FILE` and
+    /// `CACHE_INVALIDATIONS_FILE`, `CACHE_BYPASS_CLIENTS`, comma separated
+    /// networks, and `CACHE_MAX_BYTES` (64 MiB by default).
+    pub fn from_env() -> Self {
+        let bypass_clients = env::var("CACHE_BYPASS_CLIENTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .filter_map(|cidr| match cidr.parse() {
+                Ok(cidr) => Some(cidr),
+                Err(e) => {
+                    warn!("Ignoring cache bypass client '{}': {}", cidr, e);
+                    invalid("CACHE_BYPASS_CLIENTS", format!("'{}': {}", cidr, e));
+                    None
+                }
+            })
+            .collect();
+        let config = CacheConfig {
+            policies: load("CACHE_ROUTES"),
+            invalidations: load("CACHE_INVALIDATIONS"),
+            bypass_clients,
+            max_bytes: parse_env("CACHE_MAX_BYTES").unwrap_or(64 * 1024 * 1024),
+        };
+        if !config.policies.is_empty() {
+            info!(
+                "Caching GET responses on {} route(s), purged by {} invalidation rule(s)",
+                config.policies.len(),
+                config.invalidations.len()
+            );
+        }
+        config
+    }
+
+    /// The policy for GET responses on `path`, when they are cached.
+    pub fn policy(&self, path: &str) -> Option<&CachePolicy> {
+        self.policies
+            .iter()
+            .find(|policy| bind(&policy.path, path).is_some())
+            .filter(|policy| policy.enabled && (policy.ttl_seconds > 0 || policy.negative_ttl_seconds > 0))
+    }
+
+    /// Whether `req` asks for a fresh answer and comes from a client
+    /// allowed to skip the cache.
+    pub fn bypass(&self, req: &HttpRequest, client: Option<IpAddr>) -> bool {
+        let no_cache = req
+            .headers()
+            .get_all("Cache-Control")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+        no_cache && client.is_some_and(|ip| self.bypass_clients.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Policies that could never cache anything.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.max_bytes == 0 && !self.policies.is_empty() {
+            problems.push("CACHE_MAX_BYTES: leaves no room for the responses CACHE_ROUTES caches".to_string());
+        }
+        for (index, policy) in self.policies.iter().enumerate() {
+            if policy.enabled && policy.ttl_seconds == 0 && policy.negative_ttl_seconds == 0 {
+                problems.push(format!("CACHE_ROUTES: {} is enabled without a ttl_seconds", policy.path));
+            }
+            if policy.negative_statuses.iter().any(|status| !(400..500).contains(status)) {
+                problems.push(format!("CACHE_ROUTES: {} lists negative_statuses outside 4xx", policy.path));
+            }
+            if policy.max_object_bytes == Some(0) {
+                problems.push(format!("CACHE_ROUTES: {} has a max_object_bytes of 0", policy.path));
+            }
+            if self.policies[..index].iter().any(|other| other.path == policy.path) {
+                problems.push(format!("CACHE_ROUTES: {} is listed more than once", policy.path));
+            }
+        }
+        problems
+    }
+
+    // Patterns of the cached paths a successful `method` on `path` makes stale
+    fn stale_after(&self, method: &str, path: &str) -> Vec<String> {
+        let mut patterns = Vec::new();
+        for rule in &self.invalidations {
+            let method_matches = match rule.methods.is_empty() {
+                true => matches!(method, "POST" | "PUT" | "DELETE"),
+                false => rule.methods.iter().any(|m| m.eq_ignore_ascii_case(method)),
+            };
+            if !method_matches {
+                continue;
+            }
+            if let Some(params) = bind(&rule.path, path) {
+                patterns.extend(rule.purge.iter().map(|purge| substitute(purge, &params)));
+            }
+        }
+        patterns
+    }
+}
+
+// Match `path` against `pattern` segment by segment; the `{name}` segments
+// it bound when it matches
+pub fn bind(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+    let (pattern, prefix) = match pattern.strip_suffix('*') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let patterns: Vec<&str> = pattern.split('/').collect();
+    let segments: Vec<&str> = path.split('/').collect();
+    if segments.len() < patterns.len() || (!prefix && segments.len() != patterns.len()) {
+        return None;
+    }
+    let mut params = Vec::new();
+    for (index, (expected, actual)) in patterns.iter().zip(&segments).enumerate() {
+        let last = index + 1 == patterns.len();
+        match expected.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+            Some(_) if actual.is_empty() => return None,
+            Some(name) => params.push((name.to_string(), actual.to_string())),
+            // The pattern's last segment is a prefix when it ended in `*`
+            None if prefix && last && !actual.starts_with(expected) => return None,
+            None if prefix && last => {}
+            None if expected != actual => return None,
+            None => {}
+        }
+    }
+    Some(params)
+}
+
+pub fn substitute(pattern: &str, params: &[(String, String)]) -> String {
+    params
+        .iter()
+        .fold(pattern.to_string(), |pattern, (name, value)| pattern.replace(&format!("{{{}}}", name), value))
+}
+
+struct Entry {
+    /// Path of the policy that cached it
+    region: String,
+    path: String,
+    status: u16,
+    /// The response as sent, so its size is what it holds
+    body: Bytes,
+    /// Bytes held for the key, path and body
+    size: usize,
+    stored: Instant,
+    lifetime: Lifetime,
+    /// Position in the recency order, higher for more recent use
+    used: u64,
+    /// A request is fetching a fresh copy
+    refreshing: bool,
+}
+
+impl Entry {
+    fn expired(&self) -> bool {
+        self.stored.elapsed() >= self.lifetime.ttl + self.lifetime.stale
+    }
+
+    fn hit(&self, warning: Option<&'static str>) -> Hit {
+        Hit {
+            status: self.status,
+            body: self.body.clone(),
+            age: self.stored.elapsed(),
+            warning,
+        }
+    }
+}
+
+/// A response served from the cache.
+pub struct Hit {
+    status: u16,
+    body: Bytes,
+    age: Duration,
+    /// RFC 7234 warning for a stale response
+    warning: Option<&'static str>,
+}
+
+impl Hit {
+    pub fn to_response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let mut response = HttpResponse::build(status);
+        response.insert_header(("Age", self.age.as_secs().to_string()));
+        match self.warning {
+            Some(warning) => response.insert_header(("Warning", warning)).insert_header(("X-Gateway-Cache", "stale")),
+            None => response.insert_header(("X-Gateway-Cache", "hit")),
+        };
+        response.content_type("application/json").body(self.body.clone())
+    }
+}
+
+pub enum Lookup {
+    Fresh(Hit),
+    /// Past its TTL; `refresh` is set for the one request that should fetch
+    /// a fresh copy
+    Stale { hit: Hit, refresh: bool },
+    Miss,
+}
+
+/// A cached response, as listed by the admin API.
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    pub key: String,
+    pub region: String,
+    pub status: u16,
+    pub bytes: usize,
+    pub age_seconds: u64,
+    pub expires_in_seconds: u64,
+    pub stale: bool,
+}
+
+/// Entries and memory held for one cache policy.
+#[derive(Debug, Serialize)]
+pub struct RegionStats {
+    pub region: String,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, least recent first
+    recency: BTreeMap<u64, String>,
+    /// Bumped on every use
+    clock: u64,
+    /// Held by all entries together
+    bytes: usize,
+    /// Regions that have held entries, so emptied ones report zero
+    regions: HashSet<String>,
+}
+
+impl Store {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+/// Upstream GET responses kept in memory within a byte budget, by path,
+/// query and whatever else their policy tells apart. When the budget is
+/// reached the least recently used responses are dropped first.
+pub struct ResponseCache {
+    store: Mutex<Store>,
+    max_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig, metrics: Arc<Metrics>) -> Self {
+        metrics.gauge_set("gateway_cache_max_bytes", &[], config.max_bytes as f64);
+        ResponseCache {
+            store: Mutex::new(Store::default()),
+            max_bytes: config.max_bytes,
+            metrics,
+        }
+    }
+
+    /// Count a lookup in `region`: hit, stale, miss or bypass.
+    pub fn record(&self, region: &str, outcome: &str) {
+        self.metrics.incr("gateway_cache_requests_total", &[("region", region), ("outcome", outcome)], 1);
+    }
+
+    pub fn get(&self, region: &str, key: &str) -> Lookup {
+        let mut store = self.store.lock().unwrap();
+        let now = store.tick();
+        let Store { entries, recency, .. } = &mut *store;
+        let entry = match entries.get_mut(key) {
+            Some(entry) if !entry.expired() => entry,
+            _ => {
+                self.record(region, "miss");
+                return Lookup::Miss;
+            }
+        };
+        recency.remove(&entry.used);
+        recency.insert(now, key.to_string());
+        entry.used = now;
+        if entry.stored.elapsed() < entry.lifetime.ttl {
+            self.record(region, "hit");
+            return Lookup::Fresh(entry.hit(None));
+        }
+        self.record(region, "stale");
+        let refresh = !entry.refreshing;
+        entry.refreshing = true;
+        Lookup::Stale {
+            hit: entry.hit(Some("110 - \"Response is Stale\"")),
+            refresh,
+        }
+    }
+
+    /// Let the next request past the TTL try refreshing `key` again.
+    pub fn refresh_failed(&self, key: &str) {
+        if let Some(entry) = self.store.lock().unwrap().entries.get_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+
+    /// Keep a response under `key`, when `policy` caches its status and
+    /// size and it fits the budget at all.
+    pub fn put(&self, key: String, policy: &CachePolicy, path: &str, status: u16, body: &Value) {
+        let lifetime = match policy.lifetime(status) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let body = match serde_json::to_vec(body) {
+            Ok(body) if policy.fits(body.len()) => Bytes::from(body),
+            _ => return,
+        };
+        let size = key.len() + path.len() + body.len();
+        if size > self.max_bytes {
+            debug!("Not caching {}: {} bytes exceed the whole cache budget", key, size);
+            return;
+        }
+        let mut store = self.store.lock().unwrap();
+        store.remove(&key);
+        self.evict(&mut store, |_, entry| entry.expired(), "expired");
+        self.make_room(&mut store, size);
+        let used = store.tick();
+        store.regions.insert(policy.path.clone());
+        store.recency.insert(used, key.clone());
+        store.bytes += size;
+        store.entries.insert(
+            key,
+            Entry {
+                region: policy.path.clone(),
+                path: path.to_string(),
+                status,
+                body,
+                size,
+                stored: Instant::now(),
+                lifetime,
+                used,
+                refreshing: false,
+            },
+        );
+        self.publish(&store);
+    }
+
+    // Drop the least recently used entries until `size` more bytes fit
+    fn make_room(&self, store: &mut Store, size: usize) {
+        let mut evicted: HashMap<String, u64> = HashMap::new();
+        while store.bytes + size > self.max_bytes {
+            let oldest = match store.recency.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            if let Some(entry) = store.remove(&oldest) {
+                *evicted.entry(entry.region).or_default() += 1;
+            }
+        }
+        for (region, count) in &evicted {
+            self.metrics
+                .incr("gateway_cache_evictions_total", &[("region", region), ("reason", "capacity")], *count);
+        }
+    }
+
+    // Drop the entries `doomed` picks, counting them as evicted for `reason`
+    fn evict(&self, store: &mut Store, doomed: impl Fn(&str, &Entry) -> bool, reason: &str) -> usize {
+        let keys: Vec<String> = store
+            .entries
+            .iter()
+            .filter(|(key, entry)| doomed(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut evicted: HashMap<String, u64> = HashMap::new();
+        for key in &keys {
+            if let Some(entry) = store.remove(key) {
+                *evicted.entry(entry.region).or_default() += 1;
+            }
+        }
+        for (region, count) in &evicted {
+            self.metrics
+                .incr("gateway_cache_evictions_total", &[("region", region), ("reason", reason)], *count);
+        }
+        keys.len()
+    }
+
+    fn stats(store: &Store) -> Vec<RegionStats> {
+        let mut regions: Vec<RegionStats> = store
+            .regions
+            .iter()
+            .map(|region| RegionStats {
+                region: region.clone(),
+                entries: 0,
+                bytes: 0,
+            })
+            .collect();
+        regions.sort_by(|a, b| a.region.cmp(&b.region));
+        for entry in store.entries.values() {
+            if let Some(stats) = regions.iter_mut().find(|stats| stats.region == entry.region) {
+                stats.entries += 1;
+                stats.bytes += entry.size;
+            }
+        }
+        regions
+    }
+
+    fn publish(&self, store: &Store) {
+        for stats in Self::stats(store) {
+            let labels = [("region", stats.region.as_str())];
+            self.metrics.gauge_set("gateway_cache_entries", &labels, stats.entries as f64);
+            self.metrics.gauge_set("gateway_cache_bytes", &labels, stats.bytes as f64);
+        }
+    }
+
+    /// Purge the responses the invalidation rules tie to a successful
+    /// `method` on `path`; the number purged.
+    pub fn invalidate(&self, config: &CacheConfig, method: &str, path: &str) -> usize {
+        let patterns = config.stale_after(method, path);
+        if patterns.is_empty() {
+            return 0;
+        }
+        let mut store = self.store.lock().unwrap();
+        let stale = |_: &str, entry: &Entry| patterns.iter().any(|pattern| bind(pattern, &entry.path).is_some());
+        let purged = self.evict(&mut store, stale, "invalidated");
+        if purged > 0 {
+            debug!("{} {} purged {} cached response(s)", method, path, purged);
+            self.publish(&store);
+        }
+        purged
+    }
+
+    /// Drop the response under `key`, or those on paths matching
+    /// `pattern`; the number dropped.
+    pub fn purge(&self, key: Option<&str>, pattern: Option<&str>) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let matches = |k: &str, entry: &Entry| {
+            key.is_some_and(|key| key == k) || pattern.is_some_and(|pattern| bind(pattern, &entry.path).is_some())
+        };
+        let purged = self.evict(&mut store, matches, "purged");
+        self.publish(&store);
+        purged
+    }
+
+    /// Drop every response; the number dropped.
+    pub fn clear(&self) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let cleared = self.evict(&mut store, |_, _| true, "flushed");
+        self.publish(&store);
+        cleared
+    }
+
+    /// Entries on paths matching `pattern`, or all of them, by key.
+    pub fn entries(&self, pattern: Option<&str>) -> Vec<EntryInfo> {
+        let store = self.store.lock().unwrap();
+        let mut entries: Vec<EntryInfo> = store
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.expired() && pattern.is_none_or(|pattern| bind(pattern, &entry.path).is_some()))
+            .map(|(key, entry)| {
+                let age = entry.stored.elapsed();
+                EntryInfo {
+                    key: key.clone(),
+                    region: entry.region.clone(),
+                    status: entry.status,
+                    bytes: entry.size,
+                    age_seconds: age.as_secs(),
+                    expires_in_seconds: (entry.lifetime.ttl + entry.lifetime.stale).saturating_sub(age).as_secs(),
+                    stale: age >= entry.lifetime.ttl,
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    pub fn regions(&self) -> Vec<RegionStats> {
+        Self::stats(&self.store.lock().unwrap())
+    }
+
+    /// Bytes held by all responses, and the budget they share.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.store.lock().unwrap().bytes, self.max_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn policy(scope: &str) -> CachePolicy {
+        serde_json::from_value(serde_json::json!({ "path": "/api/chat/rooms", "ttl_seconds": 30, "scope": scope })).unwrap()
+    }
+
+    fn claims(sub: &str) -> Claims {
+        serde_json::from_value(serde_json::json!({ "sub": sub, "username": sub, "exp": 0 })).unwrap()
+    }
+
+    fn signed_in(sub: &str) -> HttpRequest {
+        let req = TestRequest::get()
+            .uri("/api/chat/rooms")
+            .insert_header(("Authorization", format!("Bearer token-of-{}", sub)))
+            .to_http_request();
+        req.extensions_mut().insert(claims(sub));
+        req
+    }
+
+    #[test]
+    fn user_scoped_entries_are_not_shared() {
+        let policy = policy("user");
+        let alice = policy.key(&signed_in("alice")).unwrap();
+        let bob = policy.key(&signed_in("bob")).unwrap();
+        let anonymous = policy.key(&TestRequest::get().uri("/api/chat/rooms").to_http_request()).unwrap();
+        assert_ne!(alice, bob);
+        assert_ne!(alice, anonymous);
+        assert_ne!(bob, anonymous);
+    }
+
+    #[test]
+    fn a_user_cannot_pass_for_another() {
+        // A subject crafted to look like the rest of someone else's key
+        let policy = policy("user");
+        let alice = policy.key(&signed_in("alice")).unwrap();
+        let crafted = policy.key(&signed_in("alice\" user=\"alice")).unwrap();
+        assert_ne!(alice, crafted);
+    }
+
+    #[test]
+    fn unvalidated_credentials_bypass_the_cache() {
+        let policy = policy("user");
+        for (name, value) in [("Authorization", "Bearer unchecked"), ("X-Api-Key", "unchecked")] {
+            let req = TestRequest::get().uri("/api/chat/rooms").insert_header((name, value)).to_http_request();
+            assert_eq!(policy.key(&req), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn shared_entries_ignore_the_caller() {
+        let policy = policy("shared");
+        let anonymous = policy.key(&TestRequest::get().uri("/api/chat/rooms").to_http_request());
+        assert_eq!(policy.key(&signed_in("alice")), anonymous);
+    }
+
+    #[test]
+    fn query_order_does_not_matter() {
+        let policy = policy("shared");
+        let first = policy.key(&TestRequest::get().uri("/api/chat/rooms?a=1&b=2").to_http_request());
+        let second = policy.key(&TestRequest::get().uri("/api/chat/rooms?b=2&a=1").to_http_request());
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,149 @@
+use tracing::info;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+use std::env;
+
+use crate::config::parse_env;
+use crate::rbac::route_matches;
+
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone)]
+pub struct BodyCaptureConfig {
+    /// Routes whose proxied bodies are logged (exact, or a prefix when ending
+    /// in `*`); capture is off when empty
+    pub routes: Vec<String>,
+    /// Field names whose values are blanked, matched case-insensitively
+    /// anywhere in the key, so `newPassword` and `accessToken` are caught too
+    pub redact: Vec<String>,
+    /// Longest body logged; longer ones are cut
+    pub max_bytes: usize,
+    /// Share of matching requests captured
+    pub sample_rate: f64,
+}
+
+impl BodyCaptureConfig {
+    /// Read `BODY_CAPTURE_ROUTES`, `BODY_CAPTURE_REDACT`,
+    /// `BODY_CAPTURE_MAX_BYTES` and `BODY_CAPTURE_SAMPLE_RATE`.
+    pub fn from_env() -> Self {
+        let list = |key: &str, default: &str| -> Vec<String> {
+            env::var(key)
+                .unwrap_or_else(|_| default.to_string())
+                .split(',')
+                .map(|item| item.trim().to_lowercase())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+        BodyCaptureConfig {
+            routes: env::var("BODY_CAPTURE_ROUTES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|route| !route.is_empty())
+                .map(str::to_string)
+                .collect(),
+            redact: list("BODY_CAPTURE_REDACT", "password,token,secret,email,authorization,otp"),
+            max_bytes: parse_env("BODY_CAPTURE_MAX_BYTES").unwrap_or(4096),
+            sample_rate: parse_env("BODY_CAPTURE_SAMPLE_RATE")
+                .unwrap_or(1.0_f64)
+                .clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Logs proxied request and response bodies of chosen routes, redacted and
+/// size-capped, for chasing contract mismatches between clients and services.
+pub struct BodyCapture {
+    config: BodyCaptureConfig,
+    rng: SystemRandom,
+}
+
+/// A request picked for capture, whose response is logged alongside it.
+pub struct Captured<'a> {
+    capture: &'a BodyCapture,
+    method: String,
+    path: String,
+}
+
+impl BodyCapture {
+    pub fn new(config: BodyCaptureConfig) -> Self {
+        if !config.routes.is_empty() {
+            info!("Capturing proxied bodies of {:?} at a {} sample rate", config.routes, config.sample_rate);
+        }
+        BodyCapture {
+            config,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Whether to capture this request, rolling the sampling dice once so its
+    /// request and response are logged together or not at all.
+    pub fn start(&self, method: &str, path: &str) -> Option<Captured<'_>> {
+        if !self.config.routes.iter().any(|route| route_matches(route, &[], method, path)) {
+            return None;
+        }
+        let mut roll = [0u8; 4];
+        let sampled = self.config.sample_rate >= 1.0
+            || (self.rng.fill(&mut roll).is_ok() && (u32::from_be_bytes(roll) as f64 / u32::MAX as f64) < self.config.sample_rate);
+        sampled.then(|| Captured {
+            capture: self,
+            method: method.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    // Redacted, size-capped rendering of a body
+    fn render(&self, body: &Value) -> String {
+        let mut body = body.clone();
+        redact(&mut body, &self.config.redact);
+        let mut text = body.to_string();
+        if text.len() > self.config.max_bytes {
+            let mut cut = self.config.max_bytes;
+            while !text.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            let dropped = text.len() - cut;
+            text.truncate(cut);
+            text.push_str(&format!("...({} more bytes)", dropped));
+        }
+        text
+    }
+}
+
+impl Captured<'_> {
+    pub fn request(&self, body: Option<&Value>) {
+        if let Some(body) = body {
+            info!(
+                target: "body_capture",
+                direction = "request", method = %self.method, path = %self.path,
+                "{} {} request body: {}", self.method, self.path, self.capture.render(body)
+            );
+        }
+    }
+
+    pub fn response(&self, status: u16, body: &Value) {
+        info!(
+            target: "body_capture",
+            direction = "response", method = %self.method, path = %self.path, status,
+            "{} {} response {} body: {}", self.method, self.path, status, self.capture.render(body)
+        );
+    }
+}
+
+// Blank every value under a key containing one of `fields`
+fn redact(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if fields.iter().any(|field| key.contains(field.as_str())) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, fields)),
+        _ => {}
+    }
+}
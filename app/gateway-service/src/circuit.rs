@@ -0,0 +1,278 @@
+use tracing::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::parse_env;
+use crate::status::StatusFeed;
+
+#[derive(Debug, Clone)]
+pub struct CircuitConfig {
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+}
+
+impl CircuitConfig {
+    pub fn from_env() -> Self {
+        CircuitConfig {
+            failure_threshold: parse_env("CIRCUIT_FAILURE_THRESHOLD")
+                .filter(|threshold| *threshold > 0)
+                .unwrap_or(5),
+            open_duration: Duration::from_secs(
+                parse_env("CIRCUIT_OPEN_SECONDS").unwrap_or(30),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    /// When the circuit opened or, while half-open, when the trial started
+    opened_at: Option<Instant>,
+}
+
+/// Leave for one call, from `CircuitBreakers::allow`. Its outcome is recorded
+/// with `success` or `failure`; a half-open trial dropped without either,
+/// e.g. when the call was cancelled or never made, counts as a failure so
+/// the circuit cannot stay half-open.
+pub struct Permit<'a> {
+    breakers: &'a CircuitBreakers,
+    service: String,
+    trial: bool,
+    settled: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        self.settled = true;
+        self.breakers.record_success(&self.service);
+    }
+
+    pub fn failure(mut self) {
+        self.settled = true;
+        self.breakers.record_failure(&self.service);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.settled {
+            warn!("Trial request to {} ended without an outcome", self.service);
+            self.breakers.record_failure(&self.service);
+        }
+    }
+}
+
+/// Per-service circuit breakers keyed by service name.
+///
+/// After `failure_threshold` consecutive failures the circuit opens and calls
+/// are refused for `open_duration`; the next call after that is a trial, which
+/// closes the circuit on success and re-opens it on failure. A trial still
+/// unanswered after another `open_duration` is given up and a new one let
+/// through.
+pub struct CircuitBreakers {
+    config: CircuitConfig,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    /// Told about every state change
+    feed: Arc<StatusFeed>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitConfig, feed: Arc<StatusFeed>) -> Self {
+        CircuitBreakers {
+            config,
+            circuits: Mutex::new(HashMap::new()),
+            feed,
+        }
+    }
+
+    /// Leave for a call to `service` right now, if the circuit gives it.
+    pub fn allow(&self, service: &str) -> Option<Permit<'_>> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let allowed = match circuits.get_mut(service) {
+            None => Some(false),
+            Some(circuit) => {
+                let elapsed = circuit
+                    .opened_at
+                    .map(|opened| opened.elapsed() >= self.config.open_duration)
+                    .unwrap_or(true);
+                // Whether the call may proceed, and if so whether it is a trial
+                match circuit.state {
+                    CircuitState::Closed => Some(false),
+                    CircuitState::HalfOpen if elapsed => {
+                        warn!("Trial request to {} unanswered, allowing another", service);
+                        circuit.opened_at = Some(Instant::now());
+                        Some(true)
+                    }
+                    CircuitState::HalfOpen => None,
+                    CircuitState::Open if elapsed => {
+                        info!("Circuit for {} half-open, allowing trial request", service);
+                        circuit.state = CircuitState::HalfOpen;
+                        circuit.opened_at = Some(Instant::now());
+                        self.feed.circuit_changed(service, CircuitState::Open, CircuitState::HalfOpen);
+                        Some(true)
+                    }
+                    CircuitState::Open => None,
+                }
+            }
+        };
+        allowed.map(|trial| Permit {
+            breakers: self,
+            service: service.to_string(),
+            trial,
+            settled: false,
+        })
+    }
+
+    /// Whether calls to `service` are currently being refused, either while
+    /// open and cooling down or while a half-open trial is in flight.
+    pub fn is_open(&self, service: &str) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        match circuits.get(service) {
+            Some(circuit) => match circuit.state {
+                CircuitState::Closed => false,
+                CircuitState::HalfOpen | CircuitState::Open => circuit
+                    .opened_at
+                    .map(|opened| opened.elapsed() < self.config.open_duration)
+                    .unwrap_or(false),
+            },
+            None => false,
+        }
+    }
+
+    pub fn state(&self, service: &str) -> CircuitState {
+        let circuits = self.circuits.lock().unwrap();
+        circuits.get(service).map(|circuit| circuit.state).unwrap_or(CircuitState::Closed)
+    }
+
+    pub fn record_success(&self, service: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(service) {
+            if circuit.state != CircuitState::Closed {
+                info!("Circuit for {} closed", service);
+                self.feed.circuit_changed(service, circuit.state, CircuitState::Closed);
+            }
+            circuit.state = CircuitState::Closed;
+            circuit.consecutive_failures = 0;
+            circuit.opened_at = None;
+        }
+    }
+
+    /// Close the circuit of `service`, or of every service, by operator
+    /// decision. Returns the services whose circuit was not closed.
+    pub fn reset(&self, service: Option<&str>) -> Vec<String> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let mut reset = Vec::new();
+        for (name, circuit) in circuits.iter_mut() {
+            if service.is_some_and(|service| service != name) {
+                continue;
+            }
+            if circuit.state != CircuitState::Closed {
+                info!("Circuit for {} closed by an operator", name);
+                self.feed.circuit_changed(name, circuit.state, CircuitState::Closed);
+                reset.push(name.clone());
+            }
+            circuit.state = CircuitState::Closed;
+            circuit.consecutive_failures = 0;
+            circuit.opened_at = None;
+        }
+        reset
+    }
+
+    /// Services that have seen failures, with their state and failures in a row.
+    pub fn snapshot(&self) -> Vec<(String, CircuitState, u32)> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut snapshot: Vec<_> = circuits
+            .iter()
+            .map(|(name, circuit)| (name.clone(), circuit.state, circuit.consecutive_failures))
+            .collect();
+        snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshot
+    }
+
+    pub fn record_failure(&self, service: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(service.to_string()).or_insert(Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+        });
+
+        circuit.consecutive_failures += 1;
+        let should_open = circuit.state == CircuitState::HalfOpen
+            || circuit.consecutive_failures >= self.config.failure_threshold;
+        if should_open && circuit.state != CircuitState::Open {
+            warn!(
+                "Circuit for {} opened after {} consecutive failures",
+                service, circuit.consecutive_failures
+            );
+            self.feed.circuit_changed(service, circuit.state, CircuitState::Open);
+            circuit.state = CircuitState::Open;
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(open_duration: Duration) -> CircuitBreakers {
+        let config = CircuitConfig { failure_threshold: 1, open_duration };
+        CircuitBreakers::new(config, Arc::new(StatusFeed::new()))
+    }
+
+    #[test]
+    fn dropped_trial_reopens_the_circuit() {
+        let breakers = breakers(Duration::ZERO);
+        breakers.record_failure("chat");
+        let trial = breakers.allow("chat").expect("cooled down");
+        assert_eq!(breakers.state("chat"), CircuitState::HalfOpen);
+        drop(trial);
+        assert_eq!(breakers.state("chat"), CircuitState::Open);
+        breakers.allow("chat").expect("cooled down").success();
+        assert_eq!(breakers.state("chat"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn closed_permits_record_nothing_when_dropped() {
+        let breakers = breakers(Duration::from_secs(30));
+        drop(breakers.allow("chat"));
+        assert_eq!(breakers.state("chat"), CircuitState::Closed);
+        assert!(breakers.snapshot().is_empty());
+    }
+
+    #[test]
+    fn refuses_calls_while_a_trial_is_in_flight() {
+        let breakers = breakers(Duration::from_secs(30));
+        breakers.record_failure("chat");
+        breakers.circuits.lock().unwrap().get_mut("chat").unwrap().opened_at = Some(Instant::now() - Duration::from_secs(31));
+        let trial = breakers.allow("chat").expect("cooled down");
+        assert!(breakers.allow("chat").is_none());
+        assert!(breakers.is_open("chat"));
+        trial.success();
+        assert!(breakers.allow("chat").is_some());
+    }
+
+    #[test]
+    fn unanswered_trials_expire() {
+        let breakers = breakers(Duration::from_secs(30));
+        breakers.record_failure("chat");
+        breakers.circuits.lock().unwrap().get_mut("chat").unwrap().opened_at = Some(Instant::now() - Duration::from_secs(31));
+        let _trial = breakers.allow("chat").expect("cooled down");
+        breakers.circuits.lock().unwrap().get_mut("chat").unwrap().opened_at = Some(Instant::now() - Duration::from_secs(31));
+        assert!(!breakers.is_open("chat"));
+        let retrial = breakers.allow("chat").expect("trial expired");
+        retrial.success();
+        assert_eq!(breakers.state("chat"), CircuitState::Closed);
+    }
+}
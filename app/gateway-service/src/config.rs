@@ -0,0 +1,354 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::warn;
+
+/// Settings whose value is a JSON document; in a config file they are
+/// written as native tables and arrays and handed on as JSON.
+const JSON_SETTINGS: &[&str] = &[
+    "API_KEYS",
+    "API_V2_ROUTES",
+    "CACHE_INVALIDATIONS",
+    "CACHE_ROUTES",
+    "CORS_POLICIES",
+    "FALLBACK_RESPONSES",
+    "FAULT_INJECTION_RULES",
+    "FEATURE_FLAGS",
+    "FIELD_ENCRYPTION_RULES",
+    "IP_SCOPE_RULES",
+    "MODERATION_RULES",
+    "OIDC_PROVIDERS",
+    "RATE_LIMITS",
+    "RATE_LIMIT_EXEMPTIONS",
+    "ROLE_POLICIES",
+    "ROUTES",
+    "ROUTE_POLICIES",
+    "SCOPE_POLICIES",
+    "SYNTHETIC_PROBES",
+    "TOKEN_EXCHANGE_SERVICES",
+    "WAF_RULES",
+    "WEBHOOK_INTEGRATIONS",
+];
+
+// Settings found unusable while reading the configuration, since the last
+// `check`
+static INVALID: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Note a setting that could not be used. The `from_env` reading it carries
+/// on with its default; `check` decides whether the gateway may.
+pub fn invalid(name: &str, problem: impl Display) {
+    INVALID.lock().unwrap().push(format!("{}: {}", name, problem));
+}
+
+/// Parse a setting, `None` when it is unset or empty. Values that do not
+/// parse are noted with `invalid` and read as unset.
+pub fn parse_setting<T: FromStr>(name: &str, raw: Option<String>) -> Option<T> {
+    let raw = raw.filter(|raw| !raw.trim().is_empty())?;
+    match raw.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            let kind = std::any::type_name::<T>().rsplit("::").next().unwrap_or("value");
+            invalid(name, format!("'{}' is not a valid {}", raw, kind));
+            None
+        }
+    }
+}
+
+/// Parse the environment variable `name`; see `parse_setting`.
+pub fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    parse_setting(name, env::var(name).ok())
+}
+
+// Lenient mode starts with defaults in place of invalid settings
+fn lenient() -> bool {
+    env::var("GATEWAY_CONFIG_MODE").map(|mode| mode.eq_ignore_ascii_case("lenient")).unwrap_or(false)
+}
+
+/// Settle the problems noted since the last call, plus `more`: in strict
+/// mode (the default) every one of them is returned in a single error, in
+/// lenient mode (`GATEWAY_CONFIG_MODE=lenient`) they are logged and the
+/// defaults stand.
+pub fn check(more: Vec<String>) -> Result<(), String> {
+    let mut problems: Vec<String> = INVALID.lock().unwrap().drain(..).collect();
+    problems.extend(more);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if lenient() {
+        for problem in &problems {
+            warn!("Invalid setting {}; using the default", problem);
+        }
+        return Ok(());
+    }
+    Err(format!(
+        "{} invalid setting(s):\n  - {}\nFix them, or set GATEWAY_CONFIG_MODE=lenient to fall back to defaults",
+        problems.len(),
+        problems.join("\n  - ")
+    ))
+}
+
+// Variables a config file put in the environment, as opposed to ones the
+// gateway was started with, so a reload can replace or drop them
+static FROM_FILE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Name parts marking a credential, in setting names and JSON keys
+const SECRET_WORDS: &[&str] = &["SECRET", "TOKEN", "PASSWORD", "PASSPHRASE", "CREDENTIALS", "KEY", "KEYS", "WEBHOOK"];
+// Last name parts of settings that only point at or tune a credential
+const NON_SECRET_ENDINGS: &[&str] = &["PATH", "FILE", "ROUTES", "SECONDS", "BACKEND"];
+// Variables of the process environment rather than the gateway's
+const SYSTEM_VARIABLES: &[&str] = &["_", "HOME", "HOSTNAME", "LANG", "OLDPWD", "PATH", "PWD", "SHELL", "SHLVL", "TERM", "USER"];
+
+const REDACTED: &str = "[redacted]";
+
+fn secret_name(name: &str) -> bool {
+    let name = name.to_uppercase();
+    let words: Vec<&str> = name.split(['_', '-']).collect();
+    words.iter().any(|word| SECRET_WORDS.contains(word))
+        && !words.last().is_some_and(|word| NON_SECRET_ENDINGS.contains(word))
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                match secret_name(key) {
+                    true => *field = Value::String(REDACTED.to_string()),
+                    false => redact_json(field),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// `value` of the setting `name` with credentials masked: the whole value
+/// for secret names, secret fields of JSON settings, and URL passwords.
+pub fn redact(name: &str, value: &str) -> String {
+    if JSON_SETTINGS.contains(&name) {
+        return match serde_json::from_str::<Value>(value) {
+            Ok(mut json) => {
+                redact_json(&mut json);
+                json.to_string()
+            }
+            Err(_) => REDACTED.to_string(),
+        };
+    }
+    if secret_name(name) {
+        return REDACTED.to_string();
+    }
+    match reqwest::Url::parse(value) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED));
+            url.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// One setting of the running gateway and where it came from.
+#[derive(Debug, Serialize)]
+pub struct Setting {
+    pub name: String,
+    /// Masked by `redact`
+    pub value: String,
+    /// `environment` or `file`
+    pub source: &'static str,
+}
+
+/// The settings in effect after layering the config file under the
+/// environment, credentials masked.
+pub fn effective_settings() -> Vec<Setting> {
+    let from_file = FROM_FILE.lock().unwrap();
+    let mut settings: Vec<Setting> = env::vars()
+        .filter(|(name, _)| !SYSTEM_VARIABLES.contains(&name.as_str()) && !name.starts_with("LC_"))
+        // Service links Docker and Kubernetes add, e.g. `REDIS_PORT=tcp://...`
+        .filter(|(_, value)| !value.starts_with("tcp://") && !value.starts_with("udp://"))
+        .map(|(name, value)| Setting {
+            value: redact(&name, &value),
+            source: if from_file.contains(&name) { "file" } else { "environment" },
+            name,
+        })
+        .collect();
+    settings.sort_by(|a, b| a.name.cmp(&b.name));
+    settings
+}
+
+/// A value replaced whole at runtime; readers keep the version they loaded
+/// until they drop it.
+pub struct Live<T>(RwLock<Arc<T>>);
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Live(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// Settings read from a TOML or YAML file, under the names of the
+/// environment variables they stand for. Tables group settings by prefix:
+///
+/// ```toml
+/// port = 8000
+///
+/// [otel.exporter.otlp]
+/// endpoint = "http://collector:4318"   # OTEL_EXPORTER_OTLP_ENDPOINT
+///
+/// [[rate_limits]]                       # RATE_LIMITS, as JSON
+/// path = "/api/auth"
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: String,
+    pub settings: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    /// Read the file named by `GATEWAY_CONFIG`, if any.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match env::var("GATEWAY_CONFIG").ok().filter(|path| !path.is_empty()) {
+            Some(path) => Self::load(&path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse a `.toml`, `.yaml` or `.yml` file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let raw = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let document: Value = match path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).as_deref() {
+            Some("toml") => toml::from_str(&raw).map_err(|e| format!("invalid TOML in {}: {}", path, e))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&raw).map_err(|e| format!("invalid YAML in {}: {}", path, e))?,
+            _ => return Err(format!("{} must end in .toml, .yaml or .yml", path)),
+        };
+        let mut settings = BTreeMap::new();
+        match &document {
+            Value::Object(_) => flatten("", &document, &mut settings).map_err(|e| format!("{}: {}", path, e))?,
+            // An empty YAML file
+            Value::Null => {}
+            _ => return Err(format!("{}: the top level must be a table of settings", path)),
+        }
+        for name in JSON_SETTINGS {
+            if let Some(raw) = settings.get(*name) {
+                serde_json::from_str::<Value>(raw).map_err(|e| format!("{}: {} is not valid JSON: {}", path, name, e))?;
+            }
+        }
+        Ok(ConfigFile {
+            path: path.to_string(),
+            settings,
+        })
+    }
+
+    /// Make the file's settings visible to every `from_env`, in place of
+    /// those of a file applied before, leaving variables the gateway was
+    /// started with alone. Returns the names the environment overrode.
+    pub fn apply(&self) -> Vec<String> {
+        let mut from_file = FROM_FILE.lock().unwrap();
+        for name in from_file.iter().filter(|name| !self.settings.contains_key(*name)) {
+            env::remove_var(name);
+        }
+        let mut overridden = Vec::new();
+        let mut applied = BTreeSet::new();
+        for (name, value) in &self.settings {
+            if env::var_os(name).is_some() && !from_file.contains(name) {
+                overridden.push(name.clone());
+            } else {
+                env::set_var(name, value);
+                applied.insert(name.clone());
+            }
+        }
+        *from_file = applied;
+        overridden
+    }
+
+    /// The environment as `apply` would leave it before it does, to put back
+    /// if the new settings are refused.
+    pub fn snapshot(&self) -> Snapshot {
+        let from_file = FROM_FILE.lock().unwrap().clone();
+        let variables = from_file
+            .iter()
+            .chain(self.settings.keys())
+            .map(|name| (name.clone(), env::var_os(name)))
+            .collect();
+        Snapshot { variables, from_file }
+    }
+}
+
+pub struct Snapshot {
+    variables: Vec<(String, Option<std::ffi::OsString>)>,
+    from_file: BTreeSet<String>,
+}
+
+impl Snapshot {
+    pub fn restore(self) {
+        let mut from_file = FROM_FILE.lock().unwrap();
+        for (name, value) in self.variables {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+        *from_file = self.from_file;
+    }
+}
+
+// `otel.service-name` -> `OTEL_SERVICE_NAME`
+fn setting_name(prefix: &str, key: &str) -> String {
+    let key = key.trim().replace(['-', '.'], "_").to_uppercase();
+    match prefix.is_empty() {
+        true => key,
+        false => format!("{}_{}", prefix, key),
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn insert(settings: &mut BTreeMap<String, String>, name: &str, value: String) -> Result<(), String> {
+    match settings.insert(name.to_string(), value) {
+        Some(_) => Err(format!("{} is set more than once", name)),
+        None => Ok(()),
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, settings: &mut BTreeMap<String, String>) -> Result<(), String> {
+    match value {
+        _ if JSON_SETTINGS.contains(&prefix) => {
+            let json = match value {
+                // Already JSON, e.g. pasted from the environment variable
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            insert(settings, prefix, json)
+        }
+        Value::Object(table) => {
+            for (key, value) in table {
+                flatten(&setting_name(prefix, key), value, settings)?;
+            }
+            Ok(())
+        }
+        // Lists of plain values are comma-separated, like in the environment
+        Value::Array(items) => match items.iter().map(scalar).collect::<Option<Vec<_>>>() {
+            Some(items) => insert(settings, prefix, items.join(",")),
+            None => insert(settings, prefix, value.to_string()),
+        },
+        Value::Null => Err(format!("{} has no value", prefix)),
+        other => insert(settings, prefix, scalar(other).unwrap_or_default()),
+    }
+}
@@ -0,0 +1,398 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::{error, info, warn};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+use crate::config::{invalid, parse_env};
+use crate::origins::TrustedOrigins;
+use crate::profile::Profile;
+
+const DEFAULT_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+const DEFAULT_HEADERS: &str = "Authorization, Content-Type, X-CSRF-Token, X-Device-Id";
+
+// Accepts either "*", "registry", "trusted" or an explicit list of origins
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OriginsSpec {
+    Keyword(String),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct PolicySpec {
+    scope: String,
+    origins: OriginsSpec,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<String>>,
+    #[serde(default)]
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+    /// Origins of registered partner clients, looked up on every request
+    Registry,
+    /// The gateway-wide `TRUSTED_ORIGINS`
+    Trusted,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub scope: String,
+    pub origins: AllowedOrigins,
+    pub methods: String,
+    pub headers: String,
+    pub credentials: bool,
+    pub max_age: u32,
+}
+
+impl CorsPolicy {
+    fn covers(&self, path: &str) -> bool {
+        let scope = self.scope.trim_end_matches('/');
+        scope.is_empty() || path == scope || path.starts_with(&format!("{}/", scope))
+    }
+}
+
+/// Gateway-wide CORS settings: the policy for routes no scope policy covers,
+/// and what scope policies fall back to for anything they leave out.
+#[derive(Debug, Clone)]
+struct CorsDefaults {
+    /// From `CORS_ALLOWED_ORIGINS`; the trusted origins when unset
+    origins: Option<AllowedOrigins>,
+    methods: String,
+    headers: String,
+    /// From `CORS_ALLOW_CREDENTIALS`; when unset, only if
+    /// `CORS_ALLOWED_ORIGINS` lists origins
+    credentials: Option<bool>,
+    max_age: u32,
+}
+
+impl CorsDefaults {
+    /// Read `CORS_ALLOWED_ORIGINS` (comma separated, or `*`),
+    /// `CORS_ALLOWED_METHODS`, `CORS_ALLOWED_HEADERS`, `CORS_ALLOW_CREDENTIALS`
+    /// and `CORS_MAX_AGE`.
+    fn from_env() -> Self {
+        let list = |key: &str| {
+            env::var(key)
+                .ok()
+                .map(|raw| raw.split(',').map(str::trim).filter(|item| !item.is_empty()).collect::<Vec<_>>().join(", "))
+                .filter(|joined| !joined.is_empty())
+        };
+        let origins = list("CORS_ALLOWED_ORIGINS").map(|origins| match origins.as_str() {
+            "*" => AllowedOrigins::Any,
+            _ => AllowedOrigins::List(origins.split(", ").map(str::to_string).collect()),
+        });
+        CorsDefaults {
+            origins,
+            methods: list("CORS_ALLOWED_METHODS").unwrap_or_else(|| DEFAULT_METHODS.to_string()),
+            headers: list("CORS_ALLOWED_HEADERS").unwrap_or_else(|| DEFAULT_HEADERS.to_string()),
+            credentials: env::var("CORS_ALLOW_CREDENTIALS").ok().map(|v| v == "true" || v == "1"),
+            max_age: parse_env("CORS_MAX_AGE").unwrap_or(3600),
+        }
+    }
+}
+
+// Echoing any origin back with credentials would let every site act for the
+// signed-in user, so wildcard policies never allow credentials
+fn without_wildcard_credentials(mut policy: CorsPolicy) -> CorsPolicy {
+    if matches!(policy.origins, AllowedOrigins::Any) && policy.credentials {
+        warn!("CORS policy for '{}' allows any origin, ignoring its credentials setting", policy.scope);
+        policy.credentials = false;
+    }
+    policy
+}
+
+/// CORS policies per route scope, evaluated against the request path at runtime.
+#[derive(Debug, Default)]
+pub struct CorsPolicies {
+    policies: Vec<CorsPolicy>,
+    partner_origins: RwLock<HashSet<String>>,
+    trusted: TrustedOrigins,
+}
+
+impl CorsPolicies {
+    /// Load policies from `CORS_POLICIES` (JSON array of scope policies) and seed
+    /// the partner registry from `CORS_PARTNER_ORIGINS` (comma separated).
+    /// Routes no policy covers follow the `CORS_ALLOWED_*` settings, admitting
+    /// the trusted origins unless other origins are listed; with neither,
+    /// cross-origin requests get no CORS headers, except under the
+    /// development profile, which admits any origin. Those routes allow
+    /// credentials only when `CORS_ALLOWED_ORIGINS` lists origins, unless
+    /// `CORS_ALLOW_CREDENTIALS` says otherwise.
+    pub fn from_env(trusted: &TrustedOrigins, profile: Profile) -> Self {
+        let defaults = CorsDefaults::from_env();
+        let partner_origins = env::var("CORS_PARTNER_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        let specs: Vec<PolicySpec> = match env::var("CORS_POLICIES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid CORS_POLICIES configuration: {}", e);
+                invalid("CORS_POLICIES", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        let mut policies: Vec<CorsPolicy> = specs
+            .into_iter()
+            .map(|spec| CorsPolicy {
+                scope: spec.scope,
+                origins: match spec.origins {
+                    OriginsSpec::Keyword(keyword) if keyword == "*" => AllowedOrigins::Any,
+                    OriginsSpec::Keyword(keyword) if keyword == "registry" => AllowedOrigins::Registry,
+                    OriginsSpec::Keyword(keyword) if keyword == "trusted" => AllowedOrigins::Trusted,
+                    OriginsSpec::Keyword(origin) => AllowedOrigins::List(vec![origin]),
+                    OriginsSpec::List(origins) => AllowedOrigins::List(origins),
+                },
+                methods: spec.methods.map(|m| m.join(", ")).unwrap_or_else(|| defaults.methods.clone()),
+                headers: spec.headers.map(|h| h.join(", ")).unwrap_or_else(|| defaults.headers.clone()),
+                credentials: spec.credentials,
+                max_age: spec.max_age.unwrap_or(defaults.max_age),
+            })
+            .map(without_wildcard_credentials)
+            .collect();
+
+        let default_origins = match defaults.origins {
+            Some(origins) => Some(origins),
+            None if !trusted.is_empty() => Some(AllowedOrigins::Trusted),
+            None if profile.permissive_cors() => Some(AllowedOrigins::Any),
+            None => None,
+        };
+        let has_catch_all = policies.iter().any(|policy| policy.covers("/"));
+        if let (Some(origins), false) = (default_origins, has_catch_all) {
+            // Credentials are only sent to origins someone wrote down for CORS
+            let listed = matches!(origins, AllowedOrigins::List(_));
+            policies.push(without_wildcard_credentials(CorsPolicy {
+                scope: String::new(),
+                origins,
+                methods: defaults.methods,
+                headers: defaults.headers,
+                credentials: defaults.credentials.unwrap_or(listed),
+                max_age: defaults.max_age,
+            }));
+        }
+
+        info!("Loaded {} CORS scope policies", policies.len());
+        CorsPolicies {
+            policies,
+            partner_origins: RwLock::new(partner_origins),
+            trusted: trusted.clone(),
+        }
+    }
+
+    /// The most specific policy whose scope covers `path`.
+    pub fn policy_for(&self, path: &str) -> Option<&CorsPolicy> {
+        self.policies
+            .iter()
+            .filter(|policy| policy.covers(path))
+            .max_by_key(|policy| policy.scope.len())
+    }
+
+    pub fn origin_allowed(&self, policy: &CorsPolicy, origin: &str) -> bool {
+        match &policy.origins {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(origins) => origins.iter().any(|allowed| TrustedOrigins::pattern_matches(allowed, origin)),
+            AllowedOrigins::Registry => self.partner_origins.read().unwrap().contains(origin),
+            AllowedOrigins::Trusted => self.trusted.is_trusted(origin),
+        }
+    }
+}
+
+/// Middleware applying the scope-specific CORS policy to each request.
+pub struct Cors {
+    policies: Arc<CorsPolicies>,
+}
+
+impl Cors {
+    pub fn new(policies: Arc<CorsPolicies>) -> Self {
+        Cors { policies }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Cors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CorsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service: Rc::new(service),
+            policies: self.policies.clone(),
+        }))
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: Rc<S>,
+    policies: Arc<CorsPolicies>,
+}
+
+impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let policies = self.policies.clone();
+
+        Box::pin(async move {
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+
+            let (origin, policy) = match (origin, policies.policy_for(req.path())) {
+                (Some(origin), Some(policy)) => (origin, policy.clone()),
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let allowed = policies.origin_allowed(&policy, &origin);
+            let is_preflight = req.method() == Method::OPTIONS
+                && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+            if is_preflight {
+                let response = if allowed {
+                    let mut builder = HttpResponse::NoContent();
+                    builder
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.as_str()))
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, policy.methods.as_str()))
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, policy.headers.as_str()))
+                        .insert_header((header::ACCESS_CONTROL_MAX_AGE, policy.max_age.to_string()))
+                        .insert_header((header::VARY, "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"));
+                    if policy.credentials {
+                        builder.insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+                    }
+                    builder.finish()
+                } else {
+                    HttpResponse::Forbidden().json(serde_json::json!({
+                        "error": "Origin not allowed",
+                        "origin": origin
+                    }))
+                };
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            let headers = res.headers_mut();
+            // Upstreams may vary on other headers already; refused origins get
+            // it too so caches never hand them an allowed origin's response
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            if allowed {
+                if let Ok(value) = HeaderValue::from_str(&origin) {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+                }
+                if policy.credentials {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+                }
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App};
+
+    fn policies() -> CorsPolicies {
+        CorsPolicies {
+            policies: vec![CorsPolicy {
+                scope: String::new(),
+                origins: AllowedOrigins::List(vec!["https://chat.example.com".to_string()]),
+                methods: DEFAULT_METHODS.to_string(),
+                headers: DEFAULT_HEADERS.to_string(),
+                credentials: true,
+                max_age: 3600,
+            }],
+            partner_origins: RwLock::default(),
+            trusted: TrustedOrigins::default(),
+        }
+    }
+
+    fn preflight(origin: &str) -> TestRequest {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/chat/rooms")
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+    }
+
+    #[actix_web::test]
+    async fn answers_preflights_from_listed_origins() {
+        let app = test::init_service(App::new().wrap(Cors::new(Arc::new(policies())))).await;
+        let res = test::call_service(&app, preflight("https://chat.example.com").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://chat.example.com");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+    }
+
+    #[actix_web::test]
+    async fn refuses_preflights_from_spoofed_origins() {
+        let app = test::init_service(App::new().wrap(Cors::new(Arc::new(policies())))).await;
+        for origin in ["https://evil.io", "https://chat.example.com.evil.io", "http://chat.example.com", "null"] {
+            let res = test::call_service(&app, preflight(origin).to_request()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{}", origin);
+            assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none(), "{}", origin);
+            assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none(), "{}", origin);
+        }
+    }
+
+    #[actix_web::test]
+    async fn does_not_echo_spoofed_origins_on_requests() {
+        let app = App::new()
+            .wrap(Cors::new(Arc::new(policies())))
+            .route("/api/chat/rooms", web::get().to(HttpResponse::Ok));
+        let app = test::init_service(app).await;
+        let req = TestRequest::get().uri("/api/chat/rooms").insert_header((header::ORIGIN, "https://evil.io"));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+    }
+
+    #[test]
+    fn wildcard_policies_never_allow_credentials() {
+        let policy = without_wildcard_credentials(CorsPolicy {
+            scope: "/public".to_string(),
+            origins: AllowedOrigins::Any,
+            methods: DEFAULT_METHODS.to_string(),
+            headers: DEFAULT_HEADERS.to_string(),
+            credentials: true,
+            max_age: 3600,
+        });
+        assert!(!policy.credentials);
+    }
+}
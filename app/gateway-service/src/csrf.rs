@@ -0,0 +1,223 @@
+use actix_web::body::EitherBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future::LocalBoxFuture;
+use tracing::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::admin::constant_time_eq;
+use crate::rbac::routed_path;
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct CsrfConfig {
+    pub enabled: bool,
+    pub cookie_name: String,
+    pub header_name: String,
+    /// Mark the cookie `Secure`; only disable for plain-HTTP development
+    pub secure_cookie: bool,
+    /// Path prefixes skipped by the check
+    pub exempt_paths: Vec<String>,
+}
+
+impl CsrfConfig {
+    pub fn from_env() -> Self {
+        CsrfConfig {
+            enabled: env::var("CSRF_PROTECTION").map(|v| v != "false" && v != "0").unwrap_or(true),
+            cookie_name: env::var("CSRF_COOKIE_NAME").unwrap_or_else(|_| "csrf_token".to_string()),
+            header_name: env::var("CSRF_HEADER_NAME").unwrap_or_else(|_| "X-CSRF-Token".to_string()),
+            secure_cookie: env::var("CSRF_COOKIE_SECURE").map(|v| v != "false" && v != "0").unwrap_or(true),
+            exempt_paths: env::var("CSRF_EXEMPT_PATHS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    // Only writes riding on the session cookie can be forged cross-site; a
+    // browser never attaches an Authorization or API key header on its own
+    fn applies(&self, req: &ServiceRequest, session_cookie: &str) -> bool {
+        self.enabled
+            && !matches!(req.method().as_str(), "GET" | "HEAD" | "OPTIONS")
+            && routed_path(req.request()).starts_with("/api/")
+            && !self.exempt_paths.iter().any(|p| routed_path(req.request()).starts_with(p.as_str()))
+            && req.cookie(session_cookie).is_some()
+            && !req.headers().contains_key("Authorization")
+            && !req.headers().contains_key("X-Api-Key")
+    }
+}
+
+// Why a request failed the double-submit check, if it did
+fn rejection(config: &CsrfConfig, req: &ServiceRequest) -> Option<&'static str> {
+    let cookie = match req.cookie(&config.cookie_name) {
+        Some(cookie) => cookie,
+        None => return Some("missing_cookie"),
+    };
+    let header = match req.headers().get(config.header_name.as_str()).and_then(|v| v.to_str().ok()) {
+        Some(header) => header,
+        None => return Some("missing_header"),
+    };
+    if !constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) {
+        return Some("mismatch");
+    }
+    None
+}
+
+// Hand out a fresh token as a cookie and in the body, for scripts to echo
+// back in the CSRF header
+pub async fn token(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let config = &data.config.load().csrf;
+    let mut bytes = [0u8; 32];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "Could not generate CSRF token"
+        })));
+    }
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    // Readable by scripts on purpose; SameSite keeps it off cross-site requests
+    let cookie = Cookie::build(config.cookie_name.clone(), token.clone())
+        .path("/")
+        .secure(config.secure_cookie)
+        .http_only(false)
+        .same_site(SameSite::Strict)
+        .finish();
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .insert_header(("Cache-Control", "no-store"))
+        .json(serde_json::json!({
+            "csrfToken": token,
+            "header": config.header_name,
+        })))
+}
+
+/// Middleware enforcing double-submit CSRF tokens on cookie-authenticated
+/// state-changing API requests.
+pub struct CsrfGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let reason = req
+                .app_data::<web::Data<AppState>>()
+                .filter(|data| data.config.load().csrf.applies(&req, &data.config.load().session.cookie_name))
+                .and_then(|data| {
+                    let reason = rejection(&data.config.load().csrf, &req)?;
+                    data.metrics.incr("gateway_csrf_rejections_total", &[("reason", reason)], 1);
+                    Some(reason)
+                });
+            let reason = match reason {
+                Some(reason) => reason,
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            warn!("CSRF check failed ({}) for {} {}", reason, req.method(), req.path());
+            let response = HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "CSRF token missing or invalid",
+                "code": "csrf_failed",
+                "reason": reason
+            }));
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const SESSION: &str = "session";
+
+    fn config() -> CsrfConfig {
+        CsrfConfig {
+            enabled: true,
+            cookie_name: "csrf_token".to_string(),
+            header_name: "X-CSRF-Token".to_string(),
+            secure_cookie: true,
+            exempt_paths: vec!["/api/auth/login".to_string()],
+        }
+    }
+
+    // A cookie-authenticated write, as a browser would send it
+    fn write(uri: &str) -> TestRequest {
+        TestRequest::post().uri(uri).cookie(Cookie::new(SESSION, "s"))
+    }
+
+    #[test]
+    fn applies_to_cookie_authenticated_api_writes() {
+        let config = config();
+        assert!(config.applies(&write("/api/chat/rooms").to_srv_request(), SESSION));
+        assert!(config.applies(&write("/api/%63hat/rooms").to_srv_request(), SESSION));
+        assert!(!CsrfConfig { enabled: false, ..config.clone() }.applies(&write("/api/chat/rooms").to_srv_request(), SESSION));
+    }
+
+    #[test]
+    fn exempts_safe_methods_headers_and_paths() {
+        let config = config();
+        let get = TestRequest::get().uri("/api/chat/rooms").cookie(Cookie::new(SESSION, "s"));
+        assert!(!config.applies(&get.to_srv_request(), SESSION));
+        let bearer = write("/api/chat/rooms").insert_header(("Authorization", "Bearer t"));
+        assert!(!config.applies(&bearer.to_srv_request(), SESSION));
+        let api_key = write("/api/chat/rooms").insert_header(("X-Api-Key", "k"));
+        assert!(!config.applies(&api_key.to_srv_request(), SESSION));
+        assert!(!config.applies(&write("/api/auth/login").to_srv_request(), SESSION));
+        assert!(!config.applies(&write("/health").to_srv_request(), SESSION));
+        let anonymous = TestRequest::post().uri("/api/chat/rooms");
+        assert!(!config.applies(&anonymous.to_srv_request(), SESSION));
+    }
+
+    #[test]
+    fn requires_the_header_to_match_the_cookie() {
+        let config = config();
+        let token = |req: TestRequest| req.cookie(Cookie::new("csrf_token", "abc"));
+        assert_eq!(rejection(&config, &write("/api/chat/rooms").to_srv_request()), Some("missing_cookie"));
+        assert_eq!(rejection(&config, &token(write("/api/chat/rooms")).to_srv_request()), Some("missing_header"));
+        let wrong = token(write("/api/chat/rooms")).insert_header(("X-CSRF-Token", "abd"));
+        assert_eq!(rejection(&config, &wrong.to_srv_request()), Some("mismatch"));
+        let echoed = token(write("/api/chat/rooms")).insert_header(("X-CSRF-Token", "abc"));
+        assert_eq!(rejection(&config, &echoed.to_srv_request()), None);
+    }
+}
@@ -0,0 +1,169 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::admin;
+use crate::auth::AuthMiddleware;
+use crate::inflight;
+use crate::revocation;
+use crate::AppState;
+
+// Headers whose values are never echoed back
+const SECRET_HEADERS: &[&str] = &["authorization", "cookie", "x-admin-token", "x-api-key"];
+
+fn has_admin_role(claims: &Map<String, Value>) -> bool {
+    let is_admin = |v: &Value| v.as_str() == Some("admin");
+    claims.get("role").map(is_admin).unwrap_or(false)
+        || claims
+            .get("roles")
+            .and_then(Value::as_array)
+            .map(|roles| roles.iter().any(is_admin))
+            .unwrap_or(false)
+}
+
+/// Debug endpoints are open in dev mode; otherwise the caller needs the admin
+/// token or a bearer token with the admin role. The production profile does
+/// not serve them.
+#[allow(clippy::result_large_err)]
+async fn authorize(req: &HttpRequest, data: &AppState) -> Result<(), HttpResponse> {
+    if !data.config.load().profile.debug_endpoints() {
+        return Err(HttpResponse::NotFound().finish());
+    }
+    if data.config.load().dev_mode || admin::authorize(req).is_ok() {
+        return Ok(());
+    }
+    let admin_token = match AuthMiddleware::bearer_token(req) {
+        Ok(token) => AuthMiddleware::raw_claims(req, token)
+            .await
+            .map(|claims| has_admin_role(&claims))
+            .unwrap_or(false),
+        Err(_) => false,
+    };
+    if admin_token {
+        return Ok(());
+    }
+    Err(HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Debug endpoints require dev mode or the admin role"
+    })))
+}
+
+/// Scopes from an OAuth-style space-separated `scope` claim or a `scopes` array.
+fn scopes(claims: &Map<String, Value>) -> Vec<String> {
+    if let Some(scope) = claims.get("scope").and_then(Value::as_str) {
+        return scope.split_whitespace().map(str::to_string).collect();
+    }
+    claims
+        .get("scopes")
+        .and_then(Value::as_array)
+        .map(|scopes| scopes.iter().filter_map(Value::as_str).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+// Echo the request as the gateway sees it after its middleware ran
+pub async fn echo(req: HttpRequest, body: web::Bytes, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &data).await {
+        return Ok(response);
+    }
+
+    let mut headers = Map::new();
+    for (name, value) in req.headers() {
+        let value = if SECRET_HEADERS.contains(&name.as_str()) {
+            "[redacted]".to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        match headers.get_mut(name.as_str()) {
+            Some(Value::Array(values)) => values.push(Value::String(value)),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), Value::String(value)]),
+            None => {
+                headers.insert(name.to_string(), Value::String(value));
+            }
+        }
+    }
+
+    let identity = match AuthMiddleware::validate_token(&req).await {
+        Ok(claims) => serde_json::json!({ "user_id": claims.sub, "username": claims.username }),
+        Err(_) => Value::Null,
+    };
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let params: HashMap<&str, &str> = req.match_info().iter().collect();
+    let body = match serde_json::from_slice::<Value>(&body) {
+        Ok(json) => json,
+        Err(_) if body.is_empty() => Value::Null,
+        Err(_) => Value::String(String::from_utf8_lossy(&body).into_owned()),
+    };
+    let connection = req.connection_info();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "request_id": inflight::request_id(&req),
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": query,
+        "route": req.match_pattern(),
+        "params": params,
+        "http_version": format!("{:?}", req.version()),
+        "peer_addr": req.peer_addr().map(|addr| addr.to_string()),
+        "client_ip": connection.realip_remote_addr(),
+        "scheme": connection.scheme(),
+        "host": connection.host(),
+        "headers": headers,
+        "identity": identity,
+        "body": body,
+    })))
+}
+
+// Report what the gateway makes of a bearer token, or of `?token=`
+pub async fn introspect(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &data).await {
+        return Ok(response);
+    }
+
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+    let token = match query.as_ref().and_then(|q| q.get("token")) {
+        Some(token) => token.as_str(),
+        None => match AuthMiddleware::bearer_token(&req) {
+            Ok(token) => token,
+            Err(response) => return Ok(response),
+        },
+    };
+
+    let claims = match AuthMiddleware::raw_claims(&req, token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+                "active": false,
+                "error": "Invalid or expired token"
+            })))
+        }
+    };
+    let jti = claims.get("jti").and_then(Value::as_str);
+    if let Err(response) = data.revocation.ensure_active(&revocation::token_id(token, jti)).await {
+        return Ok(response);
+    }
+    if let Some(sid) = claims.get("sid").and_then(Value::as_str) {
+        if let Err(response) = data.revocation.ensure_active(&revocation::session_id(sid)).await {
+            return Ok(response);
+        }
+    }
+    let timestamp = |name: &str| {
+        claims
+            .get(name)
+            .and_then(Value::as_i64)
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+    };
+    let expires_at = timestamp("exp");
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "active": true,
+        "subject": claims.get("sub"),
+        "username": claims.get("username"),
+        "issued_at": timestamp("iat").map(|at| at.to_rfc3339()),
+        "expires_at": expires_at.map(|at| at.to_rfc3339()),
+        "expires_in_seconds": expires_at.map(|at| (at - chrono::Utc::now()).num_seconds()),
+        "scopes": scopes(&claims),
+        "admin": has_admin_role(&claims),
+        "claims": claims,
+    })))
+}
@@ -0,0 +1,183 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use tracing::{error, info};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::auth::AuthMiddleware;
+use crate::refresh::Grants;
+use crate::revocation;
+use crate::AppState;
+
+/// Longest device id taken from `X-Device-Id`; longer or unprintable ids are
+/// replaced by a generated one.
+const MAX_DEVICE_ID_LEN: usize = 128;
+
+/// One signed-in device: a login and every token rotated from it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSession {
+    pub id: String,
+    #[serde(skip)]
+    user: String,
+    pub device_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+    pub last_seen: i64,
+    /// When the session lapses unless its refresh token is used (unix seconds)
+    pub expires_at: usize,
+}
+
+/// Active device sessions per user. Like refresh token families they are kept
+/// per gateway instance; signing one out goes through the revocation store,
+/// so its tokens are refused on every instance.
+#[derive(Default)]
+pub struct DeviceSessions {
+    sessions: Mutex<HashMap<String, DeviceSession>>,
+}
+
+impl DeviceSessions {
+    fn insert(&self, session: DeviceSession) {
+        let now = chrono::Utc::now().timestamp() as usize;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        sessions.insert(session.id.clone(), session);
+    }
+
+    /// Push back the expiry of a session whose refresh token was rotated.
+    pub fn extend(&self, id: &str, ttl: Duration) {
+        let now = chrono::Utc::now();
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            session.last_seen = now.timestamp();
+            session.expires_at = now.timestamp() as usize + ttl.as_secs() as usize;
+        }
+    }
+
+    /// Note that a token of the session was just used.
+    pub fn seen(&self, id: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            session.last_seen = chrono::Utc::now().timestamp();
+        }
+    }
+
+    /// Unexpired sessions of `user`, most recently used first.
+    pub fn list(&self, user: &str) -> Vec<DeviceSession> {
+        let now = chrono::Utc::now().timestamp() as usize;
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| s.user == user && s.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+        sessions
+    }
+
+    /// Remove a session of `user`; sessions of other users are left alone.
+    pub fn end(&self, user: &str, id: &str) -> Option<DeviceSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(session) if session.user == user => sessions.remove(id),
+            _ => None,
+        }
+    }
+}
+
+// Device id the client identifies itself with, if usable
+fn device_id(req: &HttpRequest) -> Option<String> {
+    let id = req.headers().get("X-Device-Id")?.to_str().ok()?.trim();
+    let usable = !id.is_empty() && id.len() <= MAX_DEVICE_ID_LEN && id.chars().all(|c| c.is_ascii_graphic());
+    usable.then(|| id.to_string())
+}
+
+/// Start a device session for a user signing in and tie `grants` to it, so
+/// every token issued from them carries its `sid` and `did`.
+pub fn start(data: &AppState, req: &HttpRequest, user: &str, mut grants: Grants) -> Grants {
+    let now = chrono::Utc::now().timestamp();
+    let session = DeviceSession {
+        id: data.refresh.random_id(),
+        user: user.to_string(),
+        device_id: device_id(req).unwrap_or_else(|| data.refresh.random_id()),
+        user_agent: req
+            .headers()
+            .get("User-Agent")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        ip: data.ip_filter.client_ip(req).map(|ip| ip.to_string()),
+        created_at: now,
+        last_seen: now,
+        expires_at: now as usize + data.refresh.session_ttl().as_secs() as usize,
+    };
+    info!("Started session {} for user {} on device {}", session.id, user, session.device_id);
+    data.metrics.incr("gateway_device_sessions_total", &[("event", "started")], 1);
+    grants.sid = Some(session.id.clone());
+    grants.did = Some(session.device_id.clone());
+    data.devices.insert(session);
+    grants
+}
+
+/// Handle `GET /api/auth/sessions`: the caller's signed-in devices, with the
+/// one making the request marked `current`.
+pub async fn list(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let claims = match AuthMiddleware::validate_token(&req).await {
+        Ok(claims) => claims,
+        Err(response) => return Ok(response),
+    };
+    let sessions: Vec<Value> = data
+        .devices
+        .list(&claims.sub)
+        .into_iter()
+        .map(|session| {
+            let current = claims.sid.as_deref() == Some(session.id.as_str());
+            let mut json = serde_json::to_value(session).unwrap_or_default();
+            json["current"] = Value::Bool(current);
+            json
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "sessions": sessions })))
+}
+
+/// Handle `DELETE /api/auth/sessions/{id}`: sign one of the caller's devices
+/// out. Its tokens are revoked and its refresh family dropped.
+pub async fn revoke(req: HttpRequest, path: web::Path<String>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let claims = match AuthMiddleware::validate_token(&req).await {
+        Ok(claims) => claims,
+        Err(response) => return Ok(response),
+    };
+    let id = path.into_inner();
+    let session = match data.devices.end(&claims.sub, &id) {
+        Some(session) => session,
+        None => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Session not found"
+            })))
+        }
+    };
+
+    if let Err(e) = data.revocation.revoke(&revocation::session_id(&id), session.expires_at).await {
+        error!("Failed to revoke session {} of user {}: {}", id, claims.sub, e);
+        data.devices.insert(session);
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Could not revoke session, try again"
+        })));
+    }
+    data.refresh.revoke_family(&id);
+
+    info!("User {} signed out session {} on device {}", claims.sub, id, session.device_id);
+    data.metrics.incr("gateway_device_sessions_total", &[("event", "revoked")], 1);
+    data.audit.record(
+        "session_revoked",
+        &claims.sub,
+        serde_json::json!({
+            "session": id,
+            "device": session.device_id,
+            "current": claims.sid.as_deref() == Some(id.as_str()),
+        }),
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,394 @@
+use actix_web::web;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tracing::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::config::{invalid, parse_env};
+use crate::kubernetes::{self, SERVICE_ACCOUNT_DIR};
+use crate::upstream::Upstreams;
+use crate::AppState;
+
+// How long Consul holds a blocking query open before answering unchanged
+const CONSUL_WAIT: Duration = Duration::from_secs(60);
+
+/// Service registry instance addresses are read from.
+#[derive(Clone)]
+pub enum DiscoveryBackend {
+    /// Healthy instances from Consul's health API, watched with blocking queries
+    Consul {
+        url: String,
+        token: Option<String>,
+        datacenter: Option<String>,
+    },
+    /// Keys under `{prefix}{service}/` in etcd's v3 JSON API, one instance
+    /// per value, polled every interval
+    Etcd { url: String, prefix: String },
+    /// Ready pods from the EndpointSlices of Kubernetes services, watched
+    /// through the API server so traffic bypasses kube-proxy
+    Kubernetes {
+        api: String,
+        /// For services named without one
+        namespace: String,
+    },
+}
+
+impl DiscoveryBackend {
+    fn name(&self) -> &'static str {
+        match self {
+            DiscoveryBackend::Consul { .. } => "consul",
+            DiscoveryBackend::Etcd { .. } => "etcd",
+            DiscoveryBackend::Kubernetes { .. } => "kubernetes",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DiscoveryConfig {
+    pub backend: Option<DiscoveryBackend>,
+    /// Gateway service names and the names they are registered under; other
+    /// services keep their `*_SERVICE_URL`
+    pub services: Vec<(String, String)>,
+    /// Scheme for registry entries that are bare `host:port` addresses
+    pub scheme: String,
+    /// Polling interval for etcd, and the pause after a failed lookup
+    pub interval: Duration,
+}
+
+// The Consul token stays out of the startup config log
+impl fmt::Debug for DiscoveryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("DiscoveryConfig");
+        match &self.backend {
+            None => s.field("backend", &"none"),
+            Some(DiscoveryBackend::Consul { url, datacenter, .. }) => s
+                .field("backend", &"consul")
+                .field("url", url)
+                .field("datacenter", datacenter),
+            Some(DiscoveryBackend::Etcd { url, prefix }) => s
+                .field("backend", &"etcd")
+                .field("url", url)
+                .field("prefix", prefix),
+            Some(DiscoveryBackend::Kubernetes { api, namespace }) => s
+                .field("backend", &"kubernetes")
+                .field("api", api)
+                .field("namespace", namespace),
+        };
+        s.field("services", &self.services)
+            .field("scheme", &self.scheme)
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+fn with_scheme(addr: String) -> String {
+    match addr.contains("://") {
+        true => addr,
+        false => format!("http://{}", addr),
+    }
+}
+
+impl DiscoveryConfig {
+    /// Read `DISCOVERY_BACKEND` (`consul`, `etcd` or `kubernetes`) and
+    /// `DISCOVERY_SERVICES`, the services to resolve as `service` or
+    /// `service=registered-name`, comma separated. Consul is reached at
+    /// `CONSUL_HTTP_ADDR` with `CONSUL_HTTP_TOKEN` and `CONSUL_DATACENTER`;
+    /// etcd at `ETCD_ENDPOINT` under `ETCD_PREFIX` (`/services/` by default).
+    /// Kubernetes services are named `[namespace/]name[:port-name]` and
+    /// looked up in `KUBERNETES_NAMESPACE`, the pod's own by default.
+    pub fn from_env() -> Self {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let backend = match var("DISCOVERY_BACKEND").map(|v| v.to_lowercase()).as_deref() {
+            None | Some("none") => None,
+            Some("consul") => Some(DiscoveryBackend::Consul {
+                url: with_scheme(var("CONSUL_HTTP_ADDR").unwrap_or_else(|| "127.0.0.1:8500".to_string())),
+                token: var("CONSUL_HTTP_TOKEN"),
+                datacenter: var("CONSUL_DATACENTER"),
+            }),
+            Some("etcd") => Some(DiscoveryBackend::Etcd {
+                url: with_scheme(var("ETCD_ENDPOINT").unwrap_or_else(|| "127.0.0.1:2379".to_string())),
+                prefix: var("ETCD_PREFIX").unwrap_or_else(|| "/services/".to_string()),
+            }),
+            Some("kubernetes") | Some("k8s") => match (var("KUBERNETES_SERVICE_HOST"), var("KUBERNETES_SERVICE_PORT")) {
+                (Some(host), Some(port)) => Some(DiscoveryBackend::Kubernetes {
+                    api: var("KUBERNETES_API_URL").unwrap_or_else(|| match host.contains(':') {
+                        true => format!("https://[{}]:{}", host, port),
+                        false => format!("https://{}:{}", host, port),
+                    }),
+                    namespace: var("KUBERNETES_NAMESPACE")
+                        .or_else(|| fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT_DIR)).ok())
+                        .map(|namespace| namespace.trim().to_string())
+                        .unwrap_or_else(|| "default".to_string()),
+                }),
+                _ => {
+                    invalid("DISCOVERY_BACKEND", "kubernetes needs KUBERNETES_SERVICE_HOST and KUBERNETES_SERVICE_PORT, set in pods");
+                    None
+                }
+            },
+            Some(other) => {
+                warn!("Unknown DISCOVERY_BACKEND '{}', using the configured service URLs", other);
+                invalid("DISCOVERY_BACKEND", format!("'{}' is not one of consul, etcd, kubernetes", other));
+                None
+            }
+        };
+        let services: Vec<(String, String)> = var("DISCOVERY_SERVICES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once('=') {
+                Some((service, registered)) => (service.trim().to_string(), registered.trim().to_string()),
+                None => (entry.to_string(), entry.to_string()),
+            })
+            .collect();
+        if backend.is_none() && !services.is_empty() {
+            invalid("DISCOVERY_SERVICES", "set DISCOVERY_BACKEND to resolve services from a registry");
+        }
+        DiscoveryConfig {
+            backend,
+            services,
+            scheme: var("DISCOVERY_SCHEME").unwrap_or_else(|| "http".to_string()),
+            interval: Duration::from_secs(parse_env("DISCOVERY_INTERVAL_SECONDS").filter(|s| *s > 0).unwrap_or(10)),
+        }
+    }
+
+    /// Problems with services that cannot be resolved.
+    pub fn validate(&self, known: &[(String, String)]) -> Vec<String> {
+        self.services
+            .iter()
+            .filter(|(service, _)| !known.iter().any(|(name, _)| name == service))
+            .map(|(service, _)| format!("DISCOVERY_SERVICES: '{}' is not an upstream service", service))
+            .collect()
+    }
+}
+
+/// Instance URLs resolved from the registry, standing in for the configured
+/// URLs of the services they were found for.
+#[derive(Default)]
+pub struct Discovered {
+    instances: RwLock<HashMap<String, String>>,
+}
+
+impl Discovered {
+    /// `services` with the URLs of discovered services replaced.
+    pub fn apply(&self, mut services: Vec<(String, String)>) -> Vec<(String, String)> {
+        let instances = self.instances.read().unwrap();
+        for (service, urls) in services.iter_mut() {
+            if let Some(discovered) = instances.get(service) {
+                *urls = discovered.clone();
+            }
+        }
+        services
+    }
+
+    // Whether the instances differ from the last ones found
+    fn update(&self, service: &str, urls: String) -> bool {
+        let mut instances = self.instances.write().unwrap();
+        if instances.get(service) == Some(&urls) {
+            return false;
+        }
+        instances.insert(service.to_string(), urls);
+        true
+    }
+}
+
+#[derive(Deserialize)]
+struct ConsulEntry {
+    #[serde(rename = "Node")]
+    node: ConsulNode,
+    #[serde(rename = "Service")]
+    service: ConsulService,
+}
+
+#[derive(Deserialize)]
+struct ConsulNode {
+    #[serde(rename = "Address")]
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct ConsulService {
+    #[serde(rename = "Address")]
+    address: String,
+    #[serde(rename = "Port")]
+    port: u16,
+}
+
+#[derive(Deserialize)]
+struct EtcdRange {
+    #[serde(default)]
+    kvs: Vec<EtcdKv>,
+}
+
+#[derive(Deserialize)]
+struct EtcdKv {
+    #[serde(default)]
+    value: String,
+}
+
+// Healthy instances of `registered`, once the index moves past `index`;
+// returns the new index along with them
+async fn consul_instances(
+    data: &AppState,
+    url: &str,
+    token: Option<&str>,
+    datacenter: Option<&str>,
+    registered: &str,
+    scheme: &str,
+    index: u64,
+) -> Result<(Vec<String>, u64), String> {
+    let mut query = vec![
+        ("passing", "true".to_string()),
+        ("index", index.to_string()),
+        ("wait", format!("{}s", CONSUL_WAIT.as_secs())),
+    ];
+    if let Some(datacenter) = datacenter {
+        query.push(("dc", datacenter.to_string()));
+    }
+    let mut request = data
+        .http_client
+        .get(format!("{}/v1/health/service/{}", url.trim_end_matches('/'), registered))
+        .query(&query)
+        .timeout(CONSUL_WAIT + Duration::from_secs(10));
+    if let Some(token) = token {
+        request = request.header("X-Consul-Token", token);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Consul answered {}", response.status()));
+    }
+    let next = response
+        .headers()
+        .get("X-Consul-Index")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let entries: Vec<ConsulEntry> = response.json().await.map_err(|e| e.to_string())?;
+    let instances = entries
+        .into_iter()
+        .map(|entry| {
+            let address = match entry.service.address.is_empty() {
+                true => entry.node.address,
+                false => entry.service.address,
+            };
+            format!("{}://{}:{}", scheme, address, entry.service.port)
+        })
+        .collect();
+    // A reset index starts blocking over from the beginning
+    Ok((instances, if next < index { 0 } else { next }))
+}
+
+// Values under `{prefix}{registered}/`, each an instance URL or `host:port`
+async fn etcd_instances(data: &AppState, url: &str, prefix: &str, registered: &str, scheme: &str) -> Result<Vec<String>, String> {
+    let key = format!("{}{}/", prefix, registered);
+    // Everything below the key: the same bytes with the trailing `/` bumped
+    let mut range_end = key.clone().into_bytes();
+    if let Some(last) = range_end.last_mut() {
+        *last += 1;
+    }
+    let response = data
+        .http_client
+        .post(format!("{}/v3/kv/range", url.trim_end_matches('/')))
+        .json(&serde_json::json!({
+            "key": STANDARD.encode(key.as_bytes()),
+            "range_end": STANDARD.encode(&range_end),
+        }))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("etcd answered {}", response.status()));
+    }
+    let range: EtcdRange = response.json().await.map_err(|e| e.to_string())?;
+    Ok(range
+        .kvs
+        .iter()
+        .filter_map(|kv| STANDARD.decode(&kv.value).ok())
+        .filter_map(|value| String::from_utf8(value).ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .map(|value| match value.contains("://") {
+            true => value,
+            false => format!("{}://{}", scheme, value),
+        })
+        .collect())
+}
+
+/// Swap in the instances found for `service` and rebuild the upstreams.
+pub fn publish(data: &AppState, service: &str, backend: &str, mut instances: Vec<String>) {
+    data.metrics.gauge_set("gateway_discovered_instances", &[("service", service)], instances.len() as f64);
+    if instances.is_empty() {
+        // Keeping stale instances beats a service with none to pick from
+        warn!("{} lists no healthy {} instances, keeping the current ones", backend, service);
+        return;
+    }
+    instances.sort();
+    instances.dedup();
+    if !data.discovery.update(service, instances.join(",")) {
+        return;
+    }
+    info!("{} instances of {} from {}: {}", instances.len(), service, backend, instances.join(", "));
+    data.metrics.incr("gateway_discovery_changes_total", &[("service", service)], 1);
+    let services = data.discovery.apply(data.config.load().upstream_services());
+    data.upstreams.store(Upstreams::new(&services));
+}
+
+async fn watch_service(data: web::Data<AppState>, service: String, registered: String) {
+    let config = data.config.load().discovery.clone();
+    let backend = match &config.backend {
+        Some(DiscoveryBackend::Kubernetes { api, namespace }) => {
+            return kubernetes::watch(data.clone(), service, registered, api.clone(), namespace.clone()).await;
+        }
+        Some(backend) => backend.clone(),
+        None => return,
+    };
+    let mut index = 0;
+    loop {
+        let result = match &backend {
+            DiscoveryBackend::Consul { url, token, datacenter } => {
+                consul_instances(&data, url, token.as_deref(), datacenter.as_deref(), &registered, &config.scheme, index)
+                    .await
+                    .map(|(instances, next)| {
+                        index = next;
+                        instances
+                    })
+            }
+            DiscoveryBackend::Etcd { url, prefix } => etcd_instances(&data, url, prefix, &registered, &config.scheme).await,
+            DiscoveryBackend::Kubernetes { .. } => unreachable!("watched above"),
+        };
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        data.metrics.incr("gateway_discovery_lookups_total", &[("service", &service), ("backend", backend.name()), ("outcome", outcome)], 1);
+        match result {
+            Ok(instances) => publish(&data, &service, backend.name(), instances),
+            Err(e) => {
+                warn!("Looking up {} in {} failed, keeping the current instances: {}", service, backend.name(), e);
+                index = 0;
+            }
+        }
+        // Consul's blocking query already waited for a change
+        if matches!(backend, DiscoveryBackend::Etcd { .. }) || outcome == "failure" {
+            tokio::time::sleep(config.interval).await;
+        }
+    }
+}
+
+/// Watch the registry for each discovered service, feeding the instances
+/// it lists to the load balancer as they change.
+pub fn start(data: web::Data<AppState>) {
+    let config = data.config.load().discovery.clone();
+    let backend = match &config.backend {
+        Some(backend) => backend.name(),
+        None => return,
+    };
+    for (service, registered) in config.services {
+        info!("Resolving {} instances from {} as '{}'", service, backend, registered);
+        actix_web::rt::spawn(watch_service(data.clone(), service, registered));
+    }
+}
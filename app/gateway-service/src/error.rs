@@ -28,6 +28,7 @@ impl ResponseError for ApiError {
 }
 
 impl ApiError {
+    #[allow(dead_code)]
     pub fn unauthorized(message: &str) -> Self {
         ApiError {
             error: "Unauthorized".to_string(),
@@ -44,6 +45,7 @@ impl ApiError {
         }
     }
     
+    #[allow(dead_code)]
     pub fn not_found(message: &str) -> Self {
         ApiError {
             error: "Not Found".to_string(),
@@ -52,6 +54,7 @@ impl ApiError {
         }
     }
     
+    #[allow(dead_code)]
     pub fn internal_error(message: &str) -> Self {
         ApiError {
             error: "Internal Server Error".to_string(),
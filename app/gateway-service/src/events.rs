@@ -0,0 +1,380 @@
+//! Typed schema of the real-time events exchanged over chat WebSockets.
+//!
+//! Every event is a JSON object tagged by `type`, with an optional `v`
+//! carrying the schema version (1 when absent). Events of a newer version
+//! than the gateway knows, or of an unknown type, are reported as such
+//! instead of failing the connection, so the protocol can evolve one side
+//! at a time.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+
+/// Schema versions this gateway understands.
+pub const SUPPORTED_VERSIONS: std::ops::RangeInclusive<u64> = 1..=1;
+
+/// A chat message as carried by `message` events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    pub room_id: String,
+    pub user_id: i64,
+    pub username: String,
+    pub content: String,
+    pub timestamp: String,
+    /// `text`, `system`, ...
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    #[serde(default)]
+    pub reactions: HashMap<String, Vec<i64>>,
+}
+
+/// Version 1 events sent by the chat service to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerEventV1 {
+    #[serde(rename = "message", alias = "message.created")]
+    MessageCreated { message: ChatMessage },
+    #[serde(rename = "typing_update", alias = "typing")]
+    Typing {
+        room_id: String,
+        typing_users: Vec<String>,
+        timestamp: String,
+    },
+    #[serde(rename = "presence_change", alias = "presence")]
+    Presence {
+        user_id: i64,
+        username: String,
+        status: String,
+        timestamp: String,
+    },
+    #[serde(rename = "user_joined")]
+    UserJoined {
+        user_id: i64,
+        username: String,
+        room_id: String,
+        timestamp: String,
+    },
+    #[serde(rename = "user_left")]
+    UserLeft {
+        user_id: i64,
+        username: String,
+        room_id: String,
+        timestamp: String,
+    },
+    #[serde(rename = "reaction_added", alias = "reaction")]
+    Reaction {
+        message_id: String,
+        reaction: String,
+        user_id: i64,
+        #[serde(default)]
+        reactions: HashMap<String, Vec<i64>>,
+    },
+    #[serde(rename = "moderation")]
+    Moderation {
+        /// `message_removed`, `user_muted`, `user_banned`, ...
+        action: String,
+        #[serde(default)]
+        room_id: Option<String>,
+        #[serde(default)]
+        message_id: Option<String>,
+        #[serde(default)]
+        user_id: Option<i64>,
+        #[serde(default)]
+        reason: Option<String>,
+        #[serde(default)]
+        timestamp: Option<String>,
+    },
+    #[serde(rename = "pong")]
+    Pong,
+}
+
+const SERVER_TYPES_V1: &[&str] = &[
+    "message",
+    "message.created",
+    "typing_update",
+    "typing",
+    "presence_change",
+    "presence",
+    "user_joined",
+    "user_left",
+    "reaction_added",
+    "reaction",
+    "moderation",
+    "pong",
+];
+
+/// Version 1 events sent by clients to the chat service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientEventV1 {
+    #[serde(rename = "ping")]
+    Ping,
+    #[serde(rename = "typing")]
+    Typing {
+        #[serde(default)]
+        is_typing: bool,
+    },
+}
+
+const CLIENT_TYPES_V1: &[&str] = &["ping", "typing"];
+
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    V1(ServerEventV1),
+}
+
+impl ServerEvent {
+    /// Canonical event name, independent of the wire alias used.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::V1(event) => match event {
+                ServerEventV1::MessageCreated { .. } => "message.created",
+                ServerEventV1::Typing { .. } => "typing",
+                ServerEventV1::Presence { .. } => "presence",
+                ServerEventV1::UserJoined { .. } => "user_joined",
+                ServerEventV1::UserLeft { .. } => "user_left",
+                ServerEventV1::Reaction { .. } => "reaction",
+                ServerEventV1::Moderation { .. } => "moderation",
+                ServerEventV1::Pong => "pong",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    V1(ClientEventV1),
+}
+
+impl ClientEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEvent::V1(ClientEventV1::Ping) => "ping",
+            ClientEvent::V1(ClientEventV1::Typing { .. }) => "typing",
+        }
+    }
+}
+
+/// Why a frame did not match the schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventError {
+    /// Not a JSON object with a string `type`
+    Malformed(String),
+    UnsupportedVersion(u64),
+    UnknownType(String),
+    /// A known type whose fields do not match the schema
+    Invalid { kind: String, reason: String },
+}
+
+impl EventError {
+    /// Short label for metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            EventError::Malformed(_) => "malformed",
+            EventError::UnsupportedVersion(_) => "unsupported_version",
+            EventError::UnknownType(_) => "unknown_type",
+            EventError::Invalid { .. } => "invalid",
+        }
+    }
+
+    /// Error event sent back to a client whose frame was rejected.
+    pub fn to_client_event(&self) -> Value {
+        let detail = match self {
+            EventError::Malformed(reason) => reason.clone(),
+            EventError::UnsupportedVersion(version) => format!("schema version {} is not supported", version),
+            EventError::UnknownType(kind) => format!("unknown event type '{}'", kind),
+            EventError::Invalid { kind, reason } => format!("invalid '{}' event: {}", kind, reason),
+        };
+        serde_json::json!({
+            "type": "error",
+            "code": self.reason(),
+            "message": detail,
+            "supported_versions": [SUPPORTED_VERSIONS.start(), SUPPORTED_VERSIONS.end()],
+        })
+    }
+}
+
+// Split a frame into its version and type before decoding the body
+fn envelope(text: &str) -> Result<(u64, String, Value), EventError> {
+    let value: Value = serde_json::from_str(text).map_err(|e| EventError::Malformed(e.to_string()))?;
+    let kind = value
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| EventError::Malformed("missing event type".to_string()))?
+        .to_string();
+    let version = match value.get("v") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| EventError::Malformed("event version must be a positive integer".to_string()))?,
+    };
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(EventError::UnsupportedVersion(version));
+    }
+    Ok((version, kind, value))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(kind: String, known: &[&str], value: Value) -> Result<T, EventError> {
+    if !known.contains(&kind.as_str()) {
+        return Err(EventError::UnknownType(kind));
+    }
+    serde_json::from_value(value).map_err(|e| EventError::Invalid {
+        kind,
+        reason: e.to_string(),
+    })
+}
+
+pub fn parse_server_event(text: &str) -> Result<ServerEvent, EventError> {
+    // Only version 1 exists so far; newer versions get their own arms here
+    let (_version, kind, value) = envelope(text)?;
+    decode(kind, SERVER_TYPES_V1, value).map(ServerEvent::V1)
+}
+
+pub fn parse_client_event(text: &str) -> Result<ClientEvent, EventError> {
+    // Only version 1 exists so far; newer versions get their own arms here
+    let (_version, kind, value) = envelope(text)?;
+    decode(kind, CLIENT_TYPES_V1, value).map(ClientEvent::V1)
+}
+
+/// How strictly WebSocket frames are checked against the schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventValidation {
+    Off,
+    /// Count and log frames that do not match, but forward them
+    Observe,
+    /// Reject non-matching client frames with an error event and drop
+    /// non-matching upstream frames
+    Enforce,
+}
+
+impl EventValidation {
+    pub fn from_env() -> Self {
+        match env::var("WS_EVENT_VALIDATION").as_deref() {
+            Ok("off") => EventValidation::Off,
+            Ok("enforce") => EventValidation::Enforce,
+            _ => EventValidation::Observe,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(extra: Value) -> Value {
+        let mut event = json!({
+            "type": "message",
+            "message": {
+                "id": "m1",
+                "room_id": "r1",
+                "user_id": 7,
+                "username": "alice",
+                "content": "hi",
+                "timestamp": "2024-01-01T00:00:00Z",
+            },
+        });
+        if let (Some(event), Some(extra)) = (event.as_object_mut(), extra.as_object()) {
+            event.extend(extra.clone());
+        }
+        event
+    }
+
+    fn server(event: &Value) -> Result<&'static str, EventError> {
+        parse_server_event(&event.to_string()).map(|event| event.name())
+    }
+
+    #[test]
+    fn unversioned_events_are_version_1() {
+        assert_eq!(server(&message(json!({}))), Ok("message.created"));
+        assert_eq!(server(&message(json!({ "v": 1 }))), Ok("message.created"));
+        assert_eq!(parse_client_event(r#"{"type":"ping"}"#).map(|e| e.name()), Ok("ping"));
+        assert_eq!(parse_client_event(r#"{"type":"ping","v":1}"#).map(|e| e.name()), Ok("ping"));
+    }
+
+    #[test]
+    fn older_wire_names_decode_to_the_same_event() {
+        let pairs = [
+            ("message", "message.created"),
+            ("typing_update", "typing"),
+            ("presence_change", "presence"),
+            ("reaction_added", "reaction"),
+        ];
+        let bodies = [
+            message(json!({})),
+            json!({ "room_id": "r1", "typing_users": ["alice"], "timestamp": "t" }),
+            json!({ "user_id": 7, "username": "alice", "status": "online", "timestamp": "t" }),
+            json!({ "message_id": "m1", "reaction": "+1", "user_id": 7 }),
+        ];
+        for ((current, alias), body) in pairs.iter().zip(bodies) {
+            let mut body = body;
+            body["type"] = json!(current);
+            let canonical = server(&body).unwrap();
+            body["type"] = json!(alias);
+            assert_eq!(server(&body), Ok(canonical), "{} and {}", current, alias);
+        }
+    }
+
+    #[test]
+    fn fields_added_later_are_ignored() {
+        let event = message(json!({ "delivery": "fanout", "trace": { "id": "abc" } }));
+        assert_eq!(server(&event), Ok("message.created"));
+        let mut event = message(json!({}));
+        event["message"]["edited_at"] = json!("2024-01-02T00:00:00Z");
+        assert_eq!(server(&event), Ok("message.created"));
+    }
+
+    #[test]
+    fn optional_fields_may_be_missing() {
+        let event = parse_server_event(&message(json!({})).to_string()).unwrap();
+        let ServerEvent::V1(ServerEventV1::MessageCreated { message }) = event else {
+            panic!("not a message event");
+        };
+        assert_eq!(message.kind, None);
+        assert_eq!(message.parent_id, None);
+        assert!(message.reactions.is_empty());
+    }
+
+    #[test]
+    fn serialized_events_parse_back() {
+        let events = [
+            ServerEventV1::Typing { room_id: "r1".to_string(), typing_users: vec![], timestamp: "t".to_string() },
+            ServerEventV1::Pong,
+            ServerEventV1::Moderation {
+                action: "message_removed".to_string(),
+                room_id: None,
+                message_id: Some("m1".to_string()),
+                user_id: None,
+                reason: None,
+                timestamp: None,
+            },
+        ];
+        for event in events {
+            let name = ServerEvent::V1(event.clone()).name();
+            assert_eq!(server(&serde_json::to_value(&event).unwrap()), Ok(name));
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_reported_not_misread() {
+        let next = SUPPORTED_VERSIONS.end() + 1;
+        assert_eq!(server(&message(json!({ "v": next }))), Err(EventError::UnsupportedVersion(next)));
+        assert_eq!(server(&message(json!({ "v": 0 }))), Err(EventError::UnsupportedVersion(0)));
+        assert_eq!(server(&message(json!({ "v": "1" }))).map_err(|e| e.reason()), Err("malformed"));
+
+        let error = EventError::UnsupportedVersion(next).to_client_event();
+        assert_eq!(error["code"], "unsupported_version");
+        assert_eq!(error["supported_versions"], json!([SUPPORTED_VERSIONS.start(), SUPPORTED_VERSIONS.end()]));
+    }
+
+    #[test]
+    fn unknown_types_are_told_apart_from_invalid_ones() {
+        assert_eq!(server(&json!({ "type": "poll_created" })), Err(EventError::UnknownType("poll_created".to_string())));
+        let invalid = server(&json!({ "type": "user_joined", "user_id": "seven" }));
+        assert_eq!(invalid.map_err(|e| e.reason()), Err("invalid"));
+    }
+}
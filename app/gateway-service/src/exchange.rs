@@ -0,0 +1,163 @@
+use jsonwebtoken::{encode, EncodingKey, Header};
+use tracing::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::{invalid, parse_env};
+use crate::AppState;
+
+/// What a service may receive in exchanged tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceGrant {
+    /// `aud` of the service's tokens; its name when unset
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Scopes the service may act with; the caller's are narrowed to these
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct TokenExchangeConfig {
+    /// Secret exchanged tokens are signed with; no exchange when unset
+    pub secret: Option<String>,
+    pub ttl: Duration,
+    /// Services tokens are exchanged for, by upstream name
+    pub services: HashMap<String, ServiceGrant>,
+}
+
+// Keep the signing secret out of the startup config log
+impl fmt::Debug for TokenExchangeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenExchangeConfig")
+            .field("enabled", &self.secret.is_some())
+            .field("ttl", &self.ttl)
+            .field("services", &self.services)
+            .finish()
+    }
+}
+
+impl TokenExchangeConfig {
+    /// Read `TOKEN_EXCHANGE_SECRET`, `TOKEN_EXCHANGE_TTL_SECONDS` and
+    /// `TOKEN_EXCHANGE_SERVICES`, a JSON object of grants by service name.
+    pub fn from_env() -> Self {
+        let services = match env::var("TOKEN_EXCHANGE_SERVICES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid TOKEN_EXCHANGE_SERVICES ({}), using the default grants", e);
+                invalid("TOKEN_EXCHANGE_SERVICES", e);
+                default_services()
+            }),
+            Err(_) => default_services(),
+        };
+        TokenExchangeConfig {
+            secret: env::var("TOKEN_EXCHANGE_SECRET").ok().filter(|s| !s.is_empty()),
+            ttl: Duration::from_secs(parse_env("TOKEN_EXCHANGE_TTL_SECONDS").unwrap_or(60)),
+            services,
+        }
+    }
+}
+
+// Each service gets the scopes the default scope policies guard it with
+fn default_services() -> HashMap<String, ServiceGrant> {
+    [
+        ("user", &["profile:read", "profile:write"][..]),
+        ("chat", &["rooms:read", "rooms:write", "rooms:create"][..]),
+        ("message", &["messages:read", "messages:write"][..]),
+        ("media", &["media:read"][..]),
+    ]
+    .into_iter()
+    .map(|(service, scopes)| {
+        let grant = ServiceGrant {
+            audience: None,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        };
+        (service.to_string(), grant)
+    })
+    .collect()
+}
+
+/// Party acting for the subject, per RFC 8693 section 4.1.
+#[derive(Serialize)]
+struct Actor {
+    sub: &'static str,
+}
+
+#[derive(Serialize)]
+struct ExchangedClaims<'a> {
+    iss: &'static str,
+    sub: &'a str,
+    aud: &'a str,
+    username: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<&'a str>,
+    scope: String,
+    act: Actor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sid: Option<&'a str>,
+    jti: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Exchange the caller's identity for a short-lived token for `service`
+/// alone, on-behalf-of style: its audience is the service, its scopes are
+/// the caller's narrowed to the service's grant, and `act` names the gateway.
+/// `None` when exchange is off, the service has no grant, or nothing of the
+/// caller's scopes is left for it.
+pub fn token_for(data: &AppState, claims: &Claims, service: &str) -> Option<String> {
+    let config = &data.config.load().token_exchange;
+    let secret = config.secret.as_deref()?;
+    let outcome = |outcome: &str| data.metrics.incr("gateway_token_exchanges_total", &[("service", service), ("outcome", outcome)], 1);
+    let grant = match config.services.get(service) {
+        Some(grant) => grant,
+        None => {
+            outcome("no_grant");
+            return None;
+        }
+    };
+
+    // An unrestricted caller gets the whole grant, never more
+    let granted = claims.granted_scopes();
+    let scopes: Vec<&str> = grant
+        .scopes
+        .iter()
+        .map(String::as_str)
+        .filter(|scope| granted.as_ref().is_none_or(|granted| granted.contains(scope)))
+        .collect();
+    if scopes.is_empty() {
+        outcome("no_scope");
+        return None;
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    // Never outlive the token it was exchanged for; API keys may not expire
+    let ceiling = if claims.exp == 0 { i64::MAX } else { claims.exp as i64 };
+    let exchanged = ExchangedClaims {
+        iss: "gateway",
+        sub: &claims.sub,
+        aud: grant.audience.as_deref().unwrap_or(service),
+        username: &claims.username,
+        roles: claims.roles.iter().chain(claims.role.iter()).map(String::as_str).collect(),
+        scope: scopes.join(" "),
+        act: Actor { sub: "gateway" },
+        sid: claims.sid.as_deref(),
+        jti: data.refresh.random_id(),
+        iat: now,
+        exp: (now + config.ttl.as_secs() as i64).min(ceiling).max(now),
+    };
+    match encode(&Header::default(), &exchanged, &EncodingKey::from_secret(secret.as_bytes())) {
+        Ok(token) => {
+            outcome("issued");
+            Some(token)
+        }
+        Err(e) => {
+            warn!("Failed to exchange token of {} for {}: {}", claims.sub, service, e);
+            outcome("error");
+            None
+        }
+    }
+}
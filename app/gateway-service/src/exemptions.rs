@@ -0,0 +1,215 @@
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use tracing::error;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::cidr::Cidr;
+use crate::config::invalid;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubjectKind {
+    ApiKey,
+    User,
+    Cidr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ExemptionMode {
+    /// Skip rate limits entirely
+    Bypass,
+    /// Multiply rate limits by `factor`
+    Relax { factor: f64 },
+}
+
+impl ExemptionMode {
+    /// Effective limit for a subject covered by this exemption; `None` is unlimited.
+    pub fn apply(&self, limit: u64) -> Option<u64> {
+        match self {
+            ExemptionMode::Bypass => None,
+            ExemptionMode::Relax { factor } => Some((limit as f64 * factor.max(1.0)) as u64),
+        }
+    }
+}
+
+/// Exemption as submitted through `RATE_LIMIT_EXEMPTIONS` or the admin API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExemptionRequest {
+    pub kind: SubjectKind,
+    pub value: String,
+    #[serde(flatten)]
+    pub mode: ExemptionMode,
+    #[serde(default)]
+    pub reason: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Exemption {
+    pub id: u64,
+    pub kind: SubjectKind,
+    pub value: String,
+    #[serde(flatten)]
+    pub mode: ExemptionMode,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    cidr: Option<Cidr>,
+}
+
+impl Exemption {
+    /// Copy safe to show in listings, with API keys masked.
+    pub fn redacted(&self) -> Exemption {
+        let mut copy = self.clone();
+        if copy.kind == SubjectKind::ApiKey {
+            let visible: String = copy.value.chars().take(4).collect();
+            copy.value = format!("{}…", visible);
+        }
+        copy
+    }
+
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+}
+
+/// Who a request came from, as far as exemptions are concerned.
+#[derive(Debug, Default)]
+pub struct ClientIdentity<'a> {
+    pub api_key: Option<&'a str>,
+    pub user_id: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Trusted clients whose rate limits are bypassed or relaxed.
+pub struct ExemptionRegistry {
+    entries: RwLock<Vec<Exemption>>,
+    next_id: AtomicU64,
+}
+
+impl ExemptionRegistry {
+    /// Seed the registry from `RATE_LIMIT_EXEMPTIONS`, a JSON array of exemptions.
+    pub fn from_env() -> Self {
+        let registry = ExemptionRegistry {
+            entries: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        };
+
+        if let Ok(raw) = env::var("RATE_LIMIT_EXEMPTIONS") {
+            match serde_json::from_str::<Vec<ExemptionRequest>>(&raw) {
+                Ok(requests) => {
+                    for request in requests {
+                        if let Err(e) = registry.add(request) {
+                            error!("Ignoring rate-limit exemption: {}", e);
+                            invalid("RATE_LIMIT_EXEMPTIONS", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Invalid RATE_LIMIT_EXEMPTIONS: {}", e);
+                    invalid("RATE_LIMIT_EXEMPTIONS", e);
+                }
+            }
+        }
+        registry
+    }
+
+    pub fn add(&self, request: ExemptionRequest) -> Result<Exemption, String> {
+        let value = request.value.trim().to_string();
+        if value.is_empty() {
+            return Err("value must not be empty".to_string());
+        }
+        if let ExemptionMode::Relax { factor } = request.mode {
+            if !(factor.is_finite() && factor >= 1.0) {
+                return Err("relax factor must be at least 1".to_string());
+            }
+        }
+        let cidr = match request.kind {
+            SubjectKind::Cidr => Some(value.parse::<Cidr>()?),
+            _ => None,
+        };
+
+        let exemption = Exemption {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            kind: request.kind,
+            value,
+            mode: request.mode,
+            reason: request.reason,
+            created_at: Utc::now(),
+            expires_at: request.expires_at,
+            cidr,
+        };
+        self.entries.write().unwrap().push(exemption.clone());
+        Ok(exemption)
+    }
+
+    pub fn remove(&self, id: u64) -> Option<Exemption> {
+        let mut entries = self.entries.write().unwrap();
+        let index = entries.iter().position(|e| e.id == id)?;
+        Some(entries.remove(index))
+    }
+
+    /// Active exemptions; expired ones are dropped along the way.
+    pub fn list(&self) -> Vec<Exemption> {
+        let now = Utc::now();
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|e| !e.expired(now));
+        entries.clone()
+    }
+
+    /// The most generous active exemption covering this client, if any.
+    pub fn lookup(&self, client: &ClientIdentity) -> Option<Exemption> {
+        let now = Utc::now();
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .filter(|e| !e.expired(now))
+            .filter(|e| match e.kind {
+                SubjectKind::ApiKey => client.api_key == Some(e.value.as_str()),
+                SubjectKind::User => client.user_id == Some(e.value.as_str()),
+                SubjectKind::Cidr => match (e.cidr, client.ip) {
+                    (Some(cidr), Some(ip)) => cidr.contains(ip),
+                    _ => false,
+                },
+            })
+            .max_by(|a, b| {
+                let generosity = |e: &Exemption| match e.mode {
+                    ExemptionMode::Bypass => f64::INFINITY,
+                    ExemptionMode::Relax { factor } => factor,
+                };
+                generosity(a).total_cmp(&generosity(b))
+            })
+            .cloned()
+    }
+}
+
+/// Look up the exemption covering this request and audit its use.
+pub fn resolve(data: &AppState, req: &HttpRequest, user_id: Option<&str>) -> Option<ExemptionMode> {
+    let client = ClientIdentity {
+        api_key: req.headers().get("X-API-Key").and_then(|v| v.to_str().ok()),
+        user_id,
+        ip: data.ip_filter.client_ip(req),
+    };
+    let exemption = data.exemptions.lookup(&client)?;
+
+    data.audit.record(
+        "rate_limit_exemption_used",
+        user_id.unwrap_or("anonymous"),
+        serde_json::json!({
+            "exemption_id": exemption.id,
+            "kind": exemption.kind,
+            "mode": exemption.mode,
+            "method": req.method().as_str(),
+            "path": req.path(),
+            "ip": client.ip.map(|ip| ip.to_string()),
+        }),
+    );
+    Some(exemption.mode)
+}
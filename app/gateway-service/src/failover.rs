@@ -0,0 +1,126 @@
+use tracing::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use crate::circuit::CircuitState;
+use crate::health;
+use crate::metrics::Metrics;
+use crate::AppState;
+
+#[derive(Debug, Clone, Default)]
+pub struct FailoverConfig {
+    /// Standby URLs keyed by service, from `{SERVICE}_SERVICE_STANDBY_URL`
+    pub standby_urls: HashMap<String, String>,
+}
+
+impl FailoverConfig {
+    pub fn from_env(services: &[&str]) -> Self {
+        let standby_urls = services
+            .iter()
+            .filter_map(|service| {
+                env::var(format!("{}_SERVICE_STANDBY_URL", service.to_uppercase()))
+                    .ok()
+                    .filter(|url| !url.is_empty())
+                    .map(|url| (service.to_string(), url))
+            })
+            .collect();
+        FailoverConfig { standby_urls }
+    }
+}
+
+/// Name under which a service's standby instances are registered as an upstream.
+pub fn standby_name(service: &str) -> String {
+    format!("{}-standby", service)
+}
+
+/// Tracks which services are currently served by their standby upstream.
+pub struct Failover {
+    active: Mutex<HashSet<String>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Failover {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Failover {
+            active: Mutex::new(HashSet::new()),
+            metrics,
+        }
+    }
+
+    fn set_active(&self, service: &str, active: bool) {
+        let changed = {
+            let mut services = self.active.lock().unwrap();
+            if active {
+                services.insert(service.to_string())
+            } else {
+                services.remove(service)
+            }
+        };
+        if changed {
+            if active {
+                warn!("Primary {} unavailable, failing over to standby", service);
+                self.metrics.incr("gateway_failovers_total", &[("service", service)], 1);
+            } else {
+                info!("Primary {} recovered, failing back from standby", service);
+            }
+            self.metrics
+                .gauge_set("gateway_failover_active", &[("service", service)], if active { 1.0 } else { 0.0 });
+        }
+    }
+
+    pub fn is_active(&self, service: &str) -> bool {
+        self.active.lock().unwrap().contains(service)
+    }
+
+    /// Upstream that requests for `service` should go to: the primary, or its
+    /// standby while the primary's circuit is open or every primary instance
+    /// fails health checks. With background health checks the gateway stays on
+    /// the standby until a check finds a primary instance healthy again;
+    /// without them, the primary's half-open trial request decides.
+    pub async fn route(&self, data: &AppState, service: &str) -> String {
+        let standby = standby_name(service);
+        let upstreams = data.upstreams.load();
+        let primary = match upstreams.get(service) {
+            Some(primary) if upstreams.get(&standby).is_some() => primary,
+            _ => return service.to_string(),
+        };
+        let unhealthy = health::availability(data, primary).await.all_down(primary);
+
+        if !self.is_active(service) {
+            if unhealthy || data.circuits.is_open(service) {
+                self.set_active(service, true);
+                return standby;
+            }
+            return service.to_string();
+        }
+
+        if !unhealthy && data.circuits.state(service) == CircuitState::Closed {
+            self.set_active(service, false);
+            return service.to_string();
+        }
+        if data.config.load().health.interval.is_zero() && !data.circuits.is_open(service) {
+            return service.to_string();
+        }
+        standby
+    }
+}
+
+/// Called after each health-check round: close the primary circuit of any
+/// failed-over service with a healthy primary instance, so the next request
+/// fails back.
+pub async fn check_recovery(data: &AppState) {
+    let statuses = data.service_statuses.read().await;
+    for upstream in data.upstreams.load().iter() {
+        if !data.failover.is_active(&upstream.name) {
+            continue;
+        }
+        let recovered = upstream
+            .instances()
+            .iter()
+            .any(|instance| statuses.get(instance).map(|s| s.status == "healthy").unwrap_or(false));
+        if recovered {
+            data.circuits.record_success(&upstream.name);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use actix_web::HttpResponse;
+use tracing::{error, info};
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::fs;
+
+use crate::config::invalid;
+
+fn default_status() -> u16 {
+    200
+}
+
+/// Static payload served in place of an upstream response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FallbackRoute {
+    /// HTTP method to match; any method when omitted
+    pub method: Option<String>,
+    /// Gateway path to match; a trailing `*` matches any suffix
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: Value,
+}
+
+impl FallbackRoute {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let method_matches = self
+            .method
+            .as_deref()
+            .map(|m| m.eq_ignore_ascii_case(method))
+            .unwrap_or(true);
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => self.path == path,
+        };
+        method_matches && path_matches
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let status = actix_web::http::StatusCode::from_u16(self.status)
+            .unwrap_or(actix_web::http::StatusCode::OK);
+        HttpResponse::build(status)
+            .insert_header(("X-Gateway-Fallback", "true"))
+            .json(&self.body)
+    }
+}
+
+/// Per-route fallbacks used when an upstream call fails or its circuit is open.
+#[derive(Debug, Clone, Default)]
+pub struct FallbackTable {
+    routes: Vec<FallbackRoute>,
+}
+
+impl FallbackTable {
+    /// Load fallbacks from `FALLBACK_RESPONSES` (inline JSON array) or
+    /// `FALLBACK_RESPONSES_FILE` (path to a JSON file with the same shape).
+    pub fn from_env() -> Self {
+        let raw = match env::var("FALLBACK_RESPONSES") {
+            Ok(json) => json,
+            Err(_) => match env::var("FALLBACK_RESPONSES_FILE") {
+                Ok(path) => match fs::read_to_string(&path) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        error!("Failed to read fallback file {}: {}", path, e);
+                        invalid("FALLBACK_RESPONSES_FILE", format!("cannot read {}: {}", path, e));
+                        return Self::default();
+                    }
+                },
+                Err(_) => return Self::default(),
+            },
+        };
+
+        match serde_json::from_str::<Vec<FallbackRoute>>(&raw) {
+            Ok(routes) => {
+                info!("Loaded {} fallback route(s)", routes.len());
+                FallbackTable { routes }
+            }
+            Err(e) => {
+                error!("Invalid fallback configuration: {}", e);
+                invalid("FALLBACK_RESPONSES", e);
+                Self::default()
+            }
+        }
+    }
+
+    /// First configured fallback matching the request, if any.
+    pub fn lookup(&self, method: &str, path: &str) -> Option<&FallbackRoute> {
+        self.routes.iter().find(|route| route.matches(method, path))
+    }
+}
@@ -0,0 +1,207 @@
+use actix_web::web::Bytes;
+use actix_web::{http::StatusCode, HttpResponse};
+use chrono::{DateTime, Utc};
+use tracing::{error, info};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::config::invalid;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Delay the request before it is proxied
+    Latency { delay_ms: u64 },
+    /// Answer with `status` instead of calling the upstream
+    Error { status: u16 },
+    /// Close the client connection without a complete response
+    Drop,
+}
+
+impl FaultKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FaultKind::Latency { .. } => "latency",
+            FaultKind::Error { .. } => "error",
+            FaultKind::Drop => "drop",
+        }
+    }
+}
+
+/// Fault as submitted through `FAULT_INJECTION_RULES` or the admin API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FaultRequest {
+    /// Upstream service name, or `*` for every service
+    pub service: String,
+    /// Share of matching requests affected, 0 to 100
+    pub percentage: f64,
+    #[serde(flatten)]
+    pub kind: FaultKind,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fault {
+    pub id: u64,
+    pub service: String,
+    pub percentage: f64,
+    #[serde(flatten)]
+    pub kind: FaultKind,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Fault {
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+}
+
+/// Chaos rules injecting latency, errors or dropped connections into a share
+/// of proxied requests, for exercising client retry behavior.
+pub struct FaultInjector {
+    entries: RwLock<Vec<Fault>>,
+    next_id: AtomicU64,
+    rng: SystemRandom,
+}
+
+impl FaultInjector {
+    /// Seed the rules from `FAULT_INJECTION_RULES`, a JSON array of faults.
+    pub fn from_env() -> Self {
+        let injector = FaultInjector {
+            entries: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            rng: SystemRandom::new(),
+        };
+
+        if let Ok(raw) = env::var("FAULT_INJECTION_RULES") {
+            match serde_json::from_str::<Vec<FaultRequest>>(&raw) {
+                Ok(requests) => {
+                    for request in requests {
+                        match injector.add(request) {
+                            Ok(fault) => info!("Fault injection enabled: {:?}", fault),
+                            Err(e) => {
+                                error!("Ignoring fault injection rule: {}", e);
+                                invalid("FAULT_INJECTION_RULES", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Invalid FAULT_INJECTION_RULES: {}", e);
+                    invalid("FAULT_INJECTION_RULES", e);
+                }
+            }
+        }
+        injector
+    }
+
+    pub fn add(&self, request: FaultRequest) -> Result<Fault, String> {
+        let service = request.service.trim().to_string();
+        if service.is_empty() {
+            return Err("service must not be empty".to_string());
+        }
+        if !(0.0..=100.0).contains(&request.percentage) {
+            return Err("percentage must be between 0 and 100".to_string());
+        }
+        if let FaultKind::Error { status } = request.kind {
+            if !StatusCode::from_u16(status).map(|s| s.is_client_error() || s.is_server_error()).unwrap_or(false) {
+                return Err("error status must be a 4xx or 5xx code".to_string());
+            }
+        }
+
+        let fault = Fault {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            service,
+            percentage: request.percentage,
+            kind: request.kind,
+            created_at: Utc::now(),
+            expires_at: request.expires_at,
+        };
+        self.entries.write().unwrap().push(fault.clone());
+        Ok(fault)
+    }
+
+    pub fn remove(&self, id: u64) -> Option<Fault> {
+        let mut entries = self.entries.write().unwrap();
+        let index = entries.iter().position(|f| f.id == id)?;
+        Some(entries.remove(index))
+    }
+
+    /// Remove every rule, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.write().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// Active rules; expired ones are dropped along the way.
+    pub fn list(&self) -> Vec<Fault> {
+        let now = Utc::now();
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|f| !f.expired(now));
+        entries.clone()
+    }
+
+    // Uniform draw in [0, 100)
+    fn draw(&self) -> f64 {
+        let mut bytes = [0u8; 4];
+        if self.rng.fill(&mut bytes).is_err() {
+            return 100.0;
+        }
+        u32::from_le_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0) * 100.0
+    }
+
+    /// Faults hitting this request to `service`, each rule rolled independently.
+    pub fn roll(&self, service: &str) -> Vec<FaultKind> {
+        let now = Utc::now();
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .filter(|f| !f.expired(now) && (f.service == "*" || f.service == service))
+            .filter(|f| self.draw() < f.percentage)
+            .map(|f| f.kind)
+            .collect()
+    }
+}
+
+/// Apply the faults rolled for a request to `service`: sleep for any injected
+/// latency, then return the response to send instead of proxying, if any.
+pub async fn inject(data: &AppState, service: &str) -> Option<HttpResponse> {
+    let faults = data.faults.roll(service);
+    let mut outcome = None;
+    for fault in faults {
+        data.metrics.incr(
+            "gateway_faults_injected_total",
+            &[("service", service), ("fault", fault.as_str())],
+            1,
+        );
+        match fault {
+            FaultKind::Latency { delay_ms } => tokio::time::sleep(Duration::from_millis(delay_ms)).await,
+            _ => outcome = outcome.or(Some(fault)),
+        }
+    }
+
+    match outcome? {
+        FaultKind::Error { status } => Some(
+            HttpResponse::build(StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE))
+                .insert_header(("X-Fault-Injected", "error"))
+                .json(serde_json::json!({
+                    "error": "Injected fault",
+                    "service": service
+                })),
+        ),
+        // A body that fails before yielding anything makes actix abort the connection
+        FaultKind::Drop => Some(HttpResponse::Ok().streaming(futures_util::stream::once(async {
+            Err::<Bytes, _>(io::Error::new(io::ErrorKind::ConnectionAborted, "injected connection drop"))
+        }))),
+        FaultKind::Latency { .. } => None,
+    }
+}
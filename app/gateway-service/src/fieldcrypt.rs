@@ -0,0 +1,287 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use tracing::{error, info, warn};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+
+use crate::config::invalid;
+use crate::metrics::Metrics;
+use crate::rbac::route_matches;
+
+/// Prefix marking a value this gateway encrypted; the key id and the
+/// base64url nonce and ciphertext follow, separated by `:`.
+const PREFIX: &str = "enc:v1:";
+
+/// Fields encrypted on requests matching `path` (exact, or a prefix when it
+/// ends in `*`) and `methods` (any when empty). Fields are dotted paths into
+/// the JSON body; arrays along the way are walked element by element.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldRule {
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Encrypted in request bodies before they are proxied
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Decrypted in the upstream's response; defaults to `fields`
+    #[serde(default)]
+    pub response_fields: Option<Vec<String>>,
+}
+
+impl FieldRule {
+    fn response_fields(&self) -> &[String] {
+        self.response_fields.as_deref().unwrap_or(&self.fields)
+    }
+}
+
+#[derive(Clone)]
+pub struct FieldEncryptionConfig {
+    /// `id:base64 key` pairs of 256-bit keys; old keys stay listed so values
+    /// they encrypted can still be read
+    pub keys: Vec<String>,
+    /// Id of the key new values are encrypted with; the first key when unset
+    pub active_key: Option<String>,
+    pub rules: Vec<FieldRule>,
+}
+
+// Key material stays out of the startup config log
+impl fmt::Debug for FieldEncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<_> = self.keys.iter().map(|key| key.split(':').next().unwrap_or_default()).collect();
+        f.debug_struct("FieldEncryptionConfig")
+            .field("keys", &ids)
+            .field("active_key", &self.active_key)
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+impl FieldEncryptionConfig {
+    /// Keys come from `FIELD_ENCRYPTION_KEYS_FILE` (one `id:key` per line) or
+    /// `FIELD_ENCRYPTION_KEYS` (comma-separated), rules from
+    /// `FIELD_ENCRYPTION_RULES`. Without keys nothing is encrypted.
+    pub fn from_env() -> Self {
+        let keys = match env::var("FIELD_ENCRYPTION_KEYS_FILE") {
+            Ok(path) if !path.is_empty() => fs::read_to_string(&path).unwrap_or_else(|e| {
+                error!("Cannot read field encryption keys from {}: {}", path, e);
+                invalid("FIELD_ENCRYPTION_KEYS_FILE", format!("cannot read {}: {}", path, e));
+                String::new()
+            }),
+            _ => env::var("FIELD_ENCRYPTION_KEYS").unwrap_or_default().replace(',', "\n"),
+        };
+        let rules = match env::var("FIELD_ENCRYPTION_RULES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid FIELD_ENCRYPTION_RULES ({}), using the default rules", e);
+                invalid("FIELD_ENCRYPTION_RULES", e);
+                default_rules()
+            }),
+            Err(_) => default_rules(),
+        };
+        FieldEncryptionConfig {
+            keys: keys.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect(),
+            active_key: env::var("FIELD_ENCRYPTION_ACTIVE_KEY").ok().filter(|id| !id.is_empty()),
+            rules,
+        }
+    }
+}
+
+fn default_rules() -> Vec<FieldRule> {
+    vec![FieldRule {
+        path: "/api/users/profile".to_string(),
+        methods: Vec::new(),
+        fields: vec!["email".to_string()],
+        response_fields: Some(vec!["user.email".to_string()]),
+    }]
+}
+
+/// Parse an `id:base64 key` entry holding a 256-bit AES-GCM key.
+pub fn parse_key(entry: &str) -> Result<(String, LessSafeKey), String> {
+    let (id, encoded) = entry.split_once(':').ok_or_else(|| "keys must be written id:base64key".to_string())?;
+    if id.is_empty() {
+        return Err(format!("invalid key id '{}'", id));
+    }
+    let bytes = STANDARD.decode(encoded.trim()).map_err(|e| format!("key {} is not base64: {}", id, e))?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| format!("key {} must be 32 bytes", id))?;
+    Ok((id.to_string(), LessSafeKey::new(key)))
+}
+
+struct FieldKey {
+    id: String,
+    key: LessSafeKey,
+}
+
+/// Encrypts configured fields of request bodies on their way upstream and
+/// decrypts them in upstream responses, so downstream stores only ever hold
+/// ciphertext. Each value is sealed with AES-256-GCM.
+pub struct FieldEncryption {
+    keys: Vec<FieldKey>,
+    active: usize,
+    rules: Vec<FieldRule>,
+    rng: SystemRandom,
+    metrics: Arc<Metrics>,
+}
+
+/// Key ids and rules in force, for the admin API.
+#[derive(Debug, Serialize)]
+pub struct FieldEncryptionStatus {
+    pub enabled: bool,
+    pub keys: Vec<String>,
+    pub active_key: Option<String>,
+    pub rules: Vec<FieldRule>,
+}
+
+impl FieldEncryption {
+    pub fn new(config: FieldEncryptionConfig, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|entry| parse_key(entry).map(|(id, key)| FieldKey { id, key }))
+            .collect::<Result<Vec<_>, String>>()?;
+        let active = match &config.active_key {
+            Some(id) => keys.iter().position(|key| key.id == *id).ok_or_else(|| format!("active key {} is not configured", id))?,
+            None => 0,
+        };
+        if let Some(key) = keys.get(active) {
+            info!("Field encryption enabled with key {} for {} rules", key.id, config.rules.len());
+        }
+        Ok(FieldEncryption {
+            keys,
+            active,
+            rules: config.rules,
+            rng: SystemRandom::new(),
+            metrics,
+        })
+    }
+
+    pub fn status(&self) -> FieldEncryptionStatus {
+        FieldEncryptionStatus {
+            enabled: !self.keys.is_empty(),
+            keys: self.keys.iter().map(|key| key.id.clone()).collect(),
+            active_key: self.keys.get(self.active).map(|key| key.id.clone()),
+            rules: self.rules.clone(),
+        }
+    }
+
+    fn rule(&self, method: &str, route: &str) -> Option<&FieldRule> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        self.rules.iter().find(|rule| route_matches(&rule.path, &rule.methods, method, route))
+    }
+
+    fn seal(&self, field: &str, plaintext: &str) -> Result<String, String> {
+        let field = field_name(field);
+        let key = &self.keys[self.active];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| "system random source unavailable")?;
+        let mut sealed = plaintext.as_bytes().to_vec();
+        key.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(field.as_bytes()), &mut sealed)
+            .map_err(|_| "encryption failed")?;
+        let mut payload = nonce.to_vec();
+        payload.extend(sealed);
+        Ok(format!("{}{}:{}", PREFIX, key.id, URL_SAFE_NO_PAD.encode(payload)))
+    }
+
+    fn open(&self, field: &str, value: &str) -> Result<String, String> {
+        let field = field_name(field);
+        let (id, encoded) = value.strip_prefix(PREFIX).and_then(|rest| rest.split_once(':')).ok_or("not an encrypted value")?;
+        let key = self.keys.iter().find(|key| key.id == id).ok_or_else(|| format!("unknown key {}", id))?;
+        let mut payload = URL_SAFE_NO_PAD.decode(encoded).map_err(|_| "malformed ciphertext")?;
+        if payload.len() < NONCE_LEN {
+            return Err("malformed ciphertext".to_string());
+        }
+        let sealed = payload.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&payload).map_err(|_| "malformed ciphertext")?;
+        let mut sealed = sealed;
+        let plaintext = key
+            .key
+            .open_in_place(nonce, Aad::from(field.as_bytes()), &mut sealed)
+            .map_err(|_| "ciphertext does not verify")?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| "plaintext is not UTF-8".to_string())
+    }
+
+    /// Encrypt the configured fields of a request body bound upstream. Values
+    /// that are already encrypted, or not strings, are left alone.
+    pub fn encrypt(&self, method: &str, route: &str, body: &mut Value) -> Result<(), String> {
+        let rule = match self.rule(method, route) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for field in &rule.fields {
+            visit(body, &field.split('.').collect::<Vec<_>>(), &mut |value| {
+                if let Value::String(plaintext) = value {
+                    if result.is_ok() && !plaintext.starts_with(PREFIX) {
+                        match self.seal(field, plaintext) {
+                            Ok(sealed) => *plaintext = sealed,
+                            Err(e) => result = Err(e),
+                        }
+                    }
+                }
+            });
+        }
+        if result.is_ok() {
+            self.metrics.incr("gateway_field_encryption_total", &[("operation", "encrypt")], 1);
+        }
+        result
+    }
+
+    /// Decrypt the configured fields of an upstream response. A value that
+    /// will not decrypt is passed on as stored, with a warning.
+    pub fn decrypt(&self, method: &str, route: &str, body: &mut Value) {
+        let rule = match self.rule(method, route) {
+            Some(rule) => rule,
+            None => return,
+        };
+        for response_field in rule.response_fields() {
+            visit(body, &response_field.split('.').collect::<Vec<_>>(), &mut |value| {
+                if let Value::String(stored) = value {
+                    if !stored.starts_with(PREFIX) {
+                        return;
+                    }
+                    match self.open(response_field, stored) {
+                        Ok(plaintext) => {
+                            *stored = plaintext;
+                            self.metrics.incr("gateway_field_encryption_total", &[("operation", "decrypt")], 1);
+                        }
+                        Err(e) => {
+                            warn!("Could not decrypt {} on {} {}: {}", response_field, method, route, e);
+                            self.metrics.incr("gateway_field_encryption_total", &[("operation", "decrypt_failed")], 1);
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Ciphertexts are bound to the field name, the last segment of its path, so
+// a value written as `email` reads back as `user.email` but cannot be passed
+// off as another field
+fn field_name(path: &str) -> &str {
+    path.rsplit('.').next().unwrap_or(path)
+}
+
+// Apply `f` to every value at `path`, walking arrays along the way
+fn visit(value: &mut Value, path: &[&str], f: &mut dyn FnMut(&mut Value)) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| visit(item, path, f)),
+        Value::Object(map) => match path {
+            [] => f(value),
+            [name, rest @ ..] => {
+                if let Some(child) = map.get_mut(*name) {
+                    visit(child, rest, f);
+                }
+            }
+        },
+        _ if path.is_empty() => f(value),
+        _ => {}
+    }
+}
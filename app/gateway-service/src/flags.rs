@@ -0,0 +1,308 @@
+use actix_web::web;
+use tracing::{error, info, warn};
+use reqwest::Client;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::profile::Profile;
+use crate::redis::{RedisClient, Reply};
+use crate::AppState;
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn full_rollout() -> u8 {
+    100
+}
+
+/// A capability that can be switched on and off at runtime.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flag {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Share of users (or clients, for anonymous requests) the flag is on
+    /// for, 0-100; a given user stays on the same side
+    #[serde(default = "full_rollout")]
+    pub rollout_percent: u8,
+    /// Profiles the flag is on in (`development`, `staging`, `production`);
+    /// all of them when empty
+    #[serde(default)]
+    pub environments: Vec<String>,
+}
+
+impl Flag {
+    fn is_on(&self, profile: Profile, subject: Option<&str>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if !self.environments.is_empty() && !self.environments.iter().any(|env| env.eq_ignore_ascii_case(profile.as_str())) {
+            return false;
+        }
+        if self.rollout_percent >= 100 {
+            return true;
+        }
+        match subject {
+            Some(subject) => bucket(&self.name, subject) < self.rollout_percent,
+            // Without a subject the flag could flip between requests
+            None => false,
+        }
+    }
+}
+
+// Stable 0-99 bucket of a subject for a flag, the same on every gateway
+// instance; hashing the flag name in spreads each flag's rollout differently
+fn bucket(flag: &str, subject: &str) -> u8 {
+    let hash = digest(&SHA256, format!("{}:{}", flag, subject).as_bytes());
+    let bytes = hash.as_ref();
+    (u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 100) as u8
+}
+
+/// Where flags are read from after the `FEATURE_FLAGS` seed.
+#[derive(Clone)]
+pub enum FlagSource {
+    Memory,
+    /// A Redis hash of flag name to flag JSON, shared by gateway instances
+    Redis(String),
+    /// A URL answering with a JSON array of flags
+    Http(String),
+}
+
+#[derive(Clone)]
+pub struct FeatureFlagConfig {
+    pub seed: Vec<Flag>,
+    pub source: FlagSource,
+    pub redis_key: String,
+    pub refresh_interval: Duration,
+}
+
+// The Redis URL may carry a password
+impl std::fmt::Debug for FeatureFlagConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = match &self.source {
+            FlagSource::Memory => "memory".to_string(),
+            FlagSource::Redis(_) => "redis".to_string(),
+            FlagSource::Http(url) => url.clone(),
+        };
+        f.debug_struct("FeatureFlagConfig")
+            .field("seed", &self.seed)
+            .field("source", &source)
+            .field("redis_key", &self.redis_key)
+            .field("refresh_interval", &self.refresh_interval)
+            .finish()
+    }
+}
+
+impl FeatureFlagConfig {
+    /// Seed flags from `FEATURE_FLAGS`, a JSON array, and read
+    /// `FEATURE_FLAGS_SOURCE` (`memory`, `redis` or `http`) with
+    /// `FEATURE_FLAGS_REDIS_URL` (or `REDIS_URL`) and `FEATURE_FLAGS_REDIS_KEY`,
+    /// or `FEATURE_FLAGS_URL`, refreshed every `FEATURE_FLAGS_REFRESH_SECONDS`.
+    pub fn from_env() -> Self {
+        let seed = match env::var("FEATURE_FLAGS") {
+            Ok(raw) if !raw.trim().is_empty() => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid FEATURE_FLAGS ({}), starting with no flags", e);
+                invalid("FEATURE_FLAGS", e);
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let source = match var("FEATURE_FLAGS_SOURCE").map(|v| v.to_lowercase()).as_deref() {
+            None | Some("memory") => FlagSource::Memory,
+            Some("redis") => match var("FEATURE_FLAGS_REDIS_URL").or_else(|| var("REDIS_URL")) {
+                Some(url) => FlagSource::Redis(url),
+                None => {
+                    invalid("FEATURE_FLAGS_SOURCE", "redis needs FEATURE_FLAGS_REDIS_URL or REDIS_URL");
+                    FlagSource::Memory
+                }
+            },
+            Some("http") => match var("FEATURE_FLAGS_URL") {
+                Some(url) => FlagSource::Http(url),
+                None => {
+                    invalid("FEATURE_FLAGS_SOURCE", "http needs FEATURE_FLAGS_URL");
+                    FlagSource::Memory
+                }
+            },
+            Some(other) => {
+                invalid("FEATURE_FLAGS_SOURCE", format!("'{}' is not one of memory, redis, http", other));
+                FlagSource::Memory
+            }
+        };
+        FeatureFlagConfig {
+            seed,
+            source,
+            redis_key: var("FEATURE_FLAGS_REDIS_KEY").unwrap_or_else(|| "gateway:flags".to_string()),
+            refresh_interval: Duration::from_secs(parse_env("FEATURE_FLAGS_REFRESH_SECONDS").unwrap_or(30)),
+        }
+    }
+}
+
+enum Provider {
+    Memory,
+    Redis(Box<RedisClient>),
+    Http(String),
+}
+
+impl Provider {
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Memory => "memory",
+            Provider::Redis(_) => "redis",
+            Provider::Http(_) => "http",
+        }
+    }
+}
+
+/// Current flags, seeded from configuration and refreshed from the provider.
+pub struct FeatureFlags {
+    flags: RwLock<HashMap<String, Flag>>,
+    provider: Provider,
+    config: FeatureFlagConfig,
+    client: Client,
+    metrics: Arc<Metrics>,
+}
+
+impl FeatureFlags {
+    pub fn new(config: FeatureFlagConfig, client: Client, metrics: Arc<Metrics>) -> Self {
+        let provider = match &config.source {
+            FlagSource::Memory => Provider::Memory,
+            FlagSource::Redis(url) => match RedisClient::from_url(url, Duration::from_millis(500)) {
+                Ok(client) => {
+                    info!("Feature flags read from Redis at {}", client.addr);
+                    Provider::Redis(Box::new(client))
+                }
+                Err(e) => {
+                    error!("Invalid feature flag Redis URL ({}), keeping flags in memory", e);
+                    Provider::Memory
+                }
+            },
+            FlagSource::Http(url) => Provider::Http(url.clone()),
+        };
+        let flags = config.seed.iter().map(|flag| (flag.name.clone(), flag.clone())).collect();
+        FeatureFlags {
+            flags: RwLock::new(flags),
+            provider,
+            config,
+            client,
+            metrics,
+        }
+    }
+
+    /// Whether `name` is on for `subject` under `profile`; `default` when the
+    /// flag is not defined.
+    pub fn is_enabled(&self, name: &str, profile: Profile, subject: Option<&str>, default: bool) -> bool {
+        match self.flags.read().unwrap().get(name) {
+            Some(flag) => flag.is_on(profile, subject),
+            None => default,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Flag> {
+        let mut flags: Vec<Flag> = self.flags.read().unwrap().values().cloned().collect();
+        flags.sort_by(|a, b| a.name.cmp(&b.name));
+        flags
+    }
+
+    /// Define or replace a flag. With Redis it is shared with the other
+    /// instances; with an HTTP provider it lasts until the next refresh.
+    pub async fn set(&self, flag: Flag) -> Result<(), String> {
+        if flag.rollout_percent > 100 {
+            return Err("rollout_percent must be between 0 and 100".to_string());
+        }
+        if let Provider::Redis(client) = &self.provider {
+            let json = serde_json::to_string(&flag).map_err(|e| e.to_string())?;
+            client
+                .command(&["HSET", &self.config.redis_key, &flag.name, &json])
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        self.flags.write().unwrap().insert(flag.name.clone(), flag);
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<Option<Flag>, String> {
+        if let Provider::Redis(client) = &self.provider {
+            client
+                .command(&["HDEL", &self.config.redis_key, name])
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(self.flags.write().unwrap().remove(name))
+    }
+
+    async fn fetch(&self) -> Result<Option<Vec<Flag>>, String> {
+        match &self.provider {
+            Provider::Memory => Ok(None),
+            Provider::Redis(client) => match client.command(&["HGETALL", &self.config.redis_key]).await {
+                Ok(Reply::Array(items)) => {
+                    let mut flags = Vec::new();
+                    for pair in items.chunks(2) {
+                        if let [Reply::Bulk(Some(name)), Reply::Bulk(Some(json))] = pair {
+                            match serde_json::from_str::<Flag>(json) {
+                                Ok(flag) => flags.push(Flag { name: name.clone(), ..flag }),
+                                Err(e) => warn!("Ignoring feature flag {} from Redis: {}", name, e),
+                            }
+                        }
+                    }
+                    Ok(Some(flags))
+                }
+                Ok(reply) => Err(format!("unexpected reply to HGETALL: {:?}", reply)),
+                Err(e) => Err(e.to_string()),
+            },
+            Provider::Http(url) => {
+                let response = self.client.get(url).send().await.map_err(|e| e.to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("{} answered {}", url, response.status()));
+                }
+                response.json().await.map(Some).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Replace the flags with the provider's. Seeded flags the provider does
+    /// not define stay in place.
+    pub async fn refresh(&self) -> Result<(), String> {
+        if matches!(self.provider, Provider::Memory) {
+            return Ok(());
+        }
+        let result = self.fetch().await;
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        self.metrics.incr("gateway_feature_flag_refreshes_total", &[("source", self.provider.name()), ("outcome", outcome)], 1);
+        if let Some(fetched) = result? {
+            let mut flags: HashMap<String, Flag> =
+                self.config.seed.iter().map(|flag| (flag.name.clone(), flag.clone())).collect();
+            flags.extend(fetched.into_iter().map(|flag| (flag.name.clone(), flag)));
+            *self.flags.write().unwrap() = flags;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` is on for this caller in the running profile.
+pub fn enabled(data: &AppState, name: &str, subject: Option<&str>, default: bool) -> bool {
+    data.flags.is_enabled(name, data.config.load().profile, subject, default)
+}
+
+/// Periodically re-read flags from the provider.
+pub async fn refresh_flags(data: web::Data<AppState>) {
+    let interval = data.config.load().feature_flags.refresh_interval;
+    if matches!(data.config.load().feature_flags.source, FlagSource::Memory) || interval.is_zero() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = data.flags.refresh().await {
+            warn!("Feature flag refresh failed, keeping the current flags: {}", e);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{ComplexObject, Context, EmptyMutation, EmptySubscription, Object, Result, Schema, SimpleObject, ID};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use futures_util::future::join_all;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, info};
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::{health, identity, record_upstream_call, AppState};
+
+pub type GatewaySchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+#[derive(Debug, Clone)]
+pub struct GraphqlConfig {
+    /// Deepest selection a query may nest
+    pub max_depth: usize,
+    /// Fields a query may select in all
+    pub max_complexity: usize,
+    /// Messages a room or `messages` query returns at most
+    pub max_messages: usize,
+}
+
+impl GraphqlConfig {
+    /// Read `GRAPHQL_MAX_DEPTH` (10 by default), `GRAPHQL_MAX_COMPLEXITY`
+    /// (500) and `GRAPHQL_MAX_MESSAGES` (200).
+    pub fn from_env() -> Self {
+        GraphqlConfig {
+            max_depth: parse_env("GRAPHQL_MAX_DEPTH").unwrap_or(10),
+            max_complexity: parse_env("GRAPHQL_MAX_COMPLEXITY").unwrap_or(500),
+            max_messages: parse_env("GRAPHQL_MAX_MESSAGES").unwrap_or(200),
+        }
+    }
+}
+
+// Gateway prefix of each service, under which field encryption rules and
+// metrics name its routes
+fn prefix(service: &str) -> &'static str {
+    match service {
+        "user" => "/api/users",
+        "chat" => "/api/chat",
+        _ => "/api/messages",
+    }
+}
+
+/// The services as one caller sees them: resolvers reach them with the
+/// identity headers the proxy would send for the same request.
+pub struct Services {
+    data: web::Data<AppState>,
+    identity: HashMap<&'static str, Vec<(&'static str, String)>>,
+}
+
+impl Services {
+    fn new(data: web::Data<AppState>, req: &HttpRequest) -> Self {
+        let identity = ["user", "chat", "message"]
+            .into_iter()
+            .map(|service| (service, identity::headers(&data, req, service)))
+            .collect();
+        Services { data, identity }
+    }
+
+    /// GET `path` from `service`; `None` when it answers 404.
+    async fn get(&self, service: &'static str, path: &str) -> Result<Option<Value>, String> {
+        let data = &self.data;
+        // The standby upstream while the primary is failed over
+        let target = data.failover.route(data, service).await;
+        let upstreams = data.upstreams.load();
+        let upstream = upstreams.get(&target).ok_or_else(|| format!("Unknown upstream service {}", service))?;
+        let permit = data
+            .circuits
+            .allow(&target)
+            .ok_or_else(|| format!("The {} service is unavailable", service))?;
+        let instance = health::availability(data, upstream).await.choose(upstream);
+        debug!("GraphQL resolver fetching {}{}", instance, path);
+
+        let mut request = data.http_client.get(format!("{}{}", instance, path));
+        for (name, value) in self.identity.get(service).into_iter().flatten() {
+            request = request.header(*name, value);
+        }
+        let started = Instant::now();
+        let response = request.send().await;
+        record_upstream_call(data, service, started.elapsed(), &response);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                permit.failure();
+                return Err(format!("The {} service is unreachable: {}", service, e));
+            }
+        };
+        let status = response.status();
+        match status.is_server_error() {
+            true => permit.failure(),
+            false => permit.success(),
+        }
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(format!("The {} service answered {}", service, status.as_u16()));
+        }
+        let mut body: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("The {} service sent invalid JSON: {}", service, e))?;
+        data.field_crypto.decrypt("GET", &format!("{}{}", prefix(service), path), &mut body);
+        Ok(Some(body))
+    }
+}
+
+// Ids come as strings from some services and numbers from others
+fn id(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+fn text(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct User {
+    pub id: ID,
+    pub username: String,
+    pub email: Option<String>,
+}
+
+impl User {
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(User {
+            id: ID(id(value.get("_id").or_else(|| value.get("id")))?),
+            username: text(value, "username")?,
+            email: text(value, "email"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct Room {
+    pub id: ID,
+    pub name: String,
+    pub description: Option<String>,
+    pub is_private: bool,
+    pub member_count: i64,
+    pub online_members: i64,
+    pub created_at: Option<String>,
+    #[graphql(skip)]
+    pub creator_id: Option<String>,
+}
+
+impl Room {
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Room {
+            id: ID(id(value.get("id"))?),
+            name: text(value, "name")?,
+            description: text(value, "description"),
+            is_private: value.get("is_private").and_then(Value::as_bool).unwrap_or(false),
+            member_count: value.get("member_count").and_then(Value::as_i64).unwrap_or(0),
+            online_members: value.get("online_members").and_then(Value::as_i64).unwrap_or(0),
+            created_at: text(value, "created_at"),
+            creator_id: id(value.get("creator_id")),
+        })
+    }
+}
+
+#[ComplexObject]
+impl Room {
+    async fn creator(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        match &self.creator_id {
+            Some(creator_id) => Ok(ctx.data::<DataLoader<UserLoader>>()?.load_one(creator_id.clone()).await?),
+            None => Ok(None),
+        }
+    }
+
+    /// The room's most recent messages, oldest first
+    async fn messages(&self, ctx: &Context<'_>, last: Option<i32>) -> Result<Vec<Message>> {
+        recent_messages(ctx, self.id.to_string(), last).await
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct Message {
+    pub id: ID,
+    pub room_id: ID,
+    pub content: String,
+    pub created_at: Option<String>,
+    #[graphql(skip)]
+    pub sender_id: Option<String>,
+}
+
+impl Message {
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Message {
+            id: ID(id(value.get("id"))?),
+            room_id: ID(id(value.get("room_id"))?),
+            content: text(value, "content").unwrap_or_default(),
+            created_at: text(value, "created_at"),
+            sender_id: id(value.get("sender_id")),
+        })
+    }
+}
+
+#[ComplexObject]
+impl Message {
+    async fn sender(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        match &self.sender_id {
+            Some(sender_id) => Ok(ctx.data::<DataLoader<UserLoader>>()?.load_one(sender_id.clone()).await?),
+            None => Ok(None),
+        }
+    }
+
+    async fn room(&self, ctx: &Context<'_>) -> Result<Option<Room>> {
+        Ok(ctx.data::<DataLoader<RoomLoader>>()?.load_one(self.room_id.to_string()).await?)
+    }
+}
+
+async fn recent_messages(ctx: &Context<'_>, room_id: String, last: Option<i32>) -> Result<Vec<Message>> {
+    let max = ctx.data::<GraphqlConfig>()?.max_messages;
+    let last = last.map(|last| last.max(0) as usize).unwrap_or(max).min(max);
+    let mut messages = ctx
+        .data::<DataLoader<MessageLoader>>()?
+        .load_one(room_id)
+        .await?
+        .unwrap_or_default();
+    let skip = messages.len().saturating_sub(last);
+    Ok(messages.split_off(skip))
+}
+
+/// Users by id. The user service only lists every user, so one listing
+/// answers all the ids a query asked for.
+pub struct UserLoader(Arc<Services>);
+
+impl Loader<String> for UserLoader {
+    type Value = User;
+    type Error = String;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, User>, String> {
+        let body = self.0.get("user", "/users").await?.unwrap_or(Value::Null);
+        let users = body.get("users").and_then(Value::as_array).cloned().unwrap_or_default();
+        Ok(users
+            .iter()
+            .filter_map(User::from_json)
+            .filter(|user| keys.contains(&user.id))
+            .map(|user| (user.id.to_string(), user))
+            .collect())
+    }
+}
+
+/// Rooms by id, each distinct room fetched once per query.
+pub struct RoomLoader(Arc<Services>);
+
+impl Loader<String> for RoomLoader {
+    type Value = Room;
+    type Error = String;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Room>, String> {
+        let paths: Vec<String> = keys.iter().map(|key| format!("/rooms/{}", key)).collect();
+        let rooms = join_all(paths.iter().map(|path| self.0.get("chat", path))).await;
+        let mut found = HashMap::new();
+        for (key, room) in keys.iter().zip(rooms) {
+            if let Some(room) = room?.as_ref().and_then(Room::from_json) {
+                found.insert(key.clone(), room);
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Messages by room id, oldest first, each room fetched once per query.
+pub struct MessageLoader(Arc<Services>);
+
+impl Loader<String> for MessageLoader {
+    type Value = Vec<Message>;
+    type Error = String;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Vec<Message>>, String> {
+        let paths: Vec<String> = keys.iter().map(|key| format!("/rooms/{}/messages", key)).collect();
+        let pages = join_all(paths.iter().map(|path| self.0.get("message", path))).await;
+        let mut found = HashMap::new();
+        for (key, page) in keys.iter().zip(pages) {
+            let messages = page?
+                .as_ref()
+                .and_then(Value::as_array)
+                .map(|messages| messages.iter().filter_map(Message::from_json).collect())
+                .unwrap_or_default();
+            found.insert(key.clone(), messages);
+        }
+        Ok(found)
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The signed-in user
+    async fn me(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let sub = ctx.data::<Claims>()?.sub.clone();
+        Ok(ctx.data::<DataLoader<UserLoader>>()?.load_one(sub).await?)
+    }
+
+    async fn user(&self, ctx: &Context<'_>, id: ID) -> Result<Option<User>> {
+        Ok(ctx.data::<DataLoader<UserLoader>>()?.load_one(id.to_string()).await?)
+    }
+
+    async fn users(&self, ctx: &Context<'_>) -> Result<Vec<User>> {
+        let body = ctx.data::<Arc<Services>>()?.get("user", "/users").await?.unwrap_or(Value::Null);
+        let users = body.get("users").and_then(Value::as_array).cloned().unwrap_or_default();
+        Ok(users.iter().filter_map(User::from_json).collect())
+    }
+
+    async fn room(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Room>> {
+        Ok(ctx.data::<DataLoader<RoomLoader>>()?.load_one(id.to_string()).await?)
+    }
+
+    async fn rooms(&self, ctx: &Context<'_>) -> Result<Vec<Room>> {
+        let body = ctx.data::<Arc<Services>>()?.get("chat", "/rooms").await?.unwrap_or(Value::Null);
+        let rooms: Vec<Room> = body.as_array().map(|rooms| rooms.iter().filter_map(Room::from_json).collect()).unwrap_or_default();
+        // Later `room` lookups in the same query need not fetch them again
+        ctx.data::<DataLoader<RoomLoader>>()?
+            .feed_many(rooms.iter().map(|room| (room.id.to_string(), room.clone())))
+            .await;
+        Ok(rooms)
+    }
+
+    /// A room's most recent messages, oldest first
+    async fn messages(&self, ctx: &Context<'_>, room_id: ID, last: Option<i32>) -> Result<Vec<Message>> {
+        recent_messages(ctx, room_id.to_string(), last).await
+    }
+}
+
+/// Build the schema once at startup. Introspection is off in production,
+/// as the OpenAPI docs are.
+pub fn schema(config: &GraphqlConfig, production: bool) -> GatewaySchema {
+    let builder = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(config.clone())
+        .limit_depth(config.max_depth)
+        .limit_complexity(config.max_complexity);
+    let builder = match production {
+        true => builder.disable_introspection(),
+        false => builder,
+    };
+    info!("Serving GraphQL at /graphql, depth limit {}, complexity limit {}", config.max_depth, config.max_complexity);
+    builder.finish()
+}
+
+/// Handle `/graphql`. The route policies require a token; resolvers call
+/// the services as the caller, with loaders batching the lookups of one
+/// query.
+async fn execute(
+    schema: web::Data<GatewaySchema>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    claims: Claims,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let services = Arc::new(Services::new(data, &req));
+    let request = request
+        .into_inner()
+        .data(claims)
+        .data(DataLoader::new(UserLoader(services.clone()), tokio::spawn))
+        .data(DataLoader::new(RoomLoader(services.clone()), tokio::spawn))
+        .data(DataLoader::new(MessageLoader(services.clone()), tokio::spawn))
+        .data(services);
+    schema.execute(request).await.into()
+}
+
+/// GraphiQL over `/graphql`; not served in production.
+async fn graphiql(data: web::Data<AppState>) -> HttpResponse {
+    if data.config.load().profile.is_production() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+pub fn routes(schema: web::Data<GatewaySchema>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(schema)
+            .route("/graphql", web::post().to(execute))
+            .route("/graphql", web::get().to(execute))
+            .route("/graphiql", web::get().to(graphiql));
+    }
+}
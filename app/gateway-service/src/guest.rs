@@ -0,0 +1,86 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use tracing::{error, info, warn};
+use std::env;
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::refresh::Grants;
+use crate::AppState;
+
+/// Role carried by guest tokens.
+pub const GUEST_ROLE: &str = "guest";
+
+#[derive(Debug, Clone)]
+pub struct GuestConfig {
+    /// Whether `/api/auth/guest` hands out tokens at all
+    pub enabled: bool,
+    pub ttl: Duration,
+    /// Space-separated scopes guest tokens are limited to
+    pub scope: String,
+}
+
+impl GuestConfig {
+    pub fn from_env() -> Self {
+        GuestConfig {
+            enabled: env::var("GUEST_ACCESS").map(|v| v == "true" || v == "1").unwrap_or(false),
+            ttl: Duration::from_secs(parse_env("GUEST_TOKEN_TTL_SECONDS").unwrap_or(3600)),
+            scope: env::var("GUEST_SCOPES").unwrap_or_else(|_| "rooms:read messages:read".to_string()),
+        }
+    }
+}
+
+/// Keep guest tokens read-only whatever their scopes: no writes and no
+/// WebSocket sessions, which could post messages.
+#[allow(clippy::result_large_err)]
+pub fn check(claims: &Claims, method: &str, websocket: bool) -> Result<(), HttpResponse> {
+    let is_guest = claims.has_any_role(&[GUEST_ROLE.to_string()]);
+    if !is_guest || (matches!(method, "GET" | "HEAD") && !websocket) {
+        return Ok(());
+    }
+    warn!("Refused {} by guest {}", if websocket { "WebSocket" } else { method }, claims.sub);
+    Err(HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Guests have read-only access; register to take part",
+        "code": "guest_read_only"
+    })))
+}
+
+/// Handle `/api/auth/guest`: mint a short-lived token for an anonymous
+/// visitor. It carries the `guest` role and only the guest scopes, so the
+/// scope policies keep it to reading.
+pub async fn issue(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let config = &data.config.load().guest;
+    if !config.enabled {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Guest access is disabled"
+        })));
+    }
+
+    let sub = format!("guest:{}", data.refresh.random_id());
+    let grants = Grants {
+        roles: vec![GUEST_ROLE.to_string()],
+        scope: Some(config.scope.clone()),
+        ..Default::default()
+    };
+    let (token, expires_in) = match data.refresh.issue_access_for(&sub, GUEST_ROLE, &grants, config.ttl) {
+        Ok(issued) => issued,
+        Err(e) => {
+            error!("Failed to issue guest token: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to issue guest token"
+            })));
+        }
+    };
+
+    info!("Issued guest token {} to {:?}", sub, req.peer_addr());
+    data.metrics.incr("gateway_guest_tokens_issued_total", &[], 1);
+    let response = HttpResponse::Ok().json(serde_json::json!({
+        "user": { "id": sub, "username": GUEST_ROLE, "roles": [GUEST_ROLE] },
+        "tokens": {
+            "accessToken": token,
+            "expiresIn": expires_in,
+            "scope": config.scope,
+        },
+    }));
+    Ok(crate::session::attach(&data, response).await)
+}
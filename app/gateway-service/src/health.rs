@@ -0,0 +1,175 @@
+use actix_web::web;
+use tracing::{info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::config::parse_env;
+use crate::failover;
+use crate::status::StatusEvent;
+use crate::upstream::Upstream;
+use crate::{check_service_health, AppState, ServiceStatus};
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Interval between background upstream checks, disabled when zero
+    pub interval: Duration,
+    /// Checks kept per instance in its history
+    pub history: usize,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> Self {
+        HealthConfig {
+            interval: Duration::from_secs(
+                parse_env("HEALTH_CHECK_INTERVAL_SECONDS").unwrap_or(10),
+            ),
+            history: parse_env("HEALTH_HISTORY_SIZE").unwrap_or(10),
+        }
+    }
+}
+
+/// One background check of an instance.
+#[derive(Debug, Serialize, Clone)]
+pub(crate) struct HealthCheck {
+    checked_at: String,
+    healthy: bool,
+    latency_ms: u64,
+}
+
+/// Status of an instance the poller has not reached yet.
+pub(crate) fn unchecked(instance: &str, name: &str) -> ServiceStatus {
+    ServiceStatus {
+        name: name.to_string(),
+        url: instance.to_string(),
+        status: "unknown".to_string(),
+        last_checked: String::new(),
+        latency_ms: 0,
+        consecutive_failures: 0,
+        history: Vec::new(),
+    }
+}
+
+// Carry the failure streak and history of the previous status into a new one
+fn merge(previous: Option<&ServiceStatus>, mut status: ServiceStatus, keep: usize) -> ServiceStatus {
+    let healthy = status.status == "healthy";
+    if let Some(previous) = previous {
+        status.consecutive_failures = if healthy { 0 } else { previous.consecutive_failures + 1 };
+        status.history = previous.history.clone();
+    }
+    status.history.push(HealthCheck {
+        checked_at: status.last_checked.clone(),
+        healthy,
+        latency_ms: status.latency_ms,
+    });
+    let excess = status.history.len().saturating_sub(keep);
+    status.history.drain(..excess);
+    status
+}
+
+/// Periodically check every upstream instance and record the result in
+/// `service_statuses`, which the proxy consults before routing and `/health`
+/// reports.
+pub async fn poll_upstreams(data: web::Data<AppState>) {
+    let interval = data.config.load().health.interval;
+    if interval.is_zero() {
+        info!("Background upstream health checks disabled");
+        return;
+    }
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let mut checked = HashSet::new();
+        for upstream in data.upstreams.load().iter() {
+            for instance in upstream.instances() {
+                if !checked.insert(instance.clone()) {
+                    continue;
+                }
+
+                let status = check_service_health(&data.http_client, instance, &upstream.name).await;
+                let mut statuses = data.service_statuses.write().await;
+                let previous = statuses.get(instance);
+                if previous.map(|s| s.status.as_str()) != Some(status.status.as_str()) {
+                    if status.status == "healthy" {
+                        info!("Upstream {} instance {} is healthy", upstream.name, instance);
+                    } else {
+                        warn!("Upstream {} instance {} is unhealthy", upstream.name, instance);
+                    }
+                }
+                let changed_from = match previous {
+                    Some(previous) if previous.status == status.status => None,
+                    previous => Some(previous.map(|p| p.status.clone())),
+                };
+                let status = merge(previous, status, data.config.load().health.history);
+                if let Some(previous) = changed_from {
+                    data.status.publish(StatusEvent::Health {
+                        service: upstream.name.clone(),
+                        instance: instance.clone(),
+                        status: status.status.clone(),
+                        previous,
+                        consecutive_failures: status.consecutive_failures,
+                        at: status.last_checked.clone(),
+                    });
+                }
+                statuses.insert(instance.clone(), status);
+            }
+        }
+        failover::check_recovery(&data).await;
+    }
+}
+
+/// Snapshot of which instances of one service may receive traffic, combining
+/// background health checks, outlier ejections and operator overrides.
+pub struct Availability<'a> {
+    data: &'a AppState,
+    unhealthy: HashSet<String>,
+    pinned: Option<String>,
+}
+
+impl Availability<'_> {
+    pub fn allows(&self, instance: &str) -> bool {
+        if let Some(pinned) = &self.pinned {
+            return instance == pinned;
+        }
+        !self.unhealthy.contains(instance)
+            && !self.data.outliers.is_ejected(instance)
+            && !self.data.overrides.is_excluded(instance)
+    }
+
+    /// Whether health checks found every instance of `upstream` down. A pinned
+    /// service is never considered down; the operator chose its instance.
+    pub fn all_down(&self, upstream: &Upstream) -> bool {
+        self.pinned.is_none() && upstream.instances().iter().all(|instance| self.unhealthy.contains(instance))
+    }
+
+    /// Instance to send the next request to: the pinned one if set, otherwise
+    /// the next available instance in rotation.
+    pub fn choose(&self, upstream: &Upstream) -> String {
+        match &self.pinned {
+            Some(pinned) => pinned.clone(),
+            None => upstream.pick(|instance| self.allows(instance)).to_string(),
+        }
+    }
+}
+
+pub async fn availability<'a>(data: &'a AppState, upstream: &Upstream) -> Availability<'a> {
+    let statuses = data.service_statuses.read().await;
+    let unhealthy = upstream
+        .instances()
+        .iter()
+        .filter(|instance| {
+            statuses
+                .get(instance.as_str())
+                .map(|status| status.status == "unhealthy")
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+    Availability {
+        data,
+        unhealthy,
+        pinned: data.overrides.pinned(&upstream.name),
+    }
+}
@@ -0,0 +1,78 @@
+use tracing::info;
+use reqwest::{RequestBuilder, Response};
+use std::env;
+use std::time::Duration;
+
+use crate::config::parse_env;
+use crate::metrics::Metrics;
+use crate::upstream::Upstream;
+
+#[derive(Debug, Clone)]
+pub struct HedgeConfig {
+    /// Gateway paths eligible for hedging; a trailing `*` matches any suffix
+    pub routes: Vec<String>,
+    /// How long to wait for the first instance before asking a second one
+    pub delay: Duration,
+}
+
+impl HedgeConfig {
+    pub fn from_env() -> Self {
+        HedgeConfig {
+            routes: env::var("HEDGE_ROUTES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|route| route.trim().to_string())
+                .filter(|route| !route.is_empty())
+                .collect(),
+            delay: Duration::from_millis(
+                parse_env("HEDGE_DELAY_MS").unwrap_or(100),
+            ),
+        }
+    }
+
+    /// Only idempotent GETs on configured routes are hedged.
+    pub fn applies(&self, method: &str, route: &str) -> bool {
+        method == "GET"
+            && self.routes.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => route.starts_with(prefix),
+                None => pattern == route,
+            })
+    }
+}
+
+/// Send to `primary`, and if it hasn't answered within the hedge delay, send the
+/// same request to another available instance; the first response wins and the
+/// other in-flight request is dropped (cancelled). Returns the instance that answered.
+pub async fn send_hedged<'a>(
+    config: &HedgeConfig,
+    upstream: &'a Upstream,
+    primary: &'a str,
+    available: impl Fn(&str) -> bool,
+    build: impl Fn(&str) -> RequestBuilder,
+    metrics: &Metrics,
+) -> (&'a str, reqwest::Result<Response>) {
+    let first = build(primary).send();
+    tokio::pin!(first);
+
+    tokio::select! {
+        result = &mut first => return (primary, result),
+        _ = tokio::time::sleep(config.delay) => {}
+    }
+
+    let secondary = match upstream.pick_other(primary, available) {
+        Some(instance) => instance,
+        None => return (primary, first.await),
+    };
+
+    info!("Hedging slow {} request to secondary instance {}", upstream.name, secondary);
+    metrics.incr("gateway_hedged_requests_total", &[("service", &upstream.name)], 1);
+
+    let second = build(secondary).send();
+    tokio::select! {
+        result = &mut first => (primary, result),
+        result = second => {
+            metrics.incr("gateway_hedge_wins_total", &[("service", &upstream.name)], 1);
+            (secondary, result)
+        }
+    }
+}
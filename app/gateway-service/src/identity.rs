@@ -0,0 +1,203 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use tracing::{error, warn};
+use serde::Serialize;
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::AppState;
+
+/// Headers carrying the caller's identity to upstreams. Only the gateway may
+/// set them, so they never pass through from clients.
+pub const IDENTITY_HEADERS: &[&str] = &["x-user-id", "x-username", "x-user-roles", "x-user-scopes", "x-internal-token"];
+
+#[derive(Clone)]
+pub struct IdentityConfig {
+    /// Forward the validated identity to upstreams
+    pub forward: bool,
+    /// Answer 400 when a client sends identity headers instead of dropping them
+    pub reject_spoofed: bool,
+    /// Secret for the short-lived `X-Internal-Token` JWT; not sent when unset
+    pub internal_token_secret: Option<String>,
+    pub internal_token_ttl: Duration,
+}
+
+// Keep the signing secret out of the startup config log
+impl fmt::Debug for IdentityConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityConfig")
+            .field("forward", &self.forward)
+            .field("reject_spoofed", &self.reject_spoofed)
+            .field("internal_token", &self.internal_token_secret.is_some())
+            .field("internal_token_ttl", &self.internal_token_ttl)
+            .finish()
+    }
+}
+
+impl IdentityConfig {
+    pub fn from_env() -> Self {
+        IdentityConfig {
+            forward: env::var("IDENTITY_HEADERS_ENABLED").map(|v| v != "false" && v != "0").unwrap_or(true),
+            reject_spoofed: env::var("IDENTITY_HEADERS_SPOOF_ACTION").map(|v| v == "reject").unwrap_or(false),
+            internal_token_secret: env::var("INTERNAL_TOKEN_SECRET").ok().filter(|s| !s.is_empty()),
+            internal_token_ttl: Duration::from_secs(
+                parse_env("INTERNAL_TOKEN_TTL_SECONDS").unwrap_or(60),
+            ),
+        }
+    }
+}
+
+// Identity validated for this request, kept in its extensions
+#[derive(Clone)]
+struct Verified(Claims);
+
+/// Remember the identity a request was authenticated as, for `headers`.
+pub fn remember(req: &HttpRequest, claims: &Claims) {
+    req.extensions_mut().insert(Verified(claims.clone()));
+}
+
+#[derive(Serialize)]
+struct InternalClaims<'a> {
+    iss: &'static str,
+    aud: &'static str,
+    sub: &'a str,
+    username: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    iat: i64,
+    exp: i64,
+}
+
+/// Identity headers to send to `service` for a request the gateway
+/// authenticated; empty for anonymous requests.
+pub fn headers(data: &AppState, req: &HttpRequest, service: &str) -> Vec<(&'static str, String)> {
+    let config = &data.config.load().identity;
+    let claims = match req.extensions().get::<Verified>() {
+        Some(Verified(claims)) => claims.clone(),
+        None => return Vec::new(),
+    };
+    // The upstream gets a token for itself alone, never the caller's own
+    let mut headers = Vec::new();
+    if let Some(token) = crate::exchange::token_for(data, &claims, service) {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    if !config.forward {
+        return headers;
+    }
+
+    let roles: Vec<&str> = claims.roles.iter().chain(claims.role.iter()).map(String::as_str).collect();
+    let scopes = claims.granted_scopes().map(|scopes| scopes.join(" "));
+    headers.push(("X-User-Id", claims.sub.clone()));
+    headers.push(("X-Username", claims.username.clone()));
+    if !roles.is_empty() {
+        headers.push(("X-User-Roles", roles.join(",")));
+    }
+    if let Some(scopes) = &scopes {
+        headers.push(("X-User-Scopes", scopes.clone()));
+    }
+
+    if let Some(secret) = &config.internal_token_secret {
+        let now = chrono::Utc::now().timestamp();
+        let internal = InternalClaims {
+            iss: "gateway",
+            aud: "internal",
+            sub: &claims.sub,
+            username: &claims.username,
+            roles,
+            scope: scopes,
+            iat: now,
+            exp: now + config.internal_token_ttl.as_secs() as i64,
+        };
+        match encode(&Header::default(), &internal, &EncodingKey::from_secret(secret.as_bytes())) {
+            Ok(token) => headers.push(("X-Internal-Token", token)),
+            Err(e) => error!("Failed to sign internal token for {}: {}", claims.sub, e),
+        }
+    }
+    headers
+}
+
+/// Middleware dropping identity headers sent by clients, or rejecting the
+/// request when configured to.
+pub struct StripIdentityHeaders;
+
+impl<S, B> Transform<S, ServiceRequest> for StripIdentityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = StripIdentityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(StripIdentityHeadersMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct StripIdentityHeadersMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for StripIdentityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let spoofed: Vec<&str> = IDENTITY_HEADERS
+                .iter()
+                .copied()
+                .filter(|name| req.headers().contains_key(*name))
+                .collect();
+            if spoofed.is_empty() {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            let reject = req
+                .app_data::<web::Data<AppState>>()
+                .map(|data| {
+                    let reject = data.config.load().identity.reject_spoofed;
+                    let action = if reject { "rejected" } else { "stripped" };
+                    data.metrics.incr("gateway_spoofed_identity_headers_total", &[("action", action)], 1);
+                    reject
+                })
+                .unwrap_or(false);
+            warn!("Client sent identity headers {:?} on {} {}", spoofed, req.method(), req.path());
+            if reject {
+                let response = HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Identity headers are set by the gateway",
+                    "headers": spoofed
+                }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            for name in spoofed {
+                req.headers_mut().remove(name);
+            }
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,223 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+struct Entry {
+    method: String,
+    route: String,
+    user: Option<String>,
+    upstream: Option<(String, String)>,
+    started: Instant,
+    started_at: String,
+    cancel: Arc<Notify>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InflightRequest {
+    pub id: u64,
+    pub method: String,
+    pub route: String,
+    pub user: Option<String>,
+    pub service: Option<String>,
+    pub upstream: Option<String>,
+    pub started_at: String,
+    pub elapsed_ms: u128,
+}
+
+/// Requests currently being handled, for operators to inspect and cancel.
+#[derive(Default)]
+pub struct InflightRegistry {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+impl InflightRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn register(&self, method: &str, route: &str) -> (u64, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancel = Arc::new(Notify::new());
+        self.entries.lock().unwrap().insert(
+            id,
+            Entry {
+                method: method.to_string(),
+                route: route.to_string(),
+                user: None,
+                upstream: None,
+                started: Instant::now(),
+                started_at: chrono::Utc::now().to_rfc3339(),
+                cancel: cancel.clone(),
+            },
+        );
+        (id, cancel)
+    }
+
+    fn update(&self, id: u64, apply: impl FnOnce(&mut Entry)) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&id) {
+            apply(entry);
+        }
+    }
+
+    /// Snapshot of in-flight requests, longest running first.
+    pub fn list(&self) -> Vec<InflightRequest> {
+        let entries = self.entries.lock().unwrap();
+        let mut requests: Vec<InflightRequest> = entries
+            .iter()
+            .map(|(id, entry)| InflightRequest {
+                id: *id,
+                method: entry.method.clone(),
+                route: entry.route.clone(),
+                user: entry.user.clone(),
+                service: entry.upstream.as_ref().map(|(service, _)| service.clone()),
+                upstream: entry.upstream.as_ref().map(|(_, instance)| instance.clone()),
+                started_at: entry.started_at.clone(),
+                elapsed_ms: entry.started.elapsed().as_millis(),
+            })
+            .collect();
+        requests.sort_by_key(|r| std::cmp::Reverse(r.elapsed_ms));
+        requests
+    }
+
+    /// Abort a request; its client receives a 503. Returns whether it was found.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Request extension linking a request to its registry entry
+#[derive(Clone)]
+struct Tracked {
+    id: u64,
+    registry: Arc<InflightRegistry>,
+}
+
+/// In-flight registry id of a tracked request.
+pub fn request_id(req: &HttpRequest) -> Option<u64> {
+    req.extensions().get::<Tracked>().map(|tracked| tracked.id)
+}
+
+/// Record the authenticated user of a tracked request and its log lines.
+pub fn set_user(req: &HttpRequest, user: &str, username: &str) {
+    crate::logging::set_user(user, username);
+    if let Some(tracked) = req.extensions().get::<Tracked>() {
+        tracked.registry.update(tracked.id, |entry| entry.user = Some(user.to_string()));
+    }
+}
+
+/// Record which upstream instance a tracked request is waiting on.
+pub fn set_upstream(req: &HttpRequest, service: &str, instance: &str) {
+    req.extensions_mut().insert(Upstream(service.to_string(), instance.to_string()));
+    if let Some(tracked) = req.extensions().get::<Tracked>() {
+        tracked
+            .registry
+            .update(tracked.id, |entry| entry.upstream = Some((service.to_string(), instance.to_string())));
+    }
+}
+
+// Last upstream a request was sent to, kept after its entry is removed
+struct Upstream(String, String);
+
+/// Service and instance a request was last proxied to, for the access log.
+pub fn upstream(req: &HttpRequest) -> Option<(String, String)> {
+    req.extensions().get::<Upstream>().map(|upstream| (upstream.0.clone(), upstream.1.clone()))
+}
+
+// Removes the entry however the request ends, including client disconnects
+struct Deregister(Tracked);
+
+impl Drop for Deregister {
+    fn drop(&mut self) {
+        self.0.registry.entries.lock().unwrap().remove(&self.0.id);
+    }
+}
+
+/// Middleware registering every request in the `InflightRegistry`.
+pub struct InflightTracker {
+    registry: Arc<InflightRegistry>,
+}
+
+impl InflightTracker {
+    pub fn new(registry: Arc<InflightRegistry>) -> Self {
+        InflightTracker { registry }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for InflightTracker
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = InflightTrackerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(InflightTrackerMiddleware {
+            service: Rc::new(service),
+            registry: self.registry.clone(),
+        }))
+    }
+}
+
+pub struct InflightTrackerMiddleware<S> {
+    service: Rc<S>,
+    registry: Arc<InflightRegistry>,
+}
+
+impl<S, B> Service<ServiceRequest> for InflightTrackerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let (id, cancel) = self.registry.register(req.method().as_str(), req.path());
+        let tracked = Tracked {
+            id,
+            registry: self.registry.clone(),
+        };
+        req.extensions_mut().insert(tracked.clone());
+        let (method, path) = (req.method().clone(), req.path().to_string());
+
+        Box::pin(async move {
+            let _deregister = Deregister(tracked);
+            tokio::select! {
+                res = service.call(req) => res,
+                _ = cancel.notified() => {
+                    warn!("Cancelled in-flight request {} {} by operator", method, path);
+                    let response = HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                        "error": "Request cancelled",
+                        "message": "The request was cancelled by an operator"
+                    }));
+                    Err(InternalError::from_response("request cancelled", response).into())
+                }
+            }
+        })
+    }
+}
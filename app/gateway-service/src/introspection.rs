@@ -0,0 +1,143 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tracing::{info, warn};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::apikeys::ApiKeyError;
+use crate::auth::AuthMiddleware;
+use crate::revocation;
+use crate::AppState;
+
+/// API key scope a service needs to introspect tokens.
+pub const INTROSPECT_SCOPE: &str = "auth:introspect";
+
+// The calling service's API key: `X-Api-Key`, or HTTP Basic with the key
+// name as client id and the key as secret, as RFC 7662 clients send it
+fn client_credentials(req: &HttpRequest) -> Option<(Option<String>, String)> {
+    if let Some(key) = req.headers().get("X-Api-Key").and_then(|v| v.to_str().ok()) {
+        return Some((None, key.to_string()));
+    }
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))?;
+    let decoded = String::from_utf8(STANDARD.decode(basic.trim()).ok()?).ok()?;
+    let (client_id, secret) = decoded.split_once(':')?;
+    Some((Some(client_id.to_string()), secret.to_string()))
+}
+
+// Name of the authenticated service, or the response turning it away
+#[allow(clippy::result_large_err)]
+fn authenticate_client(req: &HttpRequest, data: &AppState) -> Result<String, HttpResponse> {
+    let unauthorized = || {
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"introspection\""))
+            .json(serde_json::json!({ "error": "invalid_client" }))
+    };
+    let (client_id, secret) = client_credentials(req).ok_or_else(unauthorized)?;
+    match data.api_keys.authenticate(&secret, INTROSPECT_SCOPE) {
+        Ok(key) if client_id.as_ref().is_none_or(|id| *id == key.name) => Ok(key.name),
+        Ok(_) | Err(ApiKeyError::Unknown) => Err(unauthorized()),
+        Err(ApiKeyError::MissingScope(scope)) => Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "insufficient_scope",
+            "scope": scope
+        }))),
+        Err(ApiKeyError::RateLimited(retry_after)) => Err(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(serde_json::json!({ "error": "rate_limited" }))),
+    }
+}
+
+// The `token` parameter, from a form body (RFC 7662) or a JSON one
+fn presented_token(req: &HttpRequest, body: &[u8]) -> Option<String> {
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if is_json {
+        let json: Value = serde_json::from_slice(body).ok()?;
+        return json.get("token").and_then(Value::as_str).map(str::to_string);
+    }
+    let form = web::Query::<HashMap<String, String>>::from_query(std::str::from_utf8(body).ok()?).ok()?;
+    form.get("token").cloned()
+}
+
+// RFC 7662 response for a token that verified
+fn active_response(claims: Map<String, Value>) -> Value {
+    let mut response = Map::new();
+    response.insert("active".to_string(), Value::Bool(true));
+    response.insert("token_type".to_string(), Value::String("Bearer".to_string()));
+    for name in ["sub", "username", "exp", "iat", "nbf", "iss", "aud", "jti"] {
+        if let Some(value) = claims.get(name) {
+            response.insert(name.to_string(), value.clone());
+        }
+    }
+    // RFC 7662 carries scopes as one space-separated string
+    let scope = match (claims.get("scope"), claims.get("scopes")) {
+        (Some(Value::String(scope)), _) => Some(scope.clone()),
+        (_, Some(Value::Array(scopes))) => Some(scopes.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" ")),
+        _ => None,
+    };
+    if let Some(scope) = scope {
+        response.insert("scope".to_string(), Value::String(scope));
+    }
+    if let Some(exp) = claims.get("exp").and_then(Value::as_i64) {
+        let expires_in = (exp - chrono::Utc::now().timestamp()).max(0);
+        response.insert("expires_in".to_string(), Value::from(expires_in));
+    }
+    response.insert("claims".to_string(), Value::Object(claims));
+    Value::Object(response)
+}
+
+/// Handle `/internal/auth/introspect`: tell an authenticated service whether
+/// a token is active and what it carries (RFC 7662). Inactive tokens only get
+/// `{"active": false}`.
+pub async fn introspect(req: HttpRequest, body: web::Bytes, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let outcome = |client: &str, outcome: &str| {
+        data.metrics.incr("gateway_introspections_total", &[("client", client), ("outcome", outcome)], 1)
+    };
+    let client = match authenticate_client(&req, &data) {
+        Ok(client) => client,
+        Err(response) => {
+            warn!("Rejected introspection request from {:?}", req.peer_addr());
+            outcome("unknown", "unauthorized");
+            return Ok(response);
+        }
+    };
+    let token = match presented_token(&req, &body) {
+        Some(token) => token,
+        None => {
+            outcome(&client, "invalid_request");
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid_request" })));
+        }
+    };
+
+    let inactive = || HttpResponse::Ok().json(serde_json::json!({ "active": false }));
+    let claims = match AuthMiddleware::raw_claims(&req, &token).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            outcome(&client, "inactive");
+            return Ok(inactive());
+        }
+    };
+    let jti = claims.get("jti").and_then(Value::as_str);
+    // The token itself, then the device session it belongs to
+    let session = claims.get("sid").and_then(Value::as_str).map(revocation::session_id);
+    for id in std::iter::once(revocation::token_id(&token, jti)).chain(session) {
+        if data.revocation.ensure_active(&id).await.is_err() {
+            outcome(&client, "inactive");
+            return Ok(inactive());
+        }
+    }
+
+    info!("Service {} introspected a token of {}", client, claims.get("sub").and_then(|sub| sub.as_str()).unwrap_or("?"));
+    outcome(&client, "active");
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(active_response(claims)))
+}
@@ -0,0 +1,385 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::cidr::Cidr;
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::rbac::{route_matches, routed_path};
+use crate::AppState;
+
+fn cidr_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Allow/deny lists for requests matching `path` (exact, or a prefix when it
+/// ends in `*`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopeRule {
+    pub path: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// IP rules as written in `IP_FILTER_FILE` or the environment. A non-empty
+/// allow list admits only the listed networks; deny entries always win.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IpRules {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Checked in order; the first rule matching the path applies on top of
+    /// the global lists
+    #[serde(default)]
+    pub scopes: Vec<ScopeRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IpFilterConfig {
+    /// JSON file with `allow`, `deny` and `scopes`, merged with the env lists
+    pub file: Option<String>,
+    /// How often the file is checked for changes; zero disables watching
+    pub reload_interval: Duration,
+    /// Proxies whose `X-Forwarded-For` is believed when finding the client IP
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl IpFilterConfig {
+    pub fn from_env() -> Self {
+        IpFilterConfig {
+            file: env::var("IP_FILTER_FILE").ok().filter(|path| !path.is_empty()),
+            reload_interval: Duration::from_secs(
+                parse_env("IP_FILTER_RELOAD_SECONDS").unwrap_or(30),
+            ),
+            trusted_proxies: cidr_list("TRUSTED_PROXIES")
+                .iter()
+                .filter_map(|cidr| match cidr.parse() {
+                    Ok(cidr) => Some(cidr),
+                    Err(e) => {
+                        warn!("Ignoring trusted proxy '{}': {}", cidr, e);
+                        invalid("TRUSTED_PROXIES", format!("'{}': {}", cidr, e));
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// The client address: the peer, or for a trusted proxy the last
+    /// `X-Forwarded-For` hop that is not itself a trusted proxy. Unix socket
+    /// peers have no address and count as trusted proxies, since the
+    /// socket's permissions already decide who may connect.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let trusted = |ip: IpAddr| self.trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+        if let Some(peer) = peer.filter(|peer| !trusted(*peer)) {
+            return Some(peer);
+        }
+        let forwarded = req.headers().get("X-Forwarded-For").and_then(|v| v.to_str().ok()).unwrap_or("");
+        let hops: Vec<IpAddr> = forwarded.split(',').filter_map(|hop| hop.trim().parse().ok()).collect();
+        hops.into_iter().rev().find(|ip| !trusted(*ip)).or(peer)
+    }
+}
+
+struct CompiledScope {
+    path: String,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+struct Compiled {
+    rules: IpRules,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    scopes: Vec<CompiledScope>,
+}
+
+fn parse_all(cidrs: &[String]) -> Result<Vec<Cidr>, String> {
+    cidrs.iter().map(|cidr| cidr.parse()).collect()
+}
+
+impl Compiled {
+    fn new(rules: IpRules) -> Result<Self, String> {
+        let scopes = rules
+            .scopes
+            .iter()
+            .map(|scope| {
+                Ok(CompiledScope {
+                    path: scope.path.clone(),
+                    allow: parse_all(&scope.allow)?,
+                    deny: parse_all(&scope.deny)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Compiled {
+            allow: parse_all(&rules.allow)?,
+            deny: parse_all(&rules.deny)?,
+            scopes,
+            rules,
+        })
+    }
+
+    // Which list turned the client away, if any
+    fn verdict(&self, ip: Option<IpAddr>, path: &str) -> Option<&'static str> {
+        let listed = |list: &[Cidr]| ip.map(|ip| list.iter().any(|cidr| cidr.contains(ip))).unwrap_or(false);
+        let admitted = |list: &[Cidr]| list.is_empty() || listed(list);
+        if listed(&self.deny) {
+            return Some("denylist");
+        }
+        if !admitted(&self.allow) {
+            return Some("allowlist");
+        }
+        let scope = self.scopes.iter().find(|scope| route_matches(&scope.path, &[], "", path))?;
+        if listed(&scope.deny) {
+            return Some("scope_denylist");
+        }
+        if !admitted(&scope.allow) {
+            return Some("scope_allowlist");
+        }
+        None
+    }
+}
+
+/// A network refused by an operator through the admin API, on top of the
+/// configured lists and kept across reloads.
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    pub network: String,
+    #[serde(skip)]
+    cidr: Cidr,
+    pub reason: Option<String>,
+    pub banned_by: String,
+    pub banned_at: String,
+    /// Lifted automatically at this time; permanent until unbanned when unset
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// CIDR allow/deny lists, reloadable from `IP_FILTER_FILE` at runtime.
+pub struct IpFilter {
+    config: IpFilterConfig,
+    compiled: RwLock<Compiled>,
+    bans: Mutex<Vec<Ban>>,
+    file_modified: Mutex<Option<SystemTime>>,
+    metrics: Arc<Metrics>,
+}
+
+impl IpFilter {
+    pub fn new(config: IpFilterConfig, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let filter = IpFilter {
+            config,
+            compiled: RwLock::new(Compiled::new(IpRules::default())?),
+            bans: Mutex::new(Vec::new()),
+            file_modified: Mutex::new(None),
+            metrics,
+        };
+        filter.reload()?;
+        Ok(filter)
+    }
+
+    fn file_mtime(&self) -> Option<SystemTime> {
+        let path = self.config.file.as_ref()?;
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    /// Re-read the env lists and the rules file. Invalid rules leave the
+    /// current ones in place.
+    pub fn reload(&self) -> Result<IpRules, String> {
+        let mut rules = IpRules {
+            allow: cidr_list("IP_ALLOWLIST"),
+            deny: cidr_list("IP_DENYLIST"),
+            scopes: match env::var("IP_SCOPE_RULES") {
+                Ok(raw) => serde_json::from_str(&raw).map_err(|e| format!("invalid IP_SCOPE_RULES: {}", e))?,
+                Err(_) => Vec::new(),
+            },
+        };
+        let modified = self.file_mtime();
+        if let Some(path) = &self.config.file {
+            let raw = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+            let file: IpRules = serde_json::from_str(&raw).map_err(|e| format!("invalid {}: {}", path, e))?;
+            rules.allow.extend(file.allow);
+            rules.deny.extend(file.deny);
+            rules.scopes.extend(file.scopes);
+        }
+
+        let compiled = Compiled::new(rules.clone())?;
+        info!(
+            "IP filter loaded: {} allowed, {} denied, {} scoped rules",
+            compiled.allow.len(),
+            compiled.deny.len(),
+            compiled.scopes.len()
+        );
+        *self.compiled.write().unwrap() = compiled;
+        *self.file_modified.lock().unwrap() = modified;
+        Ok(rules)
+    }
+
+    /// Rules currently enforced.
+    pub fn rules(&self) -> IpRules {
+        self.compiled.read().unwrap().rules.clone()
+    }
+
+    /// Client address, seen through the trusted proxies.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        self.config.client_ip(req)
+    }
+
+    /// Refuse `network` until `duration` has passed, or until unbanned.
+    /// Banning a network again replaces its ban.
+    pub fn ban(&self, network: &str, reason: Option<String>, duration: Option<Duration>, actor: &str) -> Result<Ban, String> {
+        let cidr: Cidr = network.parse()?;
+        let now = chrono::Utc::now();
+        let ban = Ban {
+            network: cidr.to_string(),
+            cidr,
+            reason,
+            banned_by: actor.to_string(),
+            banned_at: now.to_rfc3339(),
+            until: duration.and_then(|d| chrono::Duration::from_std(d).ok()).map(|d| now + d),
+        };
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|existing| existing.cidr != cidr);
+        bans.push(ban.clone());
+        Ok(ban)
+    }
+
+    pub fn unban(&self, network: &str) -> Result<Option<Ban>, String> {
+        let cidr: Cidr = network.parse()?;
+        let mut bans = self.bans.lock().unwrap();
+        let position = bans.iter().position(|ban| ban.cidr == cidr);
+        Ok(position.map(|position| bans.remove(position)))
+    }
+
+    /// Bans in force, expired ones dropped.
+    pub fn bans(&self) -> Vec<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        let now = chrono::Utc::now();
+        bans.retain(|ban| ban.until.is_none_or(|until| until > now));
+        bans.clone()
+    }
+
+    fn banned(&self, ip: Option<IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return false,
+        };
+        let now = chrono::Utc::now();
+        self.bans
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|ban| ban.cidr.contains(ip) && ban.until.is_none_or(|until| until > now))
+    }
+
+    fn check(&self, req: &ServiceRequest) -> Result<(), (&'static str, Option<IpAddr>)> {
+        let ip = self.client_ip(req.request());
+        if self.banned(ip) {
+            return Err(("banned", ip));
+        }
+        match self.compiled.read().unwrap().verdict(ip, routed_path(req.request())) {
+            Some(list) => Err((list, ip)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reload the rules whenever `IP_FILTER_FILE` changes.
+pub async fn watch_rules(data: web::Data<AppState>) {
+    let interval = data.config.load().ip_filter.reload_interval;
+    if data.config.load().ip_filter.file.is_none() || interval.is_zero() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let modified = data.ip_filter.file_mtime();
+        if modified == *data.ip_filter.file_modified.lock().unwrap() {
+            continue;
+        }
+        if let Err(e) = data.ip_filter.reload() {
+            warn!("IP filter reload failed, keeping the current rules: {}", e);
+        }
+    }
+}
+
+/// Middleware refusing clients excluded by the IP lists with 403, before any
+/// authentication happens.
+pub struct IpGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for IpGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = IpGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IpGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct IpGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for IpGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let verdict = match req.app_data::<web::Data<AppState>>() {
+                Some(data) => data.ip_filter.check(&req),
+                None => Ok(()),
+            };
+            let (list, ip) = match verdict {
+                Ok(()) => return service.call(req).await.map(|res| res.map_into_left_body()),
+                Err(denied) => denied,
+            };
+
+            let ip = ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+            warn!("Refused {} {} from {} ({})", req.method(), req.path(), ip, list);
+            if let Some(data) = req.app_data::<web::Data<AppState>>() {
+                data.ip_filter.metrics.incr("gateway_ip_denials_total", &[("list", list)], 1);
+            }
+            let response = HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Access denied",
+                "code": "ip_denied"
+            }));
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use tracing::info;
+use ring::aead::{Aad, LessSafeKey, Nonce, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+
+use crate::fieldcrypt::parse_key;
+
+const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct JweConfig {
+    /// `id:base64 key` pairs of 256-bit keys; old keys stay listed so tokens
+    /// they encrypted can still be read
+    pub keys: Vec<String>,
+    /// Id of the key new tokens are encrypted with; the first key when unset
+    pub active_key: Option<String>,
+    /// Refuse plain signed tokens, once every client holds encrypted ones
+    pub required: bool,
+}
+
+// Key material stays out of the startup config log
+impl fmt::Debug for JweConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<_> = self.keys.iter().map(|key| key.split(':').next().unwrap_or_default()).collect();
+        f.debug_struct("JweConfig")
+            .field("keys", &ids)
+            .field("active_key", &self.active_key)
+            .field("required", &self.required)
+            .finish()
+    }
+}
+
+impl JweConfig {
+    /// Read `JWE_KEYS` (comma-separated `id:key`), `JWE_ACTIVE_KEY` and
+    /// `JWE_REQUIRED`. Without keys, issued tokens are only signed.
+    pub fn from_env() -> Self {
+        JweConfig {
+            keys: env::var("JWE_KEYS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect(),
+            active_key: env::var("JWE_ACTIVE_KEY").ok().filter(|id| !id.is_empty()),
+            required: env::var("JWE_REQUIRED").map(|v| v == "true" || v == "1").unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+}
+
+/// Wraps signed gateway tokens in compact JWE (`dir` key agreement, A256GCM)
+/// so clients cannot read their claims, and unwraps them for validation.
+pub struct Jwe {
+    keys: Vec<(String, LessSafeKey)>,
+    active: usize,
+    required: bool,
+    rng: SystemRandom,
+}
+
+impl Jwe {
+    pub fn new(config: &JweConfig) -> Result<Self, String> {
+        let keys = config.keys.iter().map(|entry| parse_key(entry)).collect::<Result<Vec<_>, String>>()?;
+        let active = match &config.active_key {
+            Some(id) => keys.iter().position(|(kid, _)| kid == id).ok_or_else(|| format!("active key {} is not configured", id))?,
+            None => 0,
+        };
+        if config.required && keys.is_empty() {
+            return Err("JWE_REQUIRED is set but no JWE_KEYS are configured".to_string());
+        }
+        if let Some((id, _)) = keys.get(active) {
+            info!("Issued tokens are encrypted with JWE key {}", id);
+        }
+        Ok(Jwe {
+            keys,
+            active,
+            required: config.required,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Whether `token` is in JWE compact form (five segments) rather than JWS.
+    pub fn is_encrypted(token: &str) -> bool {
+        token.split('.').count() == 5
+    }
+
+    /// Encrypt a signed token; returned unchanged while JWE is off.
+    pub fn encrypt(&self, jws: &str) -> Result<String, String> {
+        let (kid, key) = match self.keys.get(self.active) {
+            Some(key) => key,
+            None => return Ok(jws.to_string()),
+        };
+        let header = JweHeader {
+            alg: "dir".to_string(),
+            enc: "A256GCM".to_string(),
+            kid: Some(kid.clone()),
+            cty: Some("JWT".to_string()),
+        };
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).map_err(|e| e.to_string())?);
+        let mut iv = [0u8; NONCE_LEN];
+        self.rng.fill(&mut iv).map_err(|_| "system random source unavailable")?;
+        let mut ciphertext = jws.as_bytes().to_vec();
+        let tag = key
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(iv), Aad::from(protected.as_bytes()), &mut ciphertext)
+            .map_err(|_| "encryption failed")?;
+        // `dir` carries no encrypted key, so the second segment is empty
+        Ok(format!(
+            "{}..{}.{}.{}",
+            protected,
+            URL_SAFE_NO_PAD.encode(iv),
+            URL_SAFE_NO_PAD.encode(ciphertext),
+            URL_SAFE_NO_PAD.encode(tag.as_ref())
+        ))
+    }
+
+    /// The signed token inside `token`. Plain JWS passes through unless
+    /// encryption is required; it still has to verify afterwards.
+    pub fn decrypt(&self, token: &str) -> Result<String, String> {
+        if !Self::is_encrypted(token) {
+            return match self.required {
+                true => Err("token is not encrypted".to_string()),
+                false => Ok(token.to_string()),
+            };
+        }
+        let segments: Vec<&str> = token.split('.').collect();
+        let (protected, encrypted_key, iv, ciphertext, tag) = (segments[0], segments[1], segments[2], segments[3], segments[4]);
+        let decode = |segment: &str| URL_SAFE_NO_PAD.decode(segment).map_err(|_| "malformed token".to_string());
+        let header: JweHeader = serde_json::from_slice(&decode(protected)?).map_err(|_| "malformed header")?;
+        if header.alg != "dir" || header.enc != "A256GCM" || !encrypted_key.is_empty() {
+            return Err(format!("unsupported JWE algorithm {}/{}", header.alg, header.enc));
+        }
+        let iv = decode(iv)?;
+        let tag = decode(tag)?;
+        if iv.len() != NONCE_LEN || tag.len() != TAG_LEN {
+            return Err("malformed token".to_string());
+        }
+        let mut sealed = decode(ciphertext)?;
+        sealed.extend(tag);
+
+        // A token without `kid` is tried against every key
+        let candidates = self.keys.iter().filter(|(id, _)| header.kid.as_ref().is_none_or(|kid| kid == id));
+        for (_, key) in candidates {
+            let nonce = Nonce::try_assume_unique_for_key(&iv).map_err(|_| "malformed token")?;
+            let mut buffer = sealed.clone();
+            if let Ok(plaintext) = key.open_in_place(nonce, Aad::from(protected.as_bytes()), &mut buffer) {
+                return String::from_utf8(plaintext.to_vec()).map_err(|_| "token is not UTF-8".to_string());
+            }
+        }
+        Err("token does not decrypt with any configured key".to_string())
+    }
+}
@@ -0,0 +1,337 @@
+use actix_web::web;
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use tracing::{info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct JwksConfig {
+    /// Algorithms tokens may be signed with; anything else is rejected
+    /// before a key is even looked up
+    pub algorithms: Vec<Algorithm>,
+    /// Endpoint publishing the public keys for asymmetric algorithms
+    pub url: Option<String>,
+    pub refresh_interval: Duration,
+    /// Minimum gap between refreshes triggered by an unknown `kid`
+    pub min_refresh_interval: Duration,
+    /// Required `iss` claim; tokens from other issuers are rejected
+    pub issuer: Option<String>,
+    /// Accepted `aud` values; a token must name at least one of them
+    pub audiences: Vec<String>,
+    /// Clock skew tolerated on `exp`, `nbf` and `iat`
+    pub leeway: Duration,
+    /// Reject tokens used before their `nbf`
+    pub validate_nbf: bool,
+    /// Longest accepted `exp - iat`; tokens without `iat` may not expire
+    /// later than this from now
+    pub max_lifetime: Option<Duration>,
+}
+
+impl JwksConfig {
+    pub fn from_env() -> Self {
+        let seconds = |key: &str, default: u64| {
+            Duration::from_secs(parse_env(key).unwrap_or(default))
+        };
+        let algorithms = env::var("JWT_ALGORITHMS")
+            .unwrap_or_else(|_| "HS256".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|alg| !alg.is_empty())
+            .filter_map(|alg| match Algorithm::from_str(alg) {
+                Ok(alg) => Some(alg),
+                Err(_) => {
+                    warn!("Ignoring unknown JWT algorithm '{}'", alg);
+                    invalid("JWT_ALGORITHMS", format!("unknown algorithm '{}'", alg));
+                    None
+                }
+            })
+            .collect();
+        JwksConfig {
+            algorithms,
+            url: env::var("JWKS_URL").ok().filter(|url| !url.is_empty()),
+            refresh_interval: seconds("JWKS_REFRESH_SECONDS", 300),
+            min_refresh_interval: seconds("JWKS_MIN_REFRESH_SECONDS", 30),
+            issuer: env::var("JWT_ISSUER").ok().filter(|iss| !iss.is_empty()),
+            audiences: env::var("JWT_AUDIENCE")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|aud| !aud.is_empty())
+                .map(str::to_string)
+                .collect(),
+            leeway: seconds("JWT_LEEWAY_SECONDS", 60),
+            validate_nbf: env::var("JWT_VALIDATE_NBF").map(|v| v == "true" || v == "1").unwrap_or(false),
+            max_lifetime: parse_env("JWT_MAX_LIFETIME_SECONDS")
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+        }
+    }
+
+    pub fn allows(&self, algorithm: Algorithm) -> bool {
+        self.algorithms.contains(&algorithm)
+    }
+
+    /// Validation for a token signed with `algorithm`. A configured issuer or
+    /// audience must also be present, so tokens minted without them (e.g. for
+    /// another environment) do not slip through.
+    pub fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = self.validate_nbf;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        if !self.audiences.is_empty() {
+            validation.set_audience(&self.audiences);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        validation
+    }
+
+    /// Check the token lifetime policy against already validated claims:
+    /// `iat` may not lie in the future and the token may not outlive
+    /// `max_lifetime`.
+    pub fn check_lifetime(&self, claims: &Value) -> Result<(), &'static str> {
+        let now = chrono::Utc::now().timestamp();
+        let leeway = self.leeway.as_secs() as i64;
+        let iat = claims.get("iat").and_then(Value::as_i64);
+        let exp = claims.get("exp").and_then(Value::as_i64).unwrap_or(now);
+        if iat.is_some_and(|iat| iat > now + leeway) {
+            return Err("issued in the future");
+        }
+        if let Some(max) = self.max_lifetime {
+            let max = max.as_secs() as i64;
+            let too_long = match iat {
+                Some(iat) => exp - iat > max,
+                None => exp - now > max + leeway,
+            };
+            if too_long {
+                return Err("lifetime exceeds the maximum");
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn is_symmetric(algorithm: Algorithm) -> bool {
+    matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+struct Key {
+    decoding: DecodingKey,
+    /// Algorithm the key is pinned to by its `alg` parameter
+    algorithm: Option<Algorithm>,
+}
+
+/// Public keys fetched from the JWKS endpoint, selected by `kid`. Keys are
+/// replaced wholesale on each refresh, so rotated-out keys stop verifying once
+/// the provider drops them.
+pub struct JwksKeys {
+    config: JwksConfig,
+    client: reqwest::Client,
+    keys: RwLock<HashMap<String, Key>>,
+    last_fetch: Mutex<Option<Instant>>,
+    metrics: Arc<Metrics>,
+}
+
+impl JwksKeys {
+    pub fn new(config: JwksConfig, client: reqwest::Client, metrics: Arc<Metrics>) -> Self {
+        JwksKeys {
+            config,
+            client,
+            keys: RwLock::new(HashMap::new()),
+            last_fetch: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    /// Fetch the key set, keeping the previous keys if that fails.
+    pub async fn refresh(&self) -> Result<usize, String> {
+        let url = self.config.url.as_deref().ok_or("JWKS_URL not set")?;
+        *self.last_fetch.lock().unwrap() = Some(Instant::now());
+
+        let fetched = async {
+            let response = self
+                .client
+                .get(url)
+                .timeout(Duration::from_secs(5))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("JWKS endpoint answered {}", response.status()));
+            }
+            response.json::<Value>().await.map_err(|e| e.to_string())
+        }
+        .await;
+        let set = match fetched {
+            Ok(set) => set,
+            Err(e) => {
+                self.metrics.incr("gateway_jwks_refreshes_total", &[("outcome", "error")], 1);
+                return Err(e);
+            }
+        };
+
+        // Parse keys one by one so a key we cannot use does not hide the rest
+        let mut keys = HashMap::new();
+        for (index, raw) in set.get("keys").and_then(Value::as_array).into_iter().flatten().enumerate() {
+            // Encryption keys are published alongside signing keys but never verify tokens
+            if raw.get("use").and_then(Value::as_str) == Some("enc") {
+                continue;
+            }
+            let jwk: Jwk = match serde_json::from_value(raw.clone()) {
+                Ok(jwk) => jwk,
+                Err(e) => {
+                    warn!("Skipping unsupported JWKS key {}: {}", index, e);
+                    continue;
+                }
+            };
+            let decoding = match DecodingKey::from_jwk(&jwk) {
+                Ok(decoding) => decoding,
+                Err(e) => {
+                    warn!("Skipping invalid JWKS key {}: {}", index, e);
+                    continue;
+                }
+            };
+            let kid = jwk.common.key_id.clone().unwrap_or_default();
+            keys.insert(
+                kid,
+                Key {
+                    decoding,
+                    algorithm: jwk.common.algorithm,
+                },
+            );
+        }
+
+        let count = keys.len();
+        *self.keys.write().unwrap() = keys;
+        self.metrics.incr("gateway_jwks_refreshes_total", &[("outcome", "success")], 1);
+        self.metrics.gauge_set("gateway_jwks_keys", &[], count as f64);
+        Ok(count)
+    }
+
+    fn lookup(&self, kid: Option<&str>, algorithm: Algorithm) -> Option<DecodingKey> {
+        let keys = self.keys.read().unwrap();
+        let key = match kid {
+            Some(kid) => keys.get(kid)?,
+            // Without a kid the choice is only unambiguous with a single key
+            None if keys.len() == 1 => keys.values().next()?,
+            None => return None,
+        };
+        match key.algorithm {
+            Some(pinned) if pinned != algorithm => None,
+            _ => Some(key.decoding.clone()),
+        }
+    }
+
+    /// Key to verify a token signed with `algorithm` by key `kid`, refetching
+    /// the set once when the kid is unknown (e.g. right after a rotation).
+    pub async fn key(&self, kid: Option<&str>, algorithm: Algorithm) -> Option<DecodingKey> {
+        if let Some(key) = self.lookup(kid, algorithm) {
+            return Some(key);
+        }
+        let stale = self
+            .last_fetch
+            .lock()
+            .unwrap()
+            .map(|at| at.elapsed() >= self.config.min_refresh_interval)
+            .unwrap_or(true);
+        if !stale || self.config.url.is_none() {
+            return None;
+        }
+        info!("Unknown JWKS key id {:?}, refreshing keys", kid);
+        if let Err(e) = self.refresh().await {
+            warn!("JWKS refresh failed: {}", e);
+        }
+        self.lookup(kid, algorithm)
+    }
+}
+
+/// Keep the key set fresh in the background.
+pub async fn refresh_keys(data: web::Data<AppState>) {
+    if data.config.load().jwks.url.is_none() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(data.config.load().jwks.refresh_interval);
+    loop {
+        ticker.tick().await;
+        match data.jwks.refresh().await {
+            Ok(count) => info!("Loaded {} signing keys from JWKS", count),
+            Err(e) => warn!("JWKS refresh failed, keeping previous keys: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{decode, encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"shared-between-environments";
+
+    fn config() -> JwksConfig {
+        JwksConfig {
+            algorithms: vec![Algorithm::HS256],
+            url: None,
+            refresh_interval: Duration::from_secs(300),
+            min_refresh_interval: Duration::from_secs(30),
+            issuer: Some("https://auth.example.com".to_string()),
+            audiences: vec!["chat-gateway".to_string()],
+            leeway: Duration::from_secs(60),
+            validate_nbf: false,
+            max_lifetime: None,
+        }
+    }
+
+    fn verify(config: &JwksConfig, claims: Value) -> Result<Value, jsonwebtoken::errors::ErrorKind> {
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(SECRET)).unwrap();
+        decode::<Value>(&token, &DecodingKey::from_secret(SECRET), &config.validation(Algorithm::HS256))
+            .map(|data| data.claims)
+            .map_err(|e| e.into_kind())
+    }
+
+    fn claims(iss: &str, aud: &str) -> Value {
+        let exp = chrono::Utc::now().timestamp() + 600;
+        json!({ "sub": "1", "username": "alice", "exp": exp, "iss": iss, "aud": aud })
+    }
+
+    #[test]
+    fn accepts_the_configured_issuer_and_audience() {
+        assert!(verify(&config(), claims("https://auth.example.com", "chat-gateway")).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_wrong_issuer() {
+        let rejected = verify(&config(), claims("https://evil.example.com", "chat-gateway"));
+        assert!(matches!(rejected, Err(jsonwebtoken::errors::ErrorKind::InvalidIssuer)));
+    }
+
+    #[test]
+    fn rejects_a_wrong_audience() {
+        let rejected = verify(&config(), claims("https://auth.example.com", "billing"));
+        assert!(matches!(rejected, Err(jsonwebtoken::errors::ErrorKind::InvalidAudience)));
+    }
+
+    #[test]
+    fn rejects_tokens_from_another_environment() {
+        // Staging signs with the same secret but names itself and its gateway
+        let staging = claims("https://auth.staging.example.com", "chat-gateway-staging");
+        assert!(verify(&config(), staging).is_err());
+
+        // A token minted without either claim is not let through either
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let bare = json!({ "sub": "1", "username": "alice", "exp": exp });
+        assert!(matches!(verify(&config(), bare), Err(jsonwebtoken::errors::ErrorKind::MissingRequiredClaim(_))));
+    }
+}
@@ -0,0 +1,306 @@
+use actix_web::web;
+use futures_util::{StreamExt, TryStreamExt};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::{Api, ListParams, ObjectList, WatchEvent, WatchParams};
+use kube::{Client, Config};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::discovery::publish;
+use crate::AppState;
+
+/// Where pods find their service account token, CA and namespace.
+pub const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// The API server ends a watch after this long and it is resumed; kube takes
+// at most 295
+const WATCH_TIMEOUT_SECS: u32 = 290;
+
+// Answered when the version a watch resumes from has been compacted away
+const GONE: u16 = 410;
+
+/// A Kubernetes service named in `DISCOVERY_SERVICES` as
+/// `[namespace/]name[:port-name]`.
+struct Target {
+    namespace: String,
+    name: String,
+    /// The slice's first port when unset
+    port: Option<String>,
+}
+
+impl Target {
+    fn parse(registered: &str, namespace: &str) -> Self {
+        let (namespace, rest) = registered.split_once('/').unwrap_or((namespace, registered));
+        let (name, port) = match rest.split_once(':') {
+            Some((name, port)) => (name, Some(port.to_string())),
+            None => (rest, None),
+        };
+        Target {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            port,
+        }
+    }
+}
+
+// Ready pod addresses in a slice, as instance URLs
+fn slice_instances(slice: &EndpointSlice, port: Option<&str>, scheme: &str) -> Vec<String> {
+    let port = slice
+        .ports
+        .iter()
+        .flatten()
+        .find(|p| port.is_none_or(|name| p.name.as_deref() == Some(name)))
+        .and_then(|p| p.port);
+    let port = match port {
+        Some(port) => port,
+        None => return Vec::new(),
+    };
+    slice
+        .endpoints
+        .iter()
+        .filter(|endpoint| endpoint.conditions.as_ref().and_then(|c| c.ready) != Some(false))
+        .flat_map(|endpoint| endpoint.addresses.iter())
+        .map(|address| match address.contains(':') {
+            true => format!("{}://[{}]:{}", scheme, address, port),
+            false => format!("{}://{}:{}", scheme, address, port),
+        })
+        .collect()
+}
+
+/// What a watch event did to the slices.
+#[derive(Debug, PartialEq)]
+enum Applied {
+    Changed,
+    Unchanged,
+    /// The watch cannot be resumed and the slices must be listed again
+    Relist,
+}
+
+/// Ready instances of one service, kept per EndpointSlice, and the
+/// `resourceVersion` to resume watching from; empty until listed.
+#[derive(Default)]
+struct Slices {
+    port: Option<String>,
+    scheme: String,
+    slices: HashMap<String, Vec<String>>,
+    version: String,
+}
+
+impl Slices {
+    fn instances(&self) -> Vec<String> {
+        self.slices.values().flatten().cloned().collect()
+    }
+
+    fn insert(&mut self, slice: &EndpointSlice) {
+        let instances = slice_instances(slice, self.port.as_deref(), &self.scheme);
+        self.slices.insert(slice.metadata.name.clone().unwrap_or_default(), instances);
+    }
+
+    fn replace(&mut self, list: &ObjectList<EndpointSlice>) {
+        self.slices.clear();
+        for slice in &list.items {
+            self.insert(slice);
+        }
+        self.version = list.metadata.resource_version.clone().unwrap_or_default();
+    }
+
+    // Versions are opaque, so the latest one seen is the one to resume from
+    fn seen(&mut self, version: Option<&String>) {
+        if let Some(version) = version.filter(|v| !v.is_empty()) {
+            self.version = version.clone();
+        }
+    }
+
+    fn apply(&mut self, event: WatchEvent<EndpointSlice>) -> Result<Applied, String> {
+        match event {
+            WatchEvent::Added(slice) | WatchEvent::Modified(slice) => {
+                self.insert(&slice);
+                self.seen(slice.metadata.resource_version.as_ref());
+                Ok(Applied::Changed)
+            }
+            WatchEvent::Deleted(slice) => {
+                self.slices.remove(slice.metadata.name.as_deref().unwrap_or_default());
+                self.seen(slice.metadata.resource_version.as_ref());
+                Ok(Applied::Changed)
+            }
+            WatchEvent::Bookmark(bookmark) => {
+                self.seen(Some(&bookmark.metadata.resource_version));
+                Ok(Applied::Unchanged)
+            }
+            WatchEvent::Error(status) if status.code == GONE => {
+                self.version.clear();
+                Ok(Applied::Relist)
+            }
+            WatchEvent::Error(status) => Err(format!("watch failed with {}: {}", status.code, status.message)),
+        }
+    }
+}
+
+// List the slices unless a version to resume from is known, then follow
+// changes until the watch can no longer be resumed
+async fn follow(api: &Api<EndpointSlice>, selector: &str, slices: &mut Slices, publish: impl Fn(Vec<String>)) -> Result<(), String> {
+    if slices.version.is_empty() {
+        let list = api.list(&ListParams::default().labels(selector)).await.map_err(|e| e.to_string())?;
+        slices.replace(&list);
+        publish(slices.instances());
+    }
+    loop {
+        let params = WatchParams::default().labels(selector).timeout(WATCH_TIMEOUT_SECS);
+        let mut events = match api.watch(&params, &slices.version).await {
+            Ok(events) => events.boxed(),
+            Err(kube::Error::Api(status)) if status.code == GONE => {
+                slices.version.clear();
+                return Ok(());
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        while let Some(event) = events.try_next().await.map_err(|e| e.to_string())? {
+            match slices.apply(event)? {
+                Applied::Changed => publish(slices.instances()),
+                Applied::Unchanged => {}
+                Applied::Relist => return Ok(()),
+            }
+        }
+    }
+}
+
+// API server client authenticating as the pod's service account, whose
+// rotated tokens kube reads again as they change
+fn client(api: &str) -> Result<Client, String> {
+    let mut config = Config::incluster().map_err(|e| e.to_string())?;
+    config.cluster_url = api.parse().map_err(|e| format!("invalid API URL {}: {}", api, e))?;
+    Client::try_from(config).map_err(|e| e.to_string())
+}
+
+/// Follow the EndpointSlices of `registered` and route to its ready pods
+/// directly. The service account needs `list` and `watch` on
+/// `endpointslices` in the service's namespace.
+pub async fn watch(data: web::Data<AppState>, service: String, registered: String, api: String, namespace: String) {
+    let client = match client(&api) {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Cannot reach the Kubernetes API, {} keeps its configured URL: {}", service, e);
+            return;
+        }
+    };
+    let target = Target::parse(&registered, &namespace);
+    let config = data.config.load().discovery.clone();
+    let api: Api<EndpointSlice> = Api::namespaced(client, &target.namespace);
+    let selector = format!("kubernetes.io/service-name={}", target.name);
+    let mut slices = Slices {
+        port: target.port.clone(),
+        scheme: config.scheme.clone(),
+        ..Slices::default()
+    };
+    loop {
+        let result = follow(&api, &selector, &mut slices, |instances| publish(&data, &service, "kubernetes", instances)).await;
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        data.metrics.incr("gateway_discovery_lookups_total", &[("service", &service), ("backend", "kubernetes"), ("outcome", outcome)], 1);
+        match result {
+            Ok(()) => info!("EndpointSlice watch for {} can no longer be resumed, listing again", service),
+            Err(e) => {
+                warn!("Watching {}/{} failed, keeping the current instances: {}", target.namespace, target.name, e);
+                tokio::time::sleep(config.interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn slice(name: &str, version: &str, addresses: &[(&str, bool)]) -> serde_json::Value {
+        json!({
+            "apiVersion": "discovery.k8s.io/v1",
+            "kind": "EndpointSlice",
+            "metadata": { "name": name, "resourceVersion": version },
+            "addressType": "IPv4",
+            "endpoints": addresses
+                .iter()
+                .map(|(address, ready)| json!({ "addresses": [address], "conditions": { "ready": ready } }))
+                .collect::<Vec<_>>(),
+            "ports": [{ "name": "metrics", "port": 9090 }, { "name": "http", "port": 8080 }],
+        })
+    }
+
+    fn event(kind: &str, object: serde_json::Value) -> WatchEvent<EndpointSlice> {
+        serde_json::from_value(json!({ "type": kind, "object": object })).unwrap()
+    }
+
+    fn listed() -> Slices {
+        let list: ObjectList<EndpointSlice> = serde_json::from_value(json!({
+            "metadata": { "resourceVersion": "100" },
+            "items": [slice("chat-a", "90", &[("10.0.0.1", true), ("10.0.0.2", false)])],
+        }))
+        .unwrap();
+        let mut slices = Slices {
+            port: Some("http".to_string()),
+            scheme: "http".to_string(),
+            ..Slices::default()
+        };
+        slices.replace(&list);
+        slices
+    }
+
+    fn sorted(slices: &Slices) -> Vec<String> {
+        let mut instances = slices.instances();
+        instances.sort();
+        instances
+    }
+
+    #[test]
+    fn targets_name_namespace_and_port() {
+        let target = Target::parse("chat", "default");
+        assert_eq!((target.namespace.as_str(), target.name.as_str(), target.port), ("default", "chat", None));
+        let target = Target::parse("apps/chat:http", "default");
+        assert_eq!((target.namespace.as_str(), target.name.as_str(), target.port.as_deref()), ("apps", "chat", Some("http")));
+    }
+
+    #[test]
+    fn listing_resumes_from_the_list_version() {
+        let slices = listed();
+        assert_eq!(slices.version, "100");
+        assert_eq!(sorted(&slices), ["http://10.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn events_move_the_resume_version_forward() {
+        let mut slices = listed();
+        let added = event("ADDED", slice("chat-b", "101", &[("fd00::1", true)]));
+        assert_eq!(slices.apply(added), Ok(Applied::Changed));
+        assert_eq!(slices.version, "101");
+        assert_eq!(sorted(&slices), ["http://10.0.0.1:8080", "http://[fd00::1]:8080"]);
+
+        let modified = event("MODIFIED", slice("chat-a", "102", &[("10.0.0.1", false), ("10.0.0.2", true)]));
+        assert_eq!(slices.apply(modified), Ok(Applied::Changed));
+        let deleted = event("DELETED", slice("chat-b", "103", &[]));
+        assert_eq!(slices.apply(deleted), Ok(Applied::Changed));
+        assert_eq!(slices.version, "103");
+        assert_eq!(sorted(&slices), ["http://10.0.0.2:8080"]);
+
+        let bookmark = event("BOOKMARK", json!({ "kind": "EndpointSlice", "apiVersion": "discovery.k8s.io/v1", "metadata": { "resourceVersion": "250" } }));
+        assert_eq!(slices.apply(bookmark), Ok(Applied::Unchanged));
+        assert_eq!(slices.version, "250");
+        assert_eq!(sorted(&slices), ["http://10.0.0.2:8080"]);
+    }
+
+    #[test]
+    fn expired_versions_relist() {
+        let mut slices = listed();
+        let gone = event("ERROR", json!({ "status": "Failure", "code": 410, "reason": "Expired", "message": "too old resource version: 100 (200)" }));
+        assert_eq!(slices.apply(gone), Ok(Applied::Relist));
+        assert_eq!(slices.version, "");
+        // Instances stay routed to until the new list replaces them
+        assert_eq!(sorted(&slices), ["http://10.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn other_watch_errors_resume_from_the_last_version() {
+        let mut slices = listed();
+        let error = event("ERROR", json!({ "status": "Failure", "code": 500, "message": "etcd unavailable" }));
+        assert!(slices.apply(error).is_err());
+        assert_eq!(slices.version, "100");
+    }
+}
@@ -0,0 +1,236 @@
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+use socket2::{Domain, Socket, Type};
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{invalid, parse_env};
+use crate::systemd;
+use crate::AppState;
+
+/// How the HTTP server accepts and serves connections.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    /// Worker threads, each running its own event loop
+    pub workers: usize,
+    /// Open connections per worker before it stops accepting
+    pub max_connections: usize,
+    /// TLS handshakes in progress per worker
+    pub max_connection_rate: usize,
+    /// Pending connections the kernel queues for the listener
+    pub backlog: i32,
+    /// Time a client has to send its request head; zero disables it
+    pub client_request_timeout: Duration,
+    /// Idle time before a kept-alive connection is closed; zero disables keep-alive
+    pub keep_alive: Duration,
+    /// Bind with `SO_REUSEPORT`, so a new gateway process can listen on the
+    /// port while the old one is still serving
+    pub reuse_port: bool,
+    /// How long a stopping gateway keeps accepting after it reports not ready
+    pub drain_delay: Duration,
+    /// How long in-flight requests get once it stops accepting
+    pub shutdown_timeout: Duration,
+    /// Whether to listen on `PORT`; off leaves the unix socket only
+    pub tcp: bool,
+    /// Unix socket path to listen on as well, e.g. for a sidecar proxy
+    pub unix_socket: Option<String>,
+    /// Permissions for the socket file, e.g. `0o660`; the umask decides when unset
+    pub unix_socket_mode: Option<u32>,
+}
+
+impl ListenerConfig {
+    /// Read `SERVER_WORKERS` (one per available CPU by default),
+    /// `SERVER_MAX_CONNECTIONS`, `SERVER_MAX_CONNECTION_RATE`,
+    /// `SERVER_BACKLOG`, `SERVER_CLIENT_REQUEST_TIMEOUT_MS`,
+    /// `SERVER_KEEP_ALIVE_SECONDS`, `LISTEN_REUSE_PORT`,
+    /// `SHUTDOWN_DRAIN_SECONDS` (0 by default), `SHUTDOWN_TIMEOUT_SECONDS`
+    /// (30 by default), `LISTEN_TCP`, `LISTEN_UNIX_SOCKET` and
+    /// `LISTEN_UNIX_SOCKET_MODE` (octal, like `660`).
+    pub fn from_env() -> Self {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let unix_socket = env::var("LISTEN_UNIX_SOCKET").ok().filter(|path| !path.is_empty());
+        if unix_socket.is_some() && cfg!(not(unix)) {
+            invalid("LISTEN_UNIX_SOCKET", "unix sockets are only supported on Unix");
+        }
+        let unix_socket_mode = env::var("LISTEN_UNIX_SOCKET_MODE").ok().filter(|mode| !mode.is_empty()).and_then(|mode| {
+            match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+                Ok(mode) if mode <= 0o777 => Some(mode),
+                _ => {
+                    invalid("LISTEN_UNIX_SOCKET_MODE", format!("'{}' is not an octal file mode", mode));
+                    None
+                }
+            }
+        });
+        let mut tcp = env::var("LISTEN_TCP").map(|v| v != "false" && v != "0").unwrap_or(true);
+        if !tcp && unix_socket.is_none() {
+            invalid("LISTEN_TCP", "turning TCP off needs LISTEN_UNIX_SOCKET to listen on instead");
+            tcp = true;
+        }
+        ListenerConfig {
+            workers: parse_env("SERVER_WORKERS").filter(|workers| *workers > 0).unwrap_or(cpus),
+            max_connections: parse_env("SERVER_MAX_CONNECTIONS").filter(|max| *max > 0).unwrap_or(25_000),
+            max_connection_rate: parse_env("SERVER_MAX_CONNECTION_RATE").filter(|max| *max > 0).unwrap_or(256),
+            backlog: parse_env("SERVER_BACKLOG").filter(|backlog| *backlog > 0).unwrap_or(2048),
+            client_request_timeout: Duration::from_millis(parse_env("SERVER_CLIENT_REQUEST_TIMEOUT_MS").unwrap_or(5_000)),
+            // Longer than the usual 60s load balancer idle timeout, so the
+            // balancer rather than the gateway closes idle connections
+            keep_alive: Duration::from_secs(parse_env("SERVER_KEEP_ALIVE_SECONDS").unwrap_or(75)),
+            reuse_port: env::var("LISTEN_REUSE_PORT").map(|v| v == "true" || v == "1").unwrap_or(false),
+            drain_delay: Duration::from_secs(parse_env("SHUTDOWN_DRAIN_SECONDS").unwrap_or(0)),
+            shutdown_timeout: Duration::from_secs(parse_env("SHUTDOWN_TIMEOUT_SECONDS").unwrap_or(30)),
+            tcp,
+            unix_socket,
+            unix_socket_mode,
+        }
+    }
+}
+
+/// Bind the gateway's port with the configured backlog, shared with other
+/// processes that set `SO_REUSEPORT` too when `reuse_port` is on.
+pub fn bind(addr: SocketAddr, config: &ListenerConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if config.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        warn!("LISTEN_REUSE_PORT is only supported on Unix; binding {} exclusively", addr);
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Listen on a unix socket at `path`, replacing a socket left there by an
+/// earlier run, with the file given `mode` when set. A socket something
+/// still listens on, or a file that is not a socket, is left alone.
+#[cfg(unix)]
+pub fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+
+    let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) && UnixStream::connect(path).is_err();
+    if stale {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+async fn stop_requested() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => "SIGTERM",
+                    _ = tokio::signal::ctrl_c() => "SIGINT",
+                }
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM, stopping on Ctrl-C only: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+/// Stop on SIGTERM or Ctrl-C without dropping requests: report not ready,
+/// keep serving for the drain delay while the load balancer and the process
+/// replacing this one take over, then stop accepting and give in-flight
+/// requests the shutdown timeout to finish.
+pub async fn drain_on_signal(data: web::Data<AppState>, server: ServerHandle) {
+    let signal = stop_requested().await;
+    let drain_delay = data.config.load().listener.drain_delay;
+    data.readiness.start_draining();
+    systemd::notify(&format!("STOPPING=1\nSTATUS=Draining after {}", signal));
+    info!("{} received, draining for {:?} before stopping", signal, drain_delay);
+    tokio::time::sleep(drain_delay).await;
+    info!("Stopping, waiting for {} in-flight request(s)", data.inflight.list().len());
+    server.stop(true).await;
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::path::PathBuf;
+
+    // A path of its own for each test, removed again when dropped
+    struct SocketPath(PathBuf);
+
+    impl SocketPath {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("gateway-{}-{}.sock", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            SocketPath(path)
+        }
+
+        fn as_str(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for SocketPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn gives_the_socket_the_configured_mode() {
+        for mode in [0o600, 0o660, 0o666] {
+            let path = SocketPath::new(&format!("mode-{:o}", mode));
+            let _listener = bind_unix(path.as_str(), Some(mode)).unwrap();
+            let meta = fs::metadata(&path.0).unwrap();
+            assert!(meta.file_type().is_socket());
+            assert_eq!(meta.permissions().mode() & 0o777, mode);
+        }
+    }
+
+    #[test]
+    fn replaces_a_stale_socket() {
+        let path = SocketPath::new("stale");
+        drop(bind_unix(path.as_str(), None).unwrap());
+        assert!(fs::symlink_metadata(&path.0).is_ok(), "a closed listener leaves its socket file");
+        let listener = bind_unix(path.as_str(), None).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&path.0).is_ok());
+        drop(listener);
+    }
+
+    #[test]
+    fn leaves_a_socket_in_use_alone() {
+        let path = SocketPath::new("live");
+        let _listener = bind_unix(path.as_str(), None).unwrap();
+        let refused = bind_unix(path.as_str(), None).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::AddrInUse);
+        assert!(std::os::unix::net::UnixStream::connect(&path.0).is_ok());
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let path = SocketPath::new("regular");
+        fs::write(&path.0, "not a socket").unwrap();
+        assert!(bind_unix(path.as_str(), None).is_err());
+        assert_eq!(fs::read_to_string(&path.0).unwrap(), "not a socket");
+    }
+}
@@ -1,30 +1,422 @@
-use log::{LevelFilter, Metadata, Record};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use log::LevelFilter;
+use serde_json::{Map, Value};
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{OnceLock, RwLock};
+use tracing::field::{self, Field, Visit};
+use tracing::span::{self, Attributes, Id};
+use tracing::{Event, Instrument, Span, Subscriber};
+use tracing_log::{AsLog, LogTracer, NormalizeEvent};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, FilterExt};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{reload, Layer, Registry};
 
-pub struct GatewayLogger;
+use crate::config::invalid;
 
-impl log::Log for GatewayLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= LevelFilter::Info
+/// Longest request id taken from `X-Request-Id`; longer or unprintable ids
+/// are replaced by the trace id.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `<time> [LEVEL] file:line spans - message fields`, for reading locally
+    Text,
+    /// One JSON object per line, for the log pipeline
+    Json,
+}
+
+impl LogFormat {
+    /// Read `LOG_FORMAT` (`text` or `json`, default `text`).
+    pub fn from_env() -> Self {
+        match env::var("LOG_FORMAT").map(|v| v.to_lowercase()).as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("text") | Ok("") | Err(_) => LogFormat::Text,
+            Ok(other) => {
+                eprintln!("Unknown LOG_FORMAT {}, logging as text", other);
+                invalid("LOG_FORMAT", format!("'{}' is not one of text, json", other));
+                LogFormat::Text
+            }
+        }
+    }
+}
+
+/// Levels a log line must reach to be written: a default, and overrides for
+/// module paths written `info,gateway_service::auth=debug,actix_web=warn`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    pub default: LevelFilter,
+    /// Module path prefixes and their level; the longest match applies
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = parse_level(level)?;
+                    filter.modules.retain(|(m, _)| m != module.trim());
+                    filter.modules.push((module.trim().to_string(), level));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    // Most verbose level any module logs at
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+
+    /// The filter in the `LOG_LEVEL` syntax.
+    pub fn spec(&self) -> String {
+        let mut directives = vec![self.default.as_str().to_lowercase()];
+        directives.extend(self.modules.iter().map(|(module, level)| format!("{}={}", module, level.as_str().to_lowercase())));
+        directives.join(",")
+    }
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.trim().parse().map_err(|_| format!("unknown log level '{}'", level.trim()))
+}
+
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+    default: LevelFilter::Info,
+    modules: Vec::new(),
+});
+
+/// The filter log lines currently pass through.
+pub fn filter() -> LogFilter {
+    FILTER.read().unwrap().clone()
+}
+
+// Handle swapping the `EnvFilter` of the installed subscriber
+static RELOAD: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Swap the filter at runtime, e.g. to turn on debug logging mid-incident.
+pub fn set_filter(filter: LogFilter) {
+    log::set_max_level(filter.max());
+    if let Some(handle) = RELOAD.get() {
+        if let Err(e) = handle.reload(EnvFilter::new(filter.spec())) {
+            eprintln!("Could not swap the log filter: {}", e);
+        }
+    }
+    *FILTER.write().unwrap() = filter;
+}
+
+// Request id, also kept on the request for the access log
+#[derive(Clone)]
+struct RequestId(String);
+
+/// Id the log lines of a request are tagged with.
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+/// Attach the authenticated user to the request span, and so to every later
+/// log line of the request.
+pub fn set_user(user: &str, username: &str) {
+    let span = Span::current();
+    span.record("user_id", user);
+    span.record("username", username);
+}
+
+// Fields recorded on a span, kept in its registry extensions
+struct SpanFields(Map<String, Value>);
+
+// Fields of a span or event; an event's `message` is kept apart, and the
+// `log.*` fields `tracing-log` adds to bridged records are dropped
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Map<String, Value>,
+    message: Option<String>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "message" => self.message = Some(plain(&value)),
+            name if name.starts_with("log.") => {}
+            name => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::String(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+}
+
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Names and fields of the spans a line was written in, outermost first
+type SpanContext = Vec<(&'static str, Map<String, Value>)>;
+
+// One log line, from a tracing event or a bridged `log` record
+struct Line<'a> {
+    metadata: &'a tracing::Metadata<'a>,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+/// Layer writing events as text or JSON lines, tagged with the fields of the
+/// spans they happen in.
+pub struct GatewayLayer {
+    format: LogFormat,
+}
+
+impl GatewayLayer {
+    // `<time> [LEVEL] file:line span{a=1}:child{b=2} - message key=value`
+    fn text(line: Line, context: SpanContext) -> String {
+        let mut text = format!(
+            "{} [{}] {}:{}",
+            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"),
+            line.metadata.level(),
+            line.metadata.file().unwrap_or("unknown"),
+            line.metadata.line().unwrap_or(0)
+        );
+        let spans: Vec<String> = context
+            .iter()
+            .map(|(name, fields)| match fields.is_empty() {
+                true => name.to_string(),
+                false => {
+                    let fields: Vec<String> = fields.iter().map(|(key, value)| format!("{}={}", key, plain(value))).collect();
+                    format!("{}{{{}}}", name, fields.join(" "))
+                }
+            })
+            .collect();
+        if !spans.is_empty() {
+            text.push(' ');
+            text.push_str(&spans.join(":"));
+        }
+        text.push_str(" - ");
+        text.push_str(&line.message);
+        for (key, value) in &line.fields {
+            text.push_str(&format!(" {}={}", key, plain(value)));
+        }
+        text
+    }
+
+    // Request, user and span fields are lifted out of the spans; the event's
+    // own fields win over them
+    fn json(line: Line, context: SpanContext) -> String {
+        let mut fields = Map::new();
+        let mut spans = Vec::new();
+        for (name, span_fields) in context {
+            spans.push(name);
+            fields.extend(span_fields);
+        }
+        let request_id = fields.remove("request_id");
+        let user_id = fields.remove("user_id");
+        let username = fields.remove("username");
+        fields.extend(line.fields);
+        if let Some(file) = line.metadata.file() {
+            fields.insert("file".to_string(), Value::from(file));
+        }
+        if let Some(number) = line.metadata.line() {
+            fields.insert("line".to_string(), Value::from(number));
+        }
+        serde_json::json!({
+            "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "level": line.metadata.level().as_log().as_str(),
+            "target": line.metadata.target(),
+            "request_id": request_id,
+            "user_id": user_id,
+            "username": username,
+            "spans": spans,
+            "msg": line.message,
+            "fields": fields,
+        })
+        .to_string()
+    }
+}
+
+impl<S> Layer<S> for GatewayLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
     }
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!(
-                "{} [{}] {}:{} - {}",
-                chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                record.args()
-            );
+    fn on_record(&self, id: &Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.extend(visitor.fields);
+            }
         }
     }
 
-    fn flush(&self) {}
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let context = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| {
+                        let fields = span.extensions().get::<SpanFields>().map(|fields| fields.0.clone());
+                        (span.name(), fields.unwrap_or_default())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Records bridged from `log` carry their real target and call site here
+        let normalized = event.normalized_metadata();
+        let line = Line {
+            metadata: normalized.as_ref().unwrap_or_else(|| event.metadata()),
+            message: visitor.message.unwrap_or_default(),
+            fields: visitor.fields,
+        };
+        match self.format {
+            LogFormat::Json => println!("{}", Self::json(line, context)),
+            LogFormat::Text => println!("{}", Self::text(line, context)),
+        }
+    }
 }
 
+/// Install the tracing subscriber, and the bridge turning `log` records from
+/// actix and other dependencies into events, with the filter from
+/// `LOG_LEVEL` (or `RUST_LOG`), `info` by default.
 pub fn setup_logging() {
-    let logger = GatewayLogger;
-    log::set_boxed_logger(Box::new(logger)).unwrap();
-    log::set_max_level(LevelFilter::Info);
-}
\ No newline at end of file
+    // Spans always pass, so that lines let through later still carry their
+    // request; events go through the `EnvFilter` `set_filter` swaps
+    let (env_filter, handle) = reload::Layer::new(EnvFilter::new(filter().spec()));
+    let _ = RELOAD.set(handle);
+    let layer = GatewayLayer { format: LogFormat::from_env() }.with_filter(filter_fn(|metadata| metadata.is_span()).or(env_filter));
+    tracing::subscriber::set_global_default(Registry::default().with(layer)).unwrap();
+    LogTracer::init().unwrap();
+    let spec = env::var("LOG_LEVEL").or_else(|_| env::var("RUST_LOG")).unwrap_or_default();
+    let filter = LogFilter::parse(&spec).unwrap_or_else(|e| {
+        eprintln!("Invalid LOG_LEVEL ({}), logging at info", e);
+        invalid("LOG_LEVEL", e);
+        filter()
+    });
+    set_filter(filter);
+}
+
+/// Middleware opening the `request` span every log line written while serving
+/// a request falls in: its `X-Request-Id`, or the trace id when the client
+/// sent none, method and path, and the user once authenticated. Must run
+/// inside `Tracing`.
+pub struct RequestContext;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestContext
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestContextMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestContextMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestContextMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestContextMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let request_id = req
+            .headers()
+            .get("X-Request-Id")
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_graphic()))
+            .map(str::to_string)
+            .or_else(|| crate::telemetry::trace_id(req.request()));
+        if let Some(id) = &request_id {
+            req.extensions_mut().insert(RequestId(id.clone()));
+        }
+        let span = tracing::info_span!(
+            "request",
+            request_id = request_id.as_deref(),
+            method = %req.method(),
+            path = req.path(),
+            user_id = field::Empty,
+            username = field::Empty,
+        );
+
+        // Inner middleware log synchronously in `call`, so it runs in the span too
+        Box::pin(async move { service.call(req).await }.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_carry_over_to_env_filter() {
+        let filter = LogFilter::parse("warn, gateway_service::auth=debug,actix_web=error").unwrap();
+        assert_eq!(filter.spec(), "warn,gateway_service::auth=debug,actix_web=error");
+        let env_filter = EnvFilter::try_new(filter.spec()).unwrap();
+        assert_eq!(env_filter.max_level_hint(), Some(tracing::level_filters::LevelFilter::DEBUG));
+        assert!(LogFilter::parse("gateway_service=loud").is_err());
+    }
+}
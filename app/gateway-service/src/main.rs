@@ -1,22 +1,171 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Result, middleware, HttpRequest};
+use actix_web::{web, App, HttpServer, HttpResponse, Result, middleware, HttpMessage, HttpRequest};
+use actix_web::http::KeepAlive;
 use serde::{Serialize};
 use serde_json::Value;
 use reqwest::Client;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use log::{info, error};
+use tracing::{debug, field, info, warn, error, Instrument};
 use std::env;
 
 mod auth;
 mod error;
 mod validation;
 mod logging;
+mod metrics;
+mod ws;
+mod circuit;
+mod fallback;
+mod bandwidth;
+mod upstream;
+mod hedge;
+mod cors;
+mod outlier;
+mod health;
+mod server_timing;
+mod alerts;
+mod probes;
+mod shedding;
+mod cidr;
+mod audit;
+mod exemptions;
+mod admin;
+mod retry;
+mod inflight;
+mod queue;
+mod overrides;
+mod failover;
+mod events;
+mod readiness;
+mod faults;
+mod debug;
+mod refresh;
+mod revocation;
+mod redis;
+mod jwks;
+mod oidc;
+mod apikeys;
+mod rbac;
+mod scopes;
+mod identity;
+mod csrf;
+mod session;
+mod secrets;
+mod ratelimit;
+mod ipfilter;
+mod webhooks;
+mod mfa;
+mod moderation;
+mod waf;
+mod introspection;
+mod policies;
+mod guest;
+mod password;
+mod media_urls;
+mod fieldcrypt;
+mod origins;
+mod jwe;
+mod devices;
+mod replay;
+mod exchange;
+mod telemetry;
+mod accesslog;
+mod status;
+mod capture;
+mod config;
+mod reload;
+mod routes;
+mod profile;
+mod flags;
+mod maintenance;
+mod listener;
+mod discovery;
+mod kubernetes;
+mod tls;
+mod redirect;
+mod selfcheck;
+mod systemd;
+mod cache;
+mod openapi;
+mod versioning;
+mod overview;
+#[cfg(feature = "graphql")]
+mod graphql;
 
-use auth::AuthMiddleware;
+use auth::{AuthMiddleware, Claims};
 use error::ApiError;
-use validation::{validate_input, AuthRequest};
-use logging::setup_logging;
+use validation::{validate_input, AuthRequest, ChangePasswordRequest, CreateUserRequest};
+use logging::{setup_logging, RequestContext};
+use metrics::{Metrics, MetricsConfig, MetricsExporter, RequestMetrics, LATENCY_BUCKETS, SIZE_BUCKETS};
+use ws::WsConfig;
+use circuit::{CircuitBreakers, CircuitConfig};
+use fallback::FallbackTable;
+use bandwidth::{BandwidthConfig, BandwidthLimiter};
+use upstream::Upstreams;
+use hedge::HedgeConfig;
+use cors::{Cors, CorsPolicies};
+use outlier::{OutlierConfig, OutlierDetector};
+use health::HealthConfig;
+use server_timing::ServerTiming;
+use alerts::{AlertConfig, Alerter};
+use probes::ProbeConfig;
+use shedding::{AdmissionControl, LoadShedder, SheddingConfig};
+use audit::{AuditConfig, AuditLog};
+use exemptions::ExemptionRegistry;
+use retry::RetryConfig;
+use inflight::{InflightRegistry, InflightTracker};
+use queue::{QueueConfig, UpstreamQueues};
+use overrides::UpstreamOverrides;
+use failover::{Failover, FailoverConfig};
+use readiness::{Readiness, ReadinessConfig};
+use faults::FaultInjector;
+use refresh::{RefreshConfig, RefreshTokens};
+use revocation::{RevocationConfig, RevocationStore};
+use jwks::{JwksConfig, JwksKeys};
+use oidc::{OidcConfig, OidcLogins};
+use apikeys::{ApiKeyConfig, ApiKeyRegistry};
+use rbac::{RoleGuard, RolePolicies};
+use policies::RoutePolicies;
+use guest::GuestConfig;
+use scopes::ScopePolicies;
+use identity::{IdentityConfig, StripIdentityHeaders};
+use csrf::{CsrfConfig, CsrfGuard};
+use session::SessionConfig;
+use secrets::{Secrets, SecretsConfig};
+use ratelimit::{RateLimit, RateLimitConfig, RateLimiter};
+use ipfilter::{IpFilter, IpFilterConfig, IpGuard};
+use webhooks::{ReplayCache, WebhookConfig};
+use mfa::{MfaChallenges, MfaConfig};
+use moderation::{Moderation, ModerationConfig};
+use waf::{Waf, WafConfig, WafGuard};
+use admin::AdminConfig;
+use password::PasswordConfig;
+use media_urls::{MediaUrlConfig, SignedMediaUrls};
+use fieldcrypt::{FieldEncryption, FieldEncryptionConfig};
+use origins::TrustedOrigins;
+use jwe::{Jwe, JweConfig};
+use devices::DeviceSessions;
+use replay::OneTimeConfig;
+use exchange::TokenExchangeConfig;
+use telemetry::{ClientSpan, TelemetryConfig, Tracer, Tracing};
+use accesslog::{request_logger, AccessLog, AccessLogConfig, AccessLogTarget, AccessLogger};
+use status::StatusFeed;
+use capture::{BodyCapture, BodyCaptureConfig};
+use config::{parse_env, ConfigFile, Live};
+use routes::{RouteTable, BUILTIN_SERVICES};
+use profile::{HttpsOnly, Profile};
+use flags::{FeatureFlagConfig, FeatureFlags};
+use maintenance::{Maintenance, MaintenanceGuard};
+use listener::ListenerConfig;
+use discovery::{Discovered, DiscoveryConfig};
+use redirect::HttpsRedirect;
+use tls::TlsConfig;
+use cache::{CacheConfig, CachePolicy, Lookup, ResponseCache};
+use versioning::{ApiVersioning, VersioningConfig};
+use overview::OverviewConfig;
+#[cfg(feature = "graphql")]
+use graphql::GraphqlConfig;
 
 // Configuration structure
 #[derive(Debug, Clone)]
@@ -24,23 +173,276 @@ struct Config {
     user_service_url: String,
     chat_service_url: String,
     message_service_url: String,
+    media_service_url: String,
+    /// Further services and the path prefixes they serve
+    routes: RouteTable,
     port: u16,
+    ws: WsConfig,
+    circuit: CircuitConfig,
+    fallbacks: FallbackTable,
+    /// GET responses served from memory and what purges them
+    cache: CacheConfig,
+    bandwidth: BandwidthConfig,
+    hedging: HedgeConfig,
+    outlier: OutlierConfig,
+    health: HealthConfig,
+    server_timing: bool,
+    /// Opens the debug endpoints without admin credentials
+    dev_mode: bool,
+    /// Deployment profile from `GATEWAY_ENV`; `production` refuses unsafe defaults
+    profile: Profile,
+    alerts: AlertConfig,
+    probes: ProbeConfig,
+    shedding: SheddingConfig,
+    audit: AuditConfig,
+    retry: RetryConfig,
+    queues: QueueConfig,
+    failover: FailoverConfig,
+    readiness: ReadinessConfig,
+    refresh: RefreshConfig,
+    revocation: RevocationConfig,
+    jwks: JwksConfig,
+    oidc: OidcConfig,
+    api_keys: ApiKeyConfig,
+    role_policies: RolePolicies,
+    route_policies: RoutePolicies,
+    scope_policies: ScopePolicies,
+    identity: IdentityConfig,
+    csrf: CsrfConfig,
+    session: SessionConfig,
+    secrets: SecretsConfig,
+    rate_limits: RateLimitConfig,
+    ip_filter: IpFilterConfig,
+    webhooks: WebhookConfig,
+    mfa: MfaConfig,
+    guest: GuestConfig,
+    moderation: ModerationConfig,
+    waf: WafConfig,
+    admin: AdminConfig,
+    password: PasswordConfig,
+    media_urls: MediaUrlConfig,
+    field_encryption: FieldEncryptionConfig,
+    origins: TrustedOrigins,
+    jwe: JweConfig,
+    one_time: OneTimeConfig,
+    token_exchange: TokenExchangeConfig,
+    telemetry: TelemetryConfig,
+    metrics: MetricsConfig,
+    access_log: AccessLogConfig,
+    body_capture: BodyCaptureConfig,
+    feature_flags: FeatureFlagConfig,
+    listener: ListenerConfig,
+    /// Services whose instances are read from Consul or etcd
+    discovery: DiscoveryConfig,
+    /// HTTPS listener, with the plain one redirecting to it
+    tls: TlsConfig,
+    /// What `/api/v1` and `/api/v2` serve, and when v1 goes away
+    versioning: VersioningConfig,
+    /// Room details aggregated from the chat and message services
+    overview: OverviewConfig,
+    #[cfg(feature = "graphql")]
+    graphql: GraphqlConfig,
+}
+
+impl Config {
+    fn from_env() -> Result<Self, String> {
+        let message_service_url = env::var("MESSAGE_SERVICE_URL").unwrap_or("http://message-service:3003".to_string());
+        let routes = RouteTable::from_env();
+        let services: Vec<String> = BUILTIN_SERVICES
+            .iter()
+            .map(|service| service.to_string())
+            .chain(routes.services().into_iter().map(|(service, _)| service))
+            .collect();
+        let services: Vec<&str> = services.iter().map(String::as_str).collect();
+        let mut route_policies = RoutePolicies::from_env();
+        route_policies.policies.extend(routes.policies());
+        let config = Config {
+            user_service_url: env::var("USER_SERVICE_URL").unwrap_or("http://user-service:3001".to_string()),
+            chat_service_url: env::var("CHAT_SERVICE_URL").unwrap_or("http://chat-service:3002".to_string()),
+            media_service_url: env::var("MEDIA_SERVICE_URL").unwrap_or(message_service_url.clone()),
+            message_service_url,
+            routes,
+            port: parse_env("PORT").unwrap_or(8000),
+            ws: WsConfig::from_env(),
+            circuit: CircuitConfig::from_env(),
+            fallbacks: FallbackTable::from_env(),
+            cache: CacheConfig::from_env(),
+            bandwidth: BandwidthConfig::from_env(),
+            hedging: HedgeConfig::from_env(),
+            outlier: OutlierConfig::from_env(),
+            health: HealthConfig::from_env(),
+            server_timing: env::var("SERVER_TIMING_ENABLED").map(|v| v == "true" || v == "1").unwrap_or(false),
+            dev_mode: env::var("GATEWAY_DEV_MODE").map(|v| v == "true" || v == "1").unwrap_or(false),
+            profile: Profile::from_env(),
+            alerts: AlertConfig::from_env(),
+            probes: ProbeConfig::from_env(),
+            shedding: SheddingConfig::from_env(),
+            audit: AuditConfig::from_env(),
+            retry: RetryConfig::from_env(),
+            queues: QueueConfig::from_env(&services),
+            failover: FailoverConfig::from_env(&services),
+            readiness: ReadinessConfig::from_env(),
+            refresh: RefreshConfig::from_env(),
+            revocation: RevocationConfig::from_env(),
+            jwks: JwksConfig::from_env(),
+            oidc: OidcConfig::from_env(),
+            api_keys: ApiKeyConfig::from_env(),
+            role_policies: RolePolicies::from_env(),
+            route_policies,
+            scope_policies: ScopePolicies::from_env(),
+            identity: IdentityConfig::from_env(),
+            csrf: CsrfConfig::from_env(),
+            session: SessionConfig::from_env(),
+            secrets: SecretsConfig::from_env(),
+            rate_limits: RateLimitConfig::from_env(),
+            ip_filter: IpFilterConfig::from_env(),
+            webhooks: WebhookConfig::from_env(),
+            mfa: MfaConfig::from_env(),
+            guest: GuestConfig::from_env(),
+            moderation: ModerationConfig::from_env(),
+            waf: WafConfig::from_env(),
+            admin: AdminConfig::from_env().map_err(|e| format!("admin API: {}", e))?,
+            password: PasswordConfig::from_env(),
+            media_urls: MediaUrlConfig::from_env(),
+            field_encryption: FieldEncryptionConfig::from_env(),
+            origins: TrustedOrigins::from_env(),
+            jwe: JweConfig::from_env(),
+            one_time: OneTimeConfig::from_env(),
+            token_exchange: TokenExchangeConfig::from_env(),
+            telemetry: TelemetryConfig::from_env(),
+            metrics: MetricsConfig::from_env(),
+            access_log: AccessLogConfig::from_env(),
+            body_capture: BodyCaptureConfig::from_env(),
+            feature_flags: FeatureFlagConfig::from_env(),
+            listener: ListenerConfig::from_env(),
+            discovery: DiscoveryConfig::from_env(),
+            tls: TlsConfig::from_env(),
+            versioning: VersioningConfig::from_env(),
+            overview: OverviewConfig::from_env(),
+            #[cfg(feature = "graphql")]
+            graphql: GraphqlConfig::from_env(),
+        };
+        config::check(config.validate())?;
+        Ok(config)
+    }
+
+    // Problems only visible across settings
+    fn validate(&self) -> Vec<String> {
+        let mut problems = self.routes.validate();
+        problems.extend(self.cache.validate());
+        problems.extend(self.versioning.validate());
+        problems.extend(self.discovery.validate(&self.upstream_services()));
+        for (service, urls) in self.upstream_services() {
+            for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    problems.push(format!("{} upstream: '{}' is not an http(s) URL", service, url));
+                }
+            }
+        }
+        if self.profile.is_production() {
+            if self.dev_mode {
+                problems.push("GATEWAY_DEV_MODE: debug endpoints cannot be opened in production".to_string());
+            }
+            // Production serves HTTPS only, which the gateway can only tell
+            // from its own HTTPS listener or a TLS proxy it trusts; unix
+            // socket peers always are
+            if self.ip_filter.trusted_proxies.is_empty() && self.listener.tcp && !self.tls.enabled() {
+                problems.push("TRUSTED_PROXIES: production needs the TLS-terminating proxy listed".to_string());
+            }
+            // Environments often share signing keys; only `iss` and `aud`
+            // keep a staging token out of production
+            if self.jwks.issuer.is_none() || self.jwks.audiences.is_empty() {
+                problems.push("JWT_ISSUER, JWT_AUDIENCE: production needs both to refuse tokens from other environments".to_string());
+            }
+        }
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            problems.push("TLS_CERT_PATH, TLS_KEY_PATH: set both to serve HTTPS, or neither".to_string());
+        }
+        if self.tls.enabled() && self.listener.tcp && self.tls.port == self.port {
+            problems.push("HTTPS_PORT: must differ from PORT, which serves plain HTTP".to_string());
+        }
+        problems
+    }
+
+    // Upstream services and the base URLs of their instances, standbys included
+    fn upstream_services(&self) -> Vec<(String, String)> {
+        let mut services = vec![
+            ("user".to_string(), self.user_service_url.clone()),
+            ("chat".to_string(), self.chat_service_url.clone()),
+            ("message".to_string(), self.message_service_url.clone()),
+            ("media".to_string(), self.media_service_url.clone()),
+        ];
+        services.extend(self.routes.services());
+        services.extend(
+            self.failover
+                .standby_urls
+                .iter()
+                .map(|(service, url)| (failover::standby_name(service), url.clone())),
+        );
+        services
+    }
 }
 
 // Service health status
 #[derive(Debug, Serialize, Clone)]
-struct ServiceStatus {
+pub(crate) struct ServiceStatus {
     name: String,
     url: String,
     status: String,
     last_checked: String,
+    /// Response time of the check, or time until it failed
+    latency_ms: u64,
+    /// Checks failed in a row, reset by a healthy one
+    consecutive_failures: u32,
+    /// Recent background checks, oldest first
+    history: Vec<health::HealthCheck>,
 }
 
 // Gateway state
 struct AppState {
-    config: Config,
+    /// Swapped by a configuration reload
+    config: Live<Config>,
     http_client: Client,
     service_statuses: Arc<RwLock<HashMap<String, ServiceStatus>>>,
+    metrics: Arc<Metrics>,
+    circuits: CircuitBreakers,
+    bandwidth: BandwidthLimiter,
+    /// Rebuilt when a reload or service discovery changes upstream URLs
+    upstreams: Live<Upstreams>,
+    discovery: Discovered,
+    outliers: OutlierDetector,
+    alerter: Alerter,
+    admission: Arc<AdmissionControl>,
+    audit: AuditLog,
+    exemptions: ExemptionRegistry,
+    inflight: Arc<InflightRegistry>,
+    queues: UpstreamQueues,
+    overrides: UpstreamOverrides,
+    failover: Failover,
+    readiness: Readiness,
+    faults: FaultInjector,
+    refresh: RefreshTokens,
+    revocation: RevocationStore,
+    jwks: JwksKeys,
+    oidc: OidcLogins,
+    api_keys: ApiKeyRegistry,
+    secrets: Arc<Secrets>,
+    rate_limiter: RateLimiter,
+    ip_filter: IpFilter,
+    webhook_replays: ReplayCache,
+    mfa: MfaChallenges,
+    moderation: Moderation,
+    waf: Waf,
+    field_crypto: FieldEncryption,
+    jwe: Arc<Jwe>,
+    devices: DeviceSessions,
+    tracer: Arc<Tracer>,
+    access_log: Arc<AccessLogger>,
+    status: Arc<StatusFeed>,
+    body_capture: BodyCapture,
+    flags: FeatureFlags,
+    maintenance: Maintenance,
+    response_cache: ResponseCache,
 }
 
 // Health check response
@@ -52,73 +454,354 @@ struct HealthResponse {
     timestamp: String,
 }
 
-// Proxy function to forward requests to microservices
+// Proxy function to forward requests to microservices, answering GETs on
+// cached routes from memory
 async fn proxy_request(
-    client: &Client,
-    service_url: &str,
+    data: &AppState,
+    req: &HttpRequest,
+    service: &str,
+    path: &str,
+    method: &str,
+    body: Option<Value>,
+) -> Result<HttpResponse> {
+    let policy = match method {
+        "GET" => data.config.load().cache.policy(req.path()).cloned(),
+        _ => None,
+    };
+    let (policy, key) = match policy.and_then(|policy| policy.key(req).map(|key| (policy, key))) {
+        Some(cached) => cached,
+        None => return forward(data, req, service, path, method, body, None).await,
+    };
+    // Trusted clients may ask for a fresh answer, e.g. for a resource just
+    // created after it was cached as missing
+    let lookup = match data.config.load().cache.bypass(req, data.ip_filter.client_ip(req)) {
+        true => {
+            data.response_cache.record(&policy.path, "bypass");
+            Lookup::Miss
+        }
+        false => data.response_cache.get(&policy.path, &key),
+    };
+    match lookup {
+        Lookup::Fresh(hit) => Ok(hit.to_response()),
+        Lookup::Stale { hit, refresh } => {
+            if refresh {
+                revalidate(req, service, path, key, policy);
+            }
+            Ok(hit.to_response())
+        }
+        Lookup::Miss => forward(data, req, service, path, method, body, Some((key, policy))).await,
+    }
+}
+
+// Fetch a fresh copy of a stale cached response off the request path
+fn revalidate(req: &HttpRequest, service: &str, path: &str, key: String, policy: CachePolicy) {
+    let data = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.clone(),
+        None => return,
+    };
+    let (req, service, path) = (req.clone(), service.to_string(), path.to_string());
+    actix_web::rt::spawn(async move {
+        debug!("Refreshing stale cached response for {}", key);
+        let refreshed = forward(&data, &req, &service, &path, "GET", None, Some((key.clone(), policy))).await;
+        if !matches!(&refreshed, Ok(response) if response.status() == actix_web::http::StatusCode::OK) {
+            data.response_cache.refresh_failed(&key);
+        }
+    });
+}
+
+// Send a request to the upstream, keeping a successful response under `cache`
+async fn forward(
+    data: &AppState,
+    req: &HttpRequest,
+    service: &str,
     path: &str,
     method: &str,
     body: Option<Value>,
+    cache: Option<(String, CachePolicy)>,
 ) -> Result<HttpResponse> {
-    let url = format!("{}{}", service_url, path);
+    let route = req.path();
+    let client = &data.http_client;
+    // The standby upstream while the primary is failed over
+    let target = data.failover.route(data, service).await;
+    let upstreams = data.upstreams.load();
+    let upstream = match upstreams.get(&target) {
+        Some(upstream) => upstream,
+        None => return Ok(fallback_response(data, method, route, "Unknown upstream service")),
+    };
+    let availability = health::availability(data, upstream).await;
+    if availability.all_down(upstream) {
+        warn!("All {} instances failed health checks, not proxying {} {}", service, method, path);
+        data.metrics.incr("gateway_fail_fast_total", &[("service", service)], 1);
+        return Ok(fallback_response(data, method, route, "Service failed health checks"));
+    }
+    let instance = availability.choose(upstream);
+    let url = format!("{}{}", instance, path);
+    
+    // Dropped without an outcome on the early returns below
+    let permit = match data.circuits.allow(&target) {
+        Some(permit) => permit,
+        None => {
+            warn!("Circuit open for {} service, not proxying {} {}", service, method, url);
+            return Ok(fallback_response(data, method, route, "Circuit open"));
+        }
+    };
+    
+    if !matches!(method, "GET" | "POST" | "PUT" | "DELETE") {
+        return Ok(HttpResponse::MethodNotAllowed().finish());
+    }
+    
+    // Held until the upstream answers so queued requests wait their turn
+    let _slot = match data.queues.acquire(service).await {
+        Ok(slot) => slot,
+        Err(rejection) => return Ok(fallback_response(data, method, route, rejection.message())),
+    };
+    
+    if let Some(response) = faults::inject(data, service).await {
+        warn!("Injected fault into {} {} for {} service", method, path, service);
+        return Ok(response);
+    }
     
     info!("Proxying {} request to: {}", method, url);
     
-    let response = match method {
-        "GET" => client.get(&url).send().await,
-        "POST" => {
-            if let Some(json_body) = body {
-                client.post(&url).json(&json_body).send().await
-            } else {
-                client.post(&url).send().await
-            }
-        },
-        "PUT" => {
-            if let Some(json_body) = body {
-                client.put(&url).json(&json_body).send().await
+    let body_sha256 = body.as_ref().and_then(|json_body| data.audit.body_digest(json_body));
+    let capture = data.body_capture.start(method, route);
+    if let Some(capture) = &capture {
+        capture.request(body.as_ref());
+    }
+
+    // Sensitive fields leave the gateway encrypted
+    let mut body = body;
+    if let Some(json_body) = body.as_mut() {
+        if let Err(e) = data.field_crypto.encrypt(method, route, json_body) {
+            error!("Field encryption failed for {} {}: {}", method, route, e);
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to encrypt request fields"
+            })));
+        }
+    }
+    
+    // Serialized once so retries and hedges resend the same bytes and the
+    // payload size is known
+    let payload = match (&body, method) {
+        (Some(json_body), "POST" | "PUT") => Some(serde_json::to_vec(json_body)?),
+        _ => None,
+    };
+    let pattern = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    let size_labels = [("service", service), ("route", pattern.as_str())];
+    if let Some(bytes) = &payload {
+        data.metrics
+            .observe_in("gateway_upstream_request_size_bytes", &size_labels, bytes.len() as f64, SIZE_BUCKETS);
+    }
+    
+    let identity_headers = identity::headers(data, req, service);
+    let timeout = data.config.load().routes.timeout(service);
+    // One span covers every attempt, retries and hedges included
+    let span = ClientSpan::start(&data.tracer, req, service, method, &url);
+    let traceparent = span.traceparent();
+    let build = |base: &str| {
+        let url = format!("{}{}", base, path);
+        let mut request = match method {
+            "GET" => client.get(&url),
+            "POST" => client.post(&url),
+            "PUT" => client.put(&url),
+            _ => client.delete(&url),
+        };
+        for (name, value) in &identity_headers {
+            request = request.header(*name, value);
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        request = request.header("traceparent", &traceparent);
+        match &payload {
+            Some(bytes) => request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(bytes.clone()),
+            None => request,
+        }
+    };
+    
+    let mut instance = instance;
+    let mut attempt = 0;
+    let sent = std::time::Instant::now();
+    let upstream_span = tracing::info_span!(
+        "upstream",
+        service,
+        method,
+        instance = field::Empty,
+        attempt = field::Empty,
+    );
+    let response = async {
+        loop {
+            inflight::set_upstream(req, service, &instance);
+            let started = std::time::Instant::now();
+            let (used, response) = if data.config.load().hedging.applies(method, route) {
+                let available = |i: &str| availability.allows(i);
+                hedge::send_hedged(&data.config.load().hedging, upstream, &instance, available, build, &data.metrics).await
             } else {
-                client.put(&url).send().await
+                (instance.as_str(), build(&instance).send().await)
+            };
+            let used = used.to_string();
+            let current = tracing::Span::current();
+            current.record("instance", used.as_str());
+            current.record("attempt", attempt + 1);
+            let succeeded = matches!(&response, Ok(resp) if !resp.status().is_server_error());
+            data.outliers.record(upstream, &used, succeeded, started.elapsed());
+            record_upstream_call(data, service, started.elapsed(), &response);
+            server_timing::record(req, service, started.elapsed());
+        
+            let (status, headers) = match &response {
+                Ok(resp) => (Some(resp.status()), Some(resp.headers())),
+                Err(_) => (None, None),
+            };
+            match data.config.load().retry.next_delay(method, attempt, status, headers) {
+                Some(delay) => {
+                    let reason = status.map(|s| s.as_u16().to_string()).unwrap_or_else(|| "error".to_string());
+                    info!("Retrying {} {} in {:?} after {}", method, path, delay, reason);
+                    data.metrics.incr("gateway_upstream_retries_total", &[("service", service), ("reason", &reason)], 1);
+                    tokio::time::sleep(delay).await;
+                    instance = upstream.pick_other(&used, |i| availability.allows(i)).map(str::to_string).unwrap_or(used);
+                    attempt += 1;
+                }
+                None => break response,
             }
-        },
-        "DELETE" => client.delete(&url).send().await,
-        _ => return Ok(HttpResponse::MethodNotAllowed().finish()),
-    };
+        }
+    }
+    .instrument(upstream_span)
+    .await;
+    let status = response.as_ref().ok().map(|resp| resp.status().as_u16());
+    span.finish(status);
+    if matches!(method, "POST" | "PUT" | "DELETE") {
+        let actor = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+        let upstream = inflight::upstream(req);
+        let request_id = logging::request_id(req);
+        data.audit.record_mutation(
+            actor.as_deref().unwrap_or("anonymous"),
+            audit::Mutation {
+                method,
+                route: &pattern,
+                path: route,
+                service,
+                instance: upstream.as_ref().map(|(_, instance)| instance.as_str()),
+                status,
+                latency: sent.elapsed(),
+                body_sha256,
+                request_id: request_id.as_deref(),
+            },
+        );
+    }
 
     match response {
         Ok(resp) => {
             let status = resp.status();
-            let json_response: Value = resp.json().await.unwrap_or(Value::Null);
+            if status.is_server_error() {
+                permit.failure();
+            } else {
+                permit.success();
+            }
+            let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER).cloned();
+            let bytes = resp.bytes().await.unwrap_or_default();
+            data.metrics
+                .observe_in("gateway_upstream_response_size_bytes", &size_labels, bytes.len() as f64, SIZE_BUCKETS);
+            let mut json_response: Value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+            data.field_crypto.decrypt(method, route, &mut json_response);
+            if let Some(capture) = &capture {
+                capture.response(status.as_u16(), &json_response);
+            }
+            if let Some((key, policy)) = cache {
+                data.response_cache.put(key, &policy, route, status.as_u16(), &json_response);
+            }
+            if status.is_success() && method != "GET" {
+                data.response_cache.invalidate(&data.config.load().cache, method, route);
+            }
             
-            Ok(HttpResponse::build(status).json(json_response))
+            let mut response = HttpResponse::build(status);
+            if let Some(value) = retry_after {
+                response.insert_header(("Retry-After", value.as_bytes()));
+            }
+            Ok(response.json(json_response))
         }
         Err(e) => {
             error!("Proxy request failed: {}", e);
-            Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
-                "error": "Service temporarily unavailable",
-                "details": e.to_string()
-            })))
+            permit.failure();
+            Ok(fallback_response(data, method, route, &e.to_string()))
         }
     }
 }
 
+// Time one upstream call, count its status class, and count it as an error
+// when it failed or got a 5xx
+fn record_upstream_call(data: &AppState, service: &str, elapsed: std::time::Duration, result: &reqwest::Result<reqwest::Response>) {
+    data.metrics.observe_in(
+        "gateway_upstream_request_duration_seconds",
+        &[("service", service)],
+        elapsed.as_secs_f64(),
+        LATENCY_BUCKETS,
+    );
+    let class = match result {
+        Ok(resp) => match resp.status().as_u16() / 100 {
+            1 => "1xx",
+            2 => "2xx",
+            3 => "3xx",
+            4 => "4xx",
+            _ => "5xx",
+        },
+        Err(_) => "error",
+    };
+    data.metrics.incr("gateway_upstream_responses_total", &[("service", service), ("class", class)], 1);
+    data.alerter.record(service, matches!(class, "5xx" | "error"));
+    let kind = match result {
+        Ok(resp) if resp.status().is_server_error() => "server_error",
+        Ok(_) => return,
+        Err(e) if e.is_timeout() => "timeout",
+        Err(e) if e.is_connect() => "connect",
+        Err(_) => "transport",
+    };
+    data.metrics.incr("gateway_upstream_errors_total", &[("service", service), ("kind", kind)], 1);
+}
+
+// Serve the configured fallback for a route, or a generic 503 when none is set
+fn fallback_response(data: &AppState, method: &str, route: &str, details: &str) -> HttpResponse {
+    match data.config.load().fallbacks.lookup(method, route) {
+        Some(fallback) => {
+            info!("Serving fallback response for {} {}", method, route);
+            fallback.to_response()
+        }
+        // The cause names upstream hosts and errors, shown in development only
+        None if data.config.load().profile.verbose_errors() => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Service temporarily unavailable",
+            "details": details
+        })),
+        None => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Service temporarily unavailable"
+        })),
+    }
+}
+
 // Health check endpoint
 async fn health_check(data: web::Data<AppState>) -> Result<HttpResponse> {
     let mut statuses = Vec::new();
     
-    // Check user service
-    let user_status = check_service_health(&data.http_client, &data.config.user_service_url, "User Service").await;
-    statuses.push(user_status);
-    
-    // Check chat service
-    let chat_status = check_service_health(&data.http_client, &data.config.chat_service_url, "Chat Service").await;
-    statuses.push(chat_status);
-    
-    // Check message service
-    let message_status = check_service_health(&data.http_client, &data.config.message_service_url, "Message Service").await;
-    statuses.push(message_status);
+    // Every instance of the user, chat and message services, as last seen by
+    // the background poller; checked live only when polling is off
+    let cached = data.service_statuses.read().await.clone();
+    let polling = !data.config.load().health.interval.is_zero();
+    for (service, name) in [("user", "User Service"), ("chat", "Chat Service"), ("message", "Message Service")] {
+        if let Some(upstream) = data.upstreams.load().get(service) {
+            for instance in upstream.instances() {
+                let status = match cached.get(instance) {
+                    Some(status) => status.clone(),
+                    None if polling => health::unchecked(instance, name),
+                    None => check_service_health(&data.http_client, instance, name).await,
+                };
+                statuses.push(status);
+            }
+        }
+    }
+    let degraded = statuses.iter().any(|status| status.status == "unhealthy");
     
     let response = HealthResponse {
-        status: "healthy".to_string(),
+        status: if degraded { "degraded" } else { "healthy" }.to_string(),
         version: "1.0.0".to_string(),
         services: statuses,
         timestamp: chrono::Utc::now().to_rfc3339(),
@@ -128,25 +811,22 @@ async fn health_check(data: web::Data<AppState>) -> Result<HttpResponse> {
 }
 
 // Check individual service health
-async fn check_service_health(client: &Client, url: &str, name: &str) -> ServiceStatus {
+pub(crate) async fn check_service_health(client: &Client, url: &str, name: &str) -> ServiceStatus {
     let health_url = format!("{}/", url.trim_end_matches('/'));
     
-    match client.get(&health_url).timeout(std::time::Duration::from_secs(5)).send().await {
-        Ok(response) => {
-            let status = if response.status().is_success() { "healthy" } else { "unhealthy" };
-            ServiceStatus {
-                name: name.to_string(),
-                url: url.to_string(),
-                status: status.to_string(),
-                last_checked: chrono::Utc::now().to_rfc3339(),
-            }
-        }
-        Err(_) => ServiceStatus {
-            name: name.to_string(),
-            url: url.to_string(),
-            status: "unhealthy".to_string(),
-            last_checked: chrono::Utc::now().to_rfc3339(),
-        }
+    let started = std::time::Instant::now();
+    let healthy = match client.get(&health_url).timeout(std::time::Duration::from_secs(5)).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(_) => false,
+    };
+    ServiceStatus {
+        name: name.to_string(),
+        url: url.to_string(),
+        status: if healthy { "healthy" } else { "unhealthy" }.to_string(),
+        last_checked: chrono::Utc::now().to_rfc3339(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        consecutive_failures: if healthy { 0 } else { 1 },
+        history: Vec::new(),
     }
 }
 
@@ -158,6 +838,11 @@ async fn index() -> Result<HttpResponse> {
         "description": "API Gateway for Chat Application Microservices",
         "endpoints": {
             "health": "/health",
+            "ready": "/health/ready",
+            "metrics": "/metrics",
+            "openapi": "/openapi.json",
+            "websocket": "/ws/{room_id}",
+            "media": "/media/*",
             "auth": "/api/auth/*",
             "users": "/api/users/*",
             "chat": "/api/chat/*",
@@ -168,6 +853,7 @@ async fn index() -> Result<HttpResponse> {
 
 // Auth endpoints with validation
 async fn validated_auth_handler(
+    req: HttpRequest,
     path: web::Path<(String,)>,
     payload: web::Json<Value>,
     data: web::Data<AppState>,
@@ -177,17 +863,55 @@ async fn validated_auth_handler(
     // Extract the JSON value once
     let json_value = payload.into_inner();
     
+    // Scopes a login asks its tokens to be limited to
+    let mut requested_scope = None;
+    
     // Validate based on endpoint
     match endpoint.as_str() {
         "login" | "register" => {
-            let auth_request: AuthRequest = serde_json::from_value(json_value.clone())
-                .map_err(|_| ApiError::bad_request("Invalid request format"))?;
+            if endpoint == "register" {
+                let create_request: CreateUserRequest = serde_json::from_value(json_value.clone())
+                    .map_err(|_| ApiError::bad_request("Invalid request format"))?;
+                
+                if let Err(errors) = validate_input(&create_request) {
+                    let mut fields: Vec<_> = errors.field_errors().into_keys().collect();
+                    fields.sort();
+                    return Err(ApiError::bad_request(&format!("Validation failed: invalid {}", fields.join(", "))));
+                }
+                let user_inputs = [create_request.username.as_str(), create_request.email.as_str()];
+                if let Err(response) = data.config.load().password.check(&create_request.password, &user_inputs) {
+                    return Ok(response);
+                }
+            } else {
+                let auth_request: AuthRequest = serde_json::from_value(json_value.clone())
+                    .map_err(|_| ApiError::bad_request("Invalid request format"))?;
+                
+                validate_input(&auth_request)
+                    .map_err(|_| ApiError::bad_request("Validation failed"))?;
+                if !data.config.load().password.admits_login(&auth_request.password) {
+                    return Err(ApiError::bad_request("Validation failed"));
+                }
+            }
             
-            validate_input(&auth_request)
-                .map_err(|_| ApiError::bad_request("Validation failed"))?;
+            if let Some(scope) = json_value.get("scope").and_then(Value::as_str) {
+                if !data.refresh.enabled() {
+                    return Err(ApiError::bad_request("Scoped tokens require gateway-issued tokens"));
+                }
+                requested_scope = scopes::parse_requested(&data.config.load().scope_policies, scope)
+                    .map_err(|e| ApiError::bad_request(&e))?;
+            }
             
             info!("Validated auth request for endpoint: {}", endpoint);
         }
+        "logout" => {
+            if let Err(response) = revocation::logout(&req, &data, &json_value).await {
+                return Ok(response);
+            }
+        }
+        // The gateway rotates its own refresh tokens when it issues them
+        "refresh" if data.refresh.enabled() => {
+            return Ok(session::attach(&data, refresh::refresh(&data, &json_value)).await);
+        }
         _ => {
             // For other auth endpoints, basic validation
             info!("Processing auth request for endpoint: {}", endpoint);
@@ -198,12 +922,26 @@ async fn validated_auth_handler(
     
     // Convert Result<HttpResponse, ApiError> to Result<HttpResponse>
     match proxy_request(
-        &data.http_client,
-        &data.config.user_service_url,
+        &data,
+        &req,
+        "user",
         &service_path,
         "POST",
         Some(json_value)
     ).await {
+        Ok(response) if matches!(endpoint.as_str(), "login" | "register") => {
+            let response = match endpoint.as_str() {
+                "login" => match mfa::on_login(&data, response, requested_scope.clone()).await {
+                    mfa::LoginStep::Challenge(challenge) => return Ok(challenge),
+                    mfa::LoginStep::Continue(response) => response,
+                },
+                _ => response,
+            };
+            let response = refresh::on_login(&data, &req, response, requested_scope).await;
+            Ok(session::attach(&data, response).await)
+        }
+        Ok(response) if endpoint == "refresh" => Ok(session::attach(&data, response).await),
+        Ok(response) if endpoint == "logout" => Ok(session::clear(&data, response)),
         Ok(response) => Ok(response),
         Err(_) => Err(ApiError::service_unavailable("User service unavailable"))
     }
@@ -222,9 +960,34 @@ async fn users_handler(
     
     let body = payload.map(|p| p.into_inner());
     
+    if endpoint == "change-password" && method == "PUT" {
+        let change = body
+            .clone()
+            .and_then(|body| serde_json::from_value::<ChangePasswordRequest>(body).ok());
+        let change = match change {
+            Some(change) => change,
+            None => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Current and new passwords required"
+                })))
+            }
+        };
+        // The account's own name may not be part of its password
+        let username = AuthMiddleware::validate_token(&req).await.map(|claims| claims.username).unwrap_or_default();
+        if let Err(response) = data.config.load().password.check(&change.new_password, &[username.as_str()]) {
+            return Ok(response);
+        }
+        if change.new_password == change.current_password {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "New password must differ from the current one"
+            })));
+        }
+    }
+    
     proxy_request(
-        &data.http_client,
-        &data.config.user_service_url,
+        &data,
+        &req,
+        "user",
         &service_path,
         method,
         body
@@ -232,6 +995,7 @@ async fn users_handler(
 }
 
 // Chat endpoints
+#[allow(dead_code)]
 async fn chat_handler(
     req: HttpRequest,
     path: web::Path<(String,)>,
@@ -245,8 +1009,9 @@ async fn chat_handler(
     let body = payload.map(|p| p.into_inner());
     
     proxy_request(
-        &data.http_client,
-        &data.config.chat_service_url,
+        &data,
+        &req,
+        "chat",
         &service_path,
         method,
         body
@@ -254,6 +1019,7 @@ async fn chat_handler(
 }
 
 // Messages endpoints
+#[allow(dead_code)]
 async fn messages_handler(
     req: HttpRequest,
     path: web::Path<(String,)>,
@@ -267,138 +1033,581 @@ async fn messages_handler(
     let body = payload.map(|p| p.into_inner());
     
     proxy_request(
-        &data.http_client,
-        &data.config.message_service_url,
+        &data,
+        &req,
+        "message",
         &service_path,
         method,
         body
     ).await
 }
 
-// Authenticated chat endpoints (require JWT token)
+// Chat endpoints; the route policies decide which need a token
 async fn authenticated_chat_handler(
     req: HttpRequest,
     path: web::Path<(String,)>,
     payload: Option<web::Json<Value>>,
     data: web::Data<AppState>,
+    claims: Option<Claims>,
 ) -> Result<HttpResponse> {
-    // Validate JWT token
-    match AuthMiddleware::validate_token(&req) {
-        Ok(claims) => {
-            info!("Authenticated user: {} accessing chat endpoint", claims.username);
-            
-            let (endpoint,) = path.into_inner();
-            let service_path = format!("/{}", endpoint);
-            let method = req.method().as_str();
-            
-            let body = payload.map(|p| p.into_inner());
-            
-            proxy_request(
-                &data.http_client,
-                &data.config.chat_service_url,
-                &service_path,
-                method,
-                body
-            ).await
-        }
-        Err(error_response) => Ok(error_response)
+    if let Some(claims) = &claims {
+        info!("Authenticated user: {} accessing chat endpoint", claims.username);
     }
+    
+    let (endpoint,) = path.into_inner();
+    let service_path = format!("/{}", endpoint);
+    let method = req.method().as_str();
+    
+    let body = payload.map(|p| p.into_inner());
+    
+    proxy_request(
+        &data,
+        &req,
+        "chat",
+        &service_path,
+        method,
+        body
+    ).await
 }
 
-// Authenticated messages endpoints (require JWT token)
+// Messages endpoints; the route policies decide which need a token
 async fn authenticated_messages_handler(
     req: HttpRequest,
     path: web::Path<(String,)>,
     payload: Option<web::Json<Value>>,
     data: web::Data<AppState>,
+    claims: Option<Claims>,
 ) -> Result<HttpResponse> {
-    // Validate JWT token
-    match AuthMiddleware::validate_token(&req) {
-        Ok(claims) => {
-            info!("Authenticated user: {} accessing messages endpoint", claims.username);
-            
-            let (endpoint,) = path.into_inner();
-            let service_path = format!("/{}", endpoint);
-            let method = req.method().as_str();
+    if let Some(claims) = &claims {
+        info!("Authenticated user: {} accessing messages endpoint", claims.username);
+    }
+    let sender = claims.as_ref().map_or("anonymous", |claims| claims.sub.as_str());
+    
+    let (endpoint,) = path.into_inner();
+    let service_path = format!("/{}", endpoint);
+    let method = req.method().as_str();
+    
+    let mut body = payload.map(|p| p.into_inner());
+    let moderated = data.moderation.applies(method, req.path()) && flags::enabled(&data, "moderation", Some(sender), true);
+    if let Some(message) = body.take_if(|_| moderated) {
+        match data.moderation.check(&data, sender, message).await {
+            moderation::Verdict::Deliver(message) => body = Some(message),
+            moderation::Verdict::Reject(response) => return Ok(response),
+        }
+    }
+    
+    proxy_request(
+        &data,
+        &req,
+        "message",
+        &service_path,
+        method,
+        body
+    ).await
+}
+
+// Media downloads streamed from the media service (token checked by AuthMiddleware)
+async fn media_handler(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    data: web::Data<AppState>,
+    claims: Claims,
+) -> Result<HttpResponse> {
+    
+    let (media_path,) = path.into_inner();
+    let target = data.failover.route(&data, "media").await;
+    let upstreams = data.upstreams.load();
+    let upstream = match upstreams.get(&target) {
+        Some(upstream) => upstream,
+        None => return Ok(fallback_response(&data, "GET", req.path(), "Unknown upstream service")),
+    };
+    let availability = health::availability(&data, upstream).await;
+    if availability.all_down(upstream) {
+        data.metrics.incr("gateway_fail_fast_total", &[("service", "media")], 1);
+        return Ok(fallback_response(&data, "GET", req.path(), "Service failed health checks"));
+    }
+    let instance = availability.choose(upstream);
+    inflight::set_upstream(&req, "media", &instance);
+    let mut url = format!("{}/media/{}", instance, media_path);
+    let query = media_urls::upstream_query(req.query_string());
+    if !query.is_empty() {
+        url = format!("{}?{}", url, query);
+    }
+    
+    let permit = match data.circuits.allow(&target) {
+        Some(permit) => permit,
+        None => return Ok(fallback_response(&data, "GET", req.path(), "Circuit open")),
+    };
+    
+    info!("Streaming media download for user {} from: {}", claims.username, url);
+    
+    let started = std::time::Instant::now();
+    let span = ClientSpan::start(&data.tracer, &req, "media", "GET", &url);
+    let mut request = data.http_client.get(&url).header("traceparent", span.traceparent());
+    for (name, value) in identity::headers(&data, &req, "media") {
+        request = request.header(name, value);
+    }
+    let result = request.send().await;
+    span.finish(result.as_ref().ok().map(|resp| resp.status().as_u16()));
+    let succeeded = matches!(&result, Ok(resp) if !resp.status().is_server_error());
+    data.outliers.record(upstream, &instance, succeeded, started.elapsed());
+    record_upstream_call(&data, "media", started.elapsed(), &result);
+    server_timing::record(&req, "media", started.elapsed());
+    
+    match result {
+        Ok(resp) => {
+            let status = resp.status();
+            if status.is_server_error() {
+                permit.failure();
+            } else {
+                permit.success();
+            }
             
-            let body = payload.map(|p| p.into_inner());
+            let mut response = HttpResponse::build(status);
+            for header in ["content-type", "content-length", "content-disposition", "etag", "last-modified", "cache-control"] {
+                if let Some(value) = resp.headers().get(header) {
+                    response.insert_header((header, value.as_bytes()));
+                }
+            }
             
-            proxy_request(
-                &data.http_client,
-                &data.config.message_service_url,
-                &service_path,
-                method,
-                body
-            ).await
+            let exemption = exemptions::resolve(&data, &req, Some(&claims.sub));
+            Ok(response.streaming(data.bandwidth.throttle(&claims.sub, exemption, resp)))
+        }
+        Err(e) => {
+            error!("Media download failed: {}", e);
+            permit.failure();
+            Ok(fallback_response(&data, "GET", req.path(), &e.to_string()))
         }
-        Err(error_response) => Ok(error_response)
     }
 }
 
+// WebSocket endpoint proxied to the chat service (token checked by AuthMiddleware)
+async fn websocket_handler(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+    claims: Claims,
+) -> Result<HttpResponse> {
+    if let Err(response) = data.config.load().origins.check_ws(&req) {
+        data.metrics.incr("gateway_ws_origin_rejections_total", &[], 1);
+        return Ok(response);
+    }
+    info!("Authenticated user: {} opening chat WebSocket", claims.username);
+    
+    let (room_id,) = path.into_inner();
+    let target = data.failover.route(&data, "chat").await;
+    let upstreams = data.upstreams.load();
+    let upstream = match upstreams.get(&target) {
+        Some(upstream) => upstream,
+        None => return Ok(HttpResponse::ServiceUnavailable().finish()),
+    };
+    let availability = health::availability(&data, upstream).await;
+    if availability.all_down(upstream) {
+        data.metrics.incr("gateway_fail_fast_total", &[("service", "chat")], 1);
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Service temporarily unavailable",
+            "details": "Service failed health checks"
+        })));
+    }
+    let instance = availability.choose(upstream);
+    inflight::set_upstream(&req, "chat", &instance);
+    let upstream_url = format!("{}/ws/{}/{}", instance, room_id, claims.sub);
+    
+    // The span covers the upstream handshake, not the connection's lifetime
+    let span = ClientSpan::start(&data.tracer, &req, "chat", "GET", &upstream_url);
+    let mut headers = identity::headers(&data, &req, "chat");
+    headers.push(("traceparent", span.traceparent()));
+    let response = ws::proxy(&req, payload, &upstream_url, &headers, &data.config.load().ws, data.metrics.clone()).await;
+    span.finish(response.as_ref().ok().map(|resp| resp.status().as_u16()));
+    response
+}
+
+// Endpoints the gateway serves itself and the built-in services it proxies;
+// `openapi` documents them
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/", web::get().to(index))
+        .route("/health", web::get().to(health_check))
+        .route("/health/ready", web::get().to(readiness::ready_handler))
+        .route("/metrics", web::get().to(metrics::scrape))
+        .route("/openapi.json", web::get().to(openapi::spec))
+        .route("/docs", web::get().to(openapi::docs))
+        .route("/ws/{room_id}", web::get().to(websocket_handler))
+        .route("/api/media/sign", web::post().to(media_urls::sign))
+        .route("/media/{path:.*}", web::get().to(media_handler))
+        .route("/api/debug/echo", web::to(debug::echo))
+        .route("/webhooks/{integration}", web::post().to(webhooks::receive))
+        .route("/internal/auth/introspect", web::post().to(introspection::introspect))
+        .configure(admin::routes)
+        // Auth routes (validated)
+        .service(
+            web::scope("/api/auth")
+                .route("/introspect", web::get().to(debug::introspect))
+                .route("/introspect", web::post().to(debug::introspect))
+                .route("/oidc/login", web::get().to(oidc::login))
+                .route("/oidc/callback", web::get().to(oidc::callback))
+                .route("/csrf", web::get().to(csrf::token))
+                .route("/mfa/verify", web::post().to(mfa::verify))
+                .route("/guest", web::post().to(guest::issue))
+                .route("/sessions", web::get().to(devices::list))
+                .route("/sessions/{id}", web::delete().to(devices::revoke))
+                .route("/{endpoint}", web::post().to(validated_auth_handler))
+        )
+        // User routes
+        .service(
+            web::scope("/api/users")
+                .route("/{endpoint:.*}", web::get().to(users_handler))
+                .route("/{endpoint:.*}", web::post().to(users_handler))
+                .route("/{endpoint:.*}", web::put().to(users_handler))
+                .route("/{endpoint:.*}", web::delete().to(users_handler))
+        )
+        // Chat routes (authenticated)
+        .service(
+            web::scope("/api/chat")
+                .route("/rooms/{room_id}/overview", web::get().to(overview::room))
+                .route("/{endpoint:.*}", web::get().to(authenticated_chat_handler))
+                .route("/{endpoint:.*}", web::post().to(authenticated_chat_handler))
+                .route("/{endpoint:.*}", web::put().to(authenticated_chat_handler))
+                .route("/{endpoint:.*}", web::delete().to(authenticated_chat_handler))
+        )
+        // Messages routes (authenticated)
+        .service(
+            web::scope("/api/messages")
+                .route("/{endpoint:.*}", web::get().to(authenticated_messages_handler))
+                .route("/{endpoint:.*}", web::post().to(authenticated_messages_handler))
+                .route("/{endpoint:.*}", web::put().to(authenticated_messages_handler))
+                .route("/{endpoint:.*}", web::delete().to(authenticated_messages_handler))
+        );
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // File settings sit under the environment, so they have to be in place
+    // before anything reads it, logging included
+    let config_file = ConfigFile::from_env().map_err(|e| {
+        eprintln!("Invalid configuration file: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let overridden = config_file.as_ref().map(ConfigFile::apply).unwrap_or_default();
     setup_logging();
+    if let Some(file) = &config_file {
+        info!("Loaded {} settings from {}", file.settings.len(), file.path);
+        if !overridden.is_empty() {
+            info!("Environment overrides {} from {}", overridden.join(", "), file.path);
+        }
+    }
+    
+    // Offline audit trail verification: `gateway-service audit verify <file>`
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() >= 2 && args[0] == "audit" && args[1] == "verify" {
+        std::process::exit(audit::verify_command(&args[2..]));
+    }
+    // Smoke test for CI: run the startup checks and exit with their outcome
+    let self_test = args.iter().any(|arg| arg == "--self-test");
     
     // Load configuration from environment
-    let config = Config {
-        user_service_url: env::var("USER_SERVICE_URL").unwrap_or("http://user-service:3001".to_string()),
-        chat_service_url: env::var("CHAT_SERVICE_URL").unwrap_or("http://chat-service:3002".to_string()),
-        message_service_url: env::var("MESSAGE_SERVICE_URL").unwrap_or("http://message-service:3003".to_string()),
-        port: env::var("PORT").unwrap_or("8000".to_string()).parse().unwrap_or(8000),
-    };
+    let config = Config::from_env().map_err(|e| {
+        error!("Invalid configuration: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
     
     info!("Starting Gateway Service with config: {:?}", config);
     
+    // Refuse to start rather than serve plain HTTP where HTTPS was configured
+    let (tls_config, certificates) = match config.tls.enabled() {
+        true => {
+            let (tls_config, certificates) = config.tls.server_config().map_err(|e| {
+                error!("Cannot serve HTTPS: {}", e);
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+            })?;
+            (Some(tls_config), Some(certificates))
+        }
+        false => (None, None),
+    };
+    
     let http_client = Client::builder()
         .timeout(std::time::Duration::from_secs(30))
         .build()
         .expect("Failed to create HTTP client");
     
+    let metrics = Arc::new(Metrics::with_exporter(&config.metrics));
+    
+    // Tokens cannot be verified without the signing secret, so a backend that
+    // cannot be read at startup is fatal rather than retried in the background
+    let secrets = Arc::new(Secrets::new(&config.secrets, http_client.clone(), metrics.clone()));
+    if let Err(e) = secrets.refresh().await {
+        error!("Failed to load secrets: {}", e);
+        return Err(std::io::Error::other(format!("failed to load secrets: {}", e)));
+    }
+    if secrets.uses_default() {
+        if config.profile.is_production() {
+            error!("Refusing to start in production with the default JWT secret; set JWT_SECRET or SECRETS_BACKEND");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "default JWT secret in production",
+            ));
+        }
+        warn!("Using the default JWT secret; set JWT_SECRET or SECRETS_BACKEND before deploying");
+    }
+    
+    // Starting without the lists would let denied clients in
+    let ip_filter = IpFilter::new(config.ip_filter.clone(), metrics.clone()).map_err(|e| {
+        error!("Failed to load IP filter rules: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    
+    let moderation = Moderation::new(config.moderation.clone(), metrics.clone()).map_err(|e| {
+        error!("Invalid moderation rules: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let waf = Waf::new(config.waf.clone(), metrics.clone()).map_err(|e| {
+        error!("Invalid WAF rules: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let jwe = Arc::new(Jwe::new(&config.jwe).map_err(|e| {
+        error!("Invalid JWE configuration: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?);
+    let field_crypto = FieldEncryption::new(config.field_encryption.clone(), metrics.clone()).map_err(|e| {
+        error!("Invalid field encryption keys: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let status = Arc::new(StatusFeed::new());
+    
     let app_state = AppState {
-        config: config.clone(),
-        http_client,
+        config: Live::new(config.clone()),
+        http_client: http_client.clone(),
         service_statuses: Arc::new(RwLock::new(HashMap::new())),
+        metrics: metrics.clone(),
+        circuits: CircuitBreakers::new(config.circuit.clone(), status.clone()),
+        bandwidth: BandwidthLimiter::new(config.bandwidth.clone(), metrics.clone()),
+        upstreams: Live::new(Upstreams::new(&config.upstream_services())),
+        discovery: Discovered::default(),
+        outliers: OutlierDetector::new(config.outlier.clone(), metrics.clone()),
+        alerter: Alerter::new(config.alerts.clone(), http_client.clone(), metrics.clone()),
+        admission: AdmissionControl::new(config.shedding.clone(), metrics.clone()),
+        audit: AuditLog::new(&config.audit),
+        exemptions: ExemptionRegistry::from_env(),
+        inflight: InflightRegistry::new(),
+        queues: UpstreamQueues::new(&config.queues, metrics.clone()),
+        overrides: UpstreamOverrides::from_env(),
+        failover: Failover::new(metrics.clone()),
+        readiness: Readiness::new(&config.readiness),
+        faults: FaultInjector::from_env(),
+        refresh: RefreshTokens::new(config.refresh.clone(), secrets.clone(), jwe.clone(), metrics.clone()),
+        revocation: RevocationStore::new(config.revocation.clone(), metrics.clone()),
+        jwks: JwksKeys::new(config.jwks.clone(), http_client.clone(), metrics.clone()),
+        oidc: OidcLogins::new(),
+        api_keys: ApiKeyRegistry::from_env(),
+        secrets,
+        rate_limiter: RateLimiter::new(&config.rate_limits, metrics.clone()),
+        ip_filter,
+        webhook_replays: ReplayCache::default(),
+        mfa: MfaChallenges::default(),
+        moderation,
+        waf,
+        field_crypto,
+        jwe,
+        devices: DeviceSessions::default(),
+        tracer: Arc::new(Tracer::new(config.telemetry.clone(), metrics.clone())),
+        access_log: Arc::new(AccessLogger::new(&config.access_log)),
+        status,
+        body_capture: BodyCapture::new(config.body_capture.clone()),
+        flags: FeatureFlags::new(config.feature_flags.clone(), http_client.clone(), metrics.clone()),
+        maintenance: Maintenance::default(),
+        response_cache: ResponseCache::new(&config.cache, metrics.clone()),
     };
     
+    app_state.metrics.describe("gateway_http_requests_total", "Requests served, by method, route pattern and status");
+    app_state.metrics.describe("gateway_http_request_duration_seconds", "Time to respond to a request, by method and route pattern");
+    app_state.metrics.describe("gateway_http_requests_in_flight", "Requests currently being served");
+    app_state.metrics.describe("gateway_upstream_request_duration_seconds", "Time for one upstream call to respond, by service");
+    app_state.metrics.describe("gateway_upstream_responses_total", "Upstream calls by service and status class (1xx-5xx, or error when no response)");
+    app_state.metrics.describe("gateway_upstream_errors_total", "Upstream calls that failed or answered 5xx, by service and kind");
+    app_state.metrics.describe("gateway_circuit_state", "Circuit breaker state per upstream service: 0 closed, 1 half-open, 2 open");
+    app_state.metrics.describe("gateway_ws_connections", "Open client WebSocket connections");
+    app_state.metrics.describe("gateway_ws_outbound_queue_depth", "Frames queued for delivery across all WebSocket clients");
+    app_state.metrics.describe("gateway_ws_outbound_dropped_frames_total", "Frames discarded because a client's outbound queue was full");
+    app_state.metrics.describe("gateway_ws_slow_consumer_disconnects_total", "WebSocket clients disconnected for falling too far behind");
+    app_state.metrics.describe("gateway_ws_events_total", "WebSocket events matching the event schema, by direction and type");
+    app_state.metrics.describe("gateway_ws_schema_mismatches_total", "WebSocket events not matching the event schema, by direction and reason");
+    app_state.metrics.describe("gateway_download_bytes_total", "Bytes streamed to clients from media downloads");
+    app_state.metrics.describe("gateway_download_throttle_waits_total", "Times a media download was paused by a bandwidth limit");
+    app_state.metrics.describe("gateway_hedged_requests_total", "Requests re-sent to a second instance after the hedge delay");
+    app_state.metrics.describe("gateway_hedge_wins_total", "Hedged requests where the second instance answered first");
+    app_state.metrics.describe("gateway_upstream_ejections_total", "Upstream instances ejected by outlier detection");
+    app_state.metrics.describe("gateway_upstream_ejected_instances", "Upstream instances currently ejected");
+    app_state.metrics.describe("gateway_fail_fast_total", "Requests rejected without a call because every instance failed health checks");
+    app_state.metrics.describe("gateway_probe_runs_total", "Synthetic probe runs by outcome");
+    app_state.metrics.describe("gateway_probe_duration_seconds", "Synthetic probe end-to-end duration");
+    app_state.metrics.describe("gateway_probe_up", "Whether the last run of a synthetic probe succeeded");
+    app_state.metrics.describe("gateway_shed_requests_total", "Requests rejected by admission control while overloaded");
+    app_state.metrics.describe("gateway_admission_in_flight", "Requests currently holding an admission slot");
+    app_state.metrics.describe("gateway_admission_queue_depth", "Requests waiting for an admission slot");
+    app_state.metrics.describe("gateway_upstream_retries_total", "Upstream calls retried, by the status that triggered the retry");
+    app_state.metrics.describe("gateway_upstream_request_size_bytes", "Size of JSON bodies sent to upstreams, by service and route");
+    app_state.metrics.describe("gateway_upstream_response_size_bytes", "Size of upstream response bodies, by service and route");
+    app_state.metrics.describe("gateway_upstream_queue_depth", "Requests waiting in a service's upstream queue");
+    app_state.metrics.describe("gateway_upstream_queue_wait_seconds", "Time requests spent waiting in an upstream queue");
+    app_state.metrics.describe("gateway_upstream_queue_rejections_total", "Requests turned away by an upstream queue, by reason");
+    app_state.metrics.describe("gateway_failovers_total", "Times a service failed over to its standby upstream");
+    app_state.metrics.describe("gateway_faults_injected_total", "Faults injected into proxied requests, by service and fault type");
+    app_state.metrics.describe("gateway_token_refreshes_total", "Gateway-issued refresh tokens, by outcome");
+    app_state.metrics.describe("gateway_tokens_revoked_total", "Access tokens revoked by logout");
+    app_state.metrics.describe("gateway_revocation_store_errors_total", "Token revocation checks that could not reach the store");
+    app_state.metrics.describe("gateway_revocation_checks_total", "Token and session revocation lookups, by outcome (active, revoked, error)");
+    app_state.metrics.describe("gateway_token_validation_seconds", "Time to validate a presented token, including key lookup and revocation, by outcome");
+    app_state.metrics.describe("gateway_auth_rejections_total", "Requests answered 401 or 403, by route pattern and status");
+    app_state.metrics.describe("gateway_jwks_refreshes_total", "JWKS key set fetches, by outcome");
+    app_state.metrics.describe("gateway_jwks_keys", "Signing keys currently loaded from JWKS");
+    app_state.metrics.describe("gateway_oidc_logins_total", "OIDC sign-ins completed at the callback, by provider and outcome");
+    app_state.metrics.describe("gateway_api_key_requests_total", "Requests authenticated with an API key, by outcome");
+    app_state.metrics.describe("gateway_role_denials_total", "Requests refused by a role policy, by policy path");
+    app_state.metrics.describe("gateway_spoofed_identity_headers_total", "Requests arriving with client-set identity headers, by action taken");
+    app_state.metrics.describe("gateway_csrf_rejections_total", "Cookie-authenticated writes refused by the CSRF check, by reason");
+    app_state.metrics.describe("gateway_secret_refreshes_total", "Reads of the JWT secret from the secrets backend, by backend and outcome");
+    app_state.metrics.describe("gateway_rate_limited_total", "Requests refused with 429 by a rate limit, by rule path and route pattern");
+    app_state.metrics.describe("gateway_rate_limit_store_errors_total", "Rate limit checks that fell back to memory because Redis failed");
+    app_state.metrics.describe("gateway_ip_denials_total", "Requests refused by the IP allow/deny lists or an operator ban, by list");
+    app_state.metrics.describe("gateway_webhooks_total", "Inbound webhook deliveries, by integration and outcome");
+    app_state.metrics.describe("gateway_mfa_challenges_total", "Logins held back pending a second factor");
+    app_state.metrics.describe("gateway_mfa_verifications_total", "MFA code checks, by outcome");
+    app_state.metrics.describe("gateway_moderation_actions_total", "Moderation rule matches on messages, by rule and action");
+    app_state.metrics.describe("gateway_moderation_errors_total", "Moderation filters that failed to evaluate a message");
+    app_state.metrics.describe("gateway_waf_matches_total", "Requests matching a WAF rule, by rule and mode");
+    app_state.metrics.describe("gateway_guest_tokens_issued_total", "Guest tokens minted for anonymous visitors");
+    app_state.metrics.describe("gateway_ws_origin_rejections_total", "WebSocket handshakes refused for an untrusted Origin");
+    app_state.metrics.describe("gateway_field_encryption_total", "Sensitive payload fields encrypted and decrypted, by operation");
+    app_state.metrics.describe("gateway_signed_media_urls_total", "Signed media URLs issued and presented, by outcome");
+    app_state.metrics.describe("gateway_device_sessions_total", "Device sessions started and signed out remotely, by event");
+    app_state.metrics.describe("gateway_one_time_tokens_total", "One-time tokens used up and replays refused, by type and outcome");
+    app_state.metrics.describe("gateway_token_exchanges_total", "Caller tokens exchanged for service-scoped internal tokens, by service and outcome");
+    app_state.metrics.describe("gateway_trace_spans_total", "Trace spans sent to the OTLP collector, by outcome (exported, failed)");
+    app_state.metrics.describe("gateway_alerts_total", "Error-rate and upstream-down alerts, by alert and outcome (sent or suppressed by cooldown)");
+    app_state.metrics.describe("gateway_status_stream_clients", "Open /admin/status/stream connections");
+    app_state.metrics.describe("gateway_introspections_total", "Token introspection requests from internal services, by client and outcome");
+    app_state.metrics.describe("gateway_failover_active", "Whether a service is currently served by its standby upstream");
+    app_state.metrics.describe("gateway_feature_flag_refreshes_total", "Feature flag reads from the flag provider, by source and outcome");
+    app_state.metrics.describe("gateway_discovery_lookups_total", "Service registry lookups, by service, backend and outcome");
+    app_state.metrics.describe("gateway_discovery_changes_total", "Times the registry changed a service's instances");
+    app_state.metrics.describe("gateway_discovered_instances", "Healthy instances the registry last listed for a service");
+    app_state.metrics.describe("gateway_cache_requests_total", "Cache lookups by region (caching policy path) and outcome: hit, stale, miss or bypass");
+    app_state.metrics.describe("gateway_cache_evictions_total", "Cached responses dropped, by region and reason");
+    app_state.metrics.describe("gateway_cache_entries", "Responses cached per region");
+    app_state.metrics.describe("gateway_cache_bytes", "Memory held by cached responses per region, in bytes");
+    app_state.metrics.describe("gateway_cache_max_bytes", "Memory all cached responses may hold together, in bytes");
+    app_state.metrics.describe("gateway_api_version_requests_total", "Requests to /api/v1 and /api/v2, by version");
+    
     let app_state_data = web::Data::new(app_state);
+    actix_web::rt::spawn(health::poll_upstreams(app_state_data.clone()));
+    actix_web::rt::spawn(readiness::wait_for_upstreams(app_state_data.clone()));
+    actix_web::rt::spawn(jwks::refresh_keys(app_state_data.clone()));
+    actix_web::rt::spawn(secrets::refresh_secrets(app_state_data.clone()));
+    actix_web::rt::spawn(ipfilter::watch_rules(app_state_data.clone()));
+    actix_web::rt::spawn(alerts::watch(app_state_data.clone()));
+    actix_web::rt::spawn(flags::refresh_flags(app_state_data.clone()));
+    actix_web::rt::spawn(reload::watch_file(app_state_data.clone()));
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload::on_hangup(app_state_data.clone()));
+    if config.metrics.exporter != MetricsExporter::Prometheus {
+        actix_web::rt::spawn(metrics::push_circuit_states(app_state_data.clone()));
+    }
+    if let Some(certificates) = certificates {
+        actix_web::rt::spawn(tls::watch_certificates(config.tls.clone(), certificates.clone()));
+        #[cfg(unix)]
+        actix_web::rt::spawn(tls::reload_on_hangup(config.tls.clone(), certificates));
+    }
+    probes::start(app_state_data.clone());
+    discovery::start(app_state_data.clone());
+    let cors_policies = Arc::new(CorsPolicies::from_env(&config.origins, config.profile));
+    let route_table = config.routes.clone();
+    // Settings read while building the components above
+    config::check(Vec::new()).map_err(|e| {
+        error!("Invalid configuration: {}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    if self_test {
+        let report = selfcheck::log_report(&app_state_data).await;
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+        std::process::exit(if report.ok { 0 } else { 1 });
+    }
+    let report_data = app_state_data.clone();
+    actix_web::rt::spawn(async move {
+        selfcheck::log_report(&report_data).await;
+    });
+    let server_config = &config.listener;
+    info!(
+        "Serving with {} workers, {} connections each, keep-alive {:?}",
+        server_config.workers, server_config.max_connections, server_config.keep_alive
+    );
+    let drain_data = app_state_data.clone();
+    let notify_data = app_state_data.clone();
+    let tracer = app_state_data.tracer.clone();
+    
+    #[cfg(feature = "graphql")]
+    let graphql_schema = web::Data::new(graphql::schema(&config.graphql, config.profile.is_production()));
     
-    HttpServer::new(move || {
-        App::new()
+    let mut server = HttpServer::new(move || {
+        let app = App::new()
             .app_data(app_state_data.clone())
-            .wrap(middleware::Logger::default())
-            .route("/", web::get().to(index))
-            .route("/health", web::get().to(health_check))
-            // Auth routes (validated)
-            .service(
-                web::scope("/api/auth")
-                    .route("/{endpoint}", web::post().to(validated_auth_handler))
-            )
-            // User routes
-            .service(
-                web::scope("/api/users")
-                    .route("/{endpoint}", web::get().to(users_handler))
-                    .route("/{endpoint}", web::post().to(users_handler))
-                    .route("/{endpoint}", web::put().to(users_handler))
-                    .route("/{endpoint}", web::delete().to(users_handler))
-            )
-            // Chat routes (authenticated)
-            .service(
-                web::scope("/api/chat")
-                    .route("/{endpoint}", web::get().to(authenticated_chat_handler))
-                    .route("/{endpoint}", web::post().to(authenticated_chat_handler))
-                    .route("/{endpoint}", web::put().to(authenticated_chat_handler))
-                    .route("/{endpoint}", web::delete().to(authenticated_chat_handler))
-            )
-            // Messages routes (authenticated)
-            .service(
-                web::scope("/api/messages")
-                    .route("/{endpoint}", web::get().to(authenticated_messages_handler))
-                    .route("/{endpoint}", web::post().to(authenticated_messages_handler))
-                    .route("/{endpoint}", web::put().to(authenticated_messages_handler))
-                    .route("/{endpoint}", web::delete().to(authenticated_messages_handler))
-            )
+            .wrap(AuthMiddleware)
+            .wrap(SignedMediaUrls)
+            .wrap(RoleGuard)
+            .wrap(StripIdentityHeaders)
+            .wrap(CsrfGuard)
+            .wrap(WafGuard)
+            .wrap(RateLimit)
+            .wrap(IpGuard)
+            .wrap(MaintenanceGuard)
+            .wrap(HttpsOnly)
+            .wrap(HttpsRedirect)
+            .wrap(middleware::Condition::new(config.access_log.target == AccessLogTarget::App, request_logger()))
+            .wrap(middleware::Condition::new(config.server_timing, ServerTiming))
+            .wrap(InflightTracker::new(app_state_data.inflight.clone()))
+            .wrap(LoadShedder::new(app_state_data.admission.clone()))
+            .wrap(RequestMetrics::new(app_state_data.metrics.clone()))
+            .wrap(AccessLog::new(app_state_data.access_log.clone()))
+            .wrap(RequestContext)
+            .wrap(Tracing::new(app_state_data.tracer.clone()))
+            .wrap(Cors::new(cors_policies.clone()))
+            .wrap(ApiVersioning)
+            .configure(routes);
+        #[cfg(feature = "graphql")]
+        let app = app.configure(graphql::routes(graphql_schema.clone()));
+        // Services added through the route table
+        app.configure(routes::configure(&route_table))
+    })
+    .workers(server_config.workers)
+    .max_connections(server_config.max_connections)
+    .max_connection_rate(server_config.max_connection_rate)
+    .client_request_timeout(server_config.client_request_timeout)
+    .keep_alive(match server_config.keep_alive.is_zero() {
+        true => KeepAlive::Disabled,
+        false => KeepAlive::Timeout(server_config.keep_alive),
     })
-    .bind(("0.0.0.0", config.port))?
-    .run()
-    .await
+    .shutdown_timeout(server_config.shutdown_timeout.as_secs())
+    // Stopping is left to the drain, which keeps serving for a while first
+    .disable_signals();
+    if server_config.tcp {
+        let listen_addr = std::net::SocketAddr::from(([0, 0, 0, 0], config.port));
+        server = server.listen(listener::bind(listen_addr, server_config)?)?;
+        info!("Listening on {}", listen_addr);
+    }
+    if let Some(tls_config) = tls_config {
+        let https_addr = std::net::SocketAddr::from(([0, 0, 0, 0], config.tls.port));
+        server = server.listen_rustls_0_23(listener::bind(https_addr, server_config)?, tls_config)?;
+        info!("Serving HTTPS on {}", https_addr);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &server_config.unix_socket {
+        server = server.listen_uds(listener::bind_unix(path, server_config.unix_socket_mode)?)?;
+        info!("Listening on unix socket {}", path);
+    }
+    let server = server.run();
+    actix_web::rt::spawn(listener::drain_on_signal(drain_data, server.handle()));
+    actix_web::rt::spawn(systemd::serve(notify_data));
+    let result = server.await;
+    tracer.shutdown();
+    result
 }
\ No newline at end of file
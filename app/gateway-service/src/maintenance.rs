@@ -0,0 +1,112 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::RwLock;
+
+use crate::AppState;
+
+// Still served while in maintenance, so operators can end it and probes
+// see the gateway itself is up
+const OPEN_PREFIXES: &[&str] = &["/admin", "/health", "/metrics"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceWindow {
+    pub message: String,
+    pub started_by: String,
+    pub started_at: String,
+    /// Expected end, sent to clients as `Retry-After`
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Whether the gateway is answering client requests with 503 while
+/// operators work on the system behind it.
+#[derive(Default)]
+pub struct Maintenance {
+    window: RwLock<Option<MaintenanceWindow>>,
+}
+
+impl Maintenance {
+    pub fn current(&self) -> Option<MaintenanceWindow> {
+        self.window.read().unwrap().clone()
+    }
+
+    pub fn start(&self, window: MaintenanceWindow) {
+        *self.window.write().unwrap() = Some(window);
+    }
+
+    pub fn end(&self) -> Option<MaintenanceWindow> {
+        self.window.write().unwrap().take()
+    }
+}
+
+/// Middleware answering 503 to everything but the admin API and probes
+/// while maintenance mode is on.
+pub struct MaintenanceGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for MaintenanceGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = MaintenanceGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MaintenanceGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct MaintenanceGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for MaintenanceGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let window = req
+                .app_data::<web::Data<AppState>>()
+                .and_then(|data| data.maintenance.current());
+            let open = OPEN_PREFIXES
+                .iter()
+                .any(|prefix| req.path() == *prefix || req.path().starts_with(&format!("{}/", prefix)));
+            let window = match window {
+                Some(window) if !open => window,
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let mut response = HttpResponse::ServiceUnavailable();
+            if let Some(until) = window.until {
+                let seconds = (until - chrono::Utc::now()).num_seconds().max(1);
+                response.insert_header(("Retry-After", seconds.to_string()));
+            }
+            let response = response.json(serde_json::json!({
+                "error": "Down for maintenance",
+                "code": "maintenance",
+                "message": window.message,
+                "until": window.until,
+            }));
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
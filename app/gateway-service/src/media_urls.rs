@@ -0,0 +1,233 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future::LocalBoxFuture;
+use tracing::{info, warn};
+use ring::hmac;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::AppState;
+
+/// Query parameters a signed URL adds; stripped before the request goes
+/// upstream.
+const SIGNATURE_PARAMS: &[&str] = &["expires", "uid", "uname", "sig"];
+
+/// Scope carried by callers admitted with a signed URL.
+pub const MEDIA_READ_SCOPE: &str = "media:read";
+
+#[derive(Debug, Clone)]
+pub struct MediaUrlConfig {
+    /// Lifetime of a signed URL when the caller does not ask for one
+    pub ttl: Duration,
+    /// Longest lifetime a caller may ask for
+    pub max_ttl: Duration,
+}
+
+impl MediaUrlConfig {
+    pub fn from_env() -> Self {
+        let seconds = |key: &str, default: u64| {
+            Duration::from_secs(parse_env(key).unwrap_or(default))
+        };
+        MediaUrlConfig {
+            ttl: seconds("MEDIA_URL_TTL_SECONDS", 300),
+            max_ttl: seconds("MEDIA_URL_MAX_TTL_SECONDS", 86400),
+        }
+    }
+}
+
+// Derived from the JWT secret so a media signature can never pass for a token
+fn signing_key(secret: &str) -> hmac::Key {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::Key::new(hmac::HMAC_SHA256, hmac::sign(&key, b"gateway-media-url").as_ref())
+}
+
+fn message(path: &str, expires: i64, uid: &str, uname: &str) -> String {
+    format!("{}\n{}\n{}\n{}", path, expires, uid, uname)
+}
+
+#[derive(Deserialize)]
+pub struct SignRequest {
+    /// Media path as it will be requested, e.g. `/media/rooms/1/cat.png`;
+    /// percent-encoded where needed
+    path: String,
+    /// Requested lifetime in seconds, capped at the configured maximum
+    ttl: Option<u64>,
+}
+
+/// Handle `POST /api/media/sign`: mint a URL for one media path that works
+/// without a token until it expires, for `<img>` tags and the like. The URL
+/// acts for the caller who signed it.
+pub async fn sign(body: web::Json<SignRequest>, data: web::Data<AppState>, claims: Claims) -> Result<HttpResponse> {
+    let path = body.path.trim();
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    if !path.starts_with("/media/") || path.contains('?') || path.split('/').any(|segment| segment == "..") {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Path must be a media path under /media/"
+        })));
+    }
+
+    let config = &data.config.load().media_urls;
+    let ttl = body.ttl.map(Duration::from_secs).unwrap_or(config.ttl).min(config.max_ttl);
+    let expires = chrono::Utc::now().timestamp() + ttl.as_secs() as i64;
+    let signature = hmac::sign(&signing_key(&data.secrets.jwt_secret()), message(&path, expires, &claims.sub, &claims.username).as_bytes());
+
+    let mut url = match reqwest::Url::parse("http://gateway").and_then(|base| base.join(&path)) {
+        Ok(url) => url,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Invalid media path"
+            })))
+        }
+    };
+    url.query_pairs_mut()
+        .append_pair("expires", &expires.to_string())
+        .append_pair("uid", &claims.sub)
+        .append_pair("uname", &claims.username)
+        .append_pair("sig", &URL_SAFE_NO_PAD.encode(signature.as_ref()));
+    let signed = format!("{}?{}", path, url.query().unwrap_or_default());
+
+    info!("Signed {} for user {} until {}", path, claims.sub, expires);
+    data.metrics.incr("gateway_signed_media_urls_total", &[("outcome", "issued")], 1);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "url": signed,
+        "expires_at": expires,
+        "expires_in": ttl.as_secs(),
+    })))
+}
+
+// Why a presented signature was refused
+#[derive(Debug, Clone, Copy)]
+enum Rejection {
+    Invalid,
+    Expired,
+}
+
+// Caller named by a valid signed URL for this request
+fn verify(data: &AppState, req: &HttpRequest) -> Result<Claims, Rejection> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).map_err(|_| Rejection::Invalid)?;
+    let param = |name: &str| query.get(name).ok_or(Rejection::Invalid);
+    let expires: i64 = param("expires")?.parse().map_err(|_| Rejection::Invalid)?;
+    let (uid, uname) = (param("uid")?, param("uname")?);
+    let signature = URL_SAFE_NO_PAD.decode(param("sig")?).map_err(|_| Rejection::Invalid)?;
+
+    let message = message(req.path(), expires, uid, uname);
+    // URLs signed before a secret rotation stay valid through its window
+    let signed = data
+        .secrets
+        .verification_secrets()
+        .iter()
+        .any(|secret| hmac::verify(&signing_key(secret), message.as_bytes(), &signature).is_ok());
+    if !signed {
+        return Err(Rejection::Invalid);
+    }
+    if expires < chrono::Utc::now().timestamp() {
+        return Err(Rejection::Expired);
+    }
+    Ok(Claims {
+        sub: uid.clone(),
+        username: uname.clone(),
+        exp: expires as usize,
+        jti: None,
+        roles: Vec::new(),
+        role: None,
+        scope: Some(MEDIA_READ_SCOPE.to_string()),
+        scopes: Vec::new(),
+        sid: None,
+        did: None,
+        typ: None,
+    })
+}
+
+/// The request's query string without the signature parameters.
+pub fn upstream_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| {
+            let name = pair.split('=').next().unwrap_or_default();
+            !pair.is_empty() && !SIGNATURE_PARAMS.contains(&name)
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Middleware admitting `/media/*` requests that carry a valid signature in
+/// place of a token: the signer's identity is left in the request for
+/// `AuthMiddleware` to accept. Bad or expired signatures get 403.
+pub struct SignedMediaUrls;
+
+impl<S, B> Transform<S, ServiceRequest> for SignedMediaUrls
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = SignedMediaUrlsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SignedMediaUrlsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct SignedMediaUrlsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for SignedMediaUrlsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let signed = req.path().starts_with("/media/")
+                && req.query_string().split('&').any(|pair| pair.starts_with("sig="));
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) if signed => data.clone(),
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            match verify(&data, req.request()) {
+                Ok(claims) => {
+                    data.metrics.incr("gateway_signed_media_urls_total", &[("outcome", "valid")], 1);
+                    crate::identity::remember(req.request(), &claims);
+                    req.extensions_mut().insert(claims);
+                    service.call(req).await.map(|res| res.map_into_left_body())
+                }
+                Err(rejection) => {
+                    let (outcome, code, error) = match rejection {
+                        Rejection::Invalid => ("invalid", "invalid_signature", "Invalid media URL signature"),
+                        Rejection::Expired => ("expired", "url_expired", "Media URL has expired"),
+                    };
+                    warn!("Refused signed media URL for {}: {}", req.path(), outcome);
+                    data.metrics.incr("gateway_signed_media_urls_total", &[("outcome", outcome)], 1);
+                    let response = HttpResponse::Forbidden().json(serde_json::json!({
+                        "error": error,
+                        "code": code
+                    }));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
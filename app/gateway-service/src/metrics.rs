@@ -0,0 +1,552 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse, Result};
+use futures_util::future::LocalBoxFuture;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::future::{ready, Ready};
+use std::net::UdpSocket;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::circuit::CircuitState;
+use crate::config::{invalid, parse_env};
+use crate::AppState;
+
+// Default histogram buckets (seconds), matching the Prometheus client defaults
+const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Histogram buckets for upstream latency (seconds), finer above 250ms than
+/// the defaults so tail percentiles like p99 can be told apart.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.4, 0.6, 0.8, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 30.0,
+];
+
+/// Histogram buckets for payload sizes (bytes), 128B to 16MiB.
+pub const SIZE_BUCKETS: &[f64] = &[
+    128.0, 512.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+/// Where metrics go: scraped from `/metrics`, or pushed over UDP to a StatsD
+/// or DogStatsD agent as they are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsExporter {
+    Prometheus,
+    /// Labels are folded into the metric name, `name.key_value`
+    Statsd,
+    /// Labels are sent as `#key:value` tags
+    DogStatsd,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub exporter: MetricsExporter,
+    /// Agent to push to, `host:port`
+    pub statsd_addr: String,
+    /// Prepended to every pushed metric name, followed by a dot
+    pub statsd_prefix: Option<String>,
+    /// Tags added to every DogStatsD metric, `key:value`
+    pub statsd_tags: Vec<String>,
+    /// How often gauges otherwise read at scrape time are pushed
+    pub push_interval: Duration,
+}
+
+impl MetricsConfig {
+    /// Read `METRICS_EXPORTER` (`prometheus`, `statsd` or `dogstatsd`,
+    /// default `prometheus`), `STATSD_ADDR` (default `127.0.0.1:8125`),
+    /// `STATSD_PREFIX`, `STATSD_TAGS` (comma-separated `key:value`) and
+    /// `STATSD_PUSH_INTERVAL_SECS` (default 10).
+    pub fn from_env() -> Self {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let exporter = match var("METRICS_EXPORTER").map(|v| v.to_lowercase()).as_deref() {
+            None | Some("prometheus") => MetricsExporter::Prometheus,
+            Some("statsd") => MetricsExporter::Statsd,
+            Some("dogstatsd") | Some("datadog") => MetricsExporter::DogStatsd,
+            Some(other) => {
+                error!("Unknown METRICS_EXPORTER {}, serving Prometheus metrics", other);
+                invalid("METRICS_EXPORTER", format!("'{}' is not one of prometheus, statsd, dogstatsd", other));
+                MetricsExporter::Prometheus
+            }
+        };
+        MetricsConfig {
+            exporter,
+            statsd_addr: var("STATSD_ADDR").unwrap_or_else(|| "127.0.0.1:8125".to_string()),
+            statsd_prefix: var("STATSD_PREFIX").map(|prefix| prefix.trim_end_matches('.').to_string()),
+            statsd_tags: var("STATSD_TAGS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect(),
+            push_interval: Duration::from_secs(parse_env("STATSD_PUSH_INTERVAL_SECS").unwrap_or(10).max(1)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Series {
+    Value(f64),
+    Histogram {
+        bounds: &'static [f64],
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Family {
+    kind: MetricKind,
+    series: BTreeMap<String, Series>,
+}
+
+// Sends every update as one StatsD line; UDP, so a missing agent costs
+// nothing and lost packets are not retried
+#[derive(Debug)]
+struct StatsdSink {
+    socket: UdpSocket,
+    exporter: MetricsExporter,
+    prefix: Option<String>,
+    tags: Vec<String>,
+}
+
+impl StatsdSink {
+    fn connect(config: &MetricsConfig) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&config.statsd_addr)?;
+        // Never hold up a request on a full send buffer
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink {
+            socket,
+            exporter: config.exporter,
+            prefix: config.statsd_prefix.clone(),
+            tags: config.statsd_tags.clone(),
+        })
+    }
+
+    // `name:value|type`, with `|#tags` for DogStatsD
+    fn line(&self, name: &str, kind: MetricKind, labels: &[(&str, &str)], value: f64) -> String {
+        let mut line = String::new();
+        if let Some(prefix) = &self.prefix {
+            let _ = write!(line, "{}.", prefix);
+        }
+        line.push_str(name);
+        if self.exporter == MetricsExporter::Statsd {
+            for (key, value) in labels {
+                let _ = write!(line, ".{}_{}", key, statsd_name_part(value));
+            }
+        }
+        let kind = match (kind, self.exporter) {
+            (MetricKind::Counter, _) => "c",
+            (MetricKind::Gauge, _) => "g",
+            (MetricKind::Histogram, MetricsExporter::DogStatsd) => "h",
+            (MetricKind::Histogram, _) => "ms",
+        };
+        let _ = write!(line, ":{}|{}", value, kind);
+        if self.exporter == MetricsExporter::DogStatsd {
+            let mut tags = self.tags.clone();
+            tags.extend(labels.iter().map(|(key, value)| format!("{}:{}", key, dogstatsd_tag_value(value))));
+            if !tags.is_empty() {
+                let _ = write!(line, "|#{}", tags.join(","));
+            }
+        }
+        line
+    }
+
+    fn send(&self, name: &str, kind: MetricKind, labels: &[(&str, &str)], value: f64) {
+        let _ = self.socket.send(self.line(name, kind, labels, value).as_bytes());
+    }
+}
+
+// Label values become part of a dotted StatsD name
+fn statsd_name_part(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+// Characters that delimit DogStatsD tags or fields
+fn dogstatsd_tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+/// In-process metrics registry rendered in the Prometheus text format, and
+/// optionally pushed to a StatsD or DogStatsD agent as well.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+    help: Mutex<BTreeMap<&'static str, &'static str>>,
+    statsd: Option<StatsdSink>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry pushing to the exporter in `config`; if the StatsD socket
+    /// cannot be set up, metrics are only kept in process.
+    pub fn with_exporter(config: &MetricsConfig) -> Self {
+        if config.exporter == MetricsExporter::Prometheus {
+            return Self::new();
+        }
+        match StatsdSink::connect(config) {
+            Ok(sink) => {
+                info!("Pushing metrics to {:?} agent at {}", config.exporter, config.statsd_addr);
+                Metrics {
+                    statsd: Some(sink),
+                    ..Self::default()
+                }
+            }
+            Err(e) => {
+                warn!("Cannot push metrics to {}: {}", config.statsd_addr, e);
+                Self::new()
+            }
+        }
+    }
+
+    fn push(&self, name: &str, kind: MetricKind, labels: &[(&str, &str)], value: f64) {
+        if let Some(statsd) = &self.statsd {
+            statsd.send(name, kind, labels, value);
+        }
+    }
+
+    /// Attach a `# HELP` line to a metric family.
+    pub fn describe(&self, name: &'static str, help: &'static str) {
+        self.help.lock().unwrap().insert(name, help);
+    }
+
+    pub fn incr(&self, name: &'static str, labels: &[(&str, &str)], by: u64) {
+        self.update(name, MetricKind::Counter, labels, |value| *value += by as f64);
+        self.push(name, MetricKind::Counter, labels, by as f64);
+    }
+
+    pub fn gauge_set(&self, name: &'static str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, MetricKind::Gauge, labels, |current| *current = value);
+        self.push(name, MetricKind::Gauge, labels, value);
+    }
+
+    /// Adjust a gauge; StatsD receives the new value rather than the delta,
+    /// which DogStatsD would not understand.
+    pub fn gauge_add(&self, name: &'static str, labels: &[(&str, &str)], delta: f64) {
+        if let Some(value) = self.update(name, MetricKind::Gauge, labels, |current| *current += delta) {
+            self.push(name, MetricKind::Gauge, labels, value);
+        }
+    }
+
+    /// Record a duration-like observation (seconds) in a histogram.
+    pub fn observe(&self, name: &'static str, labels: &[(&str, &str)], value: f64) {
+        self.observe_in(name, labels, value, DEFAULT_BUCKETS);
+    }
+
+    /// Record an observation in a histogram with the given bucket bounds,
+    /// fixed by the first observation of each series.
+    pub fn observe_in(&self, name: &'static str, labels: &[(&str, &str)], value: f64, buckets: &'static [f64]) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind: MetricKind::Histogram,
+            series: BTreeMap::new(),
+        });
+        let series = family
+            .series
+            .entry(render_labels(labels))
+            .or_insert_with(|| Series::Histogram {
+                bounds: buckets,
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        if let Series::Histogram { bounds, counts, sum, count } = series {
+            for (bound, bucket) in bounds.iter().zip(counts.iter_mut()) {
+                if value <= *bound {
+                    *bucket += 1;
+                }
+            }
+            *sum += value;
+            *count += 1;
+        }
+        drop(families);
+        self.push(name, MetricKind::Histogram, labels, value);
+    }
+
+    // Apply a change to a counter or gauge and return its new value
+    fn update(&self, name: &'static str, kind: MetricKind, labels: &[(&str, &str)], apply: impl FnOnce(&mut f64)) -> Option<f64> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind,
+            series: BTreeMap::new(),
+        });
+        if let Series::Value(value) = family
+            .series
+            .entry(render_labels(labels))
+            .or_insert(Series::Value(0.0))
+        {
+            apply(value);
+            return Some(*value);
+        }
+        None
+    }
+
+    /// Render every registered family in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let help = self.help.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, family) in families.iter() {
+            if let Some(text) = help.get(name) {
+                let _ = writeln!(out, "# HELP {} {}", name, escape_help(text));
+            }
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.as_str());
+
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(out, "{}{} {}", name, labels, value);
+                    }
+                    Series::Histogram { bounds, counts, sum, count } => {
+                        for (bound, bucket) in bounds.iter().zip(counts) {
+                            let le = bound.to_string();
+                            let _ = writeln!(out, "{}_bucket{} {}", name, with_label(labels, "le", &le), bucket);
+                        }
+                        let _ = writeln!(out, "{}_bucket{} {}", name, with_label(labels, "le", "+Inf"), count);
+                        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+// Help text may hold backslashes and line breaks, but not raw ones
+fn escape_help(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+// Append one more label to an already rendered label set
+fn with_label(rendered: &str, key: &str, value: &str) -> String {
+    let extra = format!("{}=\"{}\"", key, escape_label_value(value));
+    match rendered.strip_suffix('}') {
+        Some(prefix) => format!("{},{}}}", prefix, extra),
+        None => format!("{{{}}}", extra),
+    }
+}
+
+// Methods outside the standard set are counted together, as clients can send
+// any token there
+fn method_label(method: &actix_web::http::Method) -> &'static str {
+    use actix_web::http::Method;
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+// Circuit states change on their own as cool-downs lapse, so they are read at
+// scrape time, or on a timer when metrics are pushed: 0 closed, 1 half-open,
+// 2 open
+fn record_circuit_states(data: &AppState) {
+    for upstream in data.upstreams.load().iter() {
+        let state = match data.circuits.state(&upstream.name) {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        };
+        data.metrics.gauge_set("gateway_circuit_state", &[("service", &upstream.name)], state);
+    }
+}
+
+/// Record circuit states on the push interval, since nothing scrapes when
+/// metrics are pushed to StatsD.
+pub async fn push_circuit_states(data: web::Data<AppState>) {
+    let mut interval = tokio::time::interval(data.config.load().metrics.push_interval);
+    loop {
+        interval.tick().await;
+        record_circuit_states(&data);
+    }
+}
+
+/// `GET /metrics` in the Prometheus text format, unless metrics are pushed.
+pub async fn scrape(data: web::Data<AppState>) -> Result<HttpResponse> {
+    if data.config.load().metrics.exporter != MetricsExporter::Prometheus {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Metrics are pushed to StatsD, not served here"
+        })));
+    }
+    record_circuit_states(&data);
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.metrics.render()))
+}
+
+/// Middleware counting requests and timing them per route, labelled with the
+/// route pattern rather than the path, and never with values the client
+/// picks freely, so ids do not explode the series count.
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        RequestMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.metrics.clone(),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<Metrics>,
+}
+
+// Leaves the in-flight gauge when the request finishes or is dropped
+struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.gauge_add("gateway_http_requests_in_flight", &[], -1.0);
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = self.metrics.clone();
+        let method = method_label(req.method());
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+        Box::pin(async move {
+            metrics.gauge_add("gateway_http_requests_in_flight", &[], 1.0);
+            let _in_flight = InFlight(metrics.clone());
+            let started = Instant::now();
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            let labels = [("method", method), ("route", route.as_str())];
+            metrics.observe("gateway_http_request_duration_seconds", &labels, started.elapsed().as_secs_f64());
+            metrics.incr(
+                "gateway_http_requests_total",
+                &[("method", method), ("route", route.as_str()), ("status", status.as_str())],
+                1,
+            );
+            if matches!(status.as_u16(), 401 | 403) {
+                metrics.incr("gateway_auth_rejections_total", &[("route", route.as_str()), ("status", status.as_str())], 1);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn label_values_and_help_are_escaped() {
+        let metrics = Metrics::new();
+        metrics.describe("gateway_test_total", "Lines\nwith a \\ in them");
+        metrics.incr("gateway_test_total", &[("value", "a\\b \"c\"\nd")], 1);
+        let text = metrics.render();
+        assert!(text.contains("# HELP gateway_test_total Lines\\nwith a \\\\ in them\n"), "{}", text);
+        assert!(text.contains(r#"gateway_test_total{value="a\\b \"c\"\nd"} 1"#), "{}", text);
+        assert_eq!(text.lines().count(), 3);
+    }
+
+    #[actix_web::test]
+    async fn requests_are_labelled_with_the_route_pattern() {
+        let metrics = Arc::new(Metrics::new());
+        let app = init_service(
+            App::new()
+                .wrap(RequestMetrics::new(metrics.clone()))
+                .route("/api/users/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for uri in ["/api/users/1", "/api/users/2", "/api/users/%22%0A", "/nowhere/3"] {
+            call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        }
+        let request = TestRequest::default().method(actix_web::http::Method::from_bytes(b"BREW").unwrap());
+        call_service(&app, request.uri("/api/users/1").to_request()).await;
+
+        let text = metrics.render();
+        assert!(text.contains(r#"gateway_http_requests_total{method="GET",route="/api/users/{id}",status="200"} 3"#), "{}", text);
+        assert!(text.contains(r#"gateway_http_requests_total{method="GET",route="unmatched",status="404"} 1"#), "{}", text);
+        assert!(text.contains(r#"method="other",route="/api/users/{id}""#), "{}", text);
+        assert!(!text.contains("users/1") && !text.contains("BREW"), "{}", text);
+    }
+}
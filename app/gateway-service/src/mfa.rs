@@ -0,0 +1,452 @@
+use actix_web::{body, web, HttpRequest, HttpResponse, Result};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use tracing::{error, info, warn};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::devices;
+use crate::replay;
+use crate::refresh::{tokens_json, user_roles, Grants};
+use crate::AppState;
+
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+
+#[derive(Debug, Clone)]
+pub struct MfaConfig {
+    /// Lifetime of the `mfa_pending` token handed out after the password check
+    pub pending_ttl: Duration,
+    /// User service path returning the user's TOTP secret; `{id}` is replaced
+    /// with the user id
+    pub secret_path: String,
+    /// Time steps either side of the current one a code may come from
+    pub window: u64,
+    /// Wrong codes accepted per challenge before it is discarded
+    pub max_attempts: u32,
+}
+
+impl MfaConfig {
+    pub fn from_env() -> Self {
+        let number = |key: &str, default: u64| parse_env(key).unwrap_or(default);
+        MfaConfig {
+            pending_ttl: Duration::from_secs(number("MFA_PENDING_TTL_SECONDS", 300)),
+            secret_path: env::var("MFA_SECRET_PATH").unwrap_or_else(|_| "/users/{id}/mfa".to_string()),
+            window: number("MFA_TOTP_WINDOW", 1),
+            max_attempts: number("MFA_MAX_ATTEMPTS", 5) as u32,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingClaims {
+    sub: String,
+    username: String,
+    exp: usize,
+    jti: String,
+    typ: String,
+    #[serde(flatten)]
+    grants: Grants,
+}
+
+// A challenge awaiting its code
+struct Pending {
+    attempts: u32,
+    expires_at: usize,
+}
+
+/// Outstanding MFA challenges. Each `mfa_pending` token can complete one
+/// sign-in and is dropped after too many wrong codes.
+pub struct MfaChallenges {
+    pending: Mutex<HashMap<String, Pending>>,
+    rng: SystemRandom,
+}
+
+impl Default for MfaChallenges {
+    fn default() -> Self {
+        MfaChallenges {
+            pending: Mutex::new(HashMap::new()),
+            rng: SystemRandom::new(),
+        }
+    }
+}
+
+fn now() -> usize {
+    chrono::Utc::now().timestamp() as usize
+}
+
+// Pending tokens are signed with a key derived from the JWT secret, so they
+// can never pass for access tokens
+fn signing_key(secret: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::sign(&key, b"gateway-mfa-pending").as_ref().to_vec()
+}
+
+impl MfaChallenges {
+    fn issue(&self, data: &AppState, sub: &str, username: &str, grants: Grants) -> Result<String, String> {
+        let mut bytes = [0u8; 16];
+        self.rng.fill(&mut bytes).map_err(|_| "system random source unavailable")?;
+        let jti: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let issued = now();
+        let claims = PendingClaims {
+            sub: sub.to_string(),
+            username: username.to_string(),
+            exp: issued + data.config.load().mfa.pending_ttl.as_secs() as usize,
+            jti: jti.clone(),
+            typ: "mfa_pending".to_string(),
+            grants,
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(&signing_key(&data.secrets.jwt_secret())))
+            .map_err(|e| e.to_string())?;
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.expires_at > issued);
+        pending.insert(
+            jti,
+            Pending {
+                attempts: 0,
+                expires_at: claims.exp,
+            },
+        );
+        Ok(token)
+    }
+
+    fn decode(&self, data: &AppState, token: &str) -> Option<PendingClaims> {
+        let claims = data
+            .secrets
+            .verification_secrets()
+            .iter()
+            .find_map(|secret| {
+                decode::<PendingClaims>(
+                    token,
+                    &DecodingKey::from_secret(&signing_key(secret)),
+                    &Validation::new(Algorithm::HS256),
+                )
+                .ok()
+            })?
+            .claims;
+        let outstanding = self.pending.lock().unwrap().contains_key(&claims.jti);
+        Some(claims).filter(|claims| claims.typ == "mfa_pending" && outstanding)
+    }
+
+    // Count a wrong code; the attempts left, after which the challenge is gone
+    fn fail(&self, jti: &str, max_attempts: u32) -> u32 {
+        let mut pending = self.pending.lock().unwrap();
+        let attempts = match pending.get_mut(jti) {
+            Some(challenge) => {
+                challenge.attempts += 1;
+                challenge.attempts
+            }
+            None => return 0,
+        };
+        if attempts >= max_attempts {
+            pending.remove(jti);
+        }
+        max_attempts.saturating_sub(attempts)
+    }
+
+    // Consume a challenge; false if another request already did
+    fn complete(&self, jti: &str) -> bool {
+        self.pending.lock().unwrap().remove(jti).is_some()
+    }
+}
+
+// RFC 4648 base32, as authenticator apps show secrets
+fn decode_base32(secret: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut count = 0;
+    let mut bytes = Vec::new();
+    for c in secret.chars().filter(|c| !c.is_whitespace() && *c != '=' && *c != '-') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(bytes).filter(|bytes| !bytes.is_empty())
+}
+
+// RFC 6238 code for one time step
+fn totp(key: &hmac::Key, step: u64) -> String {
+    let digest = hmac::sign(key, &step.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+/// Whether `code` is the TOTP for `secret` within `window` steps of now.
+fn verify_code(secret: &[u8], code: &str, window: u64) -> bool {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return false;
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let current = chrono::Utc::now().timestamp() as u64 / TOTP_STEP;
+    (current.saturating_sub(window)..=current + window)
+        .any(|step| ring::constant_time::verify_slices_are_equal(totp(&key, step).as_bytes(), code.as_bytes()).is_ok())
+}
+
+/// How a login continues after the password check.
+pub enum LoginStep {
+    /// The user has MFA enabled; the response carries the `mfa_pending` token
+    Challenge(HttpResponse),
+    /// No second factor needed; the upstream response, unchanged
+    Continue(HttpResponse),
+}
+
+/// Hold back the tokens of a successful login whose user has MFA enabled,
+/// answering with an `mfa_pending` token to be redeemed at
+/// `/api/auth/mfa/verify` instead.
+pub async fn on_login(data: &AppState, response: HttpResponse, scope: Option<String>) -> LoginStep {
+    if !response.status().is_success() {
+        return LoginStep::Continue(response);
+    }
+    let status = response.status();
+    let bytes = match body::to_bytes(response.into_body()).await {
+        Ok(bytes) => bytes,
+        Err(_) => return LoginStep::Continue(HttpResponse::BadGateway().finish()),
+    };
+    let json: Value = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(_) => return LoginStep::Continue(HttpResponse::build(status).body(bytes)),
+    };
+
+    let user = json.get("user").cloned().unwrap_or(Value::Null);
+    let enabled = ["mfaEnabled", "mfa_enabled"].iter().any(|key| user.get(key) == Some(&Value::Bool(true)));
+    let sub = user.get("id").map(|id| match id {
+        Value::String(id) => id.clone(),
+        other => other.to_string(),
+    });
+    let sub = match sub {
+        Some(sub) if enabled => sub,
+        _ => return LoginStep::Continue(HttpResponse::build(status).json(json)),
+    };
+    let username = user.get("username").and_then(Value::as_str).unwrap_or_default();
+
+    let grants = Grants {
+        roles: user_roles(&user),
+        scope,
+        ..Default::default()
+    };
+    match data.mfa.issue(data, &sub, username, grants) {
+        Ok(token) => {
+            info!("Password accepted for user {}, awaiting second factor", sub);
+            data.metrics.incr("gateway_mfa_challenges_total", &[], 1);
+            LoginStep::Challenge(HttpResponse::Ok().json(serde_json::json!({
+                "mfaRequired": true,
+                "mfaToken": token,
+                "expiresIn": data.config.load().mfa.pending_ttl.as_secs(),
+            })))
+        }
+        // Never fall back to handing out the upstream tokens
+        Err(e) => {
+            error!("Failed to issue MFA challenge for user {}: {}", sub, e);
+            LoginStep::Challenge(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to start MFA challenge"
+            })))
+        }
+    }
+}
+
+// The TOTP secret of a user, from the user service
+async fn totp_secret(data: &AppState, req: &HttpRequest, claims: &PendingClaims) -> Result<Option<Vec<u8>>, String> {
+    let path = data.config.load().mfa.secret_path.replace("{id}", &claims.sub);
+    crate::identity::remember(
+        req,
+        &Claims {
+            sub: claims.sub.clone(),
+            username: claims.username.clone(),
+            exp: claims.exp,
+            jti: None,
+            roles: claims.grants.roles.clone(),
+            role: None,
+            scope: None,
+            scopes: Vec::new(),
+            sid: None,
+            did: None,
+            typ: None,
+        },
+    );
+    let response = crate::proxy_request(data, req, "user", &path, "GET", None)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("user service answered {}", response.status()));
+    }
+    let bytes = body::to_bytes(response.into_body()).await.map_err(|_| "unreadable response")?;
+    let json: Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+    let secret = json.get("secret").or_else(|| json.get("totpSecret")).and_then(Value::as_str);
+    Ok(secret.and_then(decode_base32))
+}
+
+fn outcome(data: &AppState, outcome: &str) {
+    data.metrics.incr("gateway_mfa_verifications_total", &[("outcome", outcome)], 1);
+}
+
+/// Handle `/api/auth/mfa/verify`: check the TOTP code for an `mfa_pending`
+/// token and issue the full tokens.
+pub async fn verify(req: HttpRequest, payload: web::Json<Value>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let body = payload.into_inner();
+    let field = |a: &str, b: &str| body.get(a).or_else(|| body.get(b)).and_then(Value::as_str).map(str::to_string);
+    let (token, code) = match (field("mfaToken", "mfa_token"), field("code", "totp")) {
+        (Some(token), Some(code)) => (token, code),
+        _ => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "mfaToken and code are required"
+            })))
+        }
+    };
+
+    let claims = match data.mfa.decode(&data, &token) {
+        Some(claims) => claims,
+        None => {
+            outcome(&data, "invalid_token");
+            return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Invalid or expired MFA token"
+            })));
+        }
+    };
+
+    let secret = match totp_secret(&data, &req, &claims).await {
+        Ok(Some(secret)) => secret,
+        Ok(None) => {
+            warn!("User {} has MFA enabled but no usable TOTP secret", claims.sub);
+            outcome(&data, "no_secret");
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "error": "MFA is not set up for this user"
+            })));
+        }
+        Err(e) => {
+            error!("Failed to fetch the TOTP secret of user {}: {}", claims.sub, e);
+            outcome(&data, "unavailable");
+            return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "error": "User service unavailable"
+            })));
+        }
+    };
+
+    let config = &data.config.load().mfa;
+    if !verify_code(&secret, &code, config.window) {
+        let remaining = data.mfa.fail(&claims.jti, config.max_attempts);
+        warn!("Wrong MFA code for user {}, {} attempts left", claims.sub, remaining);
+        outcome(&data, "wrong_code");
+        if remaining == 0 {
+            data.audit.record(
+                "mfa_challenge_exhausted",
+                &claims.sub,
+                serde_json::json!({ "max_attempts": config.max_attempts }),
+            );
+        }
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid MFA code",
+            "attemptsRemaining": remaining
+        })));
+    }
+    if !data.mfa.complete(&claims.jti) {
+        outcome(&data, "invalid_token");
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid or expired MFA token"
+        })));
+    }
+    // Challenges are tracked per instance; the shared store stops the same
+    // token completing a sign-in on another one
+    if let Err(response) = replay::consume(&data, "mfa_pending", &claims.sub, &claims.jti, claims.exp).await {
+        outcome(&data, "replayed");
+        return Ok(response);
+    }
+
+    // With refresh left to the user service, only an access token is issued
+    let grants = devices::start(&data, &req, &claims.sub, claims.grants.clone());
+    let tokens = if data.refresh.enabled() {
+        data.refresh.issue(&claims.sub, &claims.username, &grants).map(|pair| {
+            data.refresh.count("issued");
+            tokens_json(&pair)
+        })
+    } else {
+        data.refresh
+            .issue_access(&claims.sub, &claims.username, &grants)
+            .map(|(token, expires_in)| serde_json::json!({ "accessToken": token, "expiresIn": expires_in, "sessionId": grants.sid }))
+    };
+    let tokens = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            error!("Failed to issue tokens for user {}: {}", claims.sub, e);
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to issue tokens"
+            })));
+        }
+    };
+    info!("MFA verified for user {}", claims.sub);
+    outcome(&data, "verified");
+    let response = HttpResponse::Ok().json(serde_json::json!({
+        "user": { "id": claims.sub, "username": claims.username },
+        "tokens": tokens,
+    }));
+    Ok(crate::session::attach(&data, response).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1, truncated to our six digits
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn decodes_base32_secrets() {
+        assert_eq!(decode_base32(RFC_SECRET).unwrap(), b"12345678901234567890");
+        assert_eq!(decode_base32("gezd gnbv-gy3t qojq====").unwrap(), b"1234567890");
+        assert!(decode_base32("GEZD1").is_none());
+        assert!(decode_base32("").is_none());
+    }
+
+    #[test]
+    fn matches_the_rfc_6238_vectors() {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &decode_base32(RFC_SECRET).unwrap());
+        for (time, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(totp(&key, time / TOTP_STEP), code, "T={}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_codes_only_within_the_window() {
+        let secret = b"12345678901234567890";
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+        let current = chrono::Utc::now().timestamp() as u64 / TOTP_STEP;
+        assert!(verify_code(secret, &totp(&key, current + 1), 1));
+        assert!(!verify_code(secret, &totp(&key, current - 2), 1));
+        assert!(!verify_code(secret, "12345", 1));
+    }
+
+    #[test]
+    fn drops_the_challenge_after_max_attempts() {
+        let challenges = MfaChallenges::default();
+        challenges.pending.lock().unwrap().insert(
+            "jti".to_string(),
+            Pending {
+                attempts: 0,
+                expires_at: now() + 300,
+            },
+        );
+        assert_eq!(challenges.fail("jti", 3), 2);
+        assert_eq!(challenges.fail("jti", 3), 1);
+        assert_eq!(challenges.fail("jti", 3), 0);
+        assert!(!challenges.complete("jti"));
+        assert_eq!(challenges.fail("jti", 3), 0);
+    }
+}
@@ -0,0 +1,378 @@
+use actix_web::HttpResponse;
+use futures_util::future::BoxFuture;
+use tracing::{error, info, warn};
+use regex::Regex;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::rbac::route_matches;
+use crate::AppState;
+
+/// What happens to a message a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Refuse the message with 422
+    Block,
+    /// Deliver it, marked for review
+    Flag,
+    /// Deliver it with the matched text replaced by asterisks
+    Mask,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Block => "block",
+            Action::Flag => "flag",
+            Action::Mask => "mask",
+        }
+    }
+}
+
+/// A word list or regex rule as configured in `MODERATION_RULES`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleSpec {
+    pub name: String,
+    /// Whole words matched case-insensitively
+    #[serde(default)]
+    pub words: Vec<String>,
+    pub pattern: Option<String>,
+    pub action: Action,
+    /// Explanation returned to the sender when the rule blocks a message
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModerationConfig {
+    /// Routes whose message bodies are moderated
+    pub paths: Vec<String>,
+    /// Body fields holding message text
+    pub fields: Vec<String>,
+    pub rules: Vec<RuleSpec>,
+    /// External moderation API consulted after the local rules
+    pub api_url: Option<String>,
+    pub api_timeout: Duration,
+    /// Deliver messages when the moderation API cannot be reached
+    pub fail_open: bool,
+}
+
+fn list(key: &str, default: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl ModerationConfig {
+    pub fn from_env() -> Self {
+        let mut rules = match env::var("MODERATION_RULES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid MODERATION_RULES ({}), ignoring them", e);
+                invalid("MODERATION_RULES", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        // Shorthand for a single word list
+        let words = list("MODERATION_WORDS", "");
+        if !words.is_empty() {
+            let action = match env::var("MODERATION_WORD_ACTION").as_deref() {
+                Ok("flag") => Action::Flag,
+                Ok("mask") => Action::Mask,
+                _ => Action::Block,
+            };
+            rules.push(RuleSpec {
+                name: "word_list".to_string(),
+                words,
+                pattern: None,
+                action,
+                reason: None,
+            });
+        }
+        ModerationConfig {
+            paths: list("MODERATION_PATHS", "/api/messages/send"),
+            fields: list("MODERATION_FIELDS", "content,text,message"),
+            rules,
+            api_url: env::var("MODERATION_API_URL").ok().filter(|url| !url.is_empty()),
+            api_timeout: Duration::from_millis(
+                parse_env("MODERATION_API_TIMEOUT_MS").unwrap_or(2000),
+            ),
+            fail_open: env::var("MODERATION_API_FAIL_OPEN").map(|v| v != "false" && v != "0").unwrap_or(true),
+        }
+    }
+}
+
+/// One rule that matched a message.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub rule: String,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Byte ranges of the matched text, masked for `Action::Mask`
+    #[serde(skip)]
+    pub spans: Vec<(usize, usize)>,
+    /// Text the filter wants delivered instead, e.g. masked by the API
+    #[serde(skip)]
+    pub replacement: Option<String>,
+}
+
+/// A stage of the moderation pipeline.
+pub trait ModerationFilter: Send + Sync {
+    fn name(&self) -> &str;
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<Finding>, String>>;
+}
+
+/// Word list or regex rule evaluated in the gateway.
+pub struct PatternRule {
+    spec: RuleSpec,
+    regex: Regex,
+}
+
+impl PatternRule {
+    pub fn new(spec: RuleSpec) -> Result<Self, String> {
+        let pattern = match (&spec.pattern, spec.words.is_empty()) {
+            (Some(pattern), true) => pattern.clone(),
+            (None, false) => {
+                let words: Vec<String> = spec.words.iter().map(|w| regex::escape(w)).collect();
+                format!(r"(?i)\b(?:{})\b", words.join("|"))
+            }
+            _ => return Err(format!("rule '{}' needs exactly one of words or pattern", spec.name)),
+        };
+        let regex = Regex::new(&pattern).map_err(|e| format!("rule '{}': {}", spec.name, e))?;
+        Ok(PatternRule { spec, regex })
+    }
+}
+
+impl ModerationFilter for PatternRule {
+    fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<Finding>, String>> {
+        let spans: Vec<(usize, usize)> = self.regex.find_iter(text).map(|m| (m.start(), m.end())).collect();
+        let finding = (!spans.is_empty()).then(|| Finding {
+            rule: self.spec.name.clone(),
+            action: self.spec.action,
+            reason: self.spec.reason.clone(),
+            spans,
+            replacement: None,
+        });
+        Box::pin(async move { Ok(finding.into_iter().collect()) })
+    }
+}
+
+// Verdict of the external moderation API
+#[derive(Deserialize)]
+struct ApiVerdict {
+    #[serde(default)]
+    action: Option<String>,
+    reason: Option<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+    /// Replacement text for a `mask` verdict
+    text: Option<String>,
+}
+
+/// External moderation service. It is sent `{"text": ...}` and answers with
+/// `action` (`allow`, `block`, `flag` or `mask`), optionally with `reason`,
+/// `categories` and, for `mask`, the replacement `text`.
+pub struct ApiFilter {
+    url: String,
+    client: Client,
+}
+
+impl ApiFilter {
+    pub fn new(url: String, timeout: Duration) -> Result<Self, String> {
+        let client = Client::builder().timeout(timeout).build().map_err(|e| e.to_string())?;
+        Ok(ApiFilter { url, client })
+    }
+}
+
+impl ModerationFilter for ApiFilter {
+    fn name(&self) -> &str {
+        "moderation_api"
+    }
+
+    fn check<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<Finding>, String>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.url)
+                .json(&serde_json::json!({ "text": text }))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("moderation API answered {}", response.status()));
+            }
+            let verdict: ApiVerdict = response.json().await.map_err(|e| e.to_string())?;
+            let action = match verdict.action.as_deref() {
+                None | Some("allow") => return Ok(Vec::new()),
+                Some("block") => Action::Block,
+                Some("flag") => Action::Flag,
+                Some("mask") if verdict.text.is_some() => Action::Mask,
+                Some(other) => return Err(format!("unknown moderation action '{}'", other)),
+            };
+            let rule = match verdict.categories.is_empty() {
+                true => self.name().to_string(),
+                false => format!("{}:{}", self.name(), verdict.categories.join(",")),
+            };
+            Ok(vec![Finding {
+                rule,
+                action,
+                reason: verdict.reason,
+                spans: Vec::new(),
+                replacement: verdict.text,
+            }])
+        })
+    }
+}
+
+fn mask(text: &str, spans: &[(usize, usize)]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut last = 0;
+    for &(start, end) in spans {
+        masked.push_str(&text[last..start]);
+        masked.extend(text[start..end].chars().map(|c| if c.is_whitespace() { c } else { '*' }));
+        last = end;
+    }
+    masked.push_str(&text[last..]);
+    masked
+}
+
+/// Findings for one body field.
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub field: String,
+    #[serde(flatten)]
+    pub finding: Finding,
+}
+
+/// Outcome of moderating a message body.
+pub enum Verdict {
+    /// Deliver the (possibly masked) body
+    Deliver(Value),
+    /// Refuse it with this response
+    Reject(HttpResponse),
+}
+
+/// Filters run in order over the text fields of message bodies. Masks from
+/// one stage are applied before the next sees the text.
+pub struct Moderation {
+    config: ModerationConfig,
+    filters: Vec<Box<dyn ModerationFilter>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Moderation {
+    pub fn new(config: ModerationConfig, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let mut filters: Vec<Box<dyn ModerationFilter>> = Vec::new();
+        for spec in &config.rules {
+            filters.push(Box::new(PatternRule::new(spec.clone())?));
+        }
+        if let Some(url) = &config.api_url {
+            filters.push(Box::new(ApiFilter::new(url.clone(), config.api_timeout)?));
+        }
+        info!("Message moderation: {} filters on {:?}", filters.len(), config.paths);
+        Ok(Moderation { config, filters, metrics })
+    }
+
+    pub fn applies(&self, method: &str, path: &str) -> bool {
+        !self.filters.is_empty()
+            && method == "POST"
+            && self.config.paths.iter().any(|pattern| route_matches(pattern, &[], "", path))
+    }
+
+    // Run every filter over one text, masking as it goes
+    async fn moderate_text(&self, field: &str, mut text: String, violations: &mut Vec<Violation>) -> Result<String, String> {
+        for filter in &self.filters {
+            let findings = match filter.check(&text).await {
+                Ok(findings) => findings,
+                Err(e) if self.config.fail_open => {
+                    warn!("Moderation filter {} failed, letting the message through: {}", filter.name(), e);
+                    self.metrics.incr("gateway_moderation_errors_total", &[("filter", filter.name())], 1);
+                    continue;
+                }
+                Err(e) => {
+                    self.metrics.incr("gateway_moderation_errors_total", &[("filter", filter.name())], 1);
+                    return Err(e);
+                }
+            };
+            for finding in findings {
+                if finding.action == Action::Mask {
+                    text = match &finding.replacement {
+                        Some(replacement) => replacement.clone(),
+                        None => mask(&text, &finding.spans),
+                    };
+                }
+                violations.push(Violation {
+                    field: field.to_string(),
+                    finding,
+                });
+            }
+        }
+        Ok(text)
+    }
+
+    /// Moderate the text fields of a message body sent by `user`.
+    pub async fn check(&self, data: &AppState, user: &str, mut body: Value) -> Verdict {
+        let mut violations = Vec::new();
+        for field in &self.config.fields {
+            let text = match body.get(field).and_then(Value::as_str) {
+                Some(text) => text.to_string(),
+                None => continue,
+            };
+            match self.moderate_text(field, text, &mut violations).await {
+                Ok(text) => body[field.as_str()] = Value::String(text),
+                Err(e) => {
+                    error!("Moderation unavailable, refusing message from {}: {}", user, e);
+                    return Verdict::Reject(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                        "error": "Message moderation unavailable",
+                        "code": "moderation_unavailable"
+                    })));
+                }
+            }
+        }
+
+        for violation in &violations {
+            let finding = &violation.finding;
+            self.metrics.incr(
+                "gateway_moderation_actions_total",
+                &[("rule", &finding.rule), ("action", finding.action.as_str())],
+                1,
+            );
+        }
+        if violations.iter().any(|v| v.finding.action == Action::Block) {
+            let blocked: Vec<&Violation> = violations.iter().filter(|v| v.finding.action == Action::Block).collect();
+            info!("Blocked message from {}: {} rule matches", user, blocked.len());
+            return Verdict::Reject(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "error": "Message rejected by moderation",
+                "code": "moderation_blocked",
+                "violations": blocked,
+            })));
+        }
+
+        let flagged: Vec<&str> = violations
+            .iter()
+            .filter(|v| v.finding.action == Action::Flag)
+            .map(|v| v.finding.rule.as_str())
+            .collect();
+        if !flagged.is_empty() {
+            data.audit.record("message_flagged", user, serde_json::json!({ "rules": flagged }));
+            // Tells the message service the content awaits review
+            body["moderation"] = serde_json::json!({ "flagged": true, "rules": flagged });
+        }
+        Verdict::Deliver(body)
+    }
+}
@@ -0,0 +1,461 @@
+use actix_web::{body, http::header, web, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use tracing::{error, info, warn};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{invalid, parse_env};
+use crate::devices;
+use crate::refresh;
+use crate::session;
+use crate::AppState;
+
+/// Identity provider as configured in `OIDC_PROVIDERS`. `google` and `github`
+/// only need client credentials; their endpoints are filled in.
+#[derive(Clone, Deserialize)]
+pub struct OidcProvider {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub authorization_url: String,
+    #[serde(default)]
+    pub token_url: String,
+    #[serde(default)]
+    pub userinfo_url: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl fmt::Debug for OidcProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcProvider")
+            .field("name", &self.name)
+            .field("client_id", &self.client_id)
+            .field("authorization_url", &self.authorization_url)
+            .field("token_url", &self.token_url)
+            .field("userinfo_url", &self.userinfo_url)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl OidcProvider {
+    // Fill in the well-known endpoints of the providers we ship presets for
+    fn with_defaults(mut self) -> Self {
+        let (authorization, token, userinfo, scopes): (&str, &str, &str, &[&str]) = match self.name.as_str() {
+            "google" => (
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "https://oauth2.googleapis.com/token",
+                "https://openidconnect.googleapis.com/v1/userinfo",
+                &["openid", "email", "profile"],
+            ),
+            "github" => (
+                "https://github.com/login/oauth/authorize",
+                "https://github.com/login/oauth/access_token",
+                "https://api.github.com/user",
+                &["read:user", "user:email"],
+            ),
+            _ => ("", "", "", &["openid", "email", "profile"]),
+        };
+        let fill = |field: &mut String, default: &str| {
+            if field.is_empty() {
+                *field = default.to_string();
+            }
+        };
+        fill(&mut self.authorization_url, authorization);
+        fill(&mut self.token_url, token);
+        fill(&mut self.userinfo_url, userinfo);
+        if self.scopes.is_empty() {
+            self.scopes = scopes.iter().map(|s| s.to_string()).collect();
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub providers: Vec<OidcProvider>,
+    /// Public URL of the callback endpoint; derived from the login request
+    /// when unset
+    pub redirect_uri: Option<String>,
+    /// User service endpoint that finds or creates the account for an identity
+    pub link_path: String,
+    /// Where the browser is sent after signing in, with the tokens in the URL
+    /// fragment; the callback answers with JSON when unset
+    pub success_redirect: Option<String>,
+    pub state_ttl: Duration,
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Self {
+        let providers = match env::var("OIDC_PROVIDERS") {
+            Ok(raw) => match serde_json::from_str::<Vec<OidcProvider>>(&raw) {
+                Ok(providers) => providers
+                    .into_iter()
+                    .map(OidcProvider::with_defaults)
+                    .filter(|p| {
+                        let complete = !p.authorization_url.is_empty() && !p.token_url.is_empty() && !p.userinfo_url.is_empty();
+                        if !complete {
+                            error!("Ignoring OIDC provider '{}': endpoints missing", p.name);
+                            invalid("OIDC_PROVIDERS", format!("provider '{}' is missing endpoints", p.name));
+                        }
+                        complete
+                    })
+                    .collect(),
+                Err(e) => {
+                    error!("Invalid OIDC_PROVIDERS: {}", e);
+                    invalid("OIDC_PROVIDERS", e);
+                    Vec::new()
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        OidcConfig {
+            providers,
+            redirect_uri: env::var("OIDC_REDIRECT_URI").ok().filter(|v| !v.is_empty()),
+            link_path: env::var("OIDC_USER_LINK_PATH").unwrap_or_else(|_| "/oidc/link".to_string()),
+            success_redirect: env::var("OIDC_SUCCESS_REDIRECT").ok().filter(|v| !v.is_empty()),
+            state_ttl: Duration::from_secs(
+                parse_env("OIDC_STATE_TTL_SECONDS").unwrap_or(600),
+            ),
+        }
+    }
+
+    fn provider(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.iter().find(|p| p.name == name)
+    }
+}
+
+// A login waiting for the provider to redirect back
+struct PendingLogin {
+    provider: String,
+    code_verifier: String,
+    redirect_uri: String,
+    started: Instant,
+}
+
+/// Authorization-code logins in flight, keyed by their `state` parameter.
+pub struct OidcLogins {
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    rng: SystemRandom,
+}
+
+impl OidcLogins {
+    pub fn new() -> Self {
+        OidcLogins {
+            pending: Mutex::new(HashMap::new()),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn random_token(&self) -> String {
+        let mut bytes = [0u8; 32];
+        self.rng.fill(&mut bytes).expect("system random source unavailable");
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn start(&self, provider: &str, redirect_uri: String, ttl: Duration) -> (String, String) {
+        let state = self.random_token();
+        let code_verifier = self.random_token();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < ttl);
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                provider: provider.to_string(),
+                code_verifier: code_verifier.clone(),
+                redirect_uri,
+                started: Instant::now(),
+            },
+        );
+        (state, code_verifier)
+    }
+
+    // Each state is good for one callback
+    fn finish(&self, state: &str, ttl: Duration) -> Option<PendingLogin> {
+        let login = self.pending.lock().unwrap().remove(state)?;
+        Some(login).filter(|login| login.started.elapsed() < ttl)
+    }
+}
+
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes()))
+}
+
+/// Identity asserted by the provider's userinfo endpoint.
+#[derive(Debug)]
+struct Identity {
+    subject: String,
+    email: Option<String>,
+    email_verified: bool,
+    username: Option<String>,
+    name: Option<String>,
+}
+
+impl Identity {
+    // OIDC userinfo uses `sub`; GitHub's user API uses a numeric `id` and `login`
+    fn from_userinfo(info: &Value) -> Option<Self> {
+        let subject = match info.get("sub").or_else(|| info.get("id"))? {
+            Value::String(id) => id.clone(),
+            Value::Number(id) => id.to_string(),
+            _ => return None,
+        };
+        let text = |key: &str| info.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(Identity {
+            subject,
+            email: text("email"),
+            email_verified: info.get("email_verified").and_then(Value::as_bool).unwrap_or(false),
+            username: text("preferred_username").or_else(|| text("login")),
+            name: text("name"),
+        })
+    }
+}
+
+fn error_response(status: actix_web::http::StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({ "error": message }))
+}
+
+fn count(data: &AppState, provider: &str, outcome: &str) {
+    data.metrics
+        .incr("gateway_oidc_logins_total", &[("provider", provider), ("outcome", outcome)], 1);
+}
+
+// Send the browser to the provider, remembering the PKCE verifier for the callback
+pub async fn login(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let oidc = &data.config.load().oidc;
+    let provider = match query.get("provider").and_then(|name| oidc.provider(name)) {
+        Some(provider) => provider,
+        None => {
+            let names: Vec<&str> = oidc.providers.iter().map(|p| p.name.as_str()).collect();
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Unknown identity provider",
+                "providers": names
+            })));
+        }
+    };
+
+    let redirect_uri = oidc.redirect_uri.clone().unwrap_or_else(|| {
+        let connection = req.connection_info();
+        format!("{}://{}/api/auth/oidc/callback", connection.scheme(), connection.host())
+    });
+    let (state, verifier) = data.oidc.start(&provider.name, redirect_uri.clone(), oidc.state_ttl);
+    let scope = provider.scopes.join(" ");
+    let challenge = code_challenge(&verifier);
+    let location = reqwest::Url::parse_with_params(
+        &provider.authorization_url,
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("scope", scope.as_str()),
+            ("state", state.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    );
+    match location {
+        Ok(location) => Ok(HttpResponse::Found()
+            .insert_header((header::LOCATION, location.as_str()))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .finish()),
+        Err(e) => {
+            error!("Invalid authorization URL for OIDC provider {}: {}", provider.name, e);
+            Ok(error_response(
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Identity provider misconfigured",
+            ))
+        }
+    }
+}
+
+// Trade the authorization code for the provider's access token, then ask the
+// provider who the user is. The identity comes straight from the provider over
+// the back channel, so no id_token signature needs checking.
+async fn fetch_identity(
+    data: &AppState,
+    provider: &OidcProvider,
+    code: &str,
+    login: &PendingLogin,
+) -> Result<Identity, String> {
+    let token: Value = data
+        .http_client
+        .post(&provider.token_url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", login.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("code_verifier", login.code_verifier.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|e| format!("token request failed: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("invalid token response: {}", e))?;
+    let access_token = token.get("access_token").and_then(Value::as_str).ok_or_else(|| {
+        let reason = token.get("error").and_then(Value::as_str).unwrap_or("no access_token");
+        format!("code exchange rejected: {}", reason)
+    })?;
+
+    let response = data
+        .http_client
+        .get(&provider.userinfo_url)
+        .bearer_auth(access_token)
+        .header(reqwest::header::ACCEPT, "application/json")
+        // GitHub's API refuses requests without one
+        .header(reqwest::header::USER_AGENT, "chatapp-gateway")
+        .send()
+        .await
+        .map_err(|e| format!("userinfo request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("userinfo endpoint answered {}", response.status()));
+    }
+    let info: Value = response.json().await.map_err(|e| format!("invalid userinfo: {}", e))?;
+    Identity::from_userinfo(&info).ok_or_else(|| "userinfo has no subject".to_string())
+}
+
+// Find or create the user-service account for an external identity
+async fn link_account(data: &AppState, req: &HttpRequest, provider: &str, identity: &Identity) -> Result<Value, String> {
+    let body = serde_json::json!({
+        "provider": provider,
+        "subject": identity.subject,
+        "email": identity.email,
+        "emailVerified": identity.email_verified,
+        "username": identity.username,
+        "name": identity.name,
+    });
+    let response = crate::proxy_request(data, req, "user", &data.config.load().oidc.link_path, "POST", Some(body))
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body()).await.map_err(|_| "unreadable user service response")?;
+    if !status.is_success() {
+        return Err(format!("user service answered {}", status));
+    }
+    let json: Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+    json.get("user").cloned().ok_or_else(|| "user service response has no user".to_string())
+}
+
+// Complete the login: check state, exchange the code, map the identity to an
+// account and hand out gateway tokens
+pub async fn callback(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    use actix_web::http::StatusCode;
+
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let oidc = &data.config.load().oidc;
+    let login = match query.get("state").and_then(|state| data.oidc.finish(state, oidc.state_ttl)) {
+        Some(login) => login,
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Invalid or expired login state")),
+    };
+    let provider = match oidc.provider(&login.provider) {
+        Some(provider) => provider,
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Unknown identity provider")),
+    };
+    if let Some(reason) = query.get("error") {
+        info!("OIDC login with {} declined: {}", provider.name, reason);
+        count(&data, &provider.name, "declined");
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "Sign-in was declined"));
+    }
+    let code = match query.get("code") {
+        Some(code) => code,
+        None => return Ok(error_response(StatusCode::BAD_REQUEST, "Authorization code missing")),
+    };
+
+    let identity = match fetch_identity(&data, provider, code, &login).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("OIDC login with {} failed: {}", provider.name, e);
+            count(&data, &provider.name, "provider_error");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Could not verify sign-in with the identity provider"));
+        }
+    };
+    let user = match link_account(&data, &req, &provider.name, &identity).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Could not map {} identity {} to an account: {}", provider.name, identity.subject, e);
+            count(&data, &provider.name, "link_error");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Could not sign in with this account"));
+        }
+    };
+
+    let sub = match user.get("id") {
+        Some(Value::String(id)) => id.clone(),
+        Some(id) if !id.is_null() => id.to_string(),
+        _ => {
+            count(&data, &provider.name, "link_error");
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "Could not sign in with this account"));
+        }
+    };
+    let username = user.get("username").and_then(Value::as_str).unwrap_or_default();
+    let grants = refresh::Grants {
+        roles: refresh::user_roles(&user),
+        scope: None,
+        ..Default::default()
+    };
+    let grants = devices::start(&data, &req, &sub, grants);
+    let tokens = if data.refresh.enabled() {
+        data.refresh.issue(&sub, username, &grants).map(|pair| refresh::tokens_json(&pair))
+    } else {
+        data.refresh
+            .issue_access(&sub, username, &grants)
+            .map(|(token, expires_in)| serde_json::json!({ "accessToken": token, "expiresIn": expires_in, "sessionId": grants.sid }))
+    };
+    let mut tokens = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            error!("Failed to issue tokens for user {}: {}", sub, e);
+            return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, "Could not issue tokens"));
+        }
+    };
+    count(&data, &provider.name, "success");
+    info!("User {} signed in with {}", sub, provider.name);
+    let session_cookie = session::take_access_token(&data.config.load().session, &mut tokens);
+
+    if let Some(target) = &oidc.success_redirect {
+        let fragment: Vec<String> = tokens
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| match value {
+                Value::String(s) => format!("{}={}", key, s),
+                other => format!("{}={}", key, other),
+            })
+            .collect();
+        let mut response = HttpResponse::Found();
+        if let Some(cookie) = session_cookie {
+            response.cookie(cookie);
+        }
+        return Ok(response
+            .insert_header((header::LOCATION, format!("{}#{}", target, fragment.join("&"))))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .finish());
+    }
+    let mut response = HttpResponse::Ok();
+    if let Some(cookie) = session_cookie {
+        response.cookie(cookie);
+    }
+    Ok(response
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(serde_json::json!({
+            "user": user,
+            "provider": provider.name,
+            "tokens": tokens,
+        })))
+}
@@ -0,0 +1,254 @@
+use actix_web::{web, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::policies::Access;
+use crate::validation::{
+    AuthRequest, ChangePasswordRequest, CreateRoomRequest, CreateUserRequest, SendMessageRequest,
+};
+use crate::{AppState, Config};
+
+/// Request body described in the document's components, next to the
+/// constraints it is validated against.
+pub trait ApiSchema {
+    const NAME: &'static str;
+    fn schema() -> Value;
+}
+
+/// An endpoint the gateway serves itself or proxies to a built-in service.
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    /// Needs a bearer token, session cookie or API key
+    secured: bool,
+    /// Component schema of the JSON body
+    body: Option<&'static str>,
+}
+
+const fn op(method: &'static str, path: &'static str, tag: &'static str, summary: &'static str) -> Operation {
+    Operation {
+        method,
+        path,
+        tag,
+        summary,
+        secured: false,
+        body: None,
+    }
+}
+
+const fn secured(operation: Operation) -> Operation {
+    Operation {
+        secured: true,
+        ..operation
+    }
+}
+
+const fn with_body(operation: Operation, body: &'static str) -> Operation {
+    Operation {
+        body: Some(body),
+        ..operation
+    }
+}
+
+// Mirrors the routes registered in `main`; catch-all paths are proxied as
+// they are, `{endpoint}` possibly spanning several segments
+const OPERATIONS: &[Operation] = &[
+    op("get", "/", "gateway", "Gateway version and endpoint overview"),
+    op("get", "/health", "gateway", "Health of every upstream instance"),
+    op("get", "/health/ready", "gateway", "Readiness to take traffic"),
+    op("get", "/metrics", "gateway", "Prometheus metrics"),
+    op("get", "/openapi.json", "gateway", "This document"),
+    with_body(op("post", "/api/auth/login", "auth", "Log in with a username and password"), AuthRequest::NAME),
+    with_body(op("post", "/api/auth/register", "auth", "Create an account"), CreateUserRequest::NAME),
+    op("post", "/api/auth/refresh", "auth", "Exchange a refresh token for new tokens"),
+    op("post", "/api/auth/logout", "auth", "End the session"),
+    op("post", "/api/auth/{endpoint}", "auth", "Other account operations, proxied to the user service"),
+    op("get", "/api/auth/csrf", "auth", "CSRF token for cookie sessions"),
+    op("post", "/api/auth/mfa/verify", "auth", "Answer a login's second-factor challenge"),
+    op("post", "/api/auth/guest", "auth", "Token for a temporary guest account"),
+    op("get", "/api/auth/oidc/login", "auth", "Start a login with an OpenID Connect provider"),
+    op("get", "/api/auth/oidc/callback", "auth", "Complete an OpenID Connect login"),
+    secured(op("get", "/api/auth/sessions", "auth", "Devices signed in to the account")),
+    secured(op("delete", "/api/auth/sessions/{id}", "auth", "Sign a device out")),
+    secured(with_body(op("put", "/api/users/change-password", "users", "Change the account's password"), ChangePasswordRequest::NAME)),
+    op("get", "/api/users/{endpoint}", "users", "Proxied to the user service"),
+    op("post", "/api/users/{endpoint}", "users", "Proxied to the user service"),
+    op("put", "/api/users/{endpoint}", "users", "Proxied to the user service"),
+    op("delete", "/api/users/{endpoint}", "users", "Proxied to the user service"),
+    secured(with_body(op("post", "/api/chat/rooms", "chat", "Create a room"), CreateRoomRequest::NAME)),
+    secured(op("get", "/api/chat/rooms/{room_id}/overview", "chat", "A room, its members and recent messages in one answer")),
+    secured(op("get", "/api/chat/{endpoint}", "chat", "Proxied to the chat service")),
+    secured(op("post", "/api/chat/{endpoint}", "chat", "Proxied to the chat service")),
+    secured(op("put", "/api/chat/{endpoint}", "chat", "Proxied to the chat service")),
+    secured(op("delete", "/api/chat/{endpoint}", "chat", "Proxied to the chat service")),
+    secured(with_body(op("post", "/api/messages/messages", "messages", "Send a message"), SendMessageRequest::NAME)),
+    secured(op("get", "/api/messages/{endpoint}", "messages", "Proxied to the message service")),
+    secured(op("post", "/api/messages/{endpoint}", "messages", "Proxied to the message service")),
+    secured(op("put", "/api/messages/{endpoint}", "messages", "Proxied to the message service")),
+    secured(op("delete", "/api/messages/{endpoint}", "messages", "Proxied to the message service")),
+    secured(op("post", "/api/media/sign", "media", "Signed URL for a media file")),
+    secured(op("get", "/media/{path}", "media", "Download a media file")),
+    secured(op("get", "/ws/{room_id}", "chat", "WebSocket connection to a room")),
+    op("post", "/webhooks/{integration}", "webhooks", "Signed webhook from an integration"),
+];
+
+fn schemas() -> Value {
+    let mut schemas = Map::new();
+    schemas.insert(AuthRequest::NAME.to_string(), AuthRequest::schema());
+    schemas.insert(CreateUserRequest::NAME.to_string(), CreateUserRequest::schema());
+    schemas.insert(ChangePasswordRequest::NAME.to_string(), ChangePasswordRequest::schema());
+    schemas.insert(CreateRoomRequest::NAME.to_string(), CreateRoomRequest::schema());
+    schemas.insert(SendMessageRequest::NAME.to_string(), SendMessageRequest::schema());
+    Value::Object(schemas)
+}
+
+fn operation(tag: &str, summary: &str, path: &str, secured: bool, body: Option<&str>) -> Value {
+    let parameters: Vec<Value> = path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+        .collect();
+    let mut responses = json!({ "200": { "description": "Success" } });
+    if body.is_some() {
+        responses["400"] = json!({ "description": "Invalid request body" });
+    }
+    if secured {
+        responses["401"] = json!({ "description": "Missing or invalid credentials" });
+        responses["403"] = json!({ "description": "Insufficient role or scope" });
+    }
+    let mut operation = json!({
+        "tags": [tag],
+        "summary": summary,
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        operation["parameters"] = json!(parameters);
+    }
+    if secured {
+        operation["security"] = json!([{ "bearerAuth": [] }, { "apiKey": [] }]);
+    }
+    if let Some(body) = body {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": {
+                "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", body) } }
+            }
+        });
+    }
+    operation
+}
+
+/// OpenAPI 3 document for the gateway's own routes, the built-in services
+/// and the services configured in `ROUTES`.
+pub fn document(config: &Config) -> Value {
+    let mut paths: Map<String, Value> = Map::new();
+    let mut add = |method: &str, path: &str, operation: Value| {
+        let item = paths.entry(path.to_string()).or_insert_with(|| json!({}));
+        item[method] = operation;
+    };
+    for entry in OPERATIONS {
+        add(entry.method, entry.path, operation(entry.tag, entry.summary, entry.path, entry.secured, entry.body));
+    }
+    for route in &config.routes.routes {
+        let path = format!("{}/{{endpoint}}", route.prefix);
+        let summary = format!("Proxied to the {} service", route.service);
+        let secured = route.access == Access::Authenticated || !route.roles.is_empty() || !route.scopes.is_empty();
+        for method in ["get", "post", "put", "delete"] {
+            add(method, &path, operation(&route.service, &summary, &path, secured, None));
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Chat Gateway API",
+            "version": "1.0.0",
+            "description": "API Gateway for Chat Application Microservices",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+        },
+    })
+}
+
+pub async fn spec(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(document(&data.config.load()))
+}
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Chat Gateway API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// Swagger UI over `/openapi.json`, loaded from a CDN; not served in
+/// production.
+pub async fn docs(data: web::Data<AppState>) -> HttpResponse {
+    if data.config.load().profile.is_production() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(SWAGGER_UI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    // A path matching a documented template
+    fn concrete(template: &str) -> String {
+        template
+            .split('/')
+            .map(|segment| if segment.starts_with('{') { "x1" } else { segment })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    async fn status(method: &str, path: &str) -> StatusCode {
+        // Without app data, routed handlers fail to extract it instead of
+        // reaching upstreams; only 404 and 405 mean nothing is routed
+        let app = init_service(App::new().configure(crate::routes)).await;
+        let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+        call_service(&app, TestRequest::default().method(method).uri(path).to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn documented_operations_are_routed() {
+        for entry in OPERATIONS {
+            let status = status(entry.method, &concrete(entry.path)).await;
+            assert!(
+                !matches!(status, StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED),
+                "{} {} is documented but answers {}",
+                entry.method,
+                entry.path,
+                status
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn undocumented_operations_are_not_routed() {
+        assert!(matches!(status("patch", "/api/chat/rooms").await, StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED));
+        assert!(matches!(status("get", "/api/auth/login").await, StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(status("get", "/nowhere").await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,228 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use tracing::warn;
+use std::env;
+
+use crate::config::invalid;
+
+/// An origin as compared: lowercase scheme, host and effective port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Origin {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    // Browsers send `scheme://host[:port]`; anything else (paths, user info,
+    // `null`) is not an origin a browser would send and never matches
+    fn parse(raw: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(raw.trim()).ok()?;
+        if !matches!(url.scheme(), "http" | "https")
+            || !url.username().is_empty()
+            || url.password().is_some()
+            || url.path() != "/"
+            || url.query().is_some()
+            || url.fragment().is_some()
+        {
+            return None;
+        }
+        Some(Origin {
+            scheme: url.scheme().to_string(),
+            host: url.host_str()?.to_ascii_lowercase(),
+            port: url.port_or_known_default()?,
+        })
+    }
+}
+
+/// A trusted origin, or every subdomain of one when written
+/// `https://*.example.com`.
+#[derive(Debug, Clone)]
+struct OriginPattern {
+    origin: Origin,
+    subdomains: bool,
+}
+
+impl OriginPattern {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw.split_once("://*.") {
+            Some((scheme, rest)) => Some(OriginPattern {
+                origin: Origin::parse(&format!("{}://{}", scheme, rest))?,
+                subdomains: true,
+            }),
+            None => Some(OriginPattern {
+                origin: Origin::parse(raw)?,
+                subdomains: false,
+            }),
+        }
+    }
+
+    fn matches(&self, origin: &Origin) -> bool {
+        let host_matches = match self.subdomains {
+            true => origin
+                .host
+                .strip_suffix(&self.origin.host)
+                .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+            false => origin.host == self.origin.host,
+        };
+        host_matches && origin.scheme == self.origin.scheme && origin.port == self.origin.port
+    }
+}
+
+/// Origins browsers may call the gateway from, shared by the CORS policies
+/// and the WebSocket handshake check.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedOrigins {
+    patterns: Vec<OriginPattern>,
+    /// Refuse WebSocket handshakes without an `Origin` header; only browsers
+    /// send one, so this shuts out other clients
+    pub require_ws_origin: bool,
+}
+
+impl TrustedOrigins {
+    /// Read `TRUSTED_ORIGINS` (comma separated, `*.` for subdomains) and
+    /// `WS_REQUIRE_ORIGIN`.
+    pub fn from_env() -> Self {
+        TrustedOrigins {
+            require_ws_origin: env::var("WS_REQUIRE_ORIGIN").map(|v| v == "true" || v == "1").unwrap_or(false),
+            ..TrustedOrigins::parse(&env::var("TRUSTED_ORIGINS").unwrap_or_default())
+        }
+    }
+
+    /// Origins from a comma separated list, skipping those that are not one.
+    pub fn parse(list: &str) -> Self {
+        let patterns = list
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .filter_map(|origin| {
+                let pattern = OriginPattern::parse(origin);
+                if pattern.is_none() {
+                    warn!("Ignoring trusted origin '{}': not a scheme://host[:port] origin", origin);
+                    invalid("TRUSTED_ORIGINS", format!("'{}' is not a scheme://host[:port] origin", origin));
+                }
+                pattern
+            })
+            .collect();
+        TrustedOrigins {
+            patterns,
+            require_ws_origin: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether `origin` is listed, compared as parsed origins so that look-alikes
+    /// such as `https://example.com.evil.io` do not pass for `https://example.com`.
+    pub fn is_trusted(&self, origin: &str) -> bool {
+        match Origin::parse(origin) {
+            Some(origin) => self.patterns.iter().any(|pattern| pattern.matches(&origin)),
+            None => false,
+        }
+    }
+
+    /// Whether a single `pattern`, as written in a CORS policy, admits `origin`.
+    pub fn pattern_matches(pattern: &str, origin: &str) -> bool {
+        match (OriginPattern::parse(pattern), Origin::parse(origin)) {
+            (Some(pattern), Some(origin)) => pattern.matches(&origin),
+            _ => false,
+        }
+    }
+
+    /// Check the `Origin` of a WebSocket handshake: it must be trusted or the
+    /// gateway's own host, so other sites cannot open sockets with a visitor's
+    /// cookies.
+    #[allow(clippy::result_large_err)]
+    pub fn check_ws(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let origin = match req.headers().get(header::ORIGIN).map(|v| v.to_str()) {
+            Some(Ok(origin)) => origin,
+            Some(Err(_)) => return Err(refused("unreadable")),
+            None if self.require_ws_origin => return Err(refused("missing")),
+            None => return Ok(()),
+        };
+        if self.is_trusted(origin) || same_origin(req, origin) {
+            return Ok(());
+        }
+        warn!("Refused WebSocket handshake from origin {} for {}", origin, req.path());
+        Err(refused(origin))
+    }
+}
+
+// The page was served by the gateway itself
+fn same_origin(req: &HttpRequest, origin: &str) -> bool {
+    let host = match req.headers().get(header::HOST).and_then(|v| v.to_str().ok()) {
+        Some(host) => host.to_ascii_lowercase(),
+        None => return false,
+    };
+    Origin::parse(origin).is_some_and(|origin| {
+        let default_port = if origin.scheme == "https" { 443 } else { 80 };
+        let with_port = format!("{}:{}", origin.host, origin.port);
+        host == with_port || (origin.port == default_port && host == origin.host)
+    })
+}
+
+fn refused(origin: &str) -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Origin not allowed",
+        "code": "origin_not_allowed",
+        "origin": origin
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn trusted() -> TrustedOrigins {
+        TrustedOrigins::parse("https://chat.example.com, https://*.partner.io")
+    }
+
+    fn upgrade(origin: Option<&str>) -> HttpRequest {
+        let mut req = TestRequest::get()
+            .uri("/ws/1")
+            .insert_header((header::HOST, "gateway.example.com"))
+            .insert_header((header::UPGRADE, "websocket"));
+        if let Some(origin) = origin {
+            req = req.insert_header((header::ORIGIN, origin));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn admits_trusted_and_same_origin_handshakes() {
+        let trusted = trusted();
+        for origin in ["https://chat.example.com", "https://eu.partner.io", "https://gateway.example.com"] {
+            assert!(trusted.check_ws(&upgrade(Some(origin))).is_ok(), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn refuses_spoofed_handshake_origins() {
+        let trusted = trusted();
+        for origin in [
+            "https://evil.io",
+            "https://chat.example.com.evil.io",
+            "https://evilchat.example.com",
+            "http://chat.example.com",
+            "https://chat.example.com:8443",
+            "https://partner.io",
+            "https://user@chat.example.com",
+            "null",
+        ] {
+            let refused = trusted.check_ws(&upgrade(Some(origin))).expect_err(origin);
+            assert_eq!(refused.status(), StatusCode::FORBIDDEN, "{}", origin);
+        }
+    }
+
+    #[test]
+    fn requires_an_origin_when_configured() {
+        let mut trusted = trusted();
+        assert!(trusted.check_ws(&upgrade(None)).is_ok());
+        trusted.require_ws_origin = true;
+        assert!(trusted.check_ws(&upgrade(None)).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use tracing::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::parse_env;
+use crate::metrics::Metrics;
+use crate::upstream::Upstream;
+
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    /// Number of recent calls considered per instance
+    pub window: usize,
+    /// Calls required in the window before an instance can be ejected
+    pub min_requests: usize,
+    /// Error ratio (0.0-1.0) at or above which an instance is ejected
+    pub max_error_rate: f64,
+    /// Average latency above which an instance is ejected, disabled when unset
+    pub max_latency: Option<Duration>,
+    /// Base ejection time, multiplied by the number of times the instance was ejected
+    pub ejection_duration: Duration,
+    /// Upper bound on the share of a service's instances ejected at once
+    pub max_ejection_percent: u32,
+}
+
+impl OutlierConfig {
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+            parse_env(key)
+        }
+        OutlierConfig {
+            window: parse("OUTLIER_WINDOW").filter(|w| *w > 0).unwrap_or(20),
+            min_requests: parse("OUTLIER_MIN_REQUESTS").unwrap_or(10),
+            max_error_rate: parse("OUTLIER_MAX_ERROR_RATE").unwrap_or(0.5),
+            max_latency: parse("OUTLIER_MAX_LATENCY_MS").filter(|ms| *ms > 0).map(Duration::from_millis),
+            ejection_duration: Duration::from_secs(parse("OUTLIER_EJECTION_SECONDS").unwrap_or(30)),
+            max_ejection_percent: parse("OUTLIER_MAX_EJECTION_PERCENT").unwrap_or(50),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct InstanceStats {
+    outcomes: VecDeque<(bool, Duration)>,
+    ejected_until: Option<Instant>,
+    ejections: u32,
+}
+
+/// Tracks error rates and latencies per upstream instance and temporarily
+/// ejects instances that misbehave compared to the configured thresholds.
+pub struct OutlierDetector {
+    config: OutlierConfig,
+    instances: Mutex<HashMap<String, InstanceStats>>,
+    metrics: Arc<Metrics>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierConfig, metrics: Arc<Metrics>) -> Self {
+        OutlierDetector {
+            config,
+            instances: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Whether `instance` is currently ejected; instances whose ejection has
+    /// expired are re-admitted on probation with a fresh window.
+    pub fn is_ejected(&self, instance: &str) -> bool {
+        let mut instances = self.instances.lock().unwrap();
+        let stats = match instances.get_mut(instance) {
+            Some(stats) => stats,
+            None => return false,
+        };
+        match stats.ejected_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                info!("Re-admitting upstream instance {} on probation", instance);
+                stats.ejected_until = None;
+                stats.outcomes.clear();
+                self.metrics.gauge_add("gateway_upstream_ejected_instances", &[], -1.0);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record the outcome of a call to one instance of `upstream`.
+    pub fn record(&self, upstream: &Upstream, instance: &str, success: bool, latency: Duration) {
+        let mut instances = self.instances.lock().unwrap();
+
+        let ejected_now = upstream
+            .instances()
+            .iter()
+            .filter(|other| {
+                instances
+                    .get(other.as_str())
+                    .and_then(|stats| stats.ejected_until)
+                    .map(|until| Instant::now() < until)
+                    .unwrap_or(false)
+            })
+            .count();
+
+        let stats = instances.entry(instance.to_string()).or_default();
+        if stats.ejected_until.is_some() {
+            return;
+        }
+
+        stats.outcomes.push_back((success, latency));
+        if stats.outcomes.len() > self.config.window {
+            stats.outcomes.pop_front();
+        }
+        if stats.outcomes.len() < self.config.min_requests.max(1) {
+            return;
+        }
+
+        let calls = stats.outcomes.len() as f64;
+        let errors = stats.outcomes.iter().filter(|(ok, _)| !ok).count() as f64;
+        let error_rate = errors / calls;
+        let avg_latency = stats.outcomes.iter().map(|(_, latency)| *latency).sum::<Duration>() / stats.outcomes.len() as u32;
+
+        let too_many_errors = error_rate >= self.config.max_error_rate;
+        let too_slow = self.config.max_latency.map(|max| avg_latency > max).unwrap_or(false);
+        if !too_many_errors && !too_slow {
+            // A full healthy window forgives earlier ejections
+            if stats.outcomes.len() >= self.config.window {
+                stats.ejections = 0;
+            }
+            return;
+        }
+
+        let max_ejected = upstream.instances().len() * self.config.max_ejection_percent as usize / 100;
+        if ejected_now >= max_ejected {
+            return;
+        }
+
+        stats.ejections += 1;
+        let duration = self.config.ejection_duration * stats.ejections;
+        stats.ejected_until = Some(Instant::now() + duration);
+        stats.outcomes.clear();
+
+        warn!(
+            "Ejecting {} instance {} for {:?} (error rate {:.0}%, avg latency {:?})",
+            upstream.name,
+            instance,
+            duration,
+            error_rate * 100.0,
+            avg_latency
+        );
+        self.metrics.incr(
+            "gateway_upstream_ejections_total",
+            &[("service", &upstream.name), ("instance", instance)],
+            1,
+        );
+        self.metrics.gauge_add("gateway_upstream_ejected_instances", &[], 1.0);
+    }
+}
@@ -0,0 +1,94 @@
+use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::sync::RwLock;
+
+/// Operator overrides of upstream load balancing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OverrideState {
+    /// Service name to the only instance URL it may be sent to
+    #[serde(default)]
+    pub pins: BTreeMap<String, String>,
+    /// Instance URLs taken out of rotation
+    #[serde(default)]
+    pub excluded: BTreeSet<String>,
+}
+
+/// Runtime pins and exclusions set through the admin API. They are written to
+/// `UPSTREAM_OVERRIDES_FILE` when set, so they survive restarts until cleared.
+pub struct UpstreamOverrides {
+    state: RwLock<OverrideState>,
+    file: Option<String>,
+}
+
+impl UpstreamOverrides {
+    pub fn from_env() -> Self {
+        let file = env::var("UPSTREAM_OVERRIDES_FILE").ok().filter(|path| !path.is_empty());
+        let state = file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|raw| {
+                serde_json::from_str::<OverrideState>(&raw)
+                    .map_err(|e| error!("Invalid upstream overrides file: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+        if !state.pins.is_empty() || !state.excluded.is_empty() {
+            info!("Restored upstream overrides: {:?}", state);
+        }
+        UpstreamOverrides {
+            state: RwLock::new(state),
+            file,
+        }
+    }
+
+    pub fn snapshot(&self) -> OverrideState {
+        self.state.read().unwrap().clone()
+    }
+
+    pub fn pinned(&self, service: &str) -> Option<String> {
+        self.state.read().unwrap().pins.get(service).cloned()
+    }
+
+    pub fn is_excluded(&self, instance: &str) -> bool {
+        self.state.read().unwrap().excluded.contains(instance)
+    }
+
+    pub fn pin(&self, service: &str, target: &str) {
+        self.modify(|state| {
+            state.pins.insert(service.to_string(), target.to_string());
+        });
+    }
+
+    pub fn unpin(&self, service: &str) -> bool {
+        self.modify(|state| state.pins.remove(service).is_some())
+    }
+
+    pub fn exclude(&self, instance: &str) {
+        self.modify(|state| {
+            state.excluded.insert(instance.to_string());
+        });
+    }
+
+    pub fn include(&self, instance: &str) -> bool {
+        self.modify(|state| state.excluded.remove(instance))
+    }
+
+    fn modify<T>(&self, apply: impl FnOnce(&mut OverrideState) -> T) -> T {
+        let mut state = self.state.write().unwrap();
+        let result = apply(&mut state);
+        if let Some(path) = &self.file {
+            let tmp = format!("{}.tmp", path);
+            let written = serde_json::to_string_pretty(&*state)
+                .map_err(|e| e.to_string())
+                .and_then(|json| fs::write(&tmp, json).map_err(|e| e.to_string()))
+                .and_then(|_| fs::rename(&tmp, path).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                error!("Failed to persist upstream overrides to {}: {}", path, e);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,165 @@
+use actix_web::body;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures_util::future::join;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::parse_env;
+use crate::{forward, AppState};
+
+#[derive(Debug, Clone)]
+pub struct OverviewConfig {
+    /// How long each service may take before its part is left out
+    pub timeout: Duration,
+    /// Recent messages included when the client does not ask for a number
+    pub messages: usize,
+    pub max_messages: usize,
+}
+
+impl OverviewConfig {
+    /// Read `ROOM_OVERVIEW_TIMEOUT_MS` (3 seconds by default),
+    /// `ROOM_OVERVIEW_MESSAGES` (50) and `ROOM_OVERVIEW_MAX_MESSAGES` (200).
+    pub fn from_env() -> Self {
+        OverviewConfig {
+            timeout: Duration::from_millis(parse_env("ROOM_OVERVIEW_TIMEOUT_MS").unwrap_or(3000)),
+            messages: parse_env("ROOM_OVERVIEW_MESSAGES").unwrap_or(50),
+            max_messages: parse_env("ROOM_OVERVIEW_MAX_MESSAGES").unwrap_or(200),
+        }
+    }
+}
+
+/// Why a part of the overview is missing.
+struct Failure {
+    status: StatusCode,
+    body: Value,
+}
+
+// One upstream call, its answer read back as JSON
+async fn part(data: &AppState, req: &HttpRequest, service: &str, path: &str, timeout: Duration) -> Result<Value, Failure> {
+    let response = match tokio::time::timeout(timeout, forward(data, req, service, path, "GET", None, None)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            return Err(Failure {
+                status: StatusCode::BAD_GATEWAY,
+                body: json!({ "error": e.to_string() }),
+            })
+        }
+        Err(_) => {
+            return Err(Failure {
+                status: StatusCode::GATEWAY_TIMEOUT,
+                body: json!({ "error": format!("No answer within {:?}", timeout) }),
+            })
+        }
+    };
+    let status = response.status();
+    let body = body::to_bytes(response.into_body())
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or(Value::Null);
+    match status.is_success() {
+        true => Ok(body),
+        false => Err(Failure { status, body }),
+    }
+}
+
+fn error(part: &str, failure: &Failure) -> Value {
+    json!({
+        "part": part,
+        "status": failure.status.as_u16(),
+        "error": failure.body.get("error").or_else(|| failure.body.get("detail")).cloned().unwrap_or(Value::Null),
+    })
+}
+
+/// Handle `GET /api/chat/rooms/{room_id}/overview`: the room from the chat
+/// service and its recent messages from the message service, fetched
+/// concurrently. A part that fails is left out and listed under `errors`;
+/// the answer is only an error when the room itself is missing or
+/// forbidden, or no part could be fetched.
+pub async fn room(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (room_id,) = path.into_inner();
+    let config = data.config.load().overview.clone();
+    let limit = match query.get("messages").map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) => limit.min(config.max_messages),
+        Some(Err(_)) => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "messages must be a number",
+                "code": "invalid_query"
+            })))
+        }
+        None => config.messages,
+    };
+
+    let room_path = format!("/rooms/{}", room_id);
+    let messages_path = format!("/rooms/{}/messages", room_id);
+    let (room, messages) = join(
+        part(&data, &req, "chat", &room_path, config.timeout),
+        part(&data, &req, "message", &messages_path, config.timeout),
+    )
+    .await;
+
+    // Nothing to show for a room the client cannot see
+    if let Err(failure) = &room {
+        if matches!(failure.status.as_u16(), 401 | 403 | 404) {
+            return Ok(HttpResponse::build(failure.status).json(&failure.body));
+        }
+    }
+
+    let mut errors = Vec::new();
+    let room = match room {
+        Ok(room) => Some(room),
+        Err(failure) => {
+            errors.push(error("room", &failure));
+            None
+        }
+    };
+    let messages = match messages {
+        Ok(Value::Array(mut messages)) => {
+            // The message service answers oldest first
+            let skip = messages.len().saturating_sub(limit);
+            Some(Value::Array(messages.split_off(skip)))
+        }
+        Ok(other) => Some(other),
+        Err(failure) => {
+            errors.push(error("messages", &failure));
+            None
+        }
+    };
+    let members = room.as_ref().map(|room| {
+        json!({
+            "count": room.get("member_count").cloned().unwrap_or(Value::Null),
+            "online": room.get("online_members").cloned().unwrap_or(Value::Null),
+        })
+    });
+
+    if room.is_none() && messages.is_none() {
+        warn!("Room overview for {} failed: no part could be fetched", room_id);
+        return Ok(HttpResponse::BadGateway().json(json!({
+            "error": "Room overview unavailable",
+            "errors": errors,
+        })));
+    }
+    let partial = !errors.is_empty();
+    if partial {
+        warn!("Serving partial room overview for {}: {} part(s) missing", room_id, errors.len());
+    }
+    let mut response = HttpResponse::Ok();
+    if partial {
+        response.insert_header(("X-Gateway-Partial", "true"));
+    }
+    Ok(response.json(json!({
+        "room": room,
+        "members": members,
+        "messages": messages,
+        "partial": partial,
+        "errors": errors,
+    })))
+}
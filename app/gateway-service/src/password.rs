@@ -0,0 +1,316 @@
+use actix_web::HttpResponse;
+use tracing::{info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+
+use crate::config::parse_env;
+
+// Most common passwords from public breach corpora, checked even without a
+// denylist file
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111", "1234567", "dragon",
+    "123123", "baseball", "abc123", "football", "monkey", "letmein", "696969", "shadow", "master", "666666",
+    "qwertyuiop", "123321", "mustang", "1234567890", "michael", "654321", "superman", "1qaz2wsx", "7777777",
+    "121212", "000000", "qazwsx", "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm", "asdfgh",
+    "hunter", "buster", "soccer", "harley", "batman", "andrew", "tigger", "sunshine", "iloveyou", "2000",
+    "charlie", "robert", "thomas", "hockey", "ranger", "daniel", "starwars", "klaster", "112233", "george",
+    "computer", "michelle", "jessica", "pepper", "1111", "zxcvbn", "555555", "11111111", "131313", "freedom",
+    "777777", "pass", "maggie", "159753", "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda",
+    "summer", "love", "ashley", "nicole", "chelsea", "biteme", "matthew", "access", "yankees", "987654321",
+    "dallas", "austin", "thunder", "taylor", "matrix", "mobilemail", "welcome", "admin", "passw0rd", "password1",
+    "password123", "qwerty123", "login", "solo", "whatever", "secret", "chat", "chatapp", "changeme", "default",
+];
+
+// Keyboard rows walked in either direction
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm", "1qaz2wsx3edc4rfv", "qazwsxedcrfv"];
+
+#[derive(Clone)]
+pub struct PasswordConfig {
+    /// Lowest strength score (0-4, as zxcvbn scores) a new password may have
+    pub min_score: u8,
+    pub min_length: usize,
+    /// bcrypt ignores everything past 72 bytes, so longer limits buy nothing
+    pub max_length: usize,
+    /// Lowercased passwords refused outright and matched as words inside
+    /// longer ones
+    pub denylist: HashSet<String>,
+}
+
+// The denylist can run to thousands of entries; keep it out of the config log
+impl fmt::Debug for PasswordConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordConfig")
+            .field("min_score", &self.min_score)
+            .field("min_length", &self.min_length)
+            .field("max_length", &self.max_length)
+            .field("denylist", &self.denylist.len())
+            .finish()
+    }
+}
+
+impl PasswordConfig {
+    /// Read `PASSWORD_MIN_SCORE`, `PASSWORD_MIN_LENGTH`, `PASSWORD_MAX_LENGTH`
+    /// and `PASSWORD_DENYLIST_FILE`, one password per line, added to the
+    /// built-in list of common passwords.
+    pub fn from_env() -> Self {
+        let number = |key: &str, default: usize| parse_env(key).unwrap_or(default);
+        let mut denylist: HashSet<String> = COMMON_PASSWORDS.iter().map(|p| p.to_string()).collect();
+        if let Ok(path) = env::var("PASSWORD_DENYLIST_FILE") {
+            match fs::read_to_string(&path) {
+                Ok(raw) => {
+                    let before = denylist.len();
+                    denylist.extend(raw.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty()));
+                    info!("Loaded {} passwords from {}", denylist.len() - before, path);
+                }
+                Err(e) => warn!("Cannot read password denylist {}: {}", path, e),
+            }
+        }
+        PasswordConfig {
+            min_score: number("PASSWORD_MIN_SCORE", 3).min(4) as u8,
+            min_length: number("PASSWORD_MIN_LENGTH", 8),
+            max_length: number("PASSWORD_MAX_LENGTH", 72),
+            denylist,
+        }
+    }
+
+    /// Whether a login may present `password`. Registration never accepts
+    /// one over `max_length`, so the user service is spared hashing it.
+    pub fn admits_login(&self, password: &str) -> bool {
+        password.len() <= self.max_length
+    }
+
+    /// Check a new password, given the account's username and email so they
+    /// cannot be reused in it. Rejections carry every problem found.
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Result<Strength, HttpResponse> {
+        let strength = estimate(password, user_inputs, &self.denylist);
+        let length = password.chars().count();
+        let mut problems = Vec::new();
+        if length < self.min_length {
+            problems.push(Problem::new("too_short", format!("Password must be at least {} characters", self.min_length)));
+        }
+        if password.len() > self.max_length {
+            problems.push(Problem::new("too_long", format!("Password must be at most {} bytes", self.max_length)));
+        }
+        if self.denylist.contains(&password.to_lowercase()) || self.denylist.contains(&unleet(&password.to_lowercase())) {
+            problems.push(Problem::new("common_password", "Password is one of the most commonly used passwords".to_string()));
+        }
+        if strength.patterns.contains(&"user_input") {
+            problems.push(Problem::new("contains_user_input", "Password must not contain your username or email".to_string()));
+        }
+        if strength.score < self.min_score {
+            problems.push(Problem::new(
+                "too_guessable",
+                format!("Password is too easy to guess (strength {} of 4, at least {} required)", strength.score, self.min_score),
+            ));
+        }
+        if problems.is_empty() {
+            return Ok(strength);
+        }
+        Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Weak password",
+            "code": "weak_password",
+            "score": strength.score,
+            "min_score": self.min_score,
+            "problems": problems,
+            "suggestions": strength.suggestions(),
+        })))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Problem {
+    code: &'static str,
+    message: String,
+}
+
+impl Problem {
+    fn new(code: &'static str, message: String) -> Self {
+        Problem { code, message }
+    }
+}
+
+/// How hard a password is to guess.
+#[derive(Debug)]
+pub struct Strength {
+    /// 0 (trivial) to 4 (very strong), on zxcvbn's guess thresholds
+    pub score: u8,
+    /// Kinds of predictable pieces found
+    pub patterns: Vec<&'static str>,
+}
+
+impl Strength {
+    fn suggestions(&self) -> Vec<&'static str> {
+        let mut suggestions: Vec<_> = self
+            .patterns
+            .iter()
+            .map(|pattern| match *pattern {
+                "dictionary" => "Avoid common passwords and words, even with letters swapped for symbols",
+                "user_input" => "Leave your username and email out of the password",
+                "repeat" => "Avoid repeated characters like aaa",
+                "sequence" => "Avoid sequences like abc or 1234",
+                _ => "Avoid keyboard patterns like qwerty",
+            })
+            .collect();
+        suggestions.push("Use a longer password, e.g. several unrelated words");
+        suggestions
+    }
+}
+
+// Undo the usual letter-for-symbol swaps
+fn unleet(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '4' | '@' => 'a',
+            '3' => 'e',
+            '1' | '!' => 'i',
+            '0' => 'o',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+// A predictable run of characters and the guesses needed to hit it
+struct Match {
+    start: usize,
+    end: usize,
+    pattern: &'static str,
+    guesses: f64,
+}
+
+fn charset_size(chars: &[char]) -> f64 {
+    let mut size = 0.0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        size += 26.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        size += 26.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        size += 10.0;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        size += 33.0;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        size += 100.0;
+    }
+    f64::max(size, 10.0)
+}
+
+fn find_matches(chars: &[char], user_inputs: &[String], denylist: &HashSet<String>) -> Vec<Match> {
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    let plain: String = lower.iter().collect();
+    let unleeted: Vec<char> = unleet(&plain).chars().collect();
+    if lower.len() != chars.len() || unleeted.len() != chars.len() {
+        // Case folding changed the length; fall back to brute force
+        return Vec::new();
+    }
+    let mut matches = Vec::new();
+
+    for start in 0..chars.len() {
+        for end in start + 3..=chars.len() {
+            let word: String = lower[start..end].iter().collect();
+            let unleeted_word: String = unleeted[start..end].iter().collect();
+            let variations = if chars[start..end].iter().any(|c| c.is_ascii_uppercase()) { 2.0 } else { 1.0 };
+            if user_inputs.iter().any(|input| *input == word || *input == unleeted_word) {
+                matches.push(Match { start, end, pattern: "user_input", guesses: 10.0 * variations });
+            } else if end - start >= 4 && (denylist.contains(&word) || denylist.contains(&unleeted_word)) {
+                let leet = if word == unleeted_word { 1.0 } else { 2.0 };
+                matches.push(Match { start, end, pattern: "dictionary", guesses: 1e4 * variations * leet });
+            }
+        }
+    }
+
+    // Runs of one repeated character, ascending or descending sequences and
+    // keyboard walks
+    let mut start = 0;
+    while start < lower.len() {
+        let mut end = start + 1;
+        while end < lower.len() && lower[end] == lower[start] {
+            end += 1;
+        }
+        if end - start >= 3 {
+            matches.push(Match { start, end, pattern: "repeat", guesses: charset_size(&chars[start..start + 1]) * (end - start) as f64 });
+        }
+        start = end;
+    }
+    for step in [1i32, -1] {
+        let mut start = 0;
+        while start + 1 < lower.len() {
+            let mut end = start + 1;
+            while end < lower.len() && lower[end] as i32 - lower[end - 1] as i32 == step && lower[end].is_ascii_alphanumeric() {
+                end += 1;
+            }
+            if end - start >= 3 {
+                matches.push(Match { start, end, pattern: "sequence", guesses: 26.0 * (end - start) as f64 });
+            }
+            start = end.max(start + 1);
+        }
+    }
+    for row in KEYBOARD_ROWS {
+        let reversed: String = row.chars().rev().collect();
+        for row in [row.to_string(), reversed] {
+            for start in 0..lower.len() {
+                let mut end = start;
+                while end < lower.len() && row.contains(&lower[start..=end].iter().collect::<String>()) {
+                    end += 1;
+                }
+                if end - start >= 4 {
+                    matches.push(Match { start, end, pattern: "keyboard", guesses: 50.0 * (end - start) as f64 });
+                }
+            }
+        }
+    }
+    matches
+}
+
+/// Estimate guesses zxcvbn-style: predictable pieces (common passwords, the
+/// user's own details, repeats, sequences, keyboard walks) cost a handful of
+/// guesses each, the remaining characters cost the full character set.
+pub fn estimate(password: &str, user_inputs: &[&str], denylist: &HashSet<String>) -> Strength {
+    let chars: Vec<char> = password.chars().collect();
+    let user_inputs: Vec<String> = user_inputs
+        .iter()
+        .flat_map(|input| {
+            let input = input.to_lowercase();
+            // An email's local part is as guessable as the whole address
+            let local = input.split('@').next().unwrap_or_default().to_string();
+            [input, local]
+        })
+        .filter(|input| input.chars().count() >= 3)
+        .collect();
+
+    // Prefer the longest pieces, then the cheapest, without overlaps
+    let mut matches = find_matches(&chars, &user_inputs, denylist);
+    matches.sort_by(|a, b| (b.end - b.start).cmp(&(a.end - a.start)).then(a.guesses.total_cmp(&b.guesses)));
+    let mut covered = vec![false; chars.len()];
+    let mut patterns = Vec::new();
+    let mut guesses_log10 = 0.0;
+    for m in matches {
+        if covered[m.start..m.end].iter().any(|c| *c) {
+            continue;
+        }
+        covered[m.start..m.end].iter_mut().for_each(|c| *c = true);
+        guesses_log10 += m.guesses.log10();
+        if !patterns.contains(&m.pattern) {
+            patterns.push(m.pattern);
+        }
+    }
+    let free = covered.iter().filter(|c| !**c).count();
+    guesses_log10 += free as f64 * charset_size(&chars).log10();
+
+    let score = match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    };
+    Strength { score, patterns }
+}
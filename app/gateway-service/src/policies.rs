@@ -0,0 +1,201 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpResponse;
+use tracing::{error, info, warn};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+
+use crate::auth::Claims;
+use crate::config::invalid;
+use crate::rbac::{route_matches, routed_path};
+
+/// Whether a route can be reached without credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Public,
+    Authenticated,
+}
+
+/// Gateway routes under `/api` that answer callers without a token; other
+/// `/api` routes no policy covers need one.
+const PUBLIC_API_ROUTES: &[&str] = &["/api/auth/*", "/api/users/*", "/api/debug/*"];
+
+fn authenticated() -> Access {
+    Access::Authenticated
+}
+
+/// Authentication required for requests matching `path` (exact, or a prefix
+/// when it ends in `*`) and `methods` (any when empty).
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutePolicy {
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default = "authenticated")]
+    pub access: Access,
+    /// The token must carry one of these roles
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// A scope-restricted token must be granted one of these scopes
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl RoutePolicy {
+    fn new(path: &str, access: Access) -> Self {
+        RoutePolicy {
+            path: path.to_string(),
+            methods: Vec::new(),
+            access,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.access == Access::Public && self.roles.is_empty() && self.scopes.is_empty()
+    }
+
+    /// Check the roles and scopes of an authenticated caller.
+    #[allow(clippy::result_large_err)]
+    pub fn authorize(&self, claims: &Claims, method: &str, path: &str) -> Result<(), HttpResponse> {
+        if !self.roles.is_empty() && !claims.has_any_role(&self.roles) {
+            warn!("User {} lacks a role for {} {} under policy {}", claims.sub, method, path, self.path);
+            return Err(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Insufficient role",
+                "code": "missing_role",
+                "required_roles": self.roles,
+                "method": method,
+                "path": path,
+            })));
+        }
+        let granted = claims.granted_scopes();
+        let scoped_out = granted.is_some_and(|granted| !self.scopes.iter().any(|s| granted.contains(&s.as_str())));
+        if !self.scopes.is_empty() && scoped_out {
+            warn!("Token of {} lacks a scope for {} {} under policy {}", claims.sub, method, path, self.path);
+            return Err(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Insufficient scope",
+                "code": "missing_scope",
+                "required_scopes": self.scopes,
+                "method": method,
+                "path": path,
+            })));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutePolicies {
+    /// Checked in order; the first match applies
+    pub policies: Vec<RoutePolicy>,
+    /// Applies to requests no policy matches
+    pub fallback: RoutePolicy,
+    /// Applies instead to `/api` routes no policy matches, bar the public ones
+    pub api_fallback: RoutePolicy,
+}
+
+impl RoutePolicies {
+    /// Load the table from `ROUTE_POLICIES_FILE` or `ROUTE_POLICIES`, JSON
+    /// arrays of policies. Unmatched routes follow `ROUTE_POLICY_DEFAULT`
+    /// (`public` unless set to `authenticated`), except that unmatched `/api`
+    /// routes other than auth, users and debug always need a token.
+    pub fn from_env() -> Self {
+        // The admin scope authenticates its own callers, who may hold only
+        // the admin token
+        let default = || {
+            ["/api/chat/*", "/api/messages/*", "/api/media/*", "/media/*", "/ws/*", "/graphql"]
+                .iter()
+                .map(|path| RoutePolicy::new(path, Access::Authenticated))
+                .chain(std::iter::once(RoutePolicy::new("/admin/*", Access::Public)))
+                .collect()
+        };
+        let raw = match env::var("ROUTE_POLICIES_FILE") {
+            Ok(path) if !path.is_empty() => Some(fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))),
+            _ => env::var("ROUTE_POLICIES").ok().map(Ok),
+        };
+        let policies: Vec<RoutePolicy> = match raw {
+            Some(raw) => raw
+                .and_then(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    // Falling back to no policies would open the protected routes
+                    error!("Invalid route policies ({}), using the default policies", e);
+                    invalid("ROUTE_POLICIES", e);
+                    default()
+                }),
+            None => default(),
+        };
+        let fallback = match env::var("ROUTE_POLICY_DEFAULT").as_deref() {
+            Ok("authenticated") => Access::Authenticated,
+            _ => Access::Public,
+        };
+        info!("Loaded {} route policies", policies.len());
+        RoutePolicies {
+            policies,
+            fallback: RoutePolicy::new("*", fallback),
+            api_fallback: RoutePolicy::new("/api/*", Access::Authenticated),
+        }
+    }
+
+    /// Policy covering a request to `path`, as routed.
+    pub fn lookup(&self, method: &str, path: &str) -> &RoutePolicy {
+        let public = |path: &str| PUBLIC_API_ROUTES.iter().any(|p| route_matches(p, &[], method, path));
+        match self.policies.iter().find(|p| route_matches(&p.path, &p.methods, method, path)) {
+            Some(policy) => policy,
+            None if path.starts_with("/api/") && !public(path) => &self.api_fallback,
+            None => &self.fallback,
+        }
+    }
+
+    /// Policy covering `req`, matched on its path as routed; `None` when its
+    /// encoding moves it under another policy than its raw path would be.
+    pub fn for_request(&self, req: &ServiceRequest) -> Option<&RoutePolicy> {
+        let method = req.method().as_str();
+        let policy = self.lookup(method, routed_path(req.request()));
+        std::ptr::eq(policy, self.lookup(method, req.path())).then_some(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn policies() -> RoutePolicies {
+        RoutePolicies {
+            policies: vec![
+                RoutePolicy::new("/api/chat/*", Access::Authenticated),
+                RoutePolicy::new("/admin/*", Access::Public),
+            ],
+            fallback: RoutePolicy::new("*", Access::Public),
+            api_fallback: RoutePolicy::new("/api/*", Access::Authenticated),
+        }
+    }
+
+    #[test]
+    fn matches_the_routed_path() {
+        let req = TestRequest::with_uri("/api/chat/rooms/a%20b").to_srv_request();
+        let policies = policies();
+        assert_eq!(policies.for_request(&req).map(|p| p.path.as_str()), Some("/api/chat/*"));
+    }
+
+    #[test]
+    fn refuses_encodings_that_change_the_policy() {
+        let req = TestRequest::with_uri("/api/%63hat/rooms").to_srv_request();
+        assert_eq!(routed_path(req.request()), "/api/chat/rooms");
+        assert!(policies().for_request(&req).is_none());
+
+        let req = TestRequest::with_uri("/%61dmin/users").to_srv_request();
+        assert!(policies().for_request(&req).is_none());
+    }
+
+    #[test]
+    fn unmatched_api_routes_need_a_token() {
+        let policies = policies();
+        assert_eq!(policies.lookup("GET", "/api/presence/online").access, Access::Authenticated);
+        assert!(policies.lookup("POST", "/api/auth/login").is_public());
+        assert!(policies.lookup("GET", "/api/users/profile").is_public());
+        assert!(policies.lookup("GET", "/health").is_public());
+    }
+}
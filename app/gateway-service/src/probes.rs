@@ -0,0 +1,261 @@
+use actix_web::web;
+use tracing::{error, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::config::invalid;
+use crate::AppState;
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// One HTTP call in a synthetic probe. `{{name}}` placeholders in the path,
+/// headers and body are replaced by `run_id` or values extracted earlier.
+#[derive(Clone, Deserialize)]
+pub struct ProbeStep {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<Value>,
+    /// Expected status; any 2xx when omitted
+    pub expect_status: Option<u16>,
+    pub expect_body_contains: Option<String>,
+    /// Variables to capture from the JSON response, as JSON pointers
+    #[serde(default)]
+    pub extract: HashMap<String, String>,
+}
+
+// Probes sign in like users, so header values and bodies (tokens,
+// passwords) stay out of the startup config log
+impl fmt::Debug for ProbeStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<_> = self.headers.keys().collect();
+        f.debug_struct("ProbeStep")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("headers", &headers)
+            .field("body", &self.body.is_some())
+            .field("expect_status", &self.expect_status)
+            .field("expect_body_contains", &self.expect_body_contains)
+            .field("extract", &self.extract)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProbeDefinition {
+    pub name: String,
+    #[serde(default = "default_interval")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    pub steps: Vec<ProbeStep>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProbeConfig {
+    /// Where probes send requests; the gateway's own listener when unset
+    pub base_url: Option<String>,
+    pub probes: Vec<ProbeDefinition>,
+}
+
+impl ProbeConfig {
+    /// Load probes from `SYNTHETIC_PROBES` (inline JSON array) or
+    /// `SYNTHETIC_PROBES_FILE` (path to a JSON file with the same shape).
+    pub fn from_env() -> Self {
+        let raw = match env::var("SYNTHETIC_PROBES") {
+            Ok(json) => Some(json),
+            Err(_) => env::var("SYNTHETIC_PROBES_FILE").ok().and_then(|path| {
+                fs::read_to_string(&path)
+                    .map_err(|e| {
+                        error!("Failed to read probe file {}: {}", path, e);
+                        invalid("SYNTHETIC_PROBES_FILE", format!("cannot read {}: {}", path, e));
+                    })
+                    .ok()
+            }),
+        };
+
+        let probes = match raw {
+            Some(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid synthetic probe configuration: {}", e);
+                invalid("SYNTHETIC_PROBES", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        ProbeConfig {
+            base_url: env::var("PROBE_BASE_URL").ok(),
+            probes,
+        }
+    }
+}
+
+fn substitute(template: &str, vars: &HashMap<String, String>) -> String {
+    vars.iter().fold(template.to_string(), |acc, (name, value)| {
+        acc.replace(&format!("{{{{{}}}}}", name), value)
+    })
+}
+
+fn substitute_value(value: &Value, vars: &HashMap<String, String>) -> Value {
+    match value {
+        Value::String(s) => Value::String(substitute(s, vars)),
+        Value::Array(items) => Value::Array(items.iter().map(|v| substitute_value(v, vars)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), substitute_value(v, vars)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Start one scheduler task per configured probe.
+pub fn start(data: web::Data<AppState>) {
+    for probe in data.config.load().probes.probes.clone() {
+        info!("Scheduling synthetic probe '{}' every {}s", probe.name, probe.interval_seconds);
+        actix_web::rt::spawn(schedule(data.clone(), probe));
+    }
+}
+
+async fn schedule(data: web::Data<AppState>, probe: ProbeDefinition) {
+    let base_url = data
+        .config
+        .load()
+        .probes
+        .base_url
+        .clone()
+        .unwrap_or_else(|| format!("http://127.0.0.1:{}", data.config.load().port));
+    let mut ticker = tokio::time::interval(Duration::from_secs(probe.interval_seconds.max(1)));
+    let mut last_ok: Option<bool> = None;
+
+    loop {
+        ticker.tick().await;
+
+        let started = Instant::now();
+        let result = run(&data, &base_url, &probe).await;
+        let elapsed = started.elapsed().as_secs_f64();
+        let ok = result.is_ok();
+
+        let outcome = if ok { "success" } else { "failure" };
+        data.metrics.incr("gateway_probe_runs_total", &[("probe", &probe.name), ("result", outcome)], 1);
+        data.metrics.observe("gateway_probe_duration_seconds", &[("probe", &probe.name)], elapsed);
+        data.metrics.gauge_set("gateway_probe_up", &[("probe", &probe.name)], if ok { 1.0 } else { 0.0 });
+
+        match (&result, last_ok) {
+            (Err(reason), Some(true) | None) => {
+                warn!("Synthetic probe '{}' failed: {}", probe.name, reason);
+                data.alerter
+                    .send(&format!("Synthetic probe '{}' failing", probe.name), reason)
+                    .await;
+            }
+            (Ok(()), Some(false)) => {
+                info!("Synthetic probe '{}' recovered", probe.name);
+                data.alerter
+                    .send(&format!("Synthetic probe '{}' recovered", probe.name), &format!("Completed in {:.3}s", elapsed))
+                    .await;
+            }
+            _ => {}
+        }
+        last_ok = Some(ok);
+    }
+}
+
+async fn run(data: &AppState, base_url: &str, probe: &ProbeDefinition) -> Result<(), String> {
+    let mut vars = HashMap::new();
+    vars.insert("run_id".to_string(), chrono::Utc::now().timestamp_millis().to_string());
+
+    for (index, step) in probe.steps.iter().enumerate() {
+        let url = format!("{}{}", base_url.trim_end_matches('/'), substitute(&step.path, &vars));
+        let method = reqwest::Method::from_bytes(step.method.to_uppercase().as_bytes())
+            .map_err(|_| format!("step {}: invalid method {}", index + 1, step.method))?;
+
+        let mut request = data
+            .http_client
+            .request(method, &url)
+            .timeout(Duration::from_secs(probe.timeout_seconds));
+        for (name, value) in &step.headers {
+            request = request.header(name.as_str(), substitute(value, &vars));
+        }
+        if let Some(body) = &step.body {
+            request = request.json(&substitute_value(body, &vars));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("step {} ({} {}): {}", index + 1, step.method, step.path, e))?;
+        let status = response.status().as_u16();
+        let body = response.text().await.unwrap_or_default();
+
+        let status_ok = match step.expect_status {
+            Some(expected) => status == expected,
+            None => (200..300).contains(&status),
+        };
+        if !status_ok {
+            return Err(format!("step {} ({} {}): unexpected status {}", index + 1, step.method, step.path, status));
+        }
+
+        if let Some(needle) = &step.expect_body_contains {
+            let needle = substitute(needle, &vars);
+            if !body.contains(&needle) {
+                return Err(format!("step {} ({} {}): response missing '{}'", index + 1, step.method, step.path, needle));
+            }
+        }
+
+        if !step.extract.is_empty() {
+            let json: Value = serde_json::from_str(&body)
+                .map_err(|_| format!("step {}: response is not JSON", index + 1))?;
+            for (name, pointer) in &step.extract {
+                let value = json
+                    .pointer(pointer)
+                    .ok_or_else(|| format!("step {}: nothing at {}", index + 1, pointer))?;
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                vars.insert(name.clone(), value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_hides_header_values_and_bodies() {
+        let probes: Vec<ProbeDefinition> = serde_json::from_value(serde_json::json!([{
+            "name": "login",
+            "steps": [{
+                "method": "POST",
+                "path": "/api/auth/login",
+                "headers": { "Authorization": "Bearer probe-secret-token" },
+                "body": { "username": "probe", "password": "probe-password" },
+            }],
+        }]))
+        .unwrap();
+        let logged = format!("{:?}", ProbeConfig { base_url: None, probes });
+        assert!(logged.contains("Authorization"), "{}", logged);
+        assert!(!logged.contains("probe-secret-token") && !logged.contains("probe-password"), "{}", logged);
+    }
+}
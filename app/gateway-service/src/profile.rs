@@ -0,0 +1,157 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::warn;
+use serde::Serialize;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::config::invalid;
+use crate::AppState;
+
+/// Served over plain HTTP in any case, since probes reach the gateway
+/// directly rather than through TLS.
+pub const PLAIN_HTTP_PATHS: &[&str] = &["/health", "/health/ready", "/metrics"];
+
+/// Deployment profile from `GATEWAY_ENV`, deciding the defaults that help
+/// in development and are unsafe in production.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Any origin allowed when CORS is not configured, upstream error
+    /// details in responses
+    Development,
+    Staging,
+    /// HTTPS only, a real JWT secret, no debug endpoints
+    Production,
+}
+
+impl Profile {
+    /// Read `GATEWAY_ENV` (`development`, `staging` or `production`, or
+    /// `dev`, `stage`, `prod`), `development` by default.
+    pub fn from_env() -> Self {
+        match env::var("GATEWAY_ENV").map(|v| v.to_lowercase()).as_deref() {
+            Ok("development") | Ok("dev") | Ok("") | Err(_) => Profile::Development,
+            Ok("staging") | Ok("stage") => Profile::Staging,
+            Ok("production") | Ok("prod") => Profile::Production,
+            Ok(other) => {
+                // Guessing development for a mistyped `production` would drop
+                // every safeguard
+                warn!("Unknown GATEWAY_ENV '{}', applying the production safeguards", other);
+                invalid("GATEWAY_ENV", format!("'{}' is not one of development, staging, production", other));
+                Profile::Production
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Profile::Development => "development",
+            Profile::Staging => "staging",
+            Profile::Production => "production",
+        }
+    }
+
+    pub fn is_production(self) -> bool {
+        self == Profile::Production
+    }
+
+    /// Whether routes without CORS settings admit any origin.
+    pub fn permissive_cors(self) -> bool {
+        self == Profile::Development
+    }
+
+    /// Whether error responses carry the underlying cause.
+    pub fn verbose_errors(self) -> bool {
+        self == Profile::Development
+    }
+
+    /// Whether the debug endpoints are served at all.
+    pub fn debug_endpoints(self) -> bool {
+        self != Profile::Production
+    }
+}
+
+/// Middleware refusing plain-HTTP requests in production. A request counts
+/// as HTTPS when it arrived on the gateway's own HTTPS listener, or when a
+/// trusted proxy (`TRUSTED_PROXIES`, or any peer on the unix socket)
+/// forwarded it with `X-Forwarded-Proto: https`.
+/// Responses carry `Strict-Transport-Security`.
+pub struct HttpsOnly;
+
+impl<S, B> Transform<S, ServiceRequest> for HttpsOnly
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HttpsOnlyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpsOnlyMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct HttpsOnlyMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpsOnlyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) if data.config.load().profile.is_production() => data.clone(),
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            if PLAIN_HTTP_PATHS.contains(&req.path()) {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            // Unix socket peers have no address and are trusted like proxies
+            let via_proxy = req
+                .peer_addr()
+                .map(|peer| data.config.load().ip_filter.trusted_proxies.iter().any(|proxy| proxy.contains(peer.ip())))
+                .unwrap_or(true);
+            let forwarded_https = req
+                .headers()
+                .get("X-Forwarded-Proto")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+            if !(req.app_config().secure() || (via_proxy && forwarded_https)) {
+                warn!("Refused plain-HTTP {} {} in production", req.method(), req.path());
+                let response = HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "HTTPS required"
+                }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            res.headers_mut().insert(
+                HeaderName::from_static("strict-transport-security"),
+                HeaderValue::from_static("max-age=31536000; includeSubDomains"),
+            );
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,346 @@
+use tracing::warn;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::config::{invalid, parse_setting};
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Reject the request that found the queue full
+    Fail,
+    /// Drop the longest-waiting request to make room
+    ShedOldest,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    /// Concurrent calls to the service, queueing disabled when zero
+    pub concurrency: usize,
+    pub depth: usize,
+    pub timeout: Duration,
+    pub overflow: QueueOverflow,
+}
+
+impl QueueSettings {
+    // `{SERVICE}_QUEUE_*` overrides the `UPSTREAM_QUEUE_*` defaults
+    fn from_env(service: Option<&str>, defaults: Option<&QueueSettings>) -> Self {
+        // The variable that set `name`, with its value
+        let read = |name: &str| {
+            let var = |key: String| env::var(&key).ok().map(|value| (key, value));
+            service
+                .and_then(|s| var(format!("{}_QUEUE_{}", s.to_uppercase(), name)))
+                .or_else(|| match defaults {
+                    Some(_) => None,
+                    None => var(format!("UPSTREAM_QUEUE_{}", name)),
+                })
+        };
+        let number = |name: &str, fallback: u64| {
+            read(name)
+                .and_then(|(key, value)| parse_setting(&key, Some(value)))
+                .unwrap_or(fallback)
+        };
+        let base = defaults.cloned().unwrap_or(QueueSettings {
+            concurrency: 0,
+            depth: 100,
+            timeout: Duration::from_secs(5),
+            overflow: QueueOverflow::Fail,
+        });
+
+        QueueSettings {
+            concurrency: number("CONCURRENCY", base.concurrency as u64) as usize,
+            depth: number("DEPTH", base.depth as u64) as usize,
+            timeout: Duration::from_millis(number("TIMEOUT_MS", base.timeout.as_millis() as u64)),
+            overflow: match read("OVERFLOW") {
+                Some((_, value)) if value == "shed_oldest" => QueueOverflow::ShedOldest,
+                Some((_, value)) if value == "fail" => QueueOverflow::Fail,
+                Some((key, other)) => {
+                    warn!("Unknown queue overflow behavior '{}', using fail", other);
+                    invalid(&key, format!("'{}' is not one of fail, shed_oldest", other));
+                    QueueOverflow::Fail
+                }
+                None => base.overflow,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub services: HashMap<String, QueueSettings>,
+}
+
+impl QueueConfig {
+    pub fn from_env(services: &[&str]) -> Self {
+        let defaults = QueueSettings::from_env(None, None);
+        QueueConfig {
+            services: services
+                .iter()
+                .map(|service| (service.to_string(), QueueSettings::from_env(Some(service), Some(&defaults))))
+                .collect(),
+        }
+    }
+}
+
+/// Why a request did not get a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Full,
+    Timeout,
+    Shed,
+}
+
+impl Rejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Full => "full",
+            Rejection::Timeout => "timeout",
+            Rejection::Shed => "shed",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Rejection::Full => "Upstream queue full",
+            Rejection::Timeout => "Timed out waiting in upstream queue",
+            Rejection::Shed => "Dropped from upstream queue under load",
+        }
+    }
+}
+
+struct Waiter {
+    id: u64,
+    /// The slot itself travels to the waiter, so one granted to a request
+    /// that goes away before taking it is released with the channel
+    grant: oneshot::Sender<Result<Slot, Rejection>>,
+}
+
+struct QueueState {
+    active: usize,
+    waiters: VecDeque<Waiter>,
+}
+
+struct ServiceQueue {
+    name: String,
+    settings: QueueSettings,
+    state: Mutex<QueueState>,
+}
+
+/// A call slot for one service; released or handed to the next waiter on drop.
+pub struct Slot {
+    queue: Option<Arc<ServiceQueue>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(queue) = &self.queue else { return };
+        let mut state = queue.state.lock().unwrap();
+        // Hand the slot straight to the oldest waiter still listening
+        while let Some(waiter) = state.waiters.pop_front() {
+            self.metrics.gauge_add("gateway_upstream_queue_depth", &[("service", &queue.name)], -1.0);
+            let slot = Slot {
+                queue: Some(queue.clone()),
+                metrics: self.metrics.clone(),
+            };
+            match waiter.grant.send(Ok(slot)) {
+                Ok(()) => return,
+                // Not handed over, so it must not release anything either
+                Err(Ok(mut slot)) => slot.queue = None,
+                Err(Err(_)) => {}
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+// Removes a waiter whose request went away while queued
+struct Dequeue<'a> {
+    queue: &'a ServiceQueue,
+    id: u64,
+    metrics: &'a Metrics,
+}
+
+impl Drop for Dequeue<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if let Some(index) = state.waiters.iter().position(|w| w.id == self.id) {
+            state.waiters.remove(index);
+            self.metrics.gauge_add("gateway_upstream_queue_depth", &[("service", &self.queue.name)], -1.0);
+        }
+    }
+}
+
+/// Per-service bounded queues absorbing short upstream stalls.
+pub struct UpstreamQueues {
+    queues: HashMap<String, Arc<ServiceQueue>>,
+    next_id: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl UpstreamQueues {
+    pub fn new(config: &QueueConfig, metrics: Arc<Metrics>) -> Self {
+        let queues = config
+            .services
+            .iter()
+            .filter(|(_, settings)| settings.concurrency > 0)
+            .map(|(name, settings)| {
+                let queue = ServiceQueue {
+                    name: name.clone(),
+                    settings: settings.clone(),
+                    state: Mutex::new(QueueState {
+                        active: 0,
+                        waiters: VecDeque::new(),
+                    }),
+                };
+                (name.clone(), Arc::new(queue))
+            })
+            .collect();
+        UpstreamQueues {
+            queues,
+            next_id: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    /// Wait for a call slot to `service`; services without a queue always get one.
+    pub async fn acquire(&self, service: &str) -> Result<Slot, Rejection> {
+        let queue = match self.queues.get(service) {
+            Some(queue) => queue,
+            None => return Ok(Slot { queue: None, metrics: self.metrics.clone() }),
+        };
+        let slot = || Slot {
+            queue: Some(queue.clone()),
+            metrics: self.metrics.clone(),
+        };
+        let labels = [("service", service)];
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (grant, mut granted) = oneshot::channel();
+        {
+            let mut state = queue.state.lock().unwrap();
+            if state.active < queue.settings.concurrency {
+                state.active += 1;
+                return Ok(slot());
+            }
+            if state.waiters.len() >= queue.settings.depth {
+                let oldest = match queue.settings.overflow {
+                    QueueOverflow::ShedOldest => state.waiters.pop_front(),
+                    QueueOverflow::Fail => None,
+                };
+                match oldest {
+                    Some(oldest) => {
+                        self.metrics.gauge_add("gateway_upstream_queue_depth", &labels, -1.0);
+                        let _ = oldest.grant.send(Err(Rejection::Shed));
+                    }
+                    None => {
+                        drop(state);
+                        return Err(self.reject(service, Rejection::Full));
+                    }
+                }
+            }
+            state.waiters.push_back(Waiter { id, grant });
+            self.metrics.gauge_add("gateway_upstream_queue_depth", &labels, 1.0);
+        }
+
+        let _dequeue = Dequeue {
+            queue,
+            id,
+            metrics: &self.metrics,
+        };
+        let started = Instant::now();
+        let outcome: Result<Slot, Rejection> = match tokio::time::timeout(queue.settings.timeout, &mut granted).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => Err(Rejection::Shed),
+            Err(_) => {
+                let mut state = queue.state.lock().unwrap();
+                match state.waiters.iter().position(|w| w.id == id) {
+                    Some(index) => {
+                        state.waiters.remove(index);
+                        self.metrics.gauge_add("gateway_upstream_queue_depth", &labels, -1.0);
+                        Err(Rejection::Timeout)
+                    }
+                    // Answered between the timeout firing and taking the lock
+                    None => granted.try_recv().unwrap_or(Err(Rejection::Shed)),
+                }
+            }
+        };
+        self.metrics.observe("gateway_upstream_queue_wait_seconds", &labels, started.elapsed().as_secs_f64());
+
+        outcome.map_err(|rejection| self.reject(service, rejection))
+    }
+
+    fn reject(&self, service: &str, rejection: Rejection) -> Rejection {
+        warn!("Upstream {} queue rejected a request: {}", service, rejection.as_str());
+        self.metrics.incr(
+            "gateway_upstream_queue_rejections_total",
+            &[("service", service), ("reason", rejection.as_str())],
+            1,
+        );
+        rejection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::task::noop_waker_ref;
+    use futures_util::FutureExt;
+    use std::task::Context;
+
+    fn queues(depth: usize) -> UpstreamQueues {
+        let settings = QueueSettings {
+            concurrency: 1,
+            depth,
+            timeout: Duration::from_secs(5),
+            overflow: QueueOverflow::Fail,
+        };
+        let config = QueueConfig {
+            services: HashMap::from([("chat".to_string(), settings)]),
+        };
+        UpstreamQueues::new(&config, Arc::new(Metrics::new()))
+    }
+
+    fn active(queues: &UpstreamQueues) -> usize {
+        queues.queues["chat"].state.lock().unwrap().active
+    }
+
+    #[tokio::test]
+    async fn hands_the_slot_to_the_next_waiter() {
+        let queues = queues(10);
+        let held = queues.acquire("chat").await.unwrap();
+        let mut waiting = Box::pin(queues.acquire("chat"));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(waiting.poll_unpin(&mut cx).is_pending());
+        drop(held);
+        let slot = waiting.await.unwrap();
+        assert_eq!(active(&queues), 1);
+        drop(slot);
+        assert_eq!(active(&queues), 0);
+    }
+
+    #[tokio::test]
+    async fn releases_a_slot_granted_to_a_waiter_that_went_away() {
+        let queues = queues(10);
+        let held = queues.acquire("chat").await.unwrap();
+        let mut waiting = Box::pin(queues.acquire("chat"));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(waiting.poll_unpin(&mut cx).is_pending());
+        // Granted, then dropped before it could take the slot
+        drop(held);
+        drop(waiting);
+        assert_eq!(active(&queues), 0);
+        assert!(queues.acquire("chat").now_or_never().is_some_and(|slot| slot.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn rejects_when_full() {
+        let queues = queues(0);
+        let _held = queues.acquire("chat").await.unwrap();
+        assert_eq!(queues.acquire("chat").await.err(), Some(Rejection::Full));
+    }
+}
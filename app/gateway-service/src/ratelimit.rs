@@ -0,0 +1,470 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
+use tracing::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::AuthMiddleware;
+use crate::config::{invalid, parse_env};
+use crate::exemptions;
+use crate::ipfilter::IpFilter;
+use crate::metrics::Metrics;
+use crate::rbac::{route_matches, routed_path};
+use crate::redis::{RedisClient, Reply};
+use crate::AppState;
+
+// Buckets kept before idle, refilled ones are swept
+const MAX_BUCKETS: usize = 10_000;
+
+/// Limit for requests matching `path` and `methods`, matched like role
+/// policies: `requests` per `per_seconds`, with bursts up to `burst`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub requests: u64,
+    #[serde(default = "default_period")]
+    pub per_seconds: u64,
+    /// Bucket capacity; defaults to `requests`
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+fn default_period() -> u64 {
+    60
+}
+
+impl RateLimitRule {
+    fn new(path: &str, methods: &[&str], requests: u64) -> Self {
+        RateLimitRule {
+            path: path.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            requests,
+            per_seconds: default_period(),
+            burst: None,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.burst.unwrap_or(self.requests).max(1)
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Checked in order; the first matching rule applies
+    pub rules: Vec<RateLimitRule>,
+    /// Redis shared by every replica; buckets are kept per instance when unset
+    pub redis_url: Option<String>,
+    pub key_prefix: String,
+    pub timeout: Duration,
+}
+
+// The Redis URL may carry a password
+impl std::fmt::Debug for RateLimitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimitConfig")
+            .field("enabled", &self.enabled)
+            .field("rules", &self.rules)
+            .field("backend", &if self.redis_url.is_some() { "redis" } else { "memory" })
+            .field("key_prefix", &self.key_prefix)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl RateLimitConfig {
+    /// Load the rules from `RATE_LIMITS`, a JSON array of rules.
+    pub fn from_env() -> Self {
+        let default = || {
+            vec![
+                RateLimitRule::new("/api/auth/login", &["POST"], 10),
+                RateLimitRule::new("/api/auth/register", &["POST"], 5),
+                RateLimitRule::new("/api/auth/mfa/verify", &["POST"], 10),
+                RateLimitRule::new("/api/auth/guest", &["POST"], 10),
+                RateLimitRule::new("/api/messages/send", &["POST"], 60),
+                RateLimitRule::new("/api/*", &[], 600),
+            ]
+        };
+        let rules = match env::var("RATE_LIMITS") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid RATE_LIMITS ({}), using the default limits", e);
+                invalid("RATE_LIMITS", e);
+                default()
+            }),
+            Err(_) => default(),
+        };
+        let redis_url = match env::var("RATE_LIMIT_BACKEND").as_deref() {
+            Ok("redis") => env::var("RATE_LIMIT_REDIS_URL")
+                .or_else(|_| env::var("REDIS_URL"))
+                .ok()
+                .filter(|url| !url.is_empty())
+                .or_else(|| {
+                    error!("RATE_LIMIT_BACKEND=redis needs RATE_LIMIT_REDIS_URL or REDIS_URL, keeping limits in memory");
+                    invalid("RATE_LIMIT_BACKEND", "redis needs RATE_LIMIT_REDIS_URL or REDIS_URL");
+                    None
+                }),
+            Ok("memory") | Err(_) => None,
+            Ok(other) => {
+                warn!("Unknown RATE_LIMIT_BACKEND '{}', keeping limits in memory", other);
+                invalid("RATE_LIMIT_BACKEND", format!("'{}' is not one of memory, redis", other));
+                None
+            }
+        };
+        RateLimitConfig {
+            enabled: env::var("RATE_LIMITING").map(|v| v != "false" && v != "0").unwrap_or(true),
+            rules,
+            redis_url,
+            key_prefix: env::var("RATE_LIMIT_KEY_PREFIX").unwrap_or_else(|_| "gateway:ratelimit:".to_string()),
+            timeout: Duration::from_millis(
+                parse_env("REDIS_TIMEOUT_MS").unwrap_or(500),
+            ),
+        }
+    }
+
+    /// First rule covering a request to `path`, as routed, and its index.
+    pub fn rule_for(&self, method: &str, path: &str) -> Option<(usize, &RateLimitRule)> {
+        self.rules.iter().enumerate().find(|(_, rule)| route_matches(&rule.path, &rule.methods, method, path))
+    }
+}
+
+/// Outcome of taking a token, with what the `X-RateLimit-*` headers report.
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the bucket is full again
+    pub reset: u64,
+    /// Seconds until the next token, when rejected
+    pub retry_after: u64,
+}
+
+impl Decision {
+    fn new(allowed: bool, tokens: f64, capacity: u64, rate: f64) -> Self {
+        Decision {
+            allowed,
+            limit: capacity,
+            remaining: tokens.floor() as u64,
+            reset: ((capacity as f64 - tokens) / rate).ceil() as u64,
+            retry_after: ((1.0 - tokens).max(0.0) / rate).ceil().max(1.0) as u64,
+        }
+    }
+}
+
+/// Storage for token buckets holding `capacity` tokens refilled at `rate`
+/// per second.
+pub trait RateLimitStore: Send + Sync {
+    fn take<'a>(&'a self, bucket: &'a str, capacity: u64, rate: f64) -> BoxFuture<'a, Result<Decision, String>>;
+    /// Forget a bucket so its client starts again with a full one.
+    fn reset<'a>(&'a self, bucket: &'a str) -> BoxFuture<'a, Result<(), String>>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets in this process; each replica enforces its own limits.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    fn take_now(&self, bucket: &str, capacity: u64, rate: f64) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity as f64);
+        }
+        let bucket = buckets.entry(bucket.to_string()).or_insert(Bucket {
+            tokens: capacity as f64,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity as f64);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision::new(allowed, bucket.tokens, capacity, rate)
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take<'a>(&'a self, bucket: &'a str, capacity: u64, rate: f64) -> BoxFuture<'a, Result<Decision, String>> {
+        let decision = self.take_now(bucket, capacity, rate);
+        Box::pin(async move { Ok(decision) })
+    }
+
+    fn reset<'a>(&'a self, bucket: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        self.buckets.lock().unwrap().remove(bucket);
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+// Refill and take atomically on the Redis side, using its clock so replicas
+// with skewed clocks agree. Returns {allowed, tokens left}.
+const TAKE_SCRIPT: &str = r#"
+redis.replicate_commands()
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+local allowed = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil((capacity - tokens) / rate) + 1)
+return {allowed, tostring(tokens)}
+"#;
+
+/// Buckets in Redis, shared by every gateway replica.
+pub struct RedisStore {
+    client: RedisClient,
+    key_prefix: String,
+}
+
+impl RateLimitStore for RedisStore {
+    fn take<'a>(&'a self, bucket: &'a str, capacity: u64, rate: f64) -> BoxFuture<'a, Result<Decision, String>> {
+        Box::pin(async move {
+            let key = format!("{}{}", self.key_prefix, bucket);
+            let reply = self
+                .client
+                .command(&["EVAL", TAKE_SCRIPT, "1", &key, &capacity.to_string(), &rate.to_string()])
+                .await
+                .map_err(|e| e.to_string())?;
+            match reply {
+                Reply::Array(items) => match items.as_slice() {
+                    [Reply::Integer(allowed), Reply::Bulk(Some(tokens))] => {
+                        let tokens: f64 = tokens.parse().map_err(|_| format!("invalid token count '{}'", tokens))?;
+                        Ok(Decision::new(*allowed == 1, tokens, capacity, rate))
+                    }
+                    _ => Err(format!("unexpected reply to EVAL: {:?}", items)),
+                },
+                reply => Err(format!("unexpected reply to EVAL: {:?}", reply)),
+            }
+        })
+    }
+
+    fn reset<'a>(&'a self, bucket: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let key = format!("{}{}", self.key_prefix, bucket);
+            self.client.command(&["DEL", &key]).await.map(|_| ()).map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Token buckets per rule and client, in the configured store. Buckets fall
+/// back to memory while Redis cannot be reached, so limits stay per replica
+/// instead of lapsing.
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+    fallback: MemoryStore,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, metrics: Arc<Metrics>) -> Self {
+        let store: Box<dyn RateLimitStore> = match config.redis_url.as_deref().map(|url| RedisClient::from_url(url, config.timeout)) {
+            Some(Ok(client)) => {
+                info!("Rate limit buckets stored in Redis at {}", client.addr);
+                Box::new(RedisStore {
+                    client,
+                    key_prefix: config.key_prefix.clone(),
+                })
+            }
+            Some(Err(e)) => {
+                error!("Invalid rate limit Redis URL ({}), keeping limits in memory", e);
+                Box::<MemoryStore>::default()
+            }
+            None => Box::<MemoryStore>::default(),
+        };
+        RateLimiter {
+            store,
+            fallback: MemoryStore::default(),
+            metrics,
+        }
+    }
+
+    /// Take a token for `key` under rule `index`.
+    pub async fn take(&self, index: usize, key: &str, capacity: u64, rate: f64) -> Decision {
+        let bucket = format!("{}:{}", index, key);
+        match self.store.take(&bucket, capacity, rate).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("Rate limit store failed, limiting in memory: {}", e);
+                self.metrics.incr("gateway_rate_limit_store_errors_total", &[], 1);
+                self.fallback.take_now(&bucket, capacity, rate)
+            }
+        }
+    }
+
+    /// Refill every bucket `key` holds, e.g. `user:42` or `ip:10.0.0.7`,
+    /// under the first `rules` rules.
+    pub async fn reset(&self, rules: usize, key: &str) -> Result<(), String> {
+        for index in 0..rules {
+            let bucket = format!("{}:{}", index, key);
+            self.fallback.reset(&bucket).await?;
+            self.store.reset(&bucket).await?;
+        }
+        Ok(())
+    }
+}
+
+// Rate-limit key: the verified user when there is a token, else the client
+// IP as seen through the trusted proxies
+async fn client_key(req: &ServiceRequest, ip_filter: &IpFilter) -> (String, Option<String>) {
+    if let Some(sub) = AuthMiddleware::token_subject(req.request()).await {
+        return (format!("user:{}", sub), Some(sub));
+    }
+    let ip = ip_filter.client_ip(req.request()).map(|ip| ip.to_string()).unwrap_or_default();
+    (format!("ip:{}", ip), None)
+}
+
+fn set_headers(headers: &mut actix_web::http::header::HeaderMap, decision: &Decision) {
+    for (name, value) in [
+        ("x-ratelimit-limit", decision.limit),
+        ("x-ratelimit-remaining", decision.remaining),
+        ("x-ratelimit-reset", decision.reset),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Middleware enforcing the per-route limits per user or client IP, answering
+/// 429 with `Retry-After` once a client's bucket is empty.
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) if data.config.load().rate_limits.enabled => data.clone(),
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            // Rules as they were when the request arrived, even if reloaded meanwhile
+            let config = data.config.load();
+            let (index, rule) = match config.rate_limits.rule_for(req.method().as_str(), routed_path(req.request())) {
+                Some(rule) => rule,
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let (key, user_id) = client_key(&req, &data.ip_filter).await;
+            let capacity = match exemptions::resolve(&data, req.request(), user_id.as_deref()) {
+                Some(mode) => match mode.apply(rule.capacity()) {
+                    Some(capacity) => capacity,
+                    None => return service.call(req).await.map(|res| res.map_into_left_body()),
+                },
+                None => rule.capacity(),
+            };
+            let rate = rule.requests as f64 / rule.per_seconds.max(1) as f64 * capacity as f64 / rule.capacity() as f64;
+            let decision = data.rate_limiter.take(index, &key, capacity, rate).await;
+
+            if !decision.allowed {
+                warn!("Rate limit {} exceeded by {} on {} {}", rule.path, key, req.method(), req.path());
+                let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                data.metrics.incr("gateway_rate_limited_total", &[("rule", &rule.path), ("route", &route)], 1);
+                let mut response = HttpResponse::TooManyRequests()
+                    .insert_header(("Retry-After", decision.retry_after.to_string()))
+                    .json(serde_json::json!({
+                        "error": "Rate limit exceeded",
+                        "retry_after": decision.retry_after
+                    }));
+                set_headers(response.headers_mut(), &decision);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            set_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn encoded_login_paths_hit_the_login_rule() {
+        let config = RateLimitConfig::from_env();
+        for uri in ["/api/auth/login", "/api/auth/%6Cogin", "/api/%61uth/login"] {
+            let req = TestRequest::post().uri(uri).to_srv_request();
+            let rule = config.rule_for("POST", routed_path(req.request())).map(|(_, rule)| rule.path.as_str());
+            assert_eq!(rule, Some("/api/auth/login"), "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn forwarded_clients_behind_a_trusted_proxy_get_separate_buckets() {
+        let config = crate::ipfilter::IpFilterConfig {
+            file: None,
+            reload_interval: Duration::ZERO,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let ip_filter = IpFilter::new(config, Arc::new(Metrics::new())).unwrap();
+        let proxy = "10.0.0.2:443".parse().unwrap();
+        let mut keys = Vec::new();
+        for client in ["203.0.113.7", "198.51.100.9"] {
+            let req = TestRequest::get()
+                .peer_addr(proxy)
+                .insert_header(("X-Forwarded-For", client))
+                .to_srv_request();
+            keys.push(client_key(&req, &ip_filter).await.0);
+        }
+        assert_eq!(keys, ["ip:203.0.113.7", "ip:198.51.100.9"]);
+    }
+}
@@ -0,0 +1,162 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::auth::AuthMiddleware;
+use crate::config::invalid;
+use crate::AppState;
+
+/// Roles required for requests matching `path` (exact, or a prefix when it
+/// ends in `*`) and, if listed, `methods`. Any one of `roles` grants access.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolePolicy {
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub roles: Vec<String>,
+}
+
+/// Whether a request matches a policy's `pattern` (exact, or a prefix when it
+/// ends in `*`) and `methods` (any method when empty).
+pub fn route_matches(pattern: &str, methods: &[String], method: &str, path: &str) -> bool {
+    let path_matches = match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    };
+    path_matches && (methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+}
+
+/// The path the router matches a request on, percent-escapes decoded except
+/// `%2F`, `%25` and `%2B`. Rules match on it rather than on the raw path,
+/// which `/api/%63hat/rooms` would otherwise slip past.
+pub fn routed_path(req: &HttpRequest) -> &str {
+    req.match_info().as_str()
+}
+
+#[derive(Debug, Clone)]
+pub struct RolePolicies {
+    pub policies: Vec<RolePolicy>,
+}
+
+impl RolePolicies {
+    /// Load the policy table from `ROLE_POLICIES`, a JSON array of policies.
+    pub fn from_env() -> Self {
+        let default = || {
+            vec![RolePolicy {
+                path: "/api/chat/admin/*".to_string(),
+                methods: Vec::new(),
+                roles: vec!["moderator".to_string(), "admin".to_string()],
+            }]
+        };
+        let policies = match env::var("ROLE_POLICIES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                // Falling back to no policies would open the protected routes
+                error!("Invalid ROLE_POLICIES ({}), using the default policies", e);
+                invalid("ROLE_POLICIES", e);
+                default()
+            }),
+            Err(_) => default(),
+        };
+        RolePolicies { policies }
+    }
+
+    /// First policy covering this request, if any.
+    pub fn lookup(&self, method: &str, path: &str) -> Option<&RolePolicy> {
+        self.policies.iter().find(|p| route_matches(&p.path, &p.methods, method, path))
+    }
+}
+
+/// Middleware rejecting requests to role-protected routes with 401 without a
+/// valid token and 403 when the token lacks every required role.
+pub struct RoleGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for RoleGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RoleGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RoleGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RoleGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RoleGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let policy = req
+                .app_data::<web::Data<AppState>>()
+                .and_then(|data| data.config.load().role_policies.lookup(req.method().as_str(), routed_path(req.request())).cloned());
+            let policy = match policy {
+                Some(policy) => policy,
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let claims = match AuthMiddleware::validate_token(req.request()).await {
+                Ok(claims) => claims,
+                Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+            };
+            if !claims.has_any_role(&policy.roles) {
+                warn!("User {} lacks a role for {} {}", claims.sub, req.method(), req.path());
+                if let Some(data) = req.app_data::<web::Data<AppState>>() {
+                    data.metrics.incr("gateway_role_denials_total", &[("policy", &policy.path)], 1);
+                }
+                let response = HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "Insufficient role",
+                    "code": "missing_role",
+                    "required_roles": policy.roles,
+                    "method": req.method().as_str(),
+                    "path": req.path(),
+                }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn matches_encoded_paths_as_routed() {
+        let policies = RolePolicies::from_env();
+        for uri in ["/api/chat/admin/rooms", "/api/chat/%61dmin/rooms", "/api/chat/adm%69n/rooms"] {
+            let req = TestRequest::with_uri(uri).to_srv_request();
+            let policy = policies.lookup("DELETE", routed_path(req.request()));
+            assert_eq!(policy.map(|p| p.path.as_str()), Some("/api/chat/admin/*"), "{}", uri);
+        }
+    }
+}
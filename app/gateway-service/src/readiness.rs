@@ -0,0 +1,142 @@
+use actix_web::{web, HttpResponse, Result};
+use tracing::{info, warn};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::parse_env;
+use crate::{check_service_health, AppState};
+
+#[derive(Debug, Clone)]
+pub struct ReadinessConfig {
+    /// Services that must answer before the gateway reports ready; when empty
+    /// it is ready as soon as it starts
+    pub critical_services: Vec<String>,
+    /// How long to wait before reporting ready anyway
+    pub timeout: Duration,
+    pub check_interval: Duration,
+}
+
+impl ReadinessConfig {
+    pub fn from_env() -> Self {
+        let millis = |key: &str, default: u64| {
+            Duration::from_millis(parse_env(key).unwrap_or(default))
+        };
+        ReadinessConfig {
+            critical_services: env::var("STARTUP_CRITICAL_SERVICES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|service| service.trim().to_string())
+                .filter(|service| !service.is_empty())
+                .collect(),
+            timeout: millis("STARTUP_WAIT_TIMEOUT_MS", 60_000),
+            check_interval: millis("STARTUP_CHECK_INTERVAL_MS", 1_000),
+        }
+    }
+}
+
+/// Startup phase tracking whether critical upstreams have answered yet, and
+/// the shutdown drain.
+pub struct Readiness {
+    ready: AtomicBool,
+    timed_out: AtomicBool,
+    draining: AtomicBool,
+    /// Whether each critical service has had an instance answer
+    services: Mutex<BTreeMap<String, bool>>,
+}
+
+impl Readiness {
+    pub fn new(config: &ReadinessConfig) -> Self {
+        Readiness {
+            ready: AtomicBool::new(config.critical_services.is_empty()),
+            timed_out: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            services: Mutex::new(config.critical_services.iter().map(|s| (s.clone(), false)).collect()),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Report not ready from now on, so load balancers move traffic away
+    /// before the gateway stops.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    fn pending(&self) -> Vec<String> {
+        let services = self.services.lock().unwrap();
+        services.iter().filter(|(_, up)| !**up).map(|(s, _)| s.clone()).collect()
+    }
+}
+
+/// Poll the critical services until each has a healthy instance or the
+/// startup timeout passes, then report ready.
+pub async fn wait_for_upstreams(data: web::Data<AppState>) {
+    let config = &data.config.load().readiness;
+    if data.readiness.is_ready() {
+        return;
+    }
+    info!("Waiting for critical upstreams before reporting ready: {:?}", config.critical_services);
+    let started = Instant::now();
+
+    loop {
+        for service in data.readiness.pending() {
+            let instances = match data.upstreams.load().get(&service) {
+                Some(upstream) => upstream.instances().to_vec(),
+                None => {
+                    warn!("Critical service {} is not a known upstream, not waiting for it", service);
+                    data.readiness.services.lock().unwrap().insert(service, true);
+                    continue;
+                }
+            };
+            for instance in instances {
+                if check_service_health(&data.http_client, &instance, &service).await.status == "healthy" {
+                    info!("Critical upstream {} is reachable at {}", service, instance);
+                    data.readiness.services.lock().unwrap().insert(service.clone(), true);
+                    break;
+                }
+            }
+        }
+
+        let pending = data.readiness.pending();
+        if pending.is_empty() {
+            info!("All critical upstreams reachable after {:?}, ready", started.elapsed());
+            break;
+        }
+        if started.elapsed() >= config.timeout {
+            warn!("Critical upstreams {:?} still unreachable after {:?}, reporting ready anyway", pending, config.timeout);
+            data.readiness.timed_out.store(true, Ordering::Relaxed);
+            break;
+        }
+        tokio::time::sleep(config.check_interval).await;
+    }
+    data.readiness.ready.store(true, Ordering::Relaxed);
+}
+
+// Readiness endpoint for load balancers: 503 until the startup phase ends,
+// and again once shutting down
+pub async fn ready_handler(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let readiness = &data.readiness;
+    if readiness.draining.load(Ordering::Relaxed) {
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "draining",
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        })));
+    }
+    if readiness.is_ready() {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "status": "ready",
+            "unreachable": if readiness.timed_out.load(Ordering::Relaxed) { readiness.pending() } else { Vec::new() },
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        })));
+    }
+    Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "status": "starting",
+        "waiting_for": readiness.pending(),
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+    })))
+}
@@ -0,0 +1,101 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::profile::PLAIN_HTTP_PATHS;
+use crate::AppState;
+
+// The request host without its port
+fn hostname(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().map(|ip| &host[..ip.len() + 2]).unwrap_or(host),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Middleware redirecting requests on the plain listener to HTTPS when the
+/// gateway serves HTTPS itself. Probes and metrics stay reachable over
+/// plain HTTP.
+pub struct HttpsRedirect;
+
+impl<S, B> Transform<S, ServiceRequest> for HttpsRedirect
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HttpsRedirectMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpsRedirectMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct HttpsRedirectMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpsRedirectMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let tls = match req.app_data::<web::Data<AppState>>() {
+                Some(data) => data.config.load().tls.clone(),
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            // `secure` is set for connections accepted on the HTTPS listener
+            let redirect = tls.enabled() && tls.redirect_http && !req.app_config().secure();
+            if !redirect || PLAIN_HTTP_PATHS.contains(&req.path()) {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            let host = hostname(req.connection_info().host()).to_string();
+            let authority = match tls.public_port {
+                443 => host,
+                port => format!("{}:{}", host, port),
+            };
+            let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+            let location = format!("https://{}{}", authority, path);
+            // A 301 turns other methods into GET in most clients; 308 keeps them
+            let mut response = match *req.method() {
+                Method::GET | Method::HEAD => HttpResponse::MovedPermanently(),
+                _ => HttpResponse::PermanentRedirect(),
+            };
+            let response = response.insert_header((header::LOCATION, location)).finish();
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostname_drops_the_port() {
+        assert_eq!(hostname("chat.example.com:8080"), "chat.example.com");
+        assert_eq!(hostname("chat.example.com"), "chat.example.com");
+        assert_eq!(hostname("[::1]:8080"), "[::1]");
+        assert_eq!(hostname("[::1]"), "[::1]");
+    }
+}
@@ -0,0 +1,187 @@
+use futures_util::future::BoxFuture;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/// Reply to a Redis command.
+#[derive(Debug)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    /// Bulk string; `None` for the nil reply
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+/// Minimal RESP client keeping a small pool of connections, one per worker
+/// thread, so one slow command does not stall every caller. Connections
+/// that fail or time out are dropped and replaced on demand.
+pub struct RedisClient {
+    pub addr: String,
+    username: Option<String>,
+    password: Option<String>,
+    db: u32,
+    /// Bounds waiting for a connection, connecting and the command itself
+    timeout: Duration,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+    permits: Semaphore,
+}
+
+impl RedisClient {
+    pub fn from_url(raw: &str, timeout: Duration) -> Result<Self, String> {
+        let url = reqwest::Url::parse(raw).map_err(|e| e.to_string())?;
+        if url.scheme() != "redis" {
+            return Err(format!("unsupported scheme '{}', expected redis://", url.scheme()));
+        }
+        let host = url.host_str().ok_or("missing host")?;
+        let db = match url.path().trim_start_matches('/') {
+            "" => 0,
+            db => db.parse().map_err(|_| format!("invalid database '{}'", db))?,
+        };
+        Ok(RedisClient {
+            addr: format!("{}:{}", host, url.port().unwrap_or(6379)),
+            username: Some(url.username().to_string()).filter(|u| !u.is_empty()),
+            password: url.password().map(str::to_string),
+            db,
+            timeout,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(thread::available_parallelism().map_or(4, |n| n.get())),
+        })
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let mut conn = BufStream::new(TcpStream::connect(&self.addr).await?);
+        if let Some(password) = &self.password {
+            let mut args = vec!["AUTH"];
+            args.extend(self.username.as_deref());
+            args.push(password);
+            send(&mut conn, &args).await?;
+        }
+        if self.db != 0 {
+            send(&mut conn, &["SELECT", &self.db.to_string()]).await?;
+        }
+        Ok(conn)
+    }
+
+    pub async fn command(&self, args: &[&str]) -> io::Result<Reply> {
+        tokio::time::timeout(self.timeout, async {
+            let _permit = self.permits.acquire().await.map_err(io::Error::other)?;
+            let idle = self.idle.lock().unwrap().pop();
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => self.connect().await?,
+            };
+            let reply = send(&mut conn, args).await?;
+            // Only a connection that read its whole reply is reused; one
+            // dropped mid-reply by an error or the timeout is closed
+            self.idle.lock().unwrap().push(conn);
+            Ok(reply)
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Redis command timed out")))
+    }
+}
+
+async fn send(conn: &mut BufStream<TcpStream>, args: &[&str]) -> io::Result<Reply> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        frame.extend_from_slice(arg.as_bytes());
+        frame.extend_from_slice(b"\r\n");
+    }
+    conn.write_all(&frame).await?;
+    conn.flush().await?;
+    read_reply(conn).await
+}
+
+// Boxed so array elements can be read recursively
+fn read_reply(conn: &mut BufStream<TcpStream>) -> BoxFuture<'_, io::Result<Reply>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection"));
+        }
+        let line = line.trim_end_matches("\r\n");
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid Redis {}", what));
+        match line.split_at(line.len().min(1)) {
+            ("+", status) => Ok(Reply::Status(status.to_string())),
+            ("-", message) => Err(io::Error::other(format!("Redis error: {}", message))),
+            (":", n) => n.parse().map(Reply::Integer).map_err(|_| invalid("integer")),
+            ("$", "-1") => Ok(Reply::Bulk(None)),
+            ("$", len) => {
+                let len: usize = len.parse().map_err(|_| invalid("bulk length"))?;
+                let mut bytes = vec![0; len + 2];
+                conn.read_exact(&mut bytes).await?;
+                bytes.truncate(len);
+                String::from_utf8(bytes).map(|s| Reply::Bulk(Some(s))).map_err(|_| invalid("bulk string"))
+            }
+            ("*", "-1") => Ok(Reply::Array(Vec::new())),
+            ("*", len) => {
+                let len: usize = len.parse().map_err(|_| invalid("array length"))?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(read_reply(conn).await?);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => Err(invalid("reply")),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    // Fake Redis answering +PONG to every command, or never when `silent`
+    async fn server(silent: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut socket = BufStream::new(socket);
+                    let mut line = String::new();
+                    while socket.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        // The last line of a one-argument command is the argument
+                        if !silent && line.ends_with("PING\r\n") {
+                            let _ = socket.write_all(b"+PONG\r\n").await;
+                            let _ = socket.flush().await;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (format!("redis://{}", addr), accepted)
+    }
+
+    #[tokio::test]
+    async fn reuses_connections_between_commands() {
+        let (url, accepted) = server(false).await;
+        let client = RedisClient::from_url(&url, Duration::from_secs(1)).unwrap();
+        for _ in 0..3 {
+            assert!(matches!(client.command(&["PING"]).await, Ok(Reply::Status(status)) if status == "PONG"));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn an_unresponsive_server_times_out() {
+        let (url, _) = server(true).await;
+        let client = RedisClient::from_url(&url, Duration::from_millis(100)).unwrap();
+        let error = client.command(&["PING"]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(client.idle.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,438 @@
+use actix_web::{body, HttpRequest, HttpResponse};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use tracing::{error, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::auth::Claims;
+use crate::config::parse_env;
+use crate::devices;
+use crate::jwe::Jwe;
+use crate::metrics::Metrics;
+use crate::secrets::Secrets;
+use crate::AppState;
+
+#[derive(Clone)]
+pub struct RefreshConfig {
+    /// Secret refresh tokens are signed with; the gateway leaves refresh to
+    /// the user service when unset
+    pub secret: Option<String>,
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+    /// `iss` and `aud` stamped on issued access tokens, matching what
+    /// validation expects (`JWT_ISSUER`, first of `JWT_AUDIENCE`)
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+// Keep the secret out of the startup config log
+impl fmt::Debug for RefreshConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshConfig")
+            .field("enabled", &self.secret.is_some())
+            .field("access_ttl", &self.access_ttl)
+            .field("refresh_ttl", &self.refresh_ttl)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl RefreshConfig {
+    pub fn from_env() -> Self {
+        let seconds = |key: &str, default: u64| {
+            Duration::from_secs(parse_env(key).unwrap_or(default))
+        };
+        RefreshConfig {
+            secret: env::var("REFRESH_TOKEN_SECRET").ok().filter(|s| !s.is_empty()),
+            access_ttl: seconds("ACCESS_TOKEN_TTL_SECONDS", 15 * 60),
+            refresh_ttl: seconds("REFRESH_TOKEN_TTL_SECONDS", 7 * 24 * 3600),
+            issuer: env::var("JWT_ISSUER").ok().filter(|iss| !iss.is_empty()),
+            audience: env::var("JWT_AUDIENCE")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .find(|aud| !aud.is_empty())
+                .map(str::to_string),
+        }
+    }
+}
+
+// Access token claims as issued
+#[derive(Serialize)]
+struct AccessClaims<'a> {
+    #[serde(flatten)]
+    claims: Claims,
+    iat: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshClaims {
+    sub: String,
+    username: String,
+    exp: usize,
+    iat: usize,
+    /// Id of this token; only the latest one of a family may be used
+    jti: String,
+    /// Id shared by every token rotated from the same login
+    fam: String,
+    typ: String,
+    #[serde(flatten)]
+    grants: Grants,
+}
+
+/// Roles and scopes a token pair is issued with, carried over to every access
+/// token rotated from it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Grants {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Space-separated scopes limiting the tokens; unrestricted when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Device session the tokens belong to; also the id of its refresh family
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// Device the session was started from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did: Option<String>,
+}
+
+// Latest token of one login's rotation chain
+struct Family {
+    current: String,
+    expires_at: usize,
+}
+
+/// Why a refresh token was turned down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefreshError {
+    Invalid,
+    /// A rotated-out token of this user was presented again; the family is
+    /// now revoked
+    Reused { subject: String },
+    Revoked,
+}
+
+impl RefreshError {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RefreshError::Invalid => "invalid",
+            RefreshError::Reused { .. } => "reused",
+            RefreshError::Revoked => "revoked",
+        }
+    }
+}
+
+/// Issues access/refresh token pairs and rotates refresh tokens, revoking a
+/// whole family when an already-rotated token is replayed.
+pub struct RefreshTokens {
+    config: RefreshConfig,
+    families: Mutex<HashMap<String, Family>>,
+    rng: SystemRandom,
+    /// Holds the secret access tokens are signed with
+    secrets: Arc<Secrets>,
+    /// Encrypts issued tokens when JWE keys are configured
+    jwe: Arc<Jwe>,
+    metrics: Arc<Metrics>,
+}
+
+/// A freshly issued token pair.
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
+    /// Device session the pair belongs to, if any
+    pub session_id: Option<String>,
+}
+
+fn now() -> usize {
+    chrono::Utc::now().timestamp() as usize
+}
+
+impl RefreshTokens {
+    pub fn new(config: RefreshConfig, secrets: Arc<Secrets>, jwe: Arc<Jwe>, metrics: Arc<Metrics>) -> Self {
+        RefreshTokens {
+            config,
+            families: Mutex::new(HashMap::new()),
+            rng: SystemRandom::new(),
+            secrets,
+            jwe,
+            metrics,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.secret.is_some()
+    }
+
+    /// Random 128-bit hex id, for token ids and anonymous subjects.
+    pub fn random_id(&self) -> String {
+        let mut bytes = [0u8; 16];
+        self.rng.fill(&mut bytes).expect("system random source unavailable");
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Sign a pair whose refresh token has id `jti` and make it the family's current one
+    fn sign(&self, sub: &str, username: &str, grants: &Grants, family: &str, jti: String) -> Result<TokenPair, String> {
+        let secret = self.config.secret.as_deref().ok_or("refresh tokens disabled")?;
+        let issued = now();
+        let refresh = RefreshClaims {
+            sub: sub.to_string(),
+            username: username.to_string(),
+            exp: issued + self.config.refresh_ttl.as_secs() as usize,
+            iat: issued,
+            jti: jti.clone(),
+            fam: family.to_string(),
+            typ: "refresh".to_string(),
+            grants: grants.clone(),
+        };
+        let refresh_token = encode(&Header::default(), &refresh, &EncodingKey::from_secret(secret.as_bytes()))
+            .map_err(|e| e.to_string())?;
+        let refresh_token = self.jwe.encrypt(&refresh_token)?;
+        let access_token = self.access_token(sub, username, grants, issued, self.config.access_ttl)?;
+
+        let mut families = self.families.lock().unwrap();
+        families.retain(|_, f| f.expires_at > issued);
+        families.insert(
+            family.to_string(),
+            Family {
+                current: jti,
+                expires_at: refresh.exp,
+            },
+        );
+        Ok(TokenPair {
+            access_token,
+            refresh_token,
+            expires_in: self.config.access_ttl.as_secs(),
+            session_id: grants.sid.clone(),
+        })
+    }
+
+    fn access_token(&self, sub: &str, username: &str, grants: &Grants, issued: usize, ttl: Duration) -> Result<String, String> {
+        let access = AccessClaims {
+            claims: Claims {
+                sub: sub.to_string(),
+                username: username.to_string(),
+                exp: issued + ttl.as_secs() as usize,
+                jti: Some(self.random_id()),
+                roles: grants.roles.clone(),
+                role: None,
+                scope: grants.scope.clone(),
+                scopes: Vec::new(),
+                sid: grants.sid.clone(),
+                did: grants.did.clone(),
+                typ: None,
+            },
+            iat: issued,
+            iss: self.config.issuer.as_deref(),
+            aud: self.config.audience.as_deref(),
+        };
+        let token = encode(&Header::default(), &access, &EncodingKey::from_secret(self.secrets.jwt_secret().as_bytes()))
+            .map_err(|e| e.to_string())?;
+        self.jwe.encrypt(&token)
+    }
+
+    /// Mint an access token without a refresh token, for gateway sign-ins
+    /// while refresh tokens are left to the user service.
+    pub fn issue_access(&self, sub: &str, username: &str, grants: &Grants) -> Result<(String, u64), String> {
+        self.issue_access_for(sub, username, grants, self.config.access_ttl)
+    }
+
+    /// Mint an access token living `ttl`, e.g. for guests.
+    pub fn issue_access_for(&self, sub: &str, username: &str, grants: &Grants, ttl: Duration) -> Result<(String, u64), String> {
+        let token = self.access_token(sub, username, grants, now(), ttl)?;
+        Ok((token, ttl.as_secs()))
+    }
+
+    /// Start a new token family for a user who just logged in, named after
+    /// its device session when it has one.
+    pub fn issue(&self, sub: &str, username: &str, grants: &Grants) -> Result<TokenPair, String> {
+        let family = grants.sid.clone().unwrap_or_else(|| self.random_id());
+        self.sign(sub, username, grants, &family, self.random_id())
+    }
+
+    /// Exchange a refresh token for a new pair, retiring the presented one.
+    pub fn rotate(&self, token: &str) -> Result<TokenPair, RefreshError> {
+        let claims = self.decode(token).ok_or(RefreshError::Invalid)?;
+
+        // Claim the successor id in the same critical section as the check, so
+        // concurrent refreshes with one token cannot both succeed
+        let jti = self.random_id();
+        {
+            let mut families = self.families.lock().unwrap();
+            match families.get_mut(&claims.fam) {
+                None => return Err(RefreshError::Revoked),
+                Some(family) if family.current != claims.jti => {
+                    families.remove(&claims.fam);
+                    return Err(RefreshError::Reused { subject: claims.sub });
+                }
+                Some(family) => family.current = jti.clone(),
+            }
+        }
+
+        let pair = self.sign(&claims.sub, &claims.username, &claims.grants, &claims.fam, jti).map_err(|e| {
+            error!("Failed to sign rotated tokens: {}", e);
+            RefreshError::Invalid
+        })?;
+        Ok(pair)
+    }
+
+    /// Revoke the family of a valid refresh token, e.g. on logout.
+    pub fn revoke(&self, token: &str) {
+        if let Some(claims) = self.decode(token) {
+            self.families.lock().unwrap().remove(&claims.fam);
+        }
+    }
+
+    /// Revoke a token family by id, e.g. when its device session is signed out.
+    pub fn revoke_family(&self, family: &str) {
+        self.families.lock().unwrap().remove(family);
+    }
+
+    /// How long a device session lasts without being used: the refresh
+    /// token lifetime, or the access token's while refresh is left upstream.
+    pub fn session_ttl(&self) -> Duration {
+        match self.enabled() {
+            true => self.config.refresh_ttl,
+            false => self.config.access_ttl,
+        }
+    }
+
+    fn decode(&self, token: &str) -> Option<RefreshClaims> {
+        let secret = self.config.secret.as_deref()?;
+        let token = self.jwe.decrypt(token).ok()?;
+        decode::<RefreshClaims>(
+            &token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .ok()
+        .map(|data| data.claims)
+        .filter(|claims| claims.typ == "refresh")
+    }
+
+    pub fn count(&self, outcome: &str) {
+        self.metrics.incr("gateway_token_refreshes_total", &[("outcome", outcome)], 1);
+    }
+}
+
+/// Roles of a user object from the user service, from `roles` or `role`.
+pub fn user_roles(user: &Value) -> Vec<String> {
+    match (user.get("roles"), user.get("role")) {
+        (Some(Value::Array(roles)), _) => roles.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        (_, Some(Value::String(role))) => vec![role.clone()],
+        _ => Vec::new(),
+    }
+}
+
+pub fn tokens_json(pair: &TokenPair) -> Value {
+    let mut tokens = serde_json::json!({
+        "accessToken": pair.access_token,
+        "refreshToken": pair.refresh_token,
+        "expiresIn": pair.expires_in,
+    });
+    if let Some(session_id) = &pair.session_id {
+        tokens["sessionId"] = Value::String(session_id.clone());
+    }
+    tokens
+}
+
+/// Handle `/api/auth/refresh`: rotate the presented refresh token.
+pub fn refresh(data: &AppState, body: &Value) -> HttpResponse {
+    let token = body
+        .get("refreshToken")
+        .or_else(|| body.get("refresh_token"))
+        .and_then(Value::as_str);
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Refresh token required"
+            }))
+        }
+    };
+
+    match data.refresh.rotate(token) {
+        Ok(pair) => {
+            data.refresh.count("rotated");
+            if let Some(session_id) = &pair.session_id {
+                data.devices.extend(session_id, data.refresh.session_ttl());
+            }
+            HttpResponse::Ok().json(serde_json::json!({ "tokens": tokens_json(&pair) }))
+        }
+        Err(e) => {
+            data.refresh.count(e.as_str());
+            if let RefreshError::Reused { subject } = &e {
+                warn!("Refresh token reuse detected for user {}, revoking its token family", subject);
+                data.audit.record(
+                    "refresh_token_reuse_detected",
+                    subject,
+                    serde_json::json!({ "action": "token_family_revoked" }),
+                );
+            }
+            HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Invalid refresh token"
+            }))
+        }
+    }
+}
+
+/// Replace the tokens in a successful login or register response from the
+/// user service with a gateway-issued pair starting a new refresh family,
+/// limited to `scope` when the client asked for one. The pair is tied to a new
+/// device session.
+pub async fn on_login(data: &AppState, req: &HttpRequest, response: HttpResponse, scope: Option<String>) -> HttpResponse {
+    if !data.refresh.enabled() || !response.status().is_success() {
+        return response;
+    }
+    let status = response.status();
+    let bytes = match body::to_bytes(response.into_body()).await {
+        Ok(bytes) => bytes,
+        Err(_) => return HttpResponse::BadGateway().finish(),
+    };
+    let rebuild = |json: &Value| HttpResponse::build(status).json(json);
+    let mut json: Value = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(_) => return HttpResponse::build(status).body(bytes),
+    };
+
+    let user = json.get("user");
+    let sub = user.and_then(|u| u.get("id")).map(|id| match id {
+        Value::String(id) => id.clone(),
+        other => other.to_string(),
+    });
+    let username = user.and_then(|u| u.get("username")).and_then(Value::as_str).unwrap_or_default();
+    let sub = match sub {
+        Some(sub) => sub,
+        None => {
+            warn!("Login response has no user id, leaving its tokens as issued upstream");
+            return rebuild(&json);
+        }
+    };
+
+    let grants = Grants {
+        roles: user.map(user_roles).unwrap_or_default(),
+        scope,
+        ..Default::default()
+    };
+    let grants = devices::start(data, req, &sub, grants);
+    match data.refresh.issue(&sub, username, &grants) {
+        Ok(pair) => {
+            data.refresh.count("issued");
+            json["tokens"] = tokens_json(&pair);
+        }
+        Err(e) => error!("Failed to issue tokens for user {}: {}", sub, e),
+    }
+    rebuild(&json)
+}
@@ -0,0 +1,168 @@
+use actix_web::web;
+use serde::Serialize;
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::config::{parse_env, ConfigFile};
+use crate::systemd;
+use crate::upstream::Upstreams;
+use crate::{AppState, Config};
+
+/// Outcome of a successful reload.
+#[derive(Debug, Serialize)]
+pub struct Reloaded {
+    pub path: String,
+    pub settings: usize,
+    /// File settings ignored because the environment sets them
+    pub overridden: Vec<String>,
+    pub upstreams_changed: bool,
+    /// Sections that changed but are held by components built at startup
+    pub restart_required: Vec<&'static str>,
+}
+
+// Sections copied into long-lived components at startup; a reload swaps the
+// config handlers read but cannot rebuild these
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    // Scopes are built from the prefixes; the URLs behind them are live
+    let prefixes = |config: &Config| -> Vec<(String, String)> {
+        config.routes.routes.iter().map(|route| (route.prefix.clone(), route.service.clone())).collect()
+    };
+    let (old_prefixes, new_prefixes) = (prefixes(old), prefixes(new));
+    let sections: [(&'static str, &dyn Debug, &dyn Debug); 28] = [
+        ("port", &old.port, &new.port),
+        ("listener", &old.listener, &new.listener),
+        ("routes", &old_prefixes, &new_prefixes),
+        // The CORS defaults it picks are built at startup
+        ("profile", &old.profile, &new.profile),
+        ("tls", &old.tls, &new.tls),
+        ("circuit", &old.circuit, &new.circuit),
+        ("bandwidth", &old.bandwidth, &new.bandwidth),
+        ("outlier", &old.outlier, &new.outlier),
+        ("alerts", &old.alerts, &new.alerts),
+        ("shedding", &old.shedding, &new.shedding),
+        ("audit", &old.audit, &new.audit),
+        ("queues", &old.queues, &new.queues),
+        ("readiness", &old.readiness, &new.readiness),
+        ("refresh", &old.refresh, &new.refresh),
+        ("revocation", &old.revocation, &new.revocation),
+        ("jwks", &old.jwks, &new.jwks),
+        ("secrets", &old.secrets, &new.secrets),
+        ("ip_filter", &old.ip_filter, &new.ip_filter),
+        ("moderation", &old.moderation, &new.moderation),
+        ("waf", &old.waf, &new.waf),
+        ("jwe", &old.jwe, &new.jwe),
+        ("field_encryption", &old.field_encryption, &new.field_encryption),
+        ("telemetry", &old.telemetry, &new.telemetry),
+        ("metrics", &old.metrics, &new.metrics),
+        ("access_log", &old.access_log, &new.access_log),
+        ("feature_flags", &old.feature_flags, &new.feature_flags),
+        ("discovery", &old.discovery, &new.discovery),
+        // The cache's budget is fixed when it is created
+        ("cache.max_bytes", &old.cache.max_bytes, &new.cache.max_bytes),
+    ];
+    sections
+        .iter()
+        .filter(|(_, old, new)| format!("{:?}", old) != format!("{:?}", new))
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+/// Re-read `GATEWAY_CONFIG` under the environment and swap the result in
+/// for requests that start from now on. An unreadable or invalid file
+/// leaves the running configuration and the environment as they were.
+pub fn reload(data: &AppState) -> Result<Reloaded, String> {
+    systemd::notify("RELOADING=1");
+    let result = apply(data);
+    systemd::notify("READY=1");
+    result
+}
+
+fn apply(data: &AppState) -> Result<Reloaded, String> {
+    let file = ConfigFile::from_env()?.ok_or("GATEWAY_CONFIG is not set")?;
+    let snapshot = file.snapshot();
+    let overridden = file.apply();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            snapshot.restore();
+            return Err(e);
+        }
+    };
+
+    let current = data.config.load();
+    let restart_required = restart_required(&current, &config);
+    // Discovered instances keep standing in for the configured URLs
+    let services = data.discovery.apply(config.upstream_services());
+    let upstreams_changed = services != data.discovery.apply(current.upstream_services());
+    if upstreams_changed {
+        data.upstreams.store(Upstreams::new(&services));
+    }
+    data.config.store(config);
+
+    Ok(Reloaded {
+        path: file.path,
+        settings: file.settings.len(),
+        overridden,
+        upstreams_changed,
+        restart_required,
+    })
+}
+
+fn log_reload(trigger: &str, result: Result<Reloaded, String>) {
+    match result {
+        Ok(reloaded) => {
+            info!("Reloaded {} settings from {} on {}", reloaded.settings, reloaded.path, trigger);
+            if !reloaded.restart_required.is_empty() {
+                warn!("Changes to {} take effect only after a restart", reloaded.restart_required.join(", "));
+            }
+        }
+        Err(e) => error!("Configuration reload on {} failed, keeping the running configuration: {}", trigger, e),
+    }
+}
+
+/// Reload the configuration on SIGHUP.
+#[cfg(unix)]
+pub async fn on_hangup(data: web::Data<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, configuration reloads only through the admin API: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log_reload("SIGHUP", reload(&data));
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Reload the configuration whenever the `GATEWAY_CONFIG` file changes,
+/// checked every `GATEWAY_CONFIG_WATCH_SECONDS` (off by default).
+pub async fn watch_file(data: web::Data<AppState>) {
+    let path = match env::var("GATEWAY_CONFIG").ok().filter(|path| !path.is_empty()) {
+        Some(path) => path,
+        None => return,
+    };
+    let interval = parse_env("GATEWAY_CONFIG_WATCH_SECONDS").unwrap_or(0);
+    if interval == 0 {
+        return;
+    }
+    let mut last = modified(&path);
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        let current = modified(&path);
+        if current != last {
+            last = current;
+            log_reload("file change", reload(&data));
+        }
+    }
+}
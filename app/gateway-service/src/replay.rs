@@ -0,0 +1,103 @@
+use actix_web::{HttpRequest, HttpResponse};
+use tracing::warn;
+use std::env;
+
+use crate::auth::Claims;
+use crate::rbac::route_matches;
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct OneTimeConfig {
+    /// `typ` claims of tokens that may be used once, e.g. password resets
+    pub types: Vec<String>,
+    /// Routes on which any presented token is used up (exact, or a prefix when
+    /// ending in `*`)
+    pub routes: Vec<String>,
+}
+
+impl OneTimeConfig {
+    /// Read `ONE_TIME_TOKEN_TYPES` and `ONE_TIME_TOKEN_ROUTES`, both comma
+    /// separated.
+    pub fn from_env() -> Self {
+        let list = |key: &str, default: &str| -> Vec<String> {
+            env::var(key)
+                .unwrap_or_else(|_| default.to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        OneTimeConfig {
+            types: list("ONE_TIME_TOKEN_TYPES", "password_reset,email_verification,invite"),
+            routes: list("ONE_TIME_TOKEN_ROUTES", ""),
+        }
+    }
+
+    // Kind of one-time token `claims` is on this route, for metrics. Routes
+    // match on the path as routed, percent-escapes decoded
+    fn kind<'a>(&self, req: &HttpRequest, claims: &'a Claims) -> Option<&'a str> {
+        let path = req.match_info().as_str();
+        match claims.typ.as_deref() {
+            Some(typ) if self.types.iter().any(|t| t == typ) => Some(typ),
+            _ if self.routes.iter().any(|route| route_matches(route, &[], req.method().as_str(), path)) => {
+                Some(claims.typ.as_deref().unwrap_or("route"))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Accept a one-time token on its first use only. Its `jti` is recorded in the
+/// revocation store until the token expires, so a replay is refused on every
+/// gateway instance.
+#[allow(clippy::result_large_err)]
+pub async fn check(req: &HttpRequest, data: &AppState, claims: &Claims) -> Result<(), HttpResponse> {
+    let kind = match data.config.load().one_time.kind(req, claims) {
+        Some(kind) => kind,
+        None => return Ok(()),
+    };
+    match &claims.jti {
+        Some(jti) => consume(data, kind, &claims.sub, jti, claims.exp).await,
+        None => {
+            data.metrics.incr("gateway_one_time_tokens_total", &[("type", kind), ("outcome", "missing_jti")], 1);
+            Err(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "One-time token has no id"
+            })))
+        }
+    }
+}
+
+/// Use up the one-time token `jti` of `subject`. Unlike revocation checks this
+/// fails closed: a token that cannot be marked used is refused.
+#[allow(clippy::result_large_err)]
+pub async fn consume(data: &AppState, kind: &str, subject: &str, jti: &str, expires_at: usize) -> Result<(), HttpResponse> {
+    let outcome = |outcome: &str| data.metrics.incr("gateway_one_time_tokens_total", &[("type", kind), ("outcome", outcome)], 1);
+    match data.revocation.consume(&format!("used:{}", jti), expires_at).await {
+        Ok(true) => {
+            outcome("consumed");
+            Ok(())
+        }
+        Ok(false) => {
+            warn!("Replayed {} token {} of user {}", kind, jti, subject);
+            outcome("replayed");
+            data.audit.record(
+                "token_replay_detected",
+                subject,
+                serde_json::json!({ "type": kind, "jti": jti }),
+            );
+            Err(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Token has already been used",
+                "code": "token_replayed"
+            })))
+        }
+        Err(e) => {
+            warn!("Could not record use of {} token {}: {}", kind, jti, e);
+            outcome("store_error");
+            data.metrics.incr("gateway_revocation_store_errors_total", &[], 1);
+            Err(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "error": "One-time token status unavailable"
+            })))
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::env;
+use std::time::Duration;
+
+use crate::config::parse_env;
+
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Extra attempts for idempotent requests, disabled when zero
+    pub max_retries: u32,
+    /// First backoff delay, doubled on every further attempt
+    pub backoff: Duration,
+    /// Wait for the upstream's `Retry-After` instead of the backoff schedule
+    pub honor_retry_after: bool,
+    /// Longest `Retry-After` the gateway waits out; longer ones go to the client
+    pub max_retry_after: Duration,
+}
+
+impl RetryConfig {
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+            parse_env(key)
+        }
+        RetryConfig {
+            max_retries: parse("RETRY_MAX_ATTEMPTS").unwrap_or(0),
+            backoff: Duration::from_millis(parse("RETRY_BACKOFF_MS").unwrap_or(100)),
+            honor_retry_after: env::var("RETRY_HONOR_RETRY_AFTER").map(|v| v != "false" && v != "0").unwrap_or(true),
+            max_retry_after: Duration::from_secs(parse("RETRY_AFTER_MAX_SECONDS").unwrap_or(5)),
+        }
+    }
+
+    /// How long to wait before retrying after `attempt` (0-based) produced
+    /// `status`, or `None` when the request should not be retried.
+    pub fn next_delay(&self, method: &str, attempt: u32, status: Option<StatusCode>, headers: Option<&HeaderMap>) -> Option<Duration> {
+        if attempt >= self.max_retries || !matches!(method, "GET" | "PUT" | "DELETE") {
+            return None;
+        }
+        let retryable = match status {
+            Some(status) => matches!(status.as_u16(), 429 | 502 | 503 | 504),
+            None => true,
+        };
+        if !retryable {
+            return None;
+        }
+
+        let backoff = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+        match headers.and_then(retry_after).filter(|_| self.honor_retry_after) {
+            Some(wait) if wait > self.max_retry_after => None,
+            Some(wait) => Some(wait),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// Parse a `Retry-After` header given as delay-seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.signed_duration_since(chrono::Utc::now());
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
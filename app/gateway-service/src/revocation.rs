@@ -0,0 +1,231 @@
+use actix_web::{HttpRequest, HttpResponse};
+use tracing::{error, info, warn};
+use ring::digest::{digest, SHA256};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::auth::AuthMiddleware;
+use crate::config::parse_env;
+use crate::metrics::Metrics;
+use crate::redis::{RedisClient, Reply};
+use crate::AppState;
+
+#[derive(Clone)]
+pub struct RevocationConfig {
+    /// `redis://[user:password@]host[:port][/db]`; revocations are kept in
+    /// memory, per gateway instance, when unset
+    pub redis_url: Option<String>,
+    pub key_prefix: String,
+    pub timeout: Duration,
+    /// Reject tokens when the store cannot be reached instead of accepting them
+    pub fail_closed: bool,
+}
+
+// The Redis URL may carry a password
+impl std::fmt::Debug for RevocationConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RevocationConfig")
+            .field("backend", &if self.redis_url.is_some() { "redis" } else { "memory" })
+            .field("key_prefix", &self.key_prefix)
+            .field("timeout", &self.timeout)
+            .field("fail_closed", &self.fail_closed)
+            .finish()
+    }
+}
+
+impl RevocationConfig {
+    pub fn from_env() -> Self {
+        RevocationConfig {
+            redis_url: env::var("REDIS_URL").ok().filter(|url| !url.is_empty()),
+            key_prefix: env::var("REVOCATION_KEY_PREFIX").unwrap_or_else(|_| "gateway:revoked:".to_string()),
+            timeout: Duration::from_millis(
+                parse_env("REDIS_TIMEOUT_MS").unwrap_or(500),
+            ),
+            fail_closed: env::var("REVOCATION_FAIL_CLOSED").map(|v| v == "true" || v == "1").unwrap_or(false),
+        }
+    }
+}
+
+enum Backend {
+    /// Token id to expiry (unix seconds)
+    Memory(Mutex<HashMap<String, usize>>),
+    Redis(Box<RedisClient>),
+}
+
+/// Revoked access tokens, kept until the token would have expired anyway.
+pub struct RevocationStore {
+    backend: Backend,
+    config: RevocationConfig,
+    metrics: Arc<Metrics>,
+}
+
+/// Id a token is revoked under: its `jti`, or a hash of the token for tokens
+/// issued without one.
+pub fn token_id(token: &str, jti: Option<&str>) -> String {
+    match jti {
+        Some(jti) => jti.to_string(),
+        None => {
+            let hash = digest(&SHA256, token.as_bytes());
+            let hex: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+            format!("sha256:{}", hex)
+        }
+    }
+}
+
+/// Id a device session is revoked under; every token carrying its `sid` is
+/// refused while it is listed.
+pub fn session_id(sid: &str) -> String {
+    format!("session:{}", sid)
+}
+
+impl RevocationStore {
+    pub fn new(config: RevocationConfig, metrics: Arc<Metrics>) -> Self {
+        let backend = match config.redis_url.as_deref().map(|url| RedisClient::from_url(url, config.timeout)) {
+            Some(Ok(client)) => {
+                info!("Token revocations stored in Redis at {}", client.addr);
+                Backend::Redis(Box::new(client))
+            }
+            Some(Err(e)) => {
+                error!("Invalid REDIS_URL ({}), keeping token revocations in memory", e);
+                Backend::Memory(Mutex::new(HashMap::new()))
+            }
+            None => Backend::Memory(Mutex::new(HashMap::new())),
+        };
+        RevocationStore { backend, config, metrics }
+    }
+
+    /// Revoke a token until `expires_at` (unix seconds).
+    pub async fn revoke(&self, id: &str, expires_at: usize) -> Result<(), String> {
+        let now = chrono::Utc::now().timestamp() as usize;
+        if expires_at <= now {
+            return Ok(());
+        }
+        match &self.backend {
+            Backend::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                entries.retain(|_, exp| *exp > now);
+                entries.insert(id.to_string(), expires_at);
+                Ok(())
+            }
+            Backend::Redis(client) => {
+                let key = format!("{}{}", self.config.key_prefix, id);
+                let ttl = (expires_at - now).to_string();
+                match client.command(&["SET", &key, "1", "EX", &ttl]).await {
+                    Ok(Reply::Status(status)) if status == "OK" => Ok(()),
+                    Ok(reply) => Err(format!("unexpected reply to SET: {:?}", reply)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Mark a one-time token id as used until `expires_at`; false when it
+    /// already was. With Redis the check and the mark are a single `SET NX`,
+    /// so two instances cannot both accept the token.
+    pub async fn consume(&self, id: &str, expires_at: usize) -> Result<bool, String> {
+        let now = chrono::Utc::now().timestamp() as usize;
+        match &self.backend {
+            Backend::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                entries.retain(|_, exp| *exp > now);
+                if entries.contains_key(id) {
+                    return Ok(false);
+                }
+                entries.insert(id.to_string(), expires_at.max(now + 1));
+                Ok(true)
+            }
+            Backend::Redis(client) => {
+                let key = format!("{}{}", self.config.key_prefix, id);
+                let ttl = expires_at.saturating_sub(now).max(1).to_string();
+                match client.command(&["SET", &key, "1", "EX", &ttl, "NX"]).await {
+                    Ok(Reply::Status(status)) if status == "OK" => Ok(true),
+                    Ok(Reply::Bulk(None)) => Ok(false),
+                    Ok(reply) => Err(format!("unexpected reply to SET: {:?}", reply)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
+    }
+
+    async fn is_revoked(&self, id: &str) -> Result<bool, String> {
+        match &self.backend {
+            Backend::Memory(entries) => {
+                let now = chrono::Utc::now().timestamp() as usize;
+                Ok(entries.lock().unwrap().get(id).map(|exp| *exp > now).unwrap_or(false))
+            }
+            Backend::Redis(client) => {
+                let key = format!("{}{}", self.config.key_prefix, id);
+                match client.command(&["EXISTS", &key]).await {
+                    Ok(Reply::Integer(n)) => Ok(n > 0),
+                    Ok(reply) => Err(format!("unexpected reply to EXISTS: {:?}", reply)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Reject a revoked token. When the store cannot be reached the token is
+    /// accepted, or refused with a 503 when failing closed.
+    #[allow(clippy::result_large_err)]
+    pub async fn ensure_active(&self, id: &str) -> Result<(), HttpResponse> {
+        let checked = self.is_revoked(id).await;
+        let outcome = match &checked {
+            Ok(false) => "active",
+            Ok(true) => "revoked",
+            Err(_) => "error",
+        };
+        self.metrics.incr("gateway_revocation_checks_total", &[("outcome", outcome)], 1);
+        match checked {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Token has been revoked"
+            }))),
+            Err(e) => {
+                warn!("Token revocation check failed: {}", e);
+                self.metrics.incr("gateway_revocation_store_errors_total", &[], 1);
+                if !self.config.fail_closed {
+                    return Ok(());
+                }
+                Err(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                    "error": "Token revocation status unavailable"
+                })))
+            }
+        }
+    }
+}
+
+/// Revoke the presented access token, and the refresh token family when a
+/// `refreshToken` is included, before the logout is passed on upstream.
+#[allow(clippy::result_large_err)]
+pub async fn logout(req: &HttpRequest, data: &AppState, body: &Value) -> Result<(), HttpResponse> {
+    let claims = AuthMiddleware::validate_token(req).await?;
+    let token = AuthMiddleware::presented_token(req)?;
+
+    if let Err(e) = data.revocation.revoke(&token_id(&token, claims.jti.as_deref()), claims.exp).await {
+        error!("Failed to revoke token of user {}: {}", claims.sub, e);
+        return Err(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Could not revoke token, try again"
+        })));
+    }
+    data.metrics.incr("gateway_tokens_revoked_total", &[], 1);
+
+    let refresh_token = body
+        .get("refreshToken")
+        .or_else(|| body.get("refresh_token"))
+        .and_then(Value::as_str);
+    if let Some(refresh_token) = refresh_token {
+        data.refresh.revoke(refresh_token);
+    }
+    // Logging out ends the device session, so no token of it stays usable
+    if let Some(session) = claims.sid.as_deref().and_then(|sid| data.devices.end(&claims.sub, sid)) {
+        if let Err(e) = data.revocation.revoke(&session_id(&session.id), session.expires_at).await {
+            error!("Failed to revoke session {} of user {}: {}", session.id, claims.sub, e);
+        }
+        data.refresh.revoke_family(&session.id);
+    }
+    info!("User {} logged out, token revoked", claims.sub);
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use tracing::{error, info};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use crate::config::invalid;
+use crate::policies::{Access, RoutePolicy};
+use crate::{proxy_request, AppState};
+
+/// Services the gateway has dedicated handlers for.
+pub const BUILTIN_SERVICES: [&str; 4] = ["user", "chat", "message", "media"];
+
+// Prefixes served by the gateway itself or the built-in services
+const RESERVED_PREFIXES: &[&str] = &[
+    "/api/auth",
+    "/api/users",
+    "/api/chat",
+    "/api/messages",
+    "/api/media",
+    "/api/debug",
+    "/api/v1",
+    "/api/v2",
+    "/media",
+    "/ws",
+    "/admin",
+    "/health",
+    "/metrics",
+    "/webhooks",
+    "/internal",
+    "/graphql",
+    "/graphiql",
+];
+
+fn authenticated() -> Access {
+    Access::Authenticated
+}
+
+/// Requests under `prefix` are forwarded to `service` with the prefix
+/// stripped, e.g. `/api/presence/online` to `{url}/online`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    pub prefix: String,
+    /// Upstream name, as used in metrics and by the `{SERVICE}_QUEUE_*`
+    /// and `{SERVICE}_SERVICE_STANDBY_URL` settings
+    pub service: String,
+    /// Instance base URLs, comma separated like `*_SERVICE_URL`
+    pub url: String,
+    #[serde(default = "authenticated")]
+    pub access: Access,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Per-attempt upstream timeout, the client's 30 seconds when unset
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    pub routes: Vec<Route>,
+}
+
+impl RouteTable {
+    /// Load the table from `ROUTES_FILE` or `ROUTES`, JSON arrays of routes.
+    pub fn from_env() -> Self {
+        let raw = match env::var("ROUTES_FILE") {
+            Ok(path) if !path.is_empty() => Some(fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))),
+            _ => env::var("ROUTES").ok().filter(|raw| !raw.trim().is_empty()).map(Ok),
+        };
+        let routes: Vec<Route> = match raw {
+            Some(raw) => raw
+                .and_then(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    error!("Invalid ROUTES ({}), serving the built-in services only", e);
+                    invalid("ROUTES", e);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        let routes: Vec<Route> = routes
+            .into_iter()
+            .map(|route| Route {
+                prefix: format!("/{}", route.prefix.trim_matches('/')),
+                ..route
+            })
+            .collect();
+        if !routes.is_empty() {
+            info!("Loaded {} configured route(s)", routes.len());
+        }
+        RouteTable { routes }
+    }
+
+    /// Problems that would leave a route unreachable or ambiguous.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut urls: HashMap<&str, &str> = HashMap::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let reserved = RESERVED_PREFIXES
+                .iter()
+                .any(|reserved| route.prefix == *reserved || route.prefix.starts_with(&format!("{}/", reserved)));
+            if route.prefix == "/" || reserved {
+                problems.push(format!("ROUTES: prefix {} is served by the gateway", route.prefix));
+            }
+            if self.routes[..index].iter().any(|other| other.prefix == route.prefix) {
+                problems.push(format!("ROUTES: prefix {} is listed more than once", route.prefix));
+            }
+            if route.service.is_empty() || BUILTIN_SERVICES.contains(&route.service.as_str()) {
+                problems.push(format!("ROUTES: '{}' is not a free service name", route.service));
+            }
+            match urls.insert(&route.service, &route.url) {
+                Some(url) if url != route.url => {
+                    problems.push(format!("ROUTES: service {} is given different URLs", route.service));
+                }
+                _ => {}
+            }
+        }
+        problems
+    }
+
+    /// Configured services and their instance URLs, each once.
+    pub fn services(&self) -> Vec<(String, String)> {
+        let mut services: Vec<(String, String)> = Vec::new();
+        for route in &self.routes {
+            if !services.iter().any(|(service, _)| *service == route.service) {
+                services.push((route.service.clone(), route.url.clone()));
+            }
+        }
+        services
+    }
+
+    /// Route policies enforcing each route's access, roles and scopes.
+    pub fn policies(&self) -> Vec<RoutePolicy> {
+        self.routes
+            .iter()
+            .map(|route| RoutePolicy {
+                path: format!("{}/*", route.prefix),
+                methods: Vec::new(),
+                access: route.access,
+                roles: route.roles.clone(),
+                scopes: route.scopes.clone(),
+            })
+            .collect()
+    }
+
+    pub fn timeout(&self, service: &str) -> Option<Duration> {
+        self.routes
+            .iter()
+            .find(|route| route.service == service)
+            .and_then(|route| route.timeout_ms)
+            .map(Duration::from_millis)
+    }
+}
+
+/// One scope per configured route. Prefixes are fixed at startup; a reload
+/// can still change the URLs behind them.
+pub fn configure(table: &RouteTable) -> impl Fn(&mut web::ServiceConfig) + '_ {
+    move |cfg| {
+        for route in &table.routes {
+            cfg.service(
+                web::scope(&route.prefix)
+                    .app_data(web::Data::new(route.clone()))
+                    .route("/{endpoint:.*}", web::get().to(forward))
+                    .route("/{endpoint:.*}", web::post().to(forward))
+                    .route("/{endpoint:.*}", web::put().to(forward))
+                    .route("/{endpoint:.*}", web::delete().to(forward)),
+            );
+        }
+    }
+}
+
+async fn forward(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    payload: Option<web::Json<Value>>,
+    route: web::Data<Route>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (endpoint,) = path.into_inner();
+    let service_path = format!("/{}", endpoint);
+    let method = req.method().as_str();
+    let body = payload.map(|p| p.into_inner());
+
+    proxy_request(&data, &req, &route.service, &service_path, method, body).await
+}
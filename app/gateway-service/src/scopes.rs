@@ -0,0 +1,112 @@
+use actix_web::{HttpRequest, HttpResponse};
+use tracing::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::auth::Claims;
+use crate::config::invalid;
+use crate::rbac::route_matches;
+
+/// Scopes required for requests matching `path` and `methods`, matched like
+/// role policies. Any one of `scopes` grants access.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopePolicy {
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+impl ScopePolicy {
+    fn new(path: &str, methods: &[&str], scopes: &[&str]) -> Self {
+        ScopePolicy {
+            path: path.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// Route-to-scope table. Only tokens carrying a `scope` claim are restricted
+/// by it; first-party tokens without one keep full access.
+#[derive(Debug, Clone)]
+pub struct ScopePolicies {
+    pub policies: Vec<ScopePolicy>,
+}
+
+impl ScopePolicies {
+    /// Load the table from `SCOPE_POLICIES`, a JSON array checked in order.
+    pub fn from_env() -> Self {
+        let default = || {
+            vec![
+                ScopePolicy::new("/api/chat/rooms", &["POST"], &["rooms:create"]),
+                ScopePolicy::new("/api/chat/*", &["GET"], &["rooms:read"]),
+                ScopePolicy::new("/api/chat/*", &["POST", "PUT", "DELETE"], &["rooms:write"]),
+                ScopePolicy::new("/api/messages/*", &["GET"], &["messages:read"]),
+                ScopePolicy::new("/api/messages/*", &["POST", "PUT", "DELETE"], &["messages:write"]),
+                ScopePolicy::new("/api/users/*", &["GET"], &["profile:read"]),
+                ScopePolicy::new("/api/users/*", &["POST", "PUT", "DELETE"], &["profile:write"]),
+                ScopePolicy::new("/ws/*", &[], &["messages:read"]),
+                ScopePolicy::new("/media/*", &[], &["media:read"]),
+            ]
+        };
+        let policies = match env::var("SCOPE_POLICIES") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid SCOPE_POLICIES ({}), using the default policies", e);
+                invalid("SCOPE_POLICIES", e);
+                default()
+            }),
+            Err(_) => default(),
+        };
+        ScopePolicies { policies }
+    }
+
+    pub fn lookup(&self, method: &str, path: &str) -> Option<&ScopePolicy> {
+        self.policies
+            .iter()
+            .find(|p| route_matches(&p.path, &p.methods, method, path))
+    }
+
+    /// Whether `scope` appears anywhere in the table, i.e. can be granted.
+    pub fn knows(&self, scope: &str) -> bool {
+        self.policies.iter().any(|p| p.scopes.iter().any(|s| s == scope))
+    }
+}
+
+/// Parse a requested space-separated `scope`, rejecting scopes no route uses.
+pub fn parse_requested(policies: &ScopePolicies, requested: &str) -> Result<Option<String>, String> {
+    let scopes: Vec<&str> = requested.split_whitespace().collect();
+    if scopes.is_empty() {
+        return Ok(None);
+    }
+    if let Some(unknown) = scopes.iter().find(|s| !policies.knows(s)) {
+        return Err(format!("unknown scope '{}'", unknown));
+    }
+    Ok(Some(scopes.join(" ")))
+}
+
+/// Reject a scope-restricted token on a route it has no scope for. Routes
+/// missing from the table are closed to restricted tokens.
+#[allow(clippy::result_large_err)]
+pub fn check(req: &HttpRequest, policies: &ScopePolicies, claims: &Claims) -> Result<(), HttpResponse> {
+    let granted = match claims.granted_scopes() {
+        Some(granted) => granted,
+        None => return Ok(()),
+    };
+    let required = policies
+        .lookup(req.method().as_str(), req.path())
+        .map(|p| p.scopes.clone())
+        .unwrap_or_default();
+    if required.iter().any(|scope| granted.contains(&scope.as_str())) {
+        return Ok(());
+    }
+
+    warn!("Token of {} lacks a scope for {} {}", claims.sub, req.method(), req.path());
+    Err(HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Insufficient scope",
+        "code": "missing_scope",
+        "required_scopes": required,
+        "method": req.method().as_str(),
+        "path": req.path(),
+    })))
+}
@@ -0,0 +1,461 @@
+use actix_web::web;
+use futures_util::future::BoxFuture;
+use tracing::{info, warn};
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::AppState;
+
+/// Signing secret used when nothing else is configured; only acceptable
+/// outside production.
+pub const DEFAULT_JWT_SECRET: &str = "super-secret-gateway-key";
+
+#[derive(Clone)]
+pub enum SecretsBackend {
+    /// `JWT_SECRET` from the environment
+    Env,
+    /// A HashiCorp Vault KV secret (v1 or v2)
+    Vault {
+        addr: String,
+        token: String,
+        /// API path below `/v1/`, e.g. `secret/data/gateway`
+        path: String,
+        key: String,
+    },
+    /// An AWS Secrets Manager secret, read with `GetSecretValue`
+    Aws {
+        region: String,
+        secret_id: String,
+        /// Key to read when the secret string is a JSON object
+        key: Option<String>,
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: Option<String>,
+        /// Overrides the regional endpoint, e.g. for a VPC endpoint
+        endpoint: Option<String>,
+    },
+}
+
+#[derive(Clone)]
+pub struct SecretsConfig {
+    pub backend: SecretsBackend,
+    /// How often the secret is re-read; zero disables refreshing
+    pub refresh_interval: Duration,
+    /// How long the secret replaced by a rotation still verifies tokens;
+    /// should outlast the access token lifetime
+    pub rotation_window: Duration,
+}
+
+// Vault tokens and AWS keys stay out of the startup config log
+impl fmt::Debug for SecretsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("SecretsConfig");
+        match &self.backend {
+            SecretsBackend::Env => s.field("backend", &"env"),
+            SecretsBackend::Vault { addr, path, key, .. } => s
+                .field("backend", &"vault")
+                .field("addr", addr)
+                .field("path", path)
+                .field("key", key),
+            SecretsBackend::Aws { region, secret_id, key, endpoint, .. } => s
+                .field("backend", &"aws")
+                .field("region", region)
+                .field("secret_id", secret_id)
+                .field("key", key)
+                .field("endpoint", endpoint),
+        };
+        s.field("refresh_interval", &self.refresh_interval)
+            .field("rotation_window", &self.rotation_window)
+            .finish()
+    }
+}
+
+impl SecretsConfig {
+    pub fn from_env() -> Self {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let backend = match var("SECRETS_BACKEND").as_deref() {
+            Some("vault") => SecretsBackend::Vault {
+                addr: var("VAULT_ADDR").unwrap_or_else(|| "http://127.0.0.1:8200".to_string()),
+                token: var("VAULT_TOKEN").unwrap_or_default(),
+                path: var("VAULT_SECRET_PATH").unwrap_or_else(|| "secret/data/gateway".to_string()),
+                key: var("VAULT_SECRET_KEY").unwrap_or_else(|| "jwt_secret".to_string()),
+            },
+            Some("aws") => SecretsBackend::Aws {
+                region: var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string()),
+                secret_id: var("AWS_SECRET_ID").unwrap_or_else(|| "gateway".to_string()),
+                key: var("AWS_SECRET_KEY").or_else(|| Some("jwt_secret".to_string())),
+                access_key_id: var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+                secret_access_key: var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+                session_token: var("AWS_SESSION_TOKEN"),
+                endpoint: var("AWS_SECRETS_ENDPOINT"),
+            },
+            Some("env") | None => SecretsBackend::Env,
+            Some(other) => {
+                warn!("Unknown SECRETS_BACKEND '{}', reading JWT_SECRET from the environment", other);
+                invalid("SECRETS_BACKEND", format!("'{}' is not a known backend", other));
+                SecretsBackend::Env
+            }
+        };
+        SecretsConfig {
+            backend,
+            refresh_interval: Duration::from_secs(
+                parse_env("SECRETS_REFRESH_SECONDS").unwrap_or(300),
+            ),
+            rotation_window: Duration::from_secs(
+                parse_env("JWT_SECRET_ROTATION_WINDOW_SECONDS").unwrap_or(3600),
+            ),
+        }
+    }
+}
+
+/// Source of the JWT signing secret.
+pub trait SecretsProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn jwt_secret(&self) -> BoxFuture<'_, Result<String, String>>;
+}
+
+pub struct EnvProvider;
+
+impl SecretsProvider for EnvProvider {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn jwt_secret(&self) -> BoxFuture<'_, Result<String, String>> {
+        let secret = env::var("JWT_SECRET").ok().filter(|s| !s.is_empty());
+        Box::pin(async move { Ok(secret.unwrap_or_else(|| DEFAULT_JWT_SECRET.to_string())) })
+    }
+}
+
+pub struct VaultProvider {
+    client: reqwest::Client,
+    addr: String,
+    token: String,
+    path: String,
+    key: String,
+}
+
+impl SecretsProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn jwt_secret(&self) -> BoxFuture<'_, Result<String, String>> {
+        Box::pin(async move {
+            let url = format!("{}/v1/{}", self.addr.trim_end_matches('/'), self.path.trim_start_matches('/'));
+            let response = self
+                .client
+                .get(&url)
+                .header("X-Vault-Token", &self.token)
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("Vault answered {}", response.status()));
+            }
+            let body: Value = response.json().await.map_err(|e| e.to_string())?;
+            // KV v2 nests the secret's fields one level deeper than v1
+            let fields = body["data"].get("data").filter(|d| d.is_object()).unwrap_or(&body["data"]);
+            fields[self.key.as_str()]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("secret has no string field '{}'", self.key))
+        })
+    }
+}
+
+pub struct AwsSecretsProvider {
+    client: reqwest::Client,
+    region: String,
+    secret_id: String,
+    key: Option<String>,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    endpoint: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes()).as_ref().to_vec()
+}
+
+impl AwsSecretsProvider {
+    // Signature Version 4 headers for a GetSecretValue call
+    fn signed_headers(&self, host: &str, body: &str) -> Vec<(&'static str, String)> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let target = "secretsmanager.GetSecretValue";
+
+        // Canonical headers must be sorted by name
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", target.to_string()));
+
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        let signed = signed.join(";");
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed,
+            hex(digest(&SHA256, body.as_bytes()).as_ref())
+        );
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(digest(&SHA256, canonical_request.as_bytes()).as_ref())
+        );
+        let key = hmac_sha256(format!("AWS4{}", self.secret_access_key).as_bytes(), &date);
+        let key = hmac_sha256(&key, &self.region);
+        let key = hmac_sha256(&key, "secretsmanager");
+        let key = hmac_sha256(&key, "aws4_request");
+        let signature = hex(&hmac_sha256(&key, &string_to_sign));
+
+        // reqwest sets Host itself from the URL
+        headers.retain(|(name, _)| *name != "host");
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed, signature
+            ),
+        ));
+        headers
+    }
+}
+
+impl SecretsProvider for AwsSecretsProvider {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    fn jwt_secret(&self) -> BoxFuture<'_, Result<String, String>> {
+        Box::pin(async move {
+            let url = reqwest::Url::parse(&self.endpoint).map_err(|e| e.to_string())?;
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_string(),
+                (None, _) => return Err(format!("endpoint '{}' has no host", self.endpoint)),
+            };
+            let body = serde_json::json!({ "SecretId": self.secret_id }).to_string();
+
+            let mut request = self.client.post(url).timeout(Duration::from_secs(10));
+            for (name, value) in self.signed_headers(&host, &body) {
+                request = request.header(name, value);
+            }
+            let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+            let status = response.status();
+            let payload: Value = response.json().await.map_err(|e| e.to_string())?;
+            if !status.is_success() {
+                let kind = payload["__type"].as_str().unwrap_or("unknown error");
+                return Err(format!("Secrets Manager answered {} ({})", status, kind));
+            }
+
+            let secret = payload["SecretString"].as_str().ok_or("secret has no SecretString")?;
+            // Secrets created in the console are JSON objects of key/value pairs
+            match (serde_json::from_str::<Value>(secret), &self.key) {
+                (Ok(Value::Object(fields)), Some(key)) => fields
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("secret has no string field '{}'", key)),
+                _ => Ok(secret.to_string()),
+            }
+        })
+    }
+}
+
+/// The secret a rotation replaced, still accepted for verification.
+struct Secondary {
+    secret: String,
+    /// `None` for `JWT_SECRET_SECONDARY`, which stays until it is unset
+    until: Option<Instant>,
+}
+
+/// State of the JWT secrets, with fingerprints rather than the secrets.
+#[derive(Debug, Serialize)]
+pub struct RotationStatus {
+    pub primary: String,
+    pub secondary: Option<String>,
+    /// Seconds the secondary keeps verifying tokens; unlimited when absent
+    pub secondary_expires_in: Option<u64>,
+}
+
+// Short SHA-256 prefix telling secrets apart in logs and the admin API
+pub fn fingerprint(secret: &str) -> String {
+    digest(&SHA256, secret.as_bytes()).as_ref()[..6].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Secrets loaded from the configured provider, kept current by
+/// `refresh_secrets`. Tokens are signed with the primary JWT secret and
+/// verified against it and, during a rotation, the secondary.
+pub struct Secrets {
+    provider: Box<dyn SecretsProvider>,
+    jwt_secret: RwLock<String>,
+    secondary: RwLock<Option<Secondary>>,
+    /// Last secret the provider returned, so a secret rotated in through the
+    /// admin API stays until the provider's changes
+    fetched: RwLock<Option<String>>,
+    rotation_window: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Secrets {
+    pub fn new(config: &SecretsConfig, client: reqwest::Client, metrics: Arc<Metrics>) -> Self {
+        let provider: Box<dyn SecretsProvider> = match config.backend.clone() {
+            SecretsBackend::Env => Box::new(EnvProvider),
+            SecretsBackend::Vault { addr, token, path, key } => Box::new(VaultProvider { client, addr, token, path, key }),
+            SecretsBackend::Aws {
+                region,
+                secret_id,
+                key,
+                access_key_id,
+                secret_access_key,
+                session_token,
+                endpoint,
+            } => Box::new(AwsSecretsProvider {
+                client,
+                endpoint: endpoint.unwrap_or_else(|| format!("https://secretsmanager.{}.amazonaws.com/", region)),
+                region,
+                secret_id,
+                key,
+                access_key_id,
+                secret_access_key,
+                session_token,
+            }),
+        };
+        let secondary = env::var("JWT_SECRET_SECONDARY")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| Secondary { secret, until: None });
+        Secrets {
+            provider,
+            jwt_secret: RwLock::new(DEFAULT_JWT_SECRET.to_string()),
+            secondary: RwLock::new(secondary),
+            fetched: RwLock::new(None),
+            rotation_window: config.rotation_window,
+            metrics,
+        }
+    }
+
+    /// Secret access tokens are signed with.
+    pub fn jwt_secret(&self) -> String {
+        self.jwt_secret.read().unwrap().clone()
+    }
+
+    /// Secrets tokens may be signed with: the primary, then the secondary
+    /// while its rotation window lasts.
+    pub fn verification_secrets(&self) -> Vec<String> {
+        let mut secrets = vec![self.jwt_secret()];
+        if let Some(secondary) = &*self.secondary.read().unwrap() {
+            if secondary.until.is_none_or(|until| Instant::now() < until) {
+                secrets.push(secondary.secret.clone());
+            }
+        }
+        secrets
+    }
+
+    pub fn uses_default(&self) -> bool {
+        self.jwt_secret() == DEFAULT_JWT_SECRET
+    }
+
+    /// Make `secret` the primary, keeping the replaced one as the secondary
+    /// for the rotation window. Returns whether the secret changed.
+    pub fn rotate(&self, secret: String) -> bool {
+        let mut current = self.jwt_secret.write().unwrap();
+        if *current == secret {
+            return false;
+        }
+        let previous = std::mem::replace(&mut *current, secret);
+        // Nothing was signed with the placeholder the gateway starts from
+        if previous != DEFAULT_JWT_SECRET || self.fetched.read().unwrap().is_some() {
+            *self.secondary.write().unwrap() = Some(Secondary {
+                secret: previous,
+                until: Some(Instant::now() + self.rotation_window),
+            });
+        }
+        true
+    }
+
+    /// Stop accepting the secondary secret before its window ends.
+    pub fn retire_secondary(&self) -> bool {
+        self.secondary.write().unwrap().take().is_some()
+    }
+
+    pub fn rotation_status(&self) -> RotationStatus {
+        // Read before taking the secondary's lock, which `rotate` takes second
+        let primary = fingerprint(&self.jwt_secret());
+        let secondary = self.secondary.read().unwrap();
+        let now = Instant::now();
+        let secondary = secondary.as_ref().filter(|secondary| secondary.until.is_none_or(|until| now < until));
+        RotationStatus {
+            primary,
+            secondary: secondary.map(|secondary| fingerprint(&secondary.secret)),
+            secondary_expires_in: secondary.and_then(|secondary| secondary.until).map(|until| (until - now).as_secs()),
+        }
+    }
+
+    /// Re-read the secrets from the provider, keeping the current values on
+    /// failure. Returns whether the JWT secret changed.
+    pub async fn refresh(&self) -> Result<bool, String> {
+        let result = self.provider.jwt_secret().await.and_then(|secret| {
+            if secret.is_empty() {
+                return Err("provider returned an empty secret".to_string());
+            }
+            if self.fetched.read().unwrap().as_ref() == Some(&secret) {
+                return Ok(false);
+            }
+            let changed = self.rotate(secret.clone());
+            *self.fetched.write().unwrap() = Some(secret);
+            Ok(changed)
+        });
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        self.metrics.incr("gateway_secret_refreshes_total", &[("backend", self.provider.name()), ("outcome", outcome)], 1);
+        result
+    }
+}
+
+/// Periodically re-read secrets so rotations in the backend are picked up
+/// without a restart.
+pub async fn refresh_secrets(data: web::Data<AppState>) {
+    let interval = data.config.load().secrets.refresh_interval;
+    if matches!(data.config.load().secrets.backend, SecretsBackend::Env) || interval.is_zero() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match data.secrets.refresh().await {
+            Ok(true) => info!(
+                "JWT secret rotated by the secrets backend to {}, the previous one verifies tokens for {:?}",
+                fingerprint(&data.secrets.jwt_secret()),
+                data.config.load().secrets.rotation_window
+            ),
+            Ok(false) => {}
+            Err(e) => warn!("Secrets refresh failed, keeping the current secret: {}", e),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use tracing::{error, info};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::flags::FlagSource;
+use crate::redis::{RedisClient, Reply};
+use crate::{check_service_health, AppState};
+
+/// Outcome of one startup check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+    pub latency_ms: u64,
+}
+
+/// Whether the gateway can reach everything it was configured with.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+async fn timed<F>(name: String, check: F) -> Check
+where
+    F: std::future::Future<Output = Result<String, String>>,
+{
+    let started = Instant::now();
+    let result = check.await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(detail) => Check { name, ok: true, detail, latency_ms },
+        Err(detail) => Check { name, ok: false, detail, latency_ms },
+    }
+}
+
+// Sign and verify a token with the loaded secret, as login and auth would
+async fn jwt_secret(data: &AppState) -> Result<String, String> {
+    let secret = data.secrets.jwt_secret();
+    let claims = serde_json::json!({
+        "sub": "self-check",
+        "exp": chrono::Utc::now().timestamp() + 60,
+    });
+    let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes()))
+        .map_err(|e| format!("cannot sign: {}", e))?;
+    decode::<serde_json::Value>(&token, &DecodingKey::from_secret(secret.as_bytes()), &Validation::new(Algorithm::HS256))
+        .map_err(|e| format!("cannot verify: {}", e))?;
+    Ok(match data.secrets.uses_default() {
+        true => "signs and verifies with the default secret".to_string(),
+        false => "signs and verifies".to_string(),
+    })
+}
+
+async fn redis(url: &str) -> Result<String, String> {
+    let client = RedisClient::from_url(url, Duration::from_secs(2))?;
+    match client.command(&["PING"]).await {
+        Ok(Reply::Status(status)) if status == "PONG" => Ok(format!("{} answers PING", client.addr)),
+        Ok(reply) => Err(format!("{} answered PING with {:?}", client.addr, reply)),
+        Err(e) => Err(format!("{}: {}", client.addr, e)),
+    }
+}
+
+/// Probe every upstream instance, the JWT secret, the JWKS endpoint and the
+/// Redis servers the configuration names.
+pub async fn run(data: &AppState) -> Report {
+    let config = data.config.load();
+    let mut checks = Vec::new();
+
+    let mut probed = HashSet::new();
+    for upstream in data.upstreams.load().iter() {
+        for instance in upstream.instances() {
+            if !probed.insert(instance.clone()) {
+                continue;
+            }
+            checks.push(
+                timed(format!("upstream:{}", upstream.name), async {
+                    match check_service_health(&data.http_client, instance, &upstream.name).await.status.as_str() {
+                        "healthy" => Ok(format!("{} is healthy", instance)),
+                        _ => Err(format!("{} is unreachable or unhealthy", instance)),
+                    }
+                })
+                .await,
+            );
+        }
+    }
+
+    checks.push(timed("jwt_secret".to_string(), jwt_secret(data)).await);
+    if config.jwks.url.is_some() {
+        checks.push(
+            timed("jwks".to_string(), async {
+                match data.jwks.refresh().await? {
+                    0 => Err("the key set is empty".to_string()),
+                    keys => Ok(format!("{} signing key(s) loaded", keys)),
+                }
+            })
+            .await,
+        );
+    }
+
+    let mut redis_urls: Vec<(&str, &str)> = Vec::new();
+    if let Some(url) = &config.rate_limits.redis_url {
+        redis_urls.push(("rate_limits", url));
+    }
+    if let Some(url) = &config.revocation.redis_url {
+        redis_urls.push(("revocation", url));
+    }
+    if let FlagSource::Redis(url) = &config.feature_flags.source {
+        redis_urls.push(("feature_flags", url));
+    }
+    for (component, url) in redis_urls {
+        checks.push(timed(format!("redis:{}", component), redis(url)).await);
+    }
+
+    Report {
+        ok: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+/// Run the checks and log the report, one line per check and the whole
+/// report as JSON.
+pub async fn log_report(data: &AppState) -> Report {
+    let report = run(data).await;
+    for check in &report.checks {
+        match check.ok {
+            true => info!("Self-check {} passed in {}ms: {}", check.name, check.latency_ms, check.detail),
+            false => error!("Self-check {} failed after {}ms: {}", check.name, check.latency_ms, check.detail),
+        }
+    }
+    let failed = report.checks.iter().filter(|check| !check.ok).count();
+    info!(
+        "Self-check: {} of {} checks passed {}",
+        report.checks.len() - failed,
+        report.checks.len(),
+        serde_json::to_string(&report).unwrap_or_default()
+    );
+    report
+}
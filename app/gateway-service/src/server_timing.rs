@@ -0,0 +1,91 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use std::cell::RefCell;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Upstream call durations collected while a request is handled.
+#[derive(Clone, Default)]
+struct Timings(Rc<RefCell<Vec<(String, Duration)>>>);
+
+/// Record an upstream call duration for the `Server-Timing` header.
+/// Does nothing unless the `ServerTiming` middleware is enabled.
+pub fn record(req: &HttpRequest, service: &str, duration: Duration) {
+    if let Some(timings) = req.extensions().get::<Timings>() {
+        timings.0.borrow_mut().push((service.to_string(), duration));
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
+
+/// Middleware adding a `Server-Timing` header with the time spent in the
+/// gateway itself and in each upstream call.
+pub struct ServerTiming;
+
+impl<S, B> Transform<S, ServiceRequest> for ServerTiming
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ServerTimingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ServerTimingMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ServerTimingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ServerTimingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let started = Instant::now();
+        let timings = Timings::default();
+        req.extensions_mut().insert(timings.clone());
+
+        Box::pin(async move {
+            let mut res = service.call(req).await?;
+
+            let total = started.elapsed();
+            let calls = timings.0.borrow();
+            let upstream_total: Duration = calls.iter().map(|(_, duration)| *duration).sum();
+
+            let mut entries = vec![format!(
+                "gateway;dur={};desc=\"Gateway processing\"",
+                millis(total.saturating_sub(upstream_total))
+            )];
+            for (service, duration) in calls.iter() {
+                entries.push(format!("upstream-{};dur={}", service, millis(*duration)));
+            }
+            entries.push(format!("total;dur={}", millis(total)));
+
+            if let Ok(value) = HeaderValue::from_str(&entries.join(", ")) {
+                res.headers_mut().insert(HeaderName::from_static("server-timing"), value);
+            }
+            Ok(res)
+        })
+    }
+}
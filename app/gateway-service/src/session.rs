@@ -0,0 +1,102 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{body, HttpRequest, HttpResponse};
+use tracing::warn;
+use serde_json::Value;
+use std::env;
+
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Hand out access tokens as an HttpOnly cookie instead of in the body
+    pub enabled: bool,
+    pub cookie_name: String,
+    /// Mark the cookie `Secure`; only disable for plain-HTTP development
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        let same_site = match env::var("SESSION_COOKIE_SAMESITE").unwrap_or_default().to_ascii_lowercase().as_str() {
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            _ => SameSite::Lax,
+        };
+        SessionConfig {
+            enabled: env::var("SESSION_COOKIES").map(|v| v == "true" || v == "1").unwrap_or(false),
+            cookie_name: env::var("SESSION_COOKIE_NAME").unwrap_or_else(|_| "session".to_string()),
+            secure: env::var("SESSION_COOKIE_SECURE").map(|v| v != "false" && v != "0").unwrap_or(true),
+            same_site,
+        }
+    }
+}
+
+fn cookie(config: &SessionConfig, value: String) -> Cookie<'static> {
+    Cookie::build(config.cookie_name.clone(), value)
+        .path("/")
+        .http_only(true)
+        .secure(config.secure)
+        .same_site(config.same_site)
+        .finish()
+}
+
+/// Access token from the session cookie, if session mode is on and the
+/// request carries one.
+pub fn token(req: &HttpRequest, config: &SessionConfig) -> Option<String> {
+    if !config.enabled {
+        return None;
+    }
+    req.cookie(&config.cookie_name).map(|c| c.value().to_string())
+}
+
+/// Move `accessToken` out of a `tokens` object into a session cookie, so
+/// scripts never see it. Without `expiresIn` the cookie lasts for the browser
+/// session; the token's own expiry is enforced either way.
+pub fn take_access_token(config: &SessionConfig, tokens: &mut Value) -> Option<Cookie<'static>> {
+    if !config.enabled {
+        return None;
+    }
+    let token = tokens.as_object_mut()?.remove("accessToken")?;
+    let mut cookie = cookie(config, token.as_str()?.to_string());
+    if let Some(seconds) = tokens.get("expiresIn").and_then(Value::as_i64) {
+        cookie.set_max_age(time::Duration::seconds(seconds));
+    }
+    Some(cookie)
+}
+
+/// Apply session mode to a successful login, register or refresh response.
+pub async fn attach(data: &AppState, response: HttpResponse) -> HttpResponse {
+    let config = &data.config.load().session;
+    if !config.enabled || !response.status().is_success() {
+        return response;
+    }
+    let status = response.status();
+    let bytes = match body::to_bytes(response.into_body()).await {
+        Ok(bytes) => bytes,
+        Err(_) => return HttpResponse::BadGateway().finish(),
+    };
+    let mut json: Value = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(_) => return HttpResponse::build(status).body(bytes),
+    };
+
+    let mut builder = HttpResponse::build(status);
+    match json.get_mut("tokens").and_then(|tokens| take_access_token(config, tokens)) {
+        Some(cookie) => {
+            builder.cookie(cookie);
+        }
+        None => warn!("Session mode is on but the response carries no access token"),
+    }
+    builder.json(json)
+}
+
+/// Expire the session cookie, e.g. on logout.
+pub fn clear(data: &AppState, mut response: HttpResponse) -> HttpResponse {
+    let mut removal = cookie(&data.config.load().session, String::new());
+    removal.make_removal();
+    if let Err(e) = response.add_cookie(&removal) {
+        warn!("Failed to clear session cookie: {}", e);
+    }
+    response
+}
@@ -0,0 +1,251 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use tracing::warn;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::config::parse_env;
+use crate::metrics::Metrics;
+
+fn path_list(key: &str, default: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|path| path.trim().to_string())
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct SheddingConfig {
+    /// Requests processed concurrently before shedding starts, disabled when zero
+    pub max_concurrent: usize,
+    /// Requests allowed to wait for a free slot
+    pub max_queue: usize,
+    pub queue_timeout: Duration,
+    /// Extra concurrency reserved for priority routes
+    pub priority_reserve: usize,
+    pub retry_after_seconds: u64,
+    /// Path prefixes that are never shed
+    pub exempt_paths: Vec<String>,
+    /// Path prefixes that may use the priority reserve
+    pub priority_paths: Vec<String>,
+}
+
+impl SheddingConfig {
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+            parse_env(key)
+        }
+        let max_concurrent = parse("SHED_MAX_CONCURRENT").unwrap_or(0);
+        SheddingConfig {
+            max_concurrent,
+            max_queue: parse("SHED_MAX_QUEUE").unwrap_or(max_concurrent),
+            queue_timeout: Duration::from_millis(parse("SHED_QUEUE_TIMEOUT_MS").unwrap_or(100)),
+            priority_reserve: parse("SHED_PRIORITY_RESERVE").unwrap_or((max_concurrent / 10).max(1)),
+            retry_after_seconds: parse("SHED_RETRY_AFTER_SECONDS").unwrap_or(1),
+            exempt_paths: path_list("SHED_EXEMPT_PATHS", "/health,/metrics"),
+            priority_paths: path_list("SHED_PRIORITY_PATHS", "/api/auth"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Exempt,
+    High,
+    Normal,
+}
+
+impl Priority {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Exempt => "exempt",
+            Priority::High => "high",
+            Priority::Normal => "normal",
+        }
+    }
+}
+
+/// Admission control: caps concurrent requests, lets a bounded number wait
+/// briefly for a slot and rejects the rest before they reach an upstream.
+pub struct AdmissionControl {
+    config: SheddingConfig,
+    in_flight: AtomicUsize,
+    waiting: AtomicUsize,
+    released: Notify,
+    metrics: Arc<Metrics>,
+}
+
+/// Slot held for the duration of an admitted request.
+pub struct Permit {
+    control: Arc<AdmissionControl>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.control.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.control.metrics.gauge_add("gateway_admission_in_flight", &[], -1.0);
+        self.control.released.notify_one();
+    }
+}
+
+impl AdmissionControl {
+    pub fn new(config: SheddingConfig, metrics: Arc<Metrics>) -> Arc<Self> {
+        Arc::new(AdmissionControl {
+            config,
+            in_flight: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+            released: Notify::new(),
+            metrics,
+        })
+    }
+
+    fn priority(&self, path: &str) -> Priority {
+        let matches = |prefixes: &[String]| prefixes.iter().any(|prefix| path.starts_with(prefix.as_str()));
+        if matches(&self.config.exempt_paths) {
+            Priority::Exempt
+        } else if matches(&self.config.priority_paths) {
+            Priority::High
+        } else {
+            Priority::Normal
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>, limit: usize) -> Option<Permit> {
+        let mut current = self.in_flight.load(Ordering::Acquire);
+        while current < limit {
+            match self
+                .in_flight
+                .compare_exchange(current, current + 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    self.metrics.gauge_add("gateway_admission_in_flight", &[], 1.0);
+                    return Some(Permit { control: self.clone() });
+                }
+                Err(actual) => current = actual,
+            }
+        }
+        None
+    }
+
+    async fn admit(self: &Arc<Self>, priority: Priority) -> Option<Permit> {
+        let limit = match priority {
+            Priority::High => self.config.max_concurrent + self.config.priority_reserve,
+            _ => self.config.max_concurrent,
+        };
+        if let Some(permit) = self.try_acquire(limit) {
+            return Some(permit);
+        }
+
+        if self.waiting.fetch_add(1, Ordering::AcqRel) >= self.config.max_queue {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        self.metrics.gauge_add("gateway_admission_queue_depth", &[], 1.0);
+
+        let deadline = tokio::time::Instant::now() + self.config.queue_timeout;
+        let permit = loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if let Some(permit) = self.try_acquire(limit) {
+                break Some(permit);
+            }
+            tokio::select! {
+                _ = released => {}
+                _ = tokio::time::sleep_until(deadline) => break None,
+            }
+        };
+
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        self.metrics.gauge_add("gateway_admission_queue_depth", &[], -1.0);
+        permit
+    }
+}
+
+/// Middleware rejecting excess traffic with 503 + Retry-After.
+pub struct LoadShedder {
+    control: Arc<AdmissionControl>,
+}
+
+impl LoadShedder {
+    pub fn new(control: Arc<AdmissionControl>) -> Self {
+        LoadShedder { control }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LoadShedder
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = LoadShedderMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LoadShedderMiddleware {
+            service: Rc::new(service),
+            control: self.control.clone(),
+        }))
+    }
+}
+
+pub struct LoadShedderMiddleware<S> {
+    service: Rc<S>,
+    control: Arc<AdmissionControl>,
+}
+
+impl<S, B> Service<ServiceRequest> for LoadShedderMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let control = self.control.clone();
+
+        Box::pin(async move {
+            let priority = control.priority(req.path());
+            if control.config.max_concurrent == 0 || priority == Priority::Exempt {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            }
+
+            let _permit = match control.admit(priority).await {
+                Some(permit) => permit,
+                None => {
+                    warn!("Shedding {} {} under overload", req.method(), req.path());
+                    control
+                        .metrics
+                        .incr("gateway_shed_requests_total", &[("priority", priority.as_str())], 1);
+                    let response = HttpResponse::ServiceUnavailable()
+                        .insert_header(("Retry-After", control.config.retry_after_seconds.to_string()))
+                        .json(serde_json::json!({
+                            "error": "Service overloaded",
+                            "message": "The gateway is at capacity, retry later"
+                        }));
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
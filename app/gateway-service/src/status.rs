@@ -0,0 +1,142 @@
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures_util::stream;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::admin::authorize;
+use crate::circuit::CircuitState;
+use crate::metrics::Metrics;
+use crate::AppState;
+
+/// Events a slow subscriber may fall behind by before it skips ahead.
+const FEED_CAPACITY: usize = 256;
+
+/// Comment sent to idle streams so proxies keep them open.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// A change in upstream health or circuit state.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatusEvent {
+    Health {
+        service: String,
+        instance: String,
+        status: String,
+        previous: Option<String>,
+        consecutive_failures: u32,
+        at: String,
+    },
+    Circuit {
+        service: String,
+        state: &'static str,
+        previous: &'static str,
+        at: String,
+    },
+}
+
+impl StatusEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            StatusEvent::Health { .. } => "health",
+            StatusEvent::Circuit { .. } => "circuit",
+        }
+    }
+}
+
+pub fn circuit_state_name(state: CircuitState) -> &'static str {
+    match state {
+        CircuitState::Closed => "closed",
+        CircuitState::Open => "open",
+        CircuitState::HalfOpen => "half_open",
+    }
+}
+
+/// Fans status changes out to every open status stream.
+pub struct StatusFeed {
+    sender: broadcast::Sender<StatusEvent>,
+}
+
+impl StatusFeed {
+    pub fn new() -> Self {
+        StatusFeed {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+
+    /// Publish an event; dropped when nobody is listening.
+    pub fn publish(&self, event: StatusEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn circuit_changed(&self, service: &str, previous: CircuitState, state: CircuitState) {
+        self.publish(StatusEvent::Circuit {
+            service: service.to_string(),
+            state: circuit_state_name(state),
+            previous: circuit_state_name(previous),
+            at: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+fn sse(event: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+// Leaves the subscriber gauge when the client goes away
+struct Subscriber(Arc<Metrics>);
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.0.gauge_add("gateway_status_stream_clients", &[], -1.0);
+    }
+}
+
+/// Handle `GET /admin/status/stream`: a server-sent event stream opening with
+/// a `snapshot` of every instance's health and circuit, followed by `health`
+/// and `circuit` events as they change.
+pub async fn stream(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Err(response) = authorize(&req) {
+        return Ok(response);
+    }
+    // Subscribe before taking the snapshot so no change falls in between
+    let receiver = data.status.sender.subscribe();
+    let services: Vec<_> = data.service_statuses.read().await.values().cloned().collect();
+    let circuits: serde_json::Map<String, serde_json::Value> = data
+        .upstreams
+        .load()
+        .iter()
+        .map(|upstream| (upstream.name.clone(), circuit_state_name(data.circuits.state(&upstream.name)).into()))
+        .collect();
+    let snapshot = sse("snapshot", &serde_json::json!({ "services": services, "circuits": circuits }));
+
+    data.metrics.gauge_add("gateway_status_stream_clients", &[], 1.0);
+    let subscriber = Subscriber(data.metrics.clone());
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+    keepalive.reset();
+    let events = stream::unfold(
+        (receiver, keepalive, subscriber),
+        |(mut receiver, mut keepalive, subscriber)| async move {
+            let chunk = tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => sse(event.name(), &event),
+                    Err(RecvError::Lagged(missed)) => sse("lagged", &serde_json::json!({ "missed": missed })),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keepalive.tick() => Bytes::from_static(b": keepalive\n\n"),
+            };
+            Some((Ok::<_, actix_web::Error>(chunk), (receiver, keepalive, subscriber)))
+        },
+    );
+    let body = futures_util::StreamExt::chain(stream::once(async move { Ok(snapshot) }), events);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
+}
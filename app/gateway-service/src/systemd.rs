@@ -0,0 +1,75 @@
+use actix_web::web;
+use std::env;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::AppState;
+
+/// Send a state change to the service manager named by `NOTIFY_SOCKET`,
+/// e.g. `READY=1`; nothing happens when the gateway runs outside systemd
+/// (or under a unit that is not `Type=notify`).
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET").ok().filter(|path| !path.is_empty()) {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send(&path, state) {
+        warn!("Cannot notify systemd of {}: {}", state.lines().next().unwrap_or_default(), e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    // A leading `@` names a socket in the abstract namespace
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send(path: &str, state: &str) -> std::io::Result<()> {
+    std::os::unix::net::UnixDatagram::unbound()?.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Interval the unit's `WatchdogSec=` asks to be pinged within, when it is
+// meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Tell systemd the gateway is serving, then ping its watchdog at half the
+/// `WatchdogSec=` interval. The pings come from the main event loop, so a
+/// stalled loop misses them and systemd restarts the gateway.
+pub async fn serve(data: web::Data<AppState>) {
+    notify(&format!("READY=1\nSTATUS=Serving on port {}", data.config.load().port));
+    let interval = match watchdog_interval() {
+        Some(interval) => interval / 2,
+        None => return,
+    };
+    info!("Pinging the systemd watchdog every {:?}", interval);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        debug!("Pinging the systemd watchdog");
+        notify("WATCHDOG=1");
+    }
+}
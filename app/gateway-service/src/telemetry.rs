@@ -0,0 +1,399 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer as _, TracerProvider as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::{Protocol, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, Sampler, SdkTracer, SdkTracerProvider, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+use tracing::{info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::parse_env;
+use crate::metrics::Metrics;
+use crate::AppState;
+
+#[derive(Clone)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint; spans are only propagated, not exported,
+    /// when unset
+    pub endpoint: Option<String>,
+    /// Extra headers for the collector, e.g. an API key
+    pub headers: Vec<(String, String)>,
+    pub service_name: String,
+    /// Share of new traces recorded; incoming `traceparent` decisions are kept
+    pub sample_ratio: f64,
+    pub export_interval: Duration,
+    /// Spans held for export at most; further spans are dropped
+    pub max_queue: usize,
+}
+
+// Collector headers may carry credentials
+impl fmt::Debug for TelemetryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<_> = self.headers.iter().map(|(name, _)| name).collect();
+        f.debug_struct("TelemetryConfig")
+            .field("endpoint", &self.endpoint)
+            .field("headers", &headers)
+            .field("service_name", &self.service_name)
+            .field("sample_ratio", &self.sample_ratio)
+            .field("export_interval", &self.export_interval)
+            .field("max_queue", &self.max_queue)
+            .finish()
+    }
+}
+
+impl TelemetryConfig {
+    /// Read the standard `OTEL_*` variables: `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`
+    /// (or `OTEL_EXPORTER_OTLP_ENDPOINT` plus `/v1/traces`),
+    /// `OTEL_EXPORTER_OTLP_HEADERS`, `OTEL_SERVICE_NAME`,
+    /// `OTEL_TRACES_SAMPLER_ARG`, `OTEL_BSP_SCHEDULE_DELAY` (ms) and
+    /// `OTEL_BSP_MAX_QUEUE_SIZE`.
+    pub fn from_env() -> Self {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let endpoint = var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .or_else(|| var("OTEL_EXPORTER_OTLP_ENDPOINT").map(|base| format!("{}/v1/traces", base.trim_end_matches('/'))));
+        let headers = var("OTEL_EXPORTER_OTLP_HEADERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        TelemetryConfig {
+            endpoint,
+            headers,
+            service_name: var("OTEL_SERVICE_NAME").unwrap_or_else(|| "gateway-service".to_string()),
+            sample_ratio: parse_env("OTEL_TRACES_SAMPLER_ARG")
+                .unwrap_or(1.0_f64)
+                .clamp(0.0, 1.0),
+            export_interval: Duration::from_millis(parse_env("OTEL_BSP_SCHEDULE_DELAY").unwrap_or(5000)),
+            max_queue: parse_env("OTEL_BSP_MAX_QUEUE_SIZE").unwrap_or(2048),
+        }
+    }
+}
+
+// Counts exported and failed spans for `gateway_trace_spans_total`
+#[derive(Debug)]
+struct CountingExporter {
+    inner: opentelemetry_otlp::SpanExporter,
+    metrics: Arc<Metrics>,
+}
+
+impl SpanExporter for CountingExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let spans = batch.len() as u64;
+        let result = self.inner.export(batch).await;
+        let outcome = match &result {
+            Ok(()) => "exported",
+            Err(e) => {
+                warn!("Failed to export {} spans: {}", spans, e);
+                "failed"
+            }
+        };
+        self.metrics.incr("gateway_trace_spans_total", &[("outcome", outcome)], spans);
+        result
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+}
+
+/// Creates spans through the OpenTelemetry SDK and batches the sampled ones
+/// for export over OTLP/HTTP.
+pub struct Tracer {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+impl Tracer {
+    pub fn new(config: TelemetryConfig, metrics: Arc<Metrics>) -> Self {
+        let mut builder = SdkTracerProvider::builder()
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+            .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build());
+        if let Some(endpoint) = &config.endpoint {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpJson)
+                .with_endpoint(endpoint.as_str())
+                .with_timeout(Duration::from_secs(10))
+                .with_headers(config.headers.iter().cloned().collect())
+                .build();
+            match exporter {
+                Ok(inner) => {
+                    info!("Exporting traces to {} as {}", endpoint, config.service_name);
+                    let batch = BatchConfigBuilder::default()
+                        .with_scheduled_delay(config.export_interval)
+                        .with_max_queue_size(config.max_queue)
+                        .build();
+                    let exporter = CountingExporter { inner, metrics };
+                    builder = builder.with_span_processor(BatchSpanProcessor::builder(exporter).with_batch_config(batch).build());
+                }
+                Err(e) => warn!("Cannot export traces to {}: {}", endpoint, e),
+            }
+        }
+        let provider = builder.build();
+        Tracer {
+            tracer: provider.tracer("gateway-service"),
+            provider,
+        }
+    }
+
+    /// Export the spans still queued; called once the server has stopped.
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!("Failed to flush trace spans: {}", e);
+        }
+    }
+}
+
+// Reads `traceparent` and `tracestate` off the request
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+// Span of the request being served, kept in its extensions
+#[derive(Clone)]
+struct ActiveSpan(Context);
+
+/// Trace id of the request, for correlating its log lines.
+pub fn trace_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<ActiveSpan>().map(|span| span.0.span().span_context().trace_id().to_string())
+}
+
+/// An outgoing call to an upstream, timed from creation until `finish`.
+pub struct ClientSpan {
+    context: Context,
+}
+
+impl ClientSpan {
+    /// Start a span for calling `service`, a child of the request's span.
+    pub fn start(tracer: &Tracer, req: &HttpRequest, service: &str, method: &str, url: &str) -> Self {
+        let parent = req.extensions().get::<ActiveSpan>().map(|span| span.0.clone()).unwrap_or_default();
+        let span = tracer
+            .tracer
+            .span_builder(format!("{} {}", method, service))
+            .with_kind(SpanKind::Client)
+            .with_attributes([
+                KeyValue::new("peer.service", service.to_string()),
+                KeyValue::new("http.request.method", method.to_string()),
+                KeyValue::new("url.full", url.to_string()),
+            ])
+            .start_with_context(&tracer.tracer, &parent);
+        ClientSpan {
+            context: parent.with_span(span),
+        }
+    }
+
+    /// `traceparent` to send with the call, making the upstream's spans
+    /// children of this one.
+    pub fn traceparent(&self) -> String {
+        let mut headers = HashMap::new();
+        TraceContextPropagator::new().inject_context(&self.context, &mut headers);
+        headers.remove("traceparent").unwrap_or_default()
+    }
+
+    /// End the span with the upstream's status, or `None` when the call failed.
+    pub fn finish(self, status: Option<u16>) {
+        let span = self.context.span();
+        if let Some(status) = status {
+            span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status)));
+        }
+        if status.is_none_or(|status| status >= 500) {
+            span.set_status(Status::error(status.map_or("request failed".to_string(), |status| status.to_string())));
+        }
+        span.end();
+    }
+}
+
+/// Middleware opening a server span for every request, continuing the
+/// caller's trace when it sends a `traceparent`. The trace id is returned in
+/// `X-Trace-Id`.
+pub struct Tracing {
+    tracer: Arc<Tracer>,
+}
+
+impl Tracing {
+    pub fn new(tracer: Arc<Tracer>) -> Self {
+        Tracing { tracer }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Tracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TracingMiddleware {
+            service: Rc::new(service),
+            tracer: self.tracer.clone(),
+        }))
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: Rc<S>,
+    tracer: Arc<Tracer>,
+}
+
+impl<S, B> Service<ServiceRequest> for TracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let parent = TraceContextPropagator::new().extract(&RequestHeaders(req.headers()));
+
+        let method = req.method().to_string();
+        let route = req.match_pattern();
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", method.clone()),
+            KeyValue::new("url.path", req.path().to_string()),
+        ];
+        if let Some(route) = &route {
+            attributes.push(KeyValue::new("http.route", route.clone()));
+        }
+        let data = req.app_data::<web::Data<AppState>>();
+        if let Some(ip) = data.and_then(|data| data.ip_filter.client_ip(req.request())) {
+            attributes.push(KeyValue::new("client.address", ip.to_string()));
+        }
+        let span = self
+            .tracer
+            .tracer
+            .span_builder(format!("{} {}", method, route.as_deref().unwrap_or("unmatched")))
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer.tracer, &parent);
+        let context = parent.with_span(span);
+        req.extensions_mut().insert(ActiveSpan(context.clone()));
+
+        Box::pin(async move {
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            let span = context.span();
+            span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+            span.end();
+
+            let mut res = result?;
+            if let Ok(value) = HeaderValue::from_str(&span.span_context().trace_id().to_string()) {
+                res.headers_mut().insert(HeaderName::from_static("x-trace-id"), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn tracer(sample_ratio: f64) -> Arc<Tracer> {
+        let config = TelemetryConfig {
+            endpoint: None,
+            headers: Vec::new(),
+            service_name: "gateway-service".to_string(),
+            sample_ratio,
+            export_interval: Duration::from_secs(5),
+            max_queue: 2048,
+        };
+        Arc::new(Tracer::new(config, Arc::new(Metrics::new())))
+    }
+
+    // Answers with the `traceparent` an upstream call would carry
+    async fn upstream_traceparent(tracer: Arc<Tracer>, traceparent: Option<&str>) -> (String, String) {
+        let handler_tracer = tracer.clone();
+        let app = init_service(App::new().wrap(Tracing::new(tracer)).route(
+            "/api/chat/rooms",
+            web::get().to(move |req: HttpRequest| {
+                let span = ClientSpan::start(&handler_tracer, &req, "chat", "GET", "http://chat/rooms");
+                let traceparent = span.traceparent();
+                span.finish(Some(200));
+                async move { HttpResponse::Ok().body(traceparent) }
+            }),
+        ))
+        .await;
+        let mut request = TestRequest::get().uri("/api/chat/rooms");
+        if let Some(traceparent) = traceparent {
+            request = request.insert_header(("traceparent", traceparent));
+        }
+        let res = call_service(&app, request.to_request()).await;
+        let trace_id = res.headers().get("x-trace-id").unwrap().to_str().unwrap().to_string();
+        (trace_id, String::from_utf8(read_body(res).await.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn incoming_traces_are_continued() {
+        let (trace_id, outgoing) = upstream_traceparent(tracer(0.0), Some(PARENT)).await;
+        assert_eq!(trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        let parts: Vec<&str> = outgoing.split('-').collect();
+        assert_eq!(parts[..2], ["00", "4bf92f3577b34da6a3ce929d0e0e4736"]);
+        assert_ne!(parts[2], "00f067aa0ba902b7");
+        // The caller's sampling decision wins over the local ratio
+        assert_eq!(parts[3], "01");
+    }
+
+    #[actix_web::test]
+    async fn invalid_traceparents_start_a_new_trace() {
+        for traceparent in ["00-00000000000000000000000000000000-00f067aa0ba902b7-01", "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", "garbage"] {
+            let (trace_id, outgoing) = upstream_traceparent(tracer(1.0), Some(traceparent)).await;
+            assert_eq!(trace_id.len(), 32);
+            assert!(!traceparent.contains(&trace_id), "{}", traceparent);
+            assert!(outgoing.starts_with(&format!("00-{}-", trace_id)), "{}", outgoing);
+        }
+    }
+
+    #[actix_web::test]
+    async fn new_traces_follow_the_sample_ratio() {
+        let (_, sampled) = upstream_traceparent(tracer(1.0), None).await;
+        assert!(sampled.ends_with("-01"), "{}", sampled);
+        // Unsampled traces are still propagated
+        let (trace_id, unsampled) = upstream_traceparent(tracer(0.0), None).await;
+        assert!(unsampled.starts_with(&format!("00-{}-", trace_id)) && unsampled.ends_with("-00"), "{}", unsampled);
+    }
+}
@@ -0,0 +1,304 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::config::parse_env;
+
+/// HTTPS served by the gateway itself, next to the plain listener on `PORT`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain and private key
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    /// Port the HTTPS listener binds
+    pub port: u16,
+    /// Port clients reach HTTPS on, used in redirects; differs from `port`
+    /// when a port mapping sits in between
+    pub public_port: u16,
+    /// Whether the plain listener redirects to HTTPS instead of serving
+    pub redirect_http: bool,
+    /// How often the certificate and key files are checked for changes;
+    /// zero leaves reloads to SIGHUP
+    pub watch_interval: Duration,
+}
+
+impl TlsConfig {
+    /// Read `TLS_CERT_PATH`, `TLS_KEY_PATH`, `HTTPS_PORT` (8443 by default),
+    /// `HTTPS_PUBLIC_PORT`, `HTTP_REDIRECT` (on by default) and
+    /// `TLS_WATCH_SECONDS` (30 by default).
+    pub fn from_env() -> Self {
+        let port = parse_env("HTTPS_PORT").unwrap_or(8443);
+        TlsConfig {
+            cert_path: env::var("TLS_CERT_PATH").ok().filter(|p| !p.is_empty()),
+            key_path: env::var("TLS_KEY_PATH").ok().filter(|p| !p.is_empty()),
+            port,
+            public_port: parse_env("HTTPS_PUBLIC_PORT").unwrap_or(port),
+            redirect_http: env::var("HTTP_REDIRECT").map(|v| v != "false" && v != "0").unwrap_or(true),
+            watch_interval: Duration::from_secs(parse_env("TLS_WATCH_SECONDS").unwrap_or(30)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+
+    // The certificate chain and key, checked to belong together
+    fn certified_key(&self) -> Result<CertifiedKey, String> {
+        let (cert_path, key_path) = match (&self.cert_path, &self.key_path) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Err("TLS_CERT_PATH and TLS_KEY_PATH are both needed".to_string()),
+        };
+        let open = |path: &str| File::open(path).map(BufReader::new).map_err(|e| format!("cannot read {}: {}", path, e));
+        let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut open(cert_path)?)
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("invalid certificate in {}: {}", cert_path, e))?;
+        if certs.is_empty() {
+            return Err(format!("no certificate in {}", cert_path));
+        }
+        let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut open(key_path)?)
+            .map_err(|e| format!("invalid private key in {}: {}", key_path, e))?
+            .ok_or_else(|| format!("no private key in {}", key_path))?;
+        CertifiedKey::from_der(certs, key, &rustls::crypto::ring::default_provider())
+            .map_err(|e| format!("{} does not match {}: {}", key_path, cert_path, e))
+    }
+
+    /// Build the HTTPS listener's configuration, serving the certificate
+    /// from the returned store so it can be replaced while running. ALPN
+    /// offers `h2` and `http/1.1`, which actix-web adds when it listens.
+    pub fn server_config(&self) -> Result<(ServerConfig, Arc<CertificateStore>), String> {
+        let store = Arc::new(CertificateStore {
+            current: RwLock::new(Arc::new(self.certified_key()?)),
+        });
+        // Explicit, as the actix TLS integration may bring another provider in
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(store.clone());
+        Ok((config, store))
+    }
+
+    // Modification times of the certificate and key files
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        let modified = |path: &Option<String>| path.as_ref().and_then(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok());
+        [modified(&self.cert_path), modified(&self.key_path)]
+    }
+}
+
+/// Certificate the HTTPS listener presents, swapped in place on reload:
+/// new handshakes get the new one, open connections keep theirs.
+#[derive(Debug)]
+pub struct CertificateStore {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl CertificateStore {
+    /// Load the files again; on any error the current certificate stays.
+    pub fn reload(&self, tls: &TlsConfig) -> Result<(), String> {
+        let key = tls.certified_key()?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+fn log_reload(trigger: &str, result: Result<(), String>) {
+    match result {
+        Ok(()) => info!("Reloaded the TLS certificate on {}", trigger),
+        Err(e) => warn!("Keeping the current TLS certificate, reload on {} failed: {}", trigger, e),
+    }
+}
+
+/// Reload the certificate whenever its file or the key's changes, as when
+/// cert-manager or a mounted secret renews them. Both files are read
+/// together, so a renewal caught between writing the two only succeeds on
+/// the next check.
+pub async fn watch_certificates(tls: TlsConfig, store: Arc<CertificateStore>) {
+    if tls.watch_interval.is_zero() {
+        return;
+    }
+    let mut last = tls.modified();
+    let mut ticker = tokio::time::interval(tls.watch_interval);
+    loop {
+        ticker.tick().await;
+        let current = tls.modified();
+        if current == last {
+            continue;
+        }
+        let result = store.reload(&tls);
+        // A failed reload is retried on the next check
+        if result.is_ok() {
+            last = current;
+        }
+        log_reload("file change", result);
+    }
+}
+
+/// Reload the certificate on SIGHUP, alongside the configuration.
+#[cfg(unix)]
+pub async fn reload_on_hangup(tls: TlsConfig, store: Arc<CertificateStore>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, the TLS certificate reloads only on file changes: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log_reload("SIGHUP", store.reload(&tls));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Certificate and key files, removed when dropped
+    struct Files {
+        tls: TlsConfig,
+    }
+
+    impl Files {
+        fn new(name: &str) -> Self {
+            let path = |kind: &str| -> PathBuf { env::temp_dir().join(format!("gateway-{}-{}.{}.pem", std::process::id(), name, kind)) };
+            let mut tls = TlsConfig::from_env();
+            tls.cert_path = Some(path("cert").to_string_lossy().into_owned());
+            tls.key_path = Some(path("key").to_string_lossy().into_owned());
+            tls.watch_interval = Duration::from_millis(10);
+            Files { tls }
+        }
+
+        // Write a new self-signed certificate and its key; the certificate's DER
+        fn issue(&self) -> Vec<u8> {
+            let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            fs::write(self.tls.cert_path.as_ref().unwrap(), issued.cert.pem()).unwrap();
+            fs::write(self.tls.key_path.as_ref().unwrap(), issued.signing_key.serialize_pem()).unwrap();
+            issued.cert.der().to_vec()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            for path in [&self.tls.cert_path, &self.tls.key_path].into_iter().flatten() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn served(store: &CertificateStore) -> Vec<u8> {
+        store.current.read().unwrap().cert[0].to_vec()
+    }
+
+    #[test]
+    fn reload_serves_the_renewed_certificate() {
+        let files = Files::new("renew");
+        let first = files.issue();
+        let (_, store) = files.tls.server_config().unwrap();
+        assert_eq!(served(&store), first);
+
+        let renewed = files.issue();
+        store.reload(&files.tls).unwrap();
+        assert_eq!(served(&store), renewed);
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_current_certificate() {
+        let files = Files::new("broken");
+        let first = files.issue();
+        let (_, store) = files.tls.server_config().unwrap();
+
+        // A renewal caught halfway: the new certificate with the old key
+        let key = fs::read(files.tls.key_path.as_ref().unwrap()).unwrap();
+        files.issue();
+        fs::write(files.tls.key_path.as_ref().unwrap(), key).unwrap();
+        let error = store.reload(&files.tls).unwrap_err();
+        assert!(error.contains("does not match"), "{}", error);
+
+        fs::write(files.tls.cert_path.as_ref().unwrap(), "not a certificate").unwrap();
+        assert!(store.reload(&files.tls).is_err());
+        fs::remove_file(files.tls.key_path.as_ref().unwrap()).unwrap();
+        assert!(store.reload(&files.tls).is_err());
+        assert_eq!(served(&store), first);
+    }
+
+    // Protocol the listener agrees on with a client offering `offered`
+    fn negotiate(port: u16, certificate: Vec<u8>, offered: &[u8]) -> Option<Vec<u8>> {
+        use rustls::{ClientConfig, ClientConnection, RootCertStore};
+        use std::net::TcpStream;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(certificate)).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![offered.to_vec()];
+        let mut conn = ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket).unwrap();
+        }
+        conn.alpn_protocol().map(<[u8]>::to_vec)
+    }
+
+    #[actix_web::test]
+    async fn the_listener_negotiates_http2_and_http1() {
+        use actix_web::{App, HttpServer};
+
+        let files = Files::new("alpn");
+        let certificate = files.issue();
+        let (config, _) = files.tls.server_config().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(App::new)
+            .workers(1)
+            .listen_rustls_0_23(listener, config)
+            .unwrap()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        for protocol in [&b"h2"[..], b"http/1.1"] {
+            let certificate = certificate.clone();
+            let negotiated = tokio::task::spawn_blocking(move || negotiate(port, certificate, protocol)).await.unwrap();
+            assert_eq!(negotiated.as_deref(), Some(protocol));
+        }
+        handle.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_picked_up() {
+        let files = Files::new("watch");
+        files.issue();
+        let (_, store) = files.tls.server_config().unwrap();
+        let watcher = tokio::spawn(watch_certificates(files.tls.clone(), store.clone()));
+
+        // Let the watcher note the current files before they change
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let renewed = files.issue();
+        let picked_up = async {
+            while served(&store) != renewed {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(5), picked_up).await;
+        watcher.abort();
+        assert!(result.is_ok(), "the renewed certificate was not served");
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// One upstream service and the instances it can be reached at.
+///
+/// Service URLs may list several instances separated by commas, e.g.
+/// `MESSAGE_SERVICE_URL=http://message-a:3003,http://message-b:3003`.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    instances: Vec<String>,
+    cursor: AtomicUsize,
+}
+
+impl Upstream {
+    pub fn new(name: &str, urls: &str) -> Self {
+        let instances = urls
+            .split(',')
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        Upstream {
+            name: name.to_string(),
+            instances,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn instances(&self) -> &[String] {
+        &self.instances
+    }
+
+    /// Next instance in round-robin order among those `available` accepts,
+    /// falling back to plain round-robin when none of them are available.
+    pub fn pick(&self, available: impl Fn(&str) -> bool) -> &str {
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        (0..self.instances.len())
+            .map(|offset| self.instances[(start + offset) % self.instances.len()].as_str())
+            .find(|instance| available(instance))
+            .unwrap_or(&self.instances[start % self.instances.len()])
+    }
+
+    /// An available instance other than `exclude`, if there is one.
+    pub fn pick_other(&self, exclude: &str, available: impl Fn(&str) -> bool) -> Option<&str> {
+        let start = self.cursor.load(Ordering::Relaxed);
+        (0..self.instances.len())
+            .map(|offset| self.instances[(start + offset) % self.instances.len()].as_str())
+            .find(|instance| *instance != exclude && available(instance))
+    }
+}
+
+/// All upstream services known to the gateway, keyed by service name.
+#[derive(Debug)]
+pub struct Upstreams {
+    services: Vec<Upstream>,
+}
+
+impl Upstreams {
+    pub fn new(services: &[(String, String)]) -> Self {
+        Upstreams {
+            services: services.iter().map(|(name, urls)| Upstream::new(name, urls)).collect(),
+        }
+    }
+
+    pub fn get(&self, service: &str) -> Option<&Upstream> {
+        self.services.iter().find(|upstream| upstream.name == service)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Upstream> {
+        self.services.iter()
+    }
+}
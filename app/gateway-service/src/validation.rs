@@ -1,6 +1,9 @@
 use serde::Deserialize;
+use serde_json::{json, Value};
 use validator::Validate;
 
+use crate::openapi::ApiSchema;
+
 #[derive(Debug, Deserialize, Validate)]
 pub struct AuthRequest {
     #[validate(length(min = 3, max = 50))]
@@ -10,6 +13,22 @@ pub struct AuthRequest {
     pub password: String,
 }
 
+impl ApiSchema for AuthRequest {
+    const NAME: &'static str = "AuthRequest";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["username", "password"],
+            "properties": {
+                "username": { "type": "string", "minLength": 3, "maxLength": 50 },
+                "password": { "type": "string", "minLength": 6, "format": "password" },
+            },
+        })
+    }
+}
+
+/// Registration; the password's strength is checked by the password policy.
 #[derive(Debug, Deserialize, Validate)]
 pub struct CreateUserRequest {
     #[validate(length(min = 3, max = 50))]
@@ -18,10 +37,49 @@ pub struct CreateUserRequest {
     #[validate(email)]
     pub email: String,
     
-    #[validate(length(min = 6))]
     pub password: String,
 }
 
+impl ApiSchema for CreateUserRequest {
+    const NAME: &'static str = "CreateUserRequest";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["username", "email", "password"],
+            "properties": {
+                "username": { "type": "string", "minLength": 3, "maxLength": 50 },
+                "email": { "type": "string", "format": "email" },
+                "password": { "type": "string", "format": "password" },
+            },
+        })
+    }
+}
+
+/// Body of `PUT /api/users/change-password`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+impl ApiSchema for ChangePasswordRequest {
+    const NAME: &'static str = "ChangePasswordRequest";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["currentPassword", "newPassword"],
+            "properties": {
+                "currentPassword": { "type": "string", "format": "password" },
+                "newPassword": { "type": "string", "format": "password" },
+            },
+        })
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Validate)]
 pub struct CreateRoomRequest {
     #[validate(length(min = 1, max = 100))]
@@ -33,6 +91,23 @@ pub struct CreateRoomRequest {
     pub is_private: bool,
 }
 
+impl ApiSchema for CreateRoomRequest {
+    const NAME: &'static str = "CreateRoomRequest";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["name", "is_private"],
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 100 },
+                "description": { "type": "string", "maxLength": 500, "nullable": true },
+                "is_private": { "type": "boolean" },
+            },
+        })
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Validate)]
 pub struct SendMessageRequest {
     #[validate(length(min = 1, max = 1000))]
@@ -42,6 +117,22 @@ pub struct SendMessageRequest {
     pub sender_id: u32,
 }
 
+impl ApiSchema for SendMessageRequest {
+    const NAME: &'static str = "SendMessageRequest";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["content", "room_id", "sender_id"],
+            "properties": {
+                "content": { "type": "string", "minLength": 1, "maxLength": 1000 },
+                "room_id": { "type": "integer", "format": "int32", "minimum": 0 },
+                "sender_id": { "type": "integer", "format": "int32", "minimum": 0 },
+            },
+        })
+    }
+}
+
 pub fn validate_input<T: Validate>(input: &T) -> Result<(), validator::ValidationErrors> {
     input.validate()
 }
\ No newline at end of file
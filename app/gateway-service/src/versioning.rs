@@ -0,0 +1,283 @@
+use actix_web::body::{self, BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Uri;
+use actix_web::{web, Error, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use tracing::{debug, info, warn};
+
+use crate::cache::{bind, load, substitute};
+use crate::config::parse_env;
+use crate::AppState;
+
+/// A v2 endpoint served differently from its v1 counterpart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionRoute {
+    /// Methods it applies to; any method when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Path below `/api/v2`, with `{name}` segments and a trailing `*` as in
+    /// `CACHE_ROUTES`
+    pub path: String,
+    /// Gateway path served instead, e.g. `/api/messages/rooms/{id}/messages`
+    pub target: String,
+    /// Field JSON responses are wrapped in, e.g. `data`
+    #[serde(default)]
+    pub envelope: Option<String>,
+}
+
+impl VersionRoute {
+    fn matches(&self, method: &str, path: &str) -> Option<String> {
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return None;
+        }
+        bind(&self.path, path).map(|params| substitute(&self.target, &params))
+    }
+}
+
+/// How `/api/v1/*` and `/api/v2/*` map onto the gateway's routes. Both
+/// serve the unversioned `/api/*` routes unless a v2 route says otherwise;
+/// v1 answers carry the deprecation headers once it is being retired.
+#[derive(Debug, Clone, Default)]
+pub struct VersioningConfig {
+    pub v2_routes: Vec<VersionRoute>,
+    /// Sent as `Deprecation` on v1 answers
+    pub v1_deprecated_at: Option<DateTime<Utc>>,
+    /// Sent as `Sunset` on v1 answers; v1 answers 410 from then on
+    pub v1_sunset_at: Option<DateTime<Utc>>,
+    /// Migration guide linked from v1 answers
+    pub v1_deprecation_link: Option<String>,
+}
+
+impl VersioningConfig {
+    /// Read `API_V2_ROUTES`, a JSON array given inline or in the file named
+    /// by `API_V2_ROUTES_FILE`, `API_V1_DEPRECATED_AT` and
+    /// `API_V1_SUNSET_AT`, RFC 3339 times, and `API_V1_DEPRECATION_LINK`.
+    pub fn from_env() -> Self {
+        let config = VersioningConfig {
+            v2_routes: load("API_V2_ROUTES"),
+            v1_deprecated_at: parse_env("API_V1_DEPRECATED_AT"),
+            v1_sunset_at: parse_env("API_V1_SUNSET_AT"),
+            v1_deprecation_link: env::var("API_V1_DEPRECATION_LINK").ok().filter(|link| !link.is_empty()),
+        };
+        if !config.v2_routes.is_empty() {
+            info!("Serving {} API v2 route(s) differently from v1", config.v2_routes.len());
+        }
+        if let Some(sunset) = config.v1_sunset_at {
+            info!("API v1 is sunset at {}", sunset.to_rfc3339());
+        }
+        config
+    }
+
+    /// Routes whose target could not be served.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for route in &self.v2_routes {
+            if !route.target.starts_with('/') || route.target.starts_with("/api/v1/") || route.target.starts_with("/api/v2/") {
+                problems.push(format!("API_V2_ROUTES: {} targets '{}', not an unversioned gateway path", route.path, route.target));
+            }
+            if route.envelope.as_deref() == Some("") {
+                problems.push(format!("API_V2_ROUTES: {} has an empty envelope", route.path));
+            }
+        }
+        if let (Some(deprecated), Some(sunset)) = (self.v1_deprecated_at, self.v1_sunset_at) {
+            if sunset < deprecated {
+                problems.push("API_V1_SUNSET_AT: comes before API_V1_DEPRECATED_AT".to_string());
+            }
+        }
+        problems
+    }
+
+    fn deprecation_headers(&self, path: &str) -> Vec<(header::HeaderName, String)> {
+        let mut headers = Vec::new();
+        if let Some(deprecated) = self.v1_deprecated_at {
+            headers.push((header::HeaderName::from_static("deprecation"), format!("@{}", deprecated.timestamp())));
+        }
+        if let Some(sunset) = self.v1_sunset_at {
+            headers.push((header::HeaderName::from_static("sunset"), http_date(sunset)));
+        }
+        if headers.is_empty() {
+            return headers;
+        }
+        if let Some(link) = &self.v1_deprecation_link {
+            headers.push((header::LINK, format!("<{}>; rel=\"deprecation\"", link)));
+        }
+        headers.push((header::LINK, format!("</api/v2/{}>; rel=\"successor-version\"", path)));
+        headers
+    }
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Point the request at `path`, keeping its query, so routing and every
+// inner middleware see the unversioned route
+fn rewrite(req: &mut ServiceRequest, path: &str) -> bool {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return false,
+    };
+    let uri = match Uri::from_parts(parts) {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    true
+}
+
+// Wrap a JSON response in `{ envelope: ... }`, leaving others as they are
+async fn wrap<B: MessageBody + 'static>(res: ServiceResponse<B>, envelope: &str) -> Result<ServiceResponse<BoxBody>, Error> {
+    let json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"));
+    if !json {
+        return Ok(res.map_into_boxed_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let bytes = body::to_bytes(body)
+        .await
+        .map_err(|e| {
+            let e: Box<dyn std::error::Error> = e.into();
+            actix_web::error::ErrorBadGateway(e.to_string())
+        })?;
+    let value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes)))),
+    };
+    let mut wrapped = serde_json::Map::new();
+    wrapped.insert(envelope.to_string(), value);
+    let bytes = serde_json::to_vec(&Value::Object(wrapped)).unwrap_or_default();
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes))))
+}
+
+enum Version {
+    V1,
+    V2 { envelope: Option<String> },
+}
+
+/// Middleware serving `/api/v1/*` and `/api/v2/*` from the unversioned
+/// routes. It runs before everything else, so auth, policies and metrics
+/// apply to the route actually served.
+pub struct ApiVersioning;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiVersioning
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiVersioningMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiVersioningMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ApiVersioningMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiVersioningMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let versioned = match req.path().strip_prefix("/api/v1/") {
+                Some(rest) => Some((true, rest.to_string())),
+                None => req.path().strip_prefix("/api/v2/").map(|rest| (false, rest.to_string())),
+            };
+            let (v1, rest) = match versioned {
+                Some(versioned) => versioned,
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) => data.clone(),
+                None => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+            let config = data.config.load();
+            let versioning = &config.versioning;
+
+            let (version, target) = if v1 {
+                data.metrics.incr("gateway_api_version_requests_total", &[("version", "v1")], 1);
+                if versioning.v1_sunset_at.is_some_and(|sunset| sunset <= Utc::now()) {
+                    let mut response = HttpResponse::Gone();
+                    for (name, value) in versioning.deprecation_headers(&rest) {
+                        response.append_header((name, value));
+                    }
+                    let response = response.json(json!({
+                        "error": "API v1 has been retired",
+                        "code": "api_version_sunset",
+                        "successor": format!("/api/v2/{}", rest),
+                    }));
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+                (Version::V1, format!("/api/{}", rest))
+            } else {
+                data.metrics.incr("gateway_api_version_requests_total", &[("version", "v2")], 1);
+                let path = format!("/{}", rest);
+                let method = req.method().as_str().to_string();
+                match versioning.v2_routes.iter().find_map(|route| route.matches(&method, &path).map(|target| (route, target))) {
+                    Some((route, target)) => {
+                        debug!("API v2 {} {} served by {}", method, path, target);
+                        (Version::V2 { envelope: route.envelope.clone() }, target)
+                    }
+                    None => (Version::V2 { envelope: None }, format!("/api/{}", rest)),
+                }
+            };
+            if !rewrite(&mut req, &target) {
+                warn!("Cannot serve {} from {}", req.path(), target);
+                let response = HttpResponse::BadRequest().json(json!({
+                    "error": "Invalid request path",
+                    "code": "invalid_path",
+                }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let res = service.call(req).await?;
+            match version {
+                Version::V1 => {
+                    let mut res = res.map_into_left_body();
+                    for (name, value) in versioning.deprecation_headers(&rest) {
+                        if let Ok(value) = HeaderValue::from_str(&value) {
+                            res.headers_mut().append(name, value);
+                        }
+                    }
+                    Ok(res)
+                }
+                Version::V2 { envelope: Some(envelope) } => wrap(res, &envelope).await.map(|res| res.map_into_right_body()),
+                Version::V2 { envelope: None } => Ok(res.map_into_left_body()),
+            }
+        })
+    }
+}
@@ -0,0 +1,476 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use tracing::{error, warn};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::config::{invalid, parse_env};
+use crate::metrics::Metrics;
+use crate::rbac::{route_matches, routed_path};
+use crate::AppState;
+
+/// Whether a matching rule refuses the request or only reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Block,
+    Log,
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Block => "block",
+            Mode::Log => "log",
+        }
+    }
+}
+
+/// Part of the request a rule inspects.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "target", rename_all = "snake_case")]
+pub enum Check {
+    /// The percent-decoded path
+    Path { pattern: String },
+    /// The percent-decoded query string
+    Query { pattern: String },
+    Header { header: String, pattern: String },
+    /// Every string and key of a JSON body, or the raw text of other bodies
+    Body { pattern: String },
+    /// JSON bodies holding an array longer than `max_items`
+    JsonArray { max_items: usize },
+}
+
+fn any_path() -> String {
+    "*".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WafRule {
+    pub name: String,
+    /// Requests the rule applies to (exact, or a prefix when it ends in `*`)
+    #[serde(default = "any_path")]
+    pub path: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(flatten)]
+    pub check: Check,
+    /// Overrides `WAF_MODE` for this rule
+    pub mode: Option<Mode>,
+}
+
+impl WafRule {
+    fn new(name: &str, check: Check) -> Self {
+        WafRule {
+            name: name.to_string(),
+            path: any_path(),
+            methods: Vec::new(),
+            check,
+            mode: None,
+        }
+    }
+}
+
+const SQLI: &str = r#"(?i)(\bunion\b[\s/*]+(all[\s/*]+)?select\b|\b(or|and)\b\s+['"]?\w+['"]?\s*=\s*['"]?\w+['"]?\s*(--|#|/\*)|'\s*(or|and)\s+'?\d+'?\s*=\s*'?\d+|;\s*(drop|truncate|alter)\s+table\b|\b(sleep|benchmark|pg_sleep)\s*\(\s*\d+|'\s*(--|#))"#;
+const XSS: &str = r#"(?i)(<\s*script\b|javascript\s*:|<[^>]+\son[a-z]+\s*=|<\s*iframe\b|<\s*object\b)"#;
+const TRAVERSAL: &str = r#"(\.\./|\.\.\\)"#;
+
+fn default_rules() -> Vec<WafRule> {
+    let pattern = |pattern: &str| pattern.to_string();
+    vec![
+        WafRule::new("path_traversal", Check::Path { pattern: pattern(TRAVERSAL) }),
+        WafRule::new("sqli_query", Check::Query { pattern: pattern(SQLI) }),
+        WafRule::new("sqli_body", Check::Body { pattern: pattern(SQLI) }),
+        WafRule::new("xss_query", Check::Query { pattern: pattern(XSS) }),
+        WafRule::new("xss_body", Check::Body { pattern: pattern(XSS) }),
+        WafRule::new("oversized_array", Check::JsonArray { max_items: 1000 }),
+    ]
+}
+
+#[derive(Debug, Clone)]
+pub struct WafConfig {
+    pub enabled: bool,
+    /// Default mode of the rules
+    pub mode: Mode,
+    pub rules: Vec<WafRule>,
+    /// Larger bodies, and bodies of unknown length, are not inspected
+    pub max_body_bytes: usize,
+}
+
+impl WafConfig {
+    /// Built-in SQLi, XSS, traversal and array size rules (unless
+    /// `WAF_DEFAULT_RULES=false`) followed by the rules in `WAF_RULES`.
+    pub fn from_env() -> Self {
+        let mut rules = match env::var("WAF_DEFAULT_RULES").as_deref() {
+            Ok("false") | Ok("0") => Vec::new(),
+            _ => default_rules(),
+        };
+        if let Ok(raw) = env::var("WAF_RULES") {
+            match serde_json::from_str::<Vec<WafRule>>(&raw) {
+                Ok(custom) => rules.extend(custom),
+                Err(e) => {
+                    error!("Invalid WAF_RULES ({}), using the built-in rules only", e);
+                    invalid("WAF_RULES", e);
+                }
+            }
+        }
+        WafConfig {
+            enabled: env::var("WAF_ENABLED").map(|v| v != "false" && v != "0").unwrap_or(true),
+            mode: match env::var("WAF_MODE").as_deref() {
+                Ok("log") => Mode::Log,
+                _ => Mode::Block,
+            },
+            rules,
+            max_body_bytes: parse_env("WAF_MAX_BODY_BYTES").unwrap_or(1024 * 1024),
+        }
+    }
+}
+
+enum Matcher {
+    Path(Regex),
+    Query(Regex),
+    Header(header::HeaderName, Regex),
+    Body(Regex),
+    JsonArray(usize),
+}
+
+struct CompiledRule {
+    name: String,
+    path: String,
+    methods: Vec<String>,
+    mode: Mode,
+    matcher: Matcher,
+}
+
+impl CompiledRule {
+    fn inspects_body(&self) -> bool {
+        matches!(self.matcher, Matcher::Body(_) | Matcher::JsonArray(_))
+    }
+}
+
+// Decode %XX escapes, and `+` in query strings, so encoded payloads match
+pub fn percent_decode(raw: &str, plus_as_space: bool) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let escaped = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = escaped {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                decoded.push(b'%');
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_matches(value: &Value, regex: &Regex) -> bool {
+    match value {
+        Value::String(s) => regex.is_match(s),
+        Value::Array(items) => items.iter().any(|item| json_matches(item, regex)),
+        Value::Object(map) => map.iter().any(|(key, item)| regex.is_match(key) || json_matches(item, regex)),
+        _ => false,
+    }
+}
+
+fn longest_array(value: &Value) -> usize {
+    match value {
+        Value::Array(items) => items.iter().map(longest_array).max().unwrap_or(0).max(items.len()),
+        Value::Object(map) => map.values().map(longest_array).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// A request body as the rules see it
+enum Body {
+    Json(Value),
+    Text(String),
+}
+
+/// Signature rules evaluated against requests before they are routed.
+pub struct Waf {
+    config: WafConfig,
+    rules: Vec<CompiledRule>,
+    metrics: Arc<Metrics>,
+}
+
+impl Waf {
+    pub fn new(config: WafConfig, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let regex = |name: &str, pattern: &str| Regex::new(pattern).map_err(|e| format!("rule '{}': {}", name, e));
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let matcher = match &rule.check {
+                    Check::Path { pattern } => Matcher::Path(regex(&rule.name, pattern)?),
+                    Check::Query { pattern } => Matcher::Query(regex(&rule.name, pattern)?),
+                    Check::Header { header, pattern } => Matcher::Header(
+                        header::HeaderName::try_from(header.as_str()).map_err(|e| format!("rule '{}': {}", rule.name, e))?,
+                        regex(&rule.name, pattern)?,
+                    ),
+                    Check::Body { pattern } => Matcher::Body(regex(&rule.name, pattern)?),
+                    Check::JsonArray { max_items } => Matcher::JsonArray(*max_items),
+                };
+                Ok(CompiledRule {
+                    name: rule.name.clone(),
+                    path: rule.path.clone(),
+                    methods: rule.methods.clone(),
+                    mode: rule.mode.unwrap_or(config.mode),
+                    matcher,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Waf { config, rules, metrics })
+    }
+
+    // Buffer the body for inspection and put it back for the handler
+    async fn read_body(&self, req: &mut ServiceRequest) -> Option<Body> {
+        let length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())?;
+        if length == 0 || length > self.config.max_body_bytes {
+            return None;
+        }
+        let mut payload = req.take_payload();
+        let mut bytes = web::BytesMut::with_capacity(length);
+        while let Some(chunk) = payload.next().await {
+            match chunk {
+                Ok(chunk) => bytes.extend_from_slice(&chunk),
+                Err(e) => {
+                    warn!("WAF could not read the request body: {}", e);
+                    break;
+                }
+            }
+        }
+        let bytes = bytes.freeze();
+        req.set_payload(Payload::from(bytes.clone()));
+        Some(match serde_json::from_slice(&bytes) {
+            Ok(json) => Body::Json(json),
+            Err(_) => Body::Text(String::from_utf8_lossy(&bytes).into_owned()),
+        })
+    }
+
+    /// Rules the request trips, in order.
+    async fn evaluate(&self, req: &mut ServiceRequest) -> Vec<(&CompiledRule, String)> {
+        let path = percent_decode(req.path(), false);
+        let query = percent_decode(req.query_string(), true);
+        let rules: Vec<&CompiledRule> = self
+            .rules
+            .iter()
+            .filter(|rule| route_matches(&rule.path, &rule.methods, req.method().as_str(), routed_path(req.request())))
+            .collect();
+        let body = match rules.iter().any(|rule| rule.inspects_body()) {
+            true => self.read_body(req).await,
+            false => None,
+        };
+
+        let mut hits = Vec::new();
+        for rule in rules {
+            let hit = match (&rule.matcher, &body) {
+                (Matcher::Path(regex), _) => regex.find(&path).map(|m| m.as_str().to_string()),
+                (Matcher::Query(regex), _) => regex.find(&query).map(|m| m.as_str().to_string()),
+                (Matcher::Header(name, regex), _) => req
+                    .headers()
+                    .get_all(name)
+                    .filter_map(|v| v.to_str().ok())
+                    .find(|v| regex.is_match(v))
+                    .map(|_| name.to_string()),
+                (Matcher::Body(regex), Some(Body::Json(json))) => json_matches(json, regex).then(|| "json body".to_string()),
+                (Matcher::Body(regex), Some(Body::Text(text))) => regex.find(text).map(|m| m.as_str().to_string()),
+                (Matcher::JsonArray(max), Some(Body::Json(json))) => {
+                    let longest = longest_array(json);
+                    (longest > *max).then(|| format!("array of {} items", longest))
+                }
+                _ => None,
+            };
+            if let Some(hit) = hit {
+                hits.push((rule, hit));
+            }
+        }
+        hits
+    }
+}
+
+/// Middleware applying the WAF rules. Runs after the IP filter and rate
+/// limits so rejected traffic is not inspected.
+pub struct WafGuard;
+
+impl<S, B> Transform<S, ServiceRequest> for WafGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = WafGuardMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(WafGuardMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct WafGuardMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for WafGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let data = match req.app_data::<web::Data<AppState>>() {
+                Some(data) if data.waf.config.enabled => data.clone(),
+                _ => return service.call(req).await.map(|res| res.map_into_left_body()),
+            };
+
+            let mut blocked = None;
+            for (rule, hit) in data.waf.evaluate(&mut req).await {
+                warn!(
+                    "WAF rule {} matched {} {} ({}), mode {}",
+                    rule.name,
+                    req.method(),
+                    req.path(),
+                    hit,
+                    rule.mode.as_str()
+                );
+                data.waf
+                    .metrics
+                    .incr("gateway_waf_matches_total", &[("rule", &rule.name), ("mode", rule.mode.as_str())], 1);
+                if rule.mode == Mode::Block && blocked.is_none() {
+                    blocked = Some(rule.name.clone());
+                }
+            }
+
+            match blocked {
+                None => service.call(req).await.map(|res| res.map_into_left_body()),
+                Some(rule) => {
+                    let response = HttpResponse::Forbidden().json(serde_json::json!({
+                        "error": "Request blocked",
+                        "code": "waf_blocked",
+                        "rule": rule
+                    }));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn waf(rules: Vec<WafRule>) -> Waf {
+        let config = WafConfig {
+            enabled: true,
+            mode: Mode::Block,
+            rules,
+            max_body_bytes: 64 * 1024,
+        };
+        Waf::new(config, Arc::new(Metrics::new())).unwrap()
+    }
+
+    // Names and modes of the rules a request trips
+    async fn tripped(waf: &Waf, req: TestRequest) -> Vec<(String, Mode)> {
+        let mut req = req.to_srv_request();
+        waf.evaluate(&mut req).await.into_iter().map(|(rule, _)| (rule.name.clone(), rule.mode)).collect()
+    }
+
+    fn blocked(name: &str) -> Vec<(String, Mode)> {
+        vec![(name.to_string(), Mode::Block)]
+    }
+
+    #[actix_web::test]
+    async fn default_rules_block_encoded_attacks() {
+        let waf = waf(default_rules());
+        let traversal = TestRequest::get().uri("/api/chat/%2e%2e/%2e%2e/etc/passwd");
+        assert_eq!(tripped(&waf, traversal).await, blocked("path_traversal"));
+        let sqli = TestRequest::get().uri("/api/users/search?q=1%27+OR+%271%27%3D%271");
+        assert_eq!(tripped(&waf, sqli).await, blocked("sqli_query"));
+        let xss = TestRequest::post()
+            .uri("/api/messages/messages")
+            .set_json(serde_json::json!({ "content": "<script>alert(1)</script>" }));
+        assert_eq!(tripped(&waf, xss).await, blocked("xss_body"));
+        let array = TestRequest::post()
+            .uri("/api/chat/rooms")
+            .set_payload(format!("{{\"members\": [{}]}}", vec!["1"; 1001].join(",")))
+            .insert_header((header::CONTENT_TYPE, "application/json"));
+        assert_eq!(tripped(&waf, array).await, blocked("oversized_array"));
+    }
+
+    #[actix_web::test]
+    async fn default_rules_allow_ordinary_requests() {
+        let waf = waf(default_rules());
+        let search = TestRequest::get().uri("/api/users/search?q=o%27brien+and+friends");
+        assert!(tripped(&waf, search).await.is_empty());
+        let message = TestRequest::post()
+            .uri("/api/messages/messages")
+            .set_json(serde_json::json!({ "content": "Let's meet at 5, or 6 -- whichever", "room_id": 1 }));
+        assert!(tripped(&waf, message).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rules_apply_to_their_routes_and_mode() {
+        let rule = WafRule {
+            path: "/api/chat/*".to_string(),
+            methods: vec!["POST".to_string()],
+            mode: Some(Mode::Log),
+            ..WafRule::new("bot", Check::Header { header: "User-Agent".to_string(), pattern: "(?i)sqlmap".to_string() })
+        };
+        let waf = waf(vec![rule]);
+        let request = |method: TestRequest, uri: &str| method.uri(uri).insert_header(("User-Agent", "sqlmap/1.7"));
+        assert_eq!(tripped(&waf, request(TestRequest::post(), "/api/chat/rooms")).await, vec![("bot".to_string(), Mode::Log)]);
+        assert!(tripped(&waf, request(TestRequest::get(), "/api/chat/rooms")).await.is_empty());
+        assert!(tripped(&waf, request(TestRequest::post(), "/api/users/me")).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn inspected_bodies_reach_the_handler() {
+        let waf = waf(default_rules());
+        let mut req = TestRequest::post()
+            .uri("/api/messages/messages")
+            .set_json(serde_json::json!({ "content": "hello" }))
+            .to_srv_request();
+        assert!(waf.evaluate(&mut req).await.is_empty());
+        let mut payload = req.take_payload();
+        let body = payload.next().await.unwrap().unwrap();
+        assert_eq!(body.as_ref(), br#"{"content":"hello"}"#);
+    }
+}
@@ -0,0 +1,258 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use tracing::{error, warn};
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::auth::Claims;
+use crate::config::invalid;
+use crate::AppState;
+
+fn default_signature_header() -> String {
+    "X-Webhook-Signature".to_string()
+}
+
+fn default_timestamp_header() -> String {
+    "X-Webhook-Timestamp".to_string()
+}
+
+fn default_tolerance() -> u64 {
+    300
+}
+
+/// A third party allowed to call `/webhooks/{name}`. Calls are signed with
+/// hex HMAC-SHA256 over `{timestamp}.{body}` using the shared `secret`.
+#[derive(Clone, Deserialize)]
+pub struct Integration {
+    pub name: String,
+    pub secret: String,
+    /// Upstream service and path verified payloads are forwarded to
+    pub service: String,
+    pub path: String,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    #[serde(default = "default_timestamp_header")]
+    pub timestamp_header: String,
+    /// Maximum age, either way, of the signed timestamp
+    #[serde(default = "default_tolerance")]
+    pub tolerance_seconds: u64,
+}
+
+// Shared secrets stay out of the startup config log
+impl fmt::Debug for Integration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Integration")
+            .field("name", &self.name)
+            .field("service", &self.service)
+            .field("path", &self.path)
+            .field("signature_header", &self.signature_header)
+            .field("timestamp_header", &self.timestamp_header)
+            .field("tolerance_seconds", &self.tolerance_seconds)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub integrations: Vec<Integration>,
+}
+
+impl WebhookConfig {
+    /// Load the integrations from `WEBHOOK_INTEGRATIONS`, a JSON array.
+    pub fn from_env() -> Self {
+        let integrations = match env::var("WEBHOOK_INTEGRATIONS") {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                error!("Invalid WEBHOOK_INTEGRATIONS ({}), no webhooks accepted", e);
+                invalid("WEBHOOK_INTEGRATIONS", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        WebhookConfig { integrations }
+    }
+
+    fn get(&self, name: &str) -> Option<&Integration> {
+        self.integrations.iter().find(|i| i.name == name)
+    }
+}
+
+/// Signatures seen within their tolerance window, so a captured delivery
+/// cannot be replayed.
+#[derive(Default)]
+pub struct ReplayCache {
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl ReplayCache {
+    // Record a signature until `expires_at`; false if it was already used
+    fn first_use(&self, id: String, now: i64, expires_at: i64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expiry| *expiry > now);
+        seen.insert(id, expires_at).is_none()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn rejected(data: &AppState, integration: &str, outcome: &str, status: StatusCode, error: &str) -> HttpResponse {
+    warn!("Rejected webhook for {}: {}", integration, error);
+    data.metrics.incr("gateway_webhooks_total", &[("integration", integration), ("outcome", outcome)], 1);
+    HttpResponse::build(status).json(serde_json::json!({ "error": error }))
+}
+
+// Why a delivery was refused: the metric outcome, status and error message
+type Rejection = (&'static str, StatusCode, &'static str);
+
+/// Check the timestamp and signature of a delivery at `now`, returning both.
+fn verify(integration: &Integration, req: &HttpRequest, body: &[u8], now: i64) -> Result<(i64, Vec<u8>), Rejection> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+
+    let timestamp = header(&integration.timestamp_header)
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .ok_or(("missing_timestamp", StatusCode::UNAUTHORIZED, "Missing or invalid webhook timestamp"))?;
+    if (now - timestamp).abs() > integration.tolerance_seconds as i64 {
+        return Err(("stale", StatusCode::UNAUTHORIZED, "Webhook timestamp outside the allowed window"));
+    }
+
+    // Providers commonly prefix the digest with its algorithm
+    let signature = header(&integration.signature_header)
+        .map(|v| v.trim().trim_start_matches("sha256="))
+        .and_then(decode_hex)
+        .ok_or(("missing_signature", StatusCode::UNAUTHORIZED, "Missing or malformed webhook signature"))?;
+    let mut signed = format!("{}.", timestamp).into_bytes();
+    signed.extend_from_slice(body);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, integration.secret.as_bytes());
+    hmac::verify(&key, &signed, &signature)
+        .map_err(|_| ("bad_signature", StatusCode::UNAUTHORIZED, "Invalid webhook signature"))?;
+    Ok((timestamp, signature))
+}
+
+/// Verify a signed webhook delivery and forward it to the integration's
+/// upstream, identified to it as `webhook:<name>`.
+pub async fn receive(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (name,) = path.into_inner();
+    let integration = match data.config.load().webhooks.get(&name) {
+        Some(integration) => integration.clone(),
+        None => return Ok(rejected(&data, "unknown", "unknown_integration", StatusCode::NOT_FOUND, "Unknown webhook integration")),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let (timestamp, signature) = match verify(&integration, &req, &body, now) {
+        Ok(verified) => verified,
+        Err((outcome, status, error)) => return Ok(rejected(&data, &name, outcome, status, error)),
+    };
+
+    let id: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+    let expires_at = timestamp + integration.tolerance_seconds as i64;
+    if !data.webhook_replays.first_use(format!("{}:{}", name, id), now, expires_at) {
+        return Ok(rejected(&data, &name, "replayed", StatusCode::CONFLICT, "Webhook delivery already received"));
+    }
+
+    data.metrics.incr("gateway_webhooks_total", &[("integration", &name), ("outcome", "accepted")], 1);
+    let sub = format!("webhook:{}", name);
+    crate::inflight::set_user(&req, &sub, &name);
+    crate::identity::remember(
+        &req,
+        &Claims {
+            sub,
+            username: name,
+            exp: 0,
+            jti: None,
+            roles: Vec::new(),
+            role: None,
+            scope: None,
+            scopes: Vec::new(),
+            sid: None,
+            did: None,
+            typ: None,
+        },
+    );
+    // Upstreams take JSON; other payloads are passed on as a JSON string
+    let payload = serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+    crate::proxy_request(&data, &req, &integration.service, &integration.path, "POST", Some(payload)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const NOW: i64 = 1_700_000_000;
+    const BODY: &[u8] = br#"{"event":"payment.succeeded"}"#;
+
+    fn integration() -> Integration {
+        serde_json::from_value(serde_json::json!({
+            "name": "billing",
+            "secret": "whsec",
+            "service": "user",
+            "path": "/billing/events",
+        }))
+        .unwrap()
+    }
+
+    fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut signed = format!("{}.", timestamp).into_bytes();
+        signed.extend_from_slice(body);
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), &signed);
+        tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn delivery(timestamp: i64, signature: &str) -> HttpRequest {
+        TestRequest::post()
+            .insert_header(("X-Webhook-Timestamp", timestamp.to_string()))
+            .insert_header(("X-Webhook-Signature", signature))
+            .to_http_request()
+    }
+
+    fn outcome(result: Result<(i64, Vec<u8>), Rejection>) -> &'static str {
+        result.map(|_| "accepted").unwrap_or_else(|(outcome, _, _)| outcome)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature_with_or_without_prefix() {
+        let signature = sign("whsec", NOW - 10, BODY);
+        assert_eq!(outcome(verify(&integration(), &delivery(NOW - 10, &signature), BODY, NOW)), "accepted");
+        let prefixed = format!("sha256={}", signature);
+        assert_eq!(outcome(verify(&integration(), &delivery(NOW - 10, &prefixed), BODY, NOW)), "accepted");
+    }
+
+    #[test]
+    fn rejects_bad_signatures_and_stale_timestamps() {
+        let integration = integration();
+        let forged = sign("other", NOW, BODY);
+        assert_eq!(outcome(verify(&integration, &delivery(NOW, &forged), BODY, NOW)), "bad_signature");
+        let signature = sign("whsec", NOW, BODY);
+        assert_eq!(outcome(verify(&integration, &delivery(NOW, &signature), b"{}", NOW)), "bad_signature");
+        assert_eq!(outcome(verify(&integration, &delivery(NOW, "not hex"), BODY, NOW)), "missing_signature");
+        let old = NOW - 301;
+        assert_eq!(outcome(verify(&integration, &delivery(old, &sign("whsec", old, BODY)), BODY, NOW)), "stale");
+        let untimed = TestRequest::post().insert_header(("X-Webhook-Signature", signature)).to_http_request();
+        assert_eq!(outcome(verify(&integration, &untimed, BODY, NOW)), "missing_timestamp");
+    }
+
+    #[test]
+    fn refuses_a_replayed_delivery_until_it_expires() {
+        let replays = ReplayCache::default();
+        assert!(replays.first_use("billing:ab".to_string(), NOW, NOW + 300));
+        assert!(!replays.first_use("billing:ab".to_string(), NOW + 1, NOW + 300));
+        assert!(replays.first_use("billing:cd".to_string(), NOW + 1, NOW + 301));
+        assert!(replays.first_use("billing:ab".to_string(), NOW + 300, NOW + 600));
+    }
+}
@@ -0,0 +1,642 @@
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_http::ws::Item;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{web, HttpRequest, HttpResponse};
+use awc::ws::{CloseCode, CloseReason, Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures_util::{SinkExt, Stream, StreamExt};
+use tracing::{debug, info, warn};
+use std::collections::VecDeque;
+use std::env;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::mpsc;
+
+use crate::config::parse_env;
+use crate::events::{self, EventError, EventValidation};
+use crate::metrics::Metrics;
+
+// What to do when a client's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowStrategy {
+    /// Discard the oldest queued message, with all its fragments, to make room
+    DropOldest,
+    /// Close the connection with a hint telling the client where to resume from
+    Disconnect,
+}
+
+impl OverflowStrategy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "drop_oldest" | "drop-oldest" => Some(OverflowStrategy::DropOldest),
+            "disconnect" => Some(OverflowStrategy::Disconnect),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            OverflowStrategy::DropOldest => "drop_oldest",
+            OverflowStrategy::Disconnect => "disconnect",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    pub outbound_queue_capacity: usize,
+    pub overflow_strategy: OverflowStrategy,
+    pub event_validation: EventValidation,
+}
+
+impl WsConfig {
+    pub fn from_env() -> Self {
+        WsConfig {
+            outbound_queue_capacity: parse_env("WS_OUTBOUND_QUEUE_CAPACITY")
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(256),
+            overflow_strategy: env::var("WS_OVERFLOW_STRATEGY")
+                .ok()
+                .and_then(|v| OverflowStrategy::parse(&v))
+                .unwrap_or(OverflowStrategy::DropOldest),
+            event_validation: EventValidation::from_env(),
+        }
+    }
+}
+
+// Where a queued frame sits in the message stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    /// First (or only) frame of a text or binary message
+    Start,
+    /// Continuation of the message started before it
+    Fragment,
+    /// Pong or close, which may sit between fragments
+    Control,
+}
+
+struct Queued {
+    frame: Bytes,
+    part: Part,
+    pong: bool,
+}
+
+struct QueueState {
+    frames: VecDeque<Queued>,
+    closed: bool,
+    waker: Option<Waker>,
+    last_delivered: chrono::DateTime<chrono::Utc>,
+    /// The last queued message still awaits its final fragment
+    open_message: bool,
+    /// That message was dropped, so its remaining fragments are too
+    discarding: bool,
+    /// Whole messages waiting for the open message to finish
+    held: Vec<Queued>,
+}
+
+/// Bounded queue of encoded frames waiting to be written to one client.
+///
+/// The response body pulls from this queue only when the connection can accept
+/// more data, so a slow reader fills the queue instead of gateway memory.
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    strategy: OverflowStrategy,
+    metrics: Arc<Metrics>,
+}
+
+impl OutboundQueue {
+    pub fn new(config: &WsConfig, metrics: Arc<Metrics>) -> Arc<Self> {
+        metrics.gauge_add("gateway_ws_connections", &[], 1.0);
+        Arc::new(OutboundQueue {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::new(),
+                closed: false,
+                waker: None,
+                last_delivered: chrono::Utc::now(),
+                open_message: false,
+                discarding: false,
+                held: Vec::new(),
+            }),
+            capacity: config.outbound_queue_capacity,
+            strategy: config.overflow_strategy,
+            metrics,
+        })
+    }
+
+    /// Queue a data frame, applying the overflow strategy when the queue is full.
+    /// Messages are dropped whole, never leaving a client half a fragmented one.
+    /// Returns false once the connection is closing and nothing more will be sent.
+    pub fn push(&self, msg: Message) -> bool {
+        let (part, last) = match &msg {
+            Message::Continuation(Item::FirstText(_) | Item::FirstBinary(_)) => (Part::Start, false),
+            Message::Continuation(Item::Continue(_)) => (Part::Fragment, false),
+            Message::Continuation(Item::Last(_)) => (Part::Fragment, true),
+            _ => (Part::Start, true),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+
+        let discard = |state: &QueueState| part == Part::Fragment && state.discarding;
+        if !discard(&state) && state.frames.len() + state.held.len() >= self.capacity {
+            let made_room = match self.strategy {
+                OverflowStrategy::DropOldest => self.drop_oldest_message(&mut state),
+                OverflowStrategy::Disconnect => false,
+            };
+            if !made_room {
+                self.disconnect(&mut state);
+                return false;
+            }
+        }
+
+        let queued = Queued { frame: encode(msg), part, pong: false };
+        if discard(&state) {
+            self.record_drops(1);
+        } else if part == Part::Start && last && state.open_message {
+            // Gateway events must not land between another message's fragments
+            state.held.push(queued);
+            return true;
+        } else {
+            self.enqueue(&mut state, queued);
+        }
+        if part == Part::Fragment && last {
+            state.discarding = false;
+            for queued in std::mem::take(&mut state.held) {
+                self.enqueue(&mut state, queued);
+            }
+        }
+        state.open_message = !last;
+        true
+    }
+
+    /// Queue a pong answering a client ping. Only the latest matters, so it
+    /// replaces one still queued; a client pinging while its queue is full
+    /// is disconnected.
+    pub fn push_pong(&self, payload: Bytes) {
+        let frame = encode(Message::Pong(payload));
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        if let Some(queued) = state.frames.iter_mut().find(|queued| queued.pong) {
+            queued.frame = frame;
+            return;
+        }
+        if state.frames.len() + state.held.len() >= self.capacity {
+            self.disconnect(&mut state);
+            return;
+        }
+        self.enqueue(&mut state, Queued { frame, part: Part::Control, pong: true });
+    }
+
+    /// Queue a close frame and stop accepting further frames.
+    pub fn close(&self, reason: Option<CloseReason>) {
+        let frame = encode(Message::Close(reason));
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            self.enqueue(&mut state, Queued { frame, part: Part::Control, pong: false });
+            state.closed = true;
+        }
+    }
+
+    // Remove the oldest message whose first frame is still queued, with its
+    // fragments; control frames stay. False when there is none to remove.
+    fn drop_oldest_message(&self, state: &mut QueueState) -> bool {
+        let Some(start) = state.frames.iter().position(|queued| queued.part == Part::Start) else {
+            return false;
+        };
+        let end = state
+            .frames
+            .iter()
+            .skip(start + 1)
+            .position(|queued| queued.part == Part::Start)
+            .map_or(state.frames.len(), |offset| start + 1 + offset);
+        if end == state.frames.len() && state.open_message {
+            state.discarding = true;
+        }
+
+        let mut index = start;
+        let mut dropped = 0;
+        for _ in start..end {
+            if state.frames[index].part == Part::Control {
+                index += 1;
+            } else {
+                state.frames.remove(index);
+                dropped += 1;
+            }
+        }
+        self.record_drops(dropped);
+        self.metrics.gauge_add("gateway_ws_outbound_queue_depth", &[], -(dropped as f64));
+        true
+    }
+
+    // Discard everything queued and close with a hint telling the client
+    // where to resume from
+    fn disconnect(&self, state: &mut QueueState) {
+        let queued = state.frames.len();
+        let dropped = queued + state.held.len();
+        state.frames.clear();
+        state.held.clear();
+        self.record_drops(dropped as u64);
+        self.metrics.gauge_add("gateway_ws_outbound_queue_depth", &[], -(queued as f64));
+        self.metrics.incr("gateway_ws_slow_consumer_disconnects_total", &[], 1);
+
+        warn!("Disconnecting slow WebSocket consumer after {} queued frames", dropped);
+        let hint = serde_json::json!({
+            "reason": "slow_consumer",
+            "resume_since": state.last_delivered.to_rfc3339(),
+        });
+        let reason = CloseReason {
+            code: CloseCode::Again,
+            description: Some(hint.to_string()),
+        };
+        let frame = encode(Message::Close(Some(reason)));
+        self.enqueue(state, Queued { frame, part: Part::Control, pong: false });
+        state.closed = true;
+    }
+
+    fn enqueue(&self, state: &mut QueueState, queued: Queued) {
+        state.frames.push_back(queued);
+        self.metrics.gauge_add("gateway_ws_outbound_queue_depth", &[], 1.0);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn record_drops(&self, count: u64) {
+        self.metrics.incr(
+            "gateway_ws_outbound_dropped_frames_total",
+            &[("strategy", self.strategy.as_str())],
+            count,
+        );
+    }
+}
+
+fn encode(msg: Message) -> Bytes {
+    let mut buf = BytesMut::new();
+    // Encoding a server-side frame cannot fail
+    let _ = Codec::new().encode(msg, &mut buf);
+    buf.freeze()
+}
+
+/// Response body stream draining an `OutboundQueue`.
+pub struct OutboundStream {
+    queue: Arc<OutboundQueue>,
+}
+
+impl Stream for OutboundStream {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.queue.state.lock().unwrap();
+        if let Some(queued) = state.frames.pop_front() {
+            state.last_delivered = chrono::Utc::now();
+            self.queue.metrics.gauge_add("gateway_ws_outbound_queue_depth", &[], -1.0);
+            return Poll::Ready(Some(Ok(queued.frame)));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for OutboundStream {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        let remaining = state.frames.len();
+        state.frames.clear();
+        state.closed = true;
+        let metrics = &self.queue.metrics;
+        metrics.gauge_add("gateway_ws_outbound_queue_depth", &[], -(remaining as f64));
+        metrics.gauge_add("gateway_ws_connections", &[], -1.0);
+    }
+}
+
+/// Upgrade the client connection and proxy frames to and from `upstream_url`.
+pub async fn proxy(
+    req: &HttpRequest,
+    payload: web::Payload,
+    upstream_url: &str,
+    headers: &[(&'static str, String)],
+    config: &WsConfig,
+    metrics: Arc<Metrics>,
+) -> actix_web::Result<HttpResponse> {
+    let mut response = actix_web_actors::ws::handshake(req)?;
+
+    let mut request = awc::Client::default().ws(upstream_url);
+    for (name, value) in headers {
+        request = request.header(*name, value.as_str());
+    }
+    let upstream = match request.connect().await {
+        Ok((_, framed)) => framed,
+        Err(e) => {
+            warn!("WebSocket upstream connection to {} failed: {}", upstream_url, e);
+            return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                "error": "Service temporarily unavailable",
+                "details": e.to_string()
+            })));
+        }
+    };
+
+    info!("Proxying WebSocket connection to: {}", upstream_url);
+
+    let queue = OutboundQueue::new(config, metrics);
+    let (to_upstream, from_client) = mpsc::channel(32);
+
+    let validation = config.event_validation;
+    actix_web::rt::spawn(pump_upstream(upstream, from_client, queue.clone(), validation));
+    actix_web::rt::spawn(pump_client(payload, to_upstream, queue.clone(), validation));
+
+    Ok(response.streaming(OutboundStream { queue }))
+}
+
+// Forward upstream frames into the client's queue and client frames upstream
+async fn pump_upstream(
+    mut upstream: Framed<BoxedSocket, Codec>,
+    mut from_client: mpsc::Receiver<Message>,
+    queue: Arc<OutboundQueue>,
+    validation: EventValidation,
+) {
+    loop {
+        tokio::select! {
+            frame = upstream.next() => {
+                let msg = match frame {
+                    Some(Ok(Frame::Text(text))) => match String::from_utf8(text.to_vec()) {
+                        Ok(text) => Message::Text(text.into()),
+                        Err(_) => {
+                            queue.close(Some(CloseCode::Invalid.into()));
+                            break;
+                        }
+                    },
+                    Some(Ok(Frame::Binary(bytes))) => Message::Binary(bytes),
+                    Some(Ok(Frame::Continuation(item))) => Message::Continuation(item),
+                    Some(Ok(Frame::Ping(bytes))) => {
+                        let _ = upstream.send(Message::Pong(bytes)).await;
+                        continue;
+                    }
+                    Some(Ok(Frame::Pong(_))) => continue,
+                    Some(Ok(Frame::Close(reason))) => {
+                        queue.close(reason);
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        warn!("WebSocket upstream protocol error: {}", e);
+                        queue.close(Some(CloseCode::Error.into()));
+                        break;
+                    }
+                    None => {
+                        queue.close(None);
+                        break;
+                    }
+                };
+
+                if let Message::Text(text) = &msg {
+                    let checked = check_event(validation, "upstream", text, &queue, |t| {
+                        events::parse_server_event(t).map(|event| event.name())
+                    });
+                    if checked.is_err() {
+                        continue;
+                    }
+                }
+
+                if !queue.push(msg) {
+                    let _ = upstream.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+            msg = from_client.recv() => match msg {
+                Some(msg) => {
+                    let closing = matches!(msg, Message::Close(_));
+                    if upstream.send(msg).await.is_err() {
+                        queue.close(Some(CloseCode::Error.into()));
+                        break;
+                    }
+                    if closing {
+                        break;
+                    }
+                }
+                None => {
+                    let _ = upstream.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Decode frames sent by the client and hand them to the upstream pump
+async fn pump_client(
+    mut payload: web::Payload,
+    to_upstream: mpsc::Sender<Message>,
+    queue: Arc<OutboundQueue>,
+    validation: EventValidation,
+) {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(bytes) => buf.extend_from_slice(&bytes),
+            Err(_) => break,
+        }
+
+        loop {
+            let frame = match codec.decode(&mut buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("WebSocket client protocol error: {}", e);
+                    queue.close(Some(CloseCode::Protocol.into()));
+                    return;
+                }
+            };
+
+            let msg = match frame {
+                Frame::Text(text) => match String::from_utf8(text.to_vec()) {
+                    Ok(text) => Message::Text(text.into()),
+                    Err(_) => {
+                        queue.close(Some(CloseCode::Invalid.into()));
+                        return;
+                    }
+                },
+                Frame::Binary(bytes) => Message::Binary(bytes),
+                Frame::Continuation(item) => Message::Continuation(item),
+                Frame::Ping(bytes) => {
+                    queue.push_pong(bytes);
+                    continue;
+                }
+                Frame::Pong(_) => continue,
+                Frame::Close(reason) => {
+                    let _ = to_upstream.send(Message::Close(reason.clone())).await;
+                    queue.close(reason);
+                    return;
+                }
+            };
+
+            if let Message::Text(text) = &msg {
+                let checked = check_event(validation, "client", text, &queue, |t| {
+                    events::parse_client_event(t).map(|event| event.name())
+                });
+                if let Err(error) = checked {
+                    if !queue.push(Message::Text(error.to_client_event().to_string().into())) {
+                        return;
+                    }
+                    continue;
+                }
+            }
+
+            if to_upstream.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Check a text frame against the event schema. Mismatches are counted and
+// logged; the error is returned only when the frame must not be forwarded.
+fn check_event(
+    validation: EventValidation,
+    direction: &str,
+    text: &str,
+    queue: &OutboundQueue,
+    parse: impl FnOnce(&str) -> Result<&'static str, EventError>,
+) -> Result<(), EventError> {
+    if validation == EventValidation::Off {
+        return Ok(());
+    }
+    let error = match parse(text) {
+        Ok(name) => {
+            queue
+                .metrics
+                .incr("gateway_ws_events_total", &[("direction", direction), ("type", name)], 1);
+            return Ok(());
+        }
+        Err(error) => error,
+    };
+    debug!("WebSocket {} event did not match the schema: {:?}", direction, error);
+    queue.metrics.incr(
+        "gateway_ws_schema_mismatches_total",
+        &[("direction", direction), ("reason", error.reason())],
+        1,
+    );
+    match validation {
+        EventValidation::Enforce => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize, strategy: OverflowStrategy) -> Arc<OutboundQueue> {
+        let config = WsConfig {
+            outbound_queue_capacity: capacity,
+            overflow_strategy: strategy,
+            event_validation: EventValidation::Off,
+        };
+        OutboundQueue::new(&config, Arc::new(Metrics::new()))
+    }
+
+    fn queued(queue: &OutboundQueue) -> Vec<Bytes> {
+        queue.state.lock().unwrap().frames.iter().map(|queued| queued.frame.clone()).collect()
+    }
+
+    fn text(text: &'static str) -> Message {
+        Message::Text(text.into())
+    }
+
+    fn fragment(item: fn(Bytes) -> Item, text: &'static str) -> Message {
+        Message::Continuation(item(Bytes::from_static(text.as_bytes())))
+    }
+
+    #[test]
+    fn drop_oldest_discards_a_whole_fragmented_message() {
+        let queue = queue(3, OverflowStrategy::DropOldest);
+        assert!(queue.push(fragment(Item::FirstText, "a")));
+        assert!(queue.push(fragment(Item::Continue, "b")));
+        assert!(queue.push(fragment(Item::Last, "c")));
+        assert!(queue.push(text("d")));
+        assert_eq!(queued(&queue), [encode(text("d"))]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_fragments_of_a_message_already_started() {
+        let queue = queue(3, OverflowStrategy::DropOldest);
+        queue.push(fragment(Item::FirstText, "a"));
+        queue.state.lock().unwrap().frames.pop_front();
+        queue.push(fragment(Item::Continue, "b"));
+        queue.push(fragment(Item::Last, "c"));
+        queue.push(text("d"));
+        assert!(queue.push(text("e")));
+        assert_eq!(
+            queued(&queue),
+            [encode(fragment(Item::Continue, "b")), encode(fragment(Item::Last, "c")), encode(text("e"))]
+        );
+    }
+
+    #[test]
+    fn drop_oldest_discards_the_rest_of_a_dropped_message() {
+        let queue = queue(2, OverflowStrategy::DropOldest);
+        queue.push(text("a"));
+        queue.push(fragment(Item::FirstText, "b"));
+        queue.push(fragment(Item::Continue, "c"));
+        assert!(queue.push(fragment(Item::Last, "d")));
+        assert!(queued(&queue).is_empty());
+        queue.push(text("e"));
+        assert_eq!(queued(&queue), [encode(text("e"))]);
+    }
+
+    #[test]
+    fn events_wait_for_the_open_message_to_finish() {
+        let queue = queue(8, OverflowStrategy::DropOldest);
+        queue.push(fragment(Item::FirstText, "a"));
+        queue.push(text("event"));
+        queue.push(fragment(Item::Last, "b"));
+        assert_eq!(
+            queued(&queue),
+            [encode(fragment(Item::FirstText, "a")), encode(fragment(Item::Last, "b")), encode(text("event"))]
+        );
+    }
+
+    #[test]
+    fn disconnect_closes_with_a_resume_hint() {
+        let queue = queue(1, OverflowStrategy::Disconnect);
+        assert!(queue.push(text("a")));
+        assert!(!queue.push(text("b")));
+        assert!(!queue.push(text("c")));
+        let frames = queued(&queue);
+        assert_eq!(frames.len(), 1);
+        let close = String::from_utf8_lossy(&frames[0]);
+        assert!(close.contains("slow_consumer") && close.contains("resume_since"), "{}", close);
+    }
+
+    #[test]
+    fn a_ping_flood_keeps_only_the_latest_pong() {
+        let queue = queue(2, OverflowStrategy::DropOldest);
+        for n in 0..1000u32 {
+            queue.push_pong(Bytes::from(n.to_string()));
+        }
+        assert_eq!(queued(&queue), [encode(Message::Pong(Bytes::from_static(b"999")))]);
+
+        queue.push(text("a"));
+        queue.push_pong(Bytes::from_static(b"again"));
+        assert_eq!(queued(&queue).len(), 2);
+        assert!(!queue.state.lock().unwrap().closed);
+    }
+
+    #[test]
+    fn pinging_with_a_full_queue_disconnects() {
+        let queue = queue(2, OverflowStrategy::DropOldest);
+        queue.push(text("a"));
+        queue.push(text("b"));
+        queue.push_pong(Bytes::from_static(b"ping"));
+        assert!(queue.state.lock().unwrap().closed);
+        assert!(!queue.push(text("c")));
+    }
+}